@@ -0,0 +1,42 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use sdk::concrete::{Output, Signature};
+use sdk::types::*;
+
+fn body(transactions: u32) -> Body<Signature, Output> {
+    Body {
+        coinbase: vec![],
+        transactions: (0..transactions)
+            .map(|i| Transaction {
+                version: TRANSACTION_VERSION,
+                inputs: vec![OutPoint::Regular {
+                    txid: [0; 32].into(),
+                    vout: i,
+                }],
+                signatures: vec![],
+                outputs: vec![Output {
+                    address: [1; 32].into(),
+                    value: Amount::from_sat(i as u64),
+                }],
+                withdrawal_outputs: vec![],
+                extra: vec![],
+            })
+            .collect(),
+    }
+}
+
+// Run with --features rayon to compare against the single threaded version.
+fn merkle_root(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merkle_root");
+    for transactions in [100, 1_000, 10_000] {
+        let body = body(transactions);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(transactions),
+            &body,
+            |b, body| b.iter(|| black_box(body).compute_merkle_root()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, merkle_root);
+criterion_main!(benches);
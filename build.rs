@@ -0,0 +1,84 @@
+fn main() {
+    // The gRPC service is generated from Rust definitions mirroring
+    // proto/sdk.proto, so building doesn't need protoc.
+    #[cfg(feature = "grpc")]
+    grpc::compile();
+}
+
+#[cfg(feature = "grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    // (rust name, route name, input, output, server streaming)
+    const METHODS: &[(&str, &str, &str, &str, bool)] = &[
+        (
+            "get_block_count",
+            "GetBlockCount",
+            "Empty",
+            "BlockCount",
+            false,
+        ),
+        ("get_block", "GetBlock", "BlockRequest", "Block", false),
+        (
+            "get_transaction",
+            "GetTransaction",
+            "TransactionRequest",
+            "Transaction",
+            false,
+        ),
+        ("get_balance", "GetBalance", "Empty", "Balance", false),
+        (
+            "get_new_address",
+            "GetNewAddress",
+            "Empty",
+            "NewAddress",
+            false,
+        ),
+        (
+            "send_to_address",
+            "SendToAddress",
+            "SendRequest",
+            "Txid",
+            false,
+        ),
+        (
+            "create_withdrawal",
+            "CreateWithdrawal",
+            "WithdrawalRequest",
+            "Txid",
+            false,
+        ),
+        (
+            "get_peg_status",
+            "GetPegStatus",
+            "Empty",
+            "PegStatus",
+            false,
+        ),
+        (
+            "subscribe_blocks",
+            "SubscribeBlocks",
+            "Empty",
+            "BlockNotification",
+            true,
+        ),
+    ];
+
+    pub fn compile() {
+        println!("cargo:rerun-if-changed=build.rs");
+        let mut service = Service::builder().name("Node").package("sdk");
+        for (name, route_name, input, output, server_streaming) in METHODS {
+            let mut method = Method::builder()
+                .name(*name)
+                .route_name(*route_name)
+                .input_type(format!("crate::grpc::proto::{}", input))
+                .output_type(format!("crate::grpc::proto::{}", output))
+                .codec_path("tonic::codec::ProstCodec");
+            if *server_streaming {
+                method = method.server_streaming();
+            }
+            service = service.method(method.build());
+        }
+        Builder::new().compile(&[service.build()]);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::ssm::{StatefulSSM, SSM};
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// Account based state kept next to the UTXO set, for sidechains that want
+// balances and nonces instead of coins. Transfers travel in
+// Transaction::extra and are signed by the sender, register validate_extra
+// with BlockChain::with_extra_validator so the UTXO layer accepts them.
+// Coins enter accounts through credit_deposits.
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: u64,
+    // Number of transfers sent from this account, the next transfer has to
+    // carry it so a signed transfer can't be replayed.
+    pub nonce: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub to: Address,
+    pub amount: u64,
+    pub nonce: u64,
+}
+
+impl Transfer {
+    pub fn to_extra(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("failed to serialize transfer")
+    }
+
+    // None for transactions that don't carry a transfer.
+    pub fn from_transaction<S, O>(transaction: &Transaction<S, O>) -> Result<Option<Self>, Error> {
+        if transaction.extra.is_empty() {
+            return Ok(None);
+        }
+        bincode::deserialize(&transaction.extra)
+            .map(Some)
+            .map_err(|_| Error::InvalidPayload)
+    }
+}
+
+pub fn validate_extra<S, O>(transaction: &Transaction<S, O>) -> Result<(), String> {
+    Transfer::from_transaction(transaction)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccountState {
+    // A BTreeMap so the state root doesn't depend on insertion order.
+    accounts: BTreeMap<Address, Account>,
+}
+
+impl AccountState {
+    pub fn get_account(&self, address: &Address) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    pub fn credit_deposits(&mut self, deposits: &DepositsChunk) {
+        for output in deposits.outputs.values() {
+            self.accounts.entry(output.address).or_default().balance += output.value.to_sat();
+        }
+    }
+
+    // Returns the sender of the transfer after checking its signature.
+    fn sender<S: Sig + Serialize + Clone, O: Serialize + Clone>(
+        transaction: &Transaction<S, O>,
+    ) -> Result<Address, Error> {
+        let signature = transaction
+            .signatures
+            .first()
+            .ok_or(Error::MissingSignature)?;
+        if !signature.is_valid(transaction.sighash()) {
+            return Err(Error::InvalidSignature);
+        }
+        Ok(signature.get_address())
+    }
+
+    // Applies the transfers in `body` to a copy of the touched accounts, so
+    // nothing is changed if any of them fails.
+    fn apply<S: Sig + Serialize + Clone, O: Serialize + Clone>(
+        &self,
+        body: &Body<S, O>,
+    ) -> Result<HashMap<Address, Account>, Error> {
+        let mut touched: HashMap<Address, Account> = HashMap::new();
+        for transaction in &body.transactions {
+            let transfer = match Transfer::from_transaction(transaction)? {
+                Some(transfer) => transfer,
+                None => continue,
+            };
+            let sender = Self::sender(transaction)?;
+            let mut from = *touched
+                .entry(sender)
+                .or_insert_with(|| self.get_account(&sender));
+            if transfer.nonce != from.nonce {
+                return Err(Error::WrongNonce {
+                    expected: from.nonce,
+                    got: transfer.nonce,
+                });
+            }
+            from.balance = from
+                .balance
+                .checked_sub(transfer.amount)
+                .ok_or(Error::InsufficientBalance(sender))?;
+            from.nonce += 1;
+            touched.insert(sender, from);
+            touched
+                .entry(transfer.to)
+                .or_insert_with(|| self.get_account(&transfer.to))
+                .balance += transfer.amount;
+        }
+        Ok(touched)
+    }
+}
+
+impl<S: Sig + Serialize + Clone, O: Serialize + Clone> SSM<S, O> for AccountState {
+    type Error = Error;
+
+    fn validate_transaction(&self, transaction: &Transaction<S, O>) -> Result<(), Error> {
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![transaction.clone()],
+        };
+        self.apply(&body).map(|_| ())
+    }
+
+    fn connect_block(&mut self, _header: &Header, body: &Body<S, O>) -> Result<(), Error> {
+        let touched = self.apply(body)?;
+        self.accounts.extend(touched);
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, _header: &Header, body: &Body<S, O>) -> Result<(), Error> {
+        for transaction in body.transactions.iter().rev() {
+            let transfer = match Transfer::from_transaction(transaction)? {
+                Some(transfer) => transfer,
+                None => continue,
+            };
+            let sender = Self::sender(transaction)?;
+            self.accounts.entry(transfer.to).or_default().balance -= transfer.amount;
+            let from = self.accounts.entry(sender).or_default();
+            from.balance += transfer.amount;
+            from.nonce -= 1;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Sig + Serialize + Clone, O: Serialize + Clone> StatefulSSM<S, O> for AccountState {
+    type Snapshot = AccountState;
+
+    fn snapshot(&self) -> AccountState {
+        self.clone()
+    }
+
+    fn restore(&mut self, snapshot: AccountState) {
+        *self = snapshot;
+    }
+
+    fn state_root(&self) -> Hash {
+        hash(&self.accounts)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("extra data is not a transfer")]
+    InvalidPayload,
+    #[error("transfer is not signed")]
+    MissingSignature,
+    #[error("transfer has an invalid signature")]
+    InvalidSignature,
+    #[error("transfer has nonce {got}, expected {expected}")]
+    WrongNonce { expected: u64, got: u64 },
+    #[error("account {0} doesn't have enough balance")]
+    InsufficientBalance(Address),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::concrete::{Output, Signature};
+
+    fn transfer(
+        keypair: &ed25519_dalek::Keypair,
+        transfer: &Transfer,
+    ) -> Transaction<Signature, Output> {
+        let transaction = Transaction {
+            version: TRANSACTION_VERSION,
+            inputs: vec![],
+            signatures: vec![],
+            outputs: vec![],
+            withdrawal_outputs: vec![],
+            extra: transfer.to_extra(),
+        };
+        Transaction {
+            signatures: vec![Signature::new(keypair, &transaction)],
+            ..transaction
+        }
+    }
+
+    #[test]
+    fn transfers_move_balances_and_bump_nonces() {
+        let keypair = ed25519_dalek::Keypair::generate(&mut rand::thread_rng());
+        let alice: Address = keypair.public.into();
+        let bob: Address = [2; 32].into();
+        let mut state = AccountState::default();
+        let deposit = bitcoin::OutPoint::default();
+        state.credit_deposits(&DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(deposit),
+                DepositOutput {
+                    address: alice,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let mut blockchain =
+            BlockChain::<Signature, Output>::new().with_extra_validator(validate_extra);
+        let pay_bob = transfer(
+            &keypair,
+            &Transfer {
+                to: bob,
+                amount: 30,
+                nonce: 0,
+            },
+        );
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![pay_bob.clone()],
+        };
+        let header = Header::new(&Hash::default().into(), &body);
+        blockchain
+            .connect_block_with(&mut state, &header, &body, None)
+            .unwrap();
+        assert_eq!(
+            state.get_account(&alice),
+            Account {
+                balance: 70,
+                nonce: 1
+            }
+        );
+        assert_eq!(state.get_account(&bob).balance, 30);
+        assert!(matches!(
+            SSM::validate_transaction(&state, &pay_bob),
+            Err(Error::WrongNonce {
+                expected: 1,
+                got: 0
+            })
+        ));
+
+        blockchain
+            .disconnect_block_with(&mut state, &header, &body)
+            .unwrap();
+        assert_eq!(
+            state.get_account(&alice),
+            Account {
+                balance: 100,
+                nonce: 0
+            }
+        );
+        assert_eq!(state.get_account(&bob).balance, 0);
+    }
+}
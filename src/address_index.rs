@@ -0,0 +1,215 @@
+use crate::blockchain::BlockChain;
+use crate::concrete::{Output, Signature};
+use crate::types::*;
+use std::collections::{HashMap, HashSet};
+
+// Confirmed transactions by the addresses they paid to or spent from, kept in
+// step with the chain by sync. Coinbase outputs and deposits aren't part of
+// any transaction, so they don't show up here.
+#[derive(Debug, Default)]
+pub struct AddressIndex {
+    // Transactions with the height of the block that confirmed them, oldest
+    // first.
+    history: HashMap<Address, Vec<(Txid, usize)>>,
+    script_hashes: HashMap<Hash, Address>,
+    // Indexed blocks from the first one up, with the addresses each one added
+    // history to, so blocks that got reorged out can be taken back out.
+    blocks: Vec<(BlockHash, Vec<Address>)>,
+}
+
+impl AddressIndex {
+    // Index of every block connected so far.
+    pub fn build(blockchain: &BlockChain<Signature, Output>) -> Self {
+        let mut index = Self::default();
+        index.sync(blockchain);
+        index
+    }
+
+    // Takes out blocks that are no longer on the chain and adds the ones
+    // connected since the last call.
+    pub fn sync(&mut self, blockchain: &BlockChain<Signature, Output>) {
+        let mut new_blocks = vec![];
+        let mut fork = None;
+        let mut block_hash = blockchain.get_best_block_hash();
+        while let Some(hash) = block_hash {
+            if let Some(position) = self
+                .blocks
+                .iter()
+                .rposition(|(indexed, _)| *indexed == hash)
+            {
+                fork = Some(position);
+                break;
+            }
+            let header = match blockchain.get_header(&hash) {
+                Some(header) => header,
+                None => break,
+            };
+            new_blocks.push(hash);
+            block_hash = Some(header.prev_block_hash);
+        }
+        let keep = fork.map_or(0, |position| position + 1);
+        while self.blocks.len() > keep {
+            self.disconnect_last_block();
+        }
+        for block_hash in new_blocks.into_iter().rev() {
+            let body = blockchain
+                .get_body(&block_hash)
+                .expect("connected block has no body");
+            self.connect_block(blockchain, block_hash, &body);
+        }
+    }
+
+    pub fn history(&self, address: &Address) -> &[(Txid, usize)] {
+        self.history.get(address).map_or(&[], Vec::as_slice)
+    }
+
+    // The address with this script hash, if it has any history.
+    pub fn address(&self, script_hash: &Hash) -> Option<Address> {
+        self.script_hashes.get(script_hash).copied()
+    }
+
+    fn connect_block(
+        &mut self,
+        blockchain: &BlockChain<Signature, Output>,
+        block_hash: BlockHash,
+        body: &Body<Signature, Output>,
+    ) {
+        let height = self.blocks.len() + 1;
+        let mut block_addresses = vec![];
+        for transaction in &body.transactions {
+            let txid = transaction.txid();
+            for address in transaction_addresses(blockchain, transaction) {
+                self.history
+                    .entry(address)
+                    .or_default()
+                    .push((txid, height));
+                self.script_hashes.insert(script_hash(&address), address);
+                block_addresses.push(address);
+            }
+        }
+        self.blocks.push((block_hash, block_addresses));
+    }
+
+    fn disconnect_last_block(&mut self) {
+        let height = self.blocks.len();
+        let (_, addresses) = match self.blocks.pop() {
+            Some(block) => block,
+            None => return,
+        };
+        for address in addresses {
+            let history = match self.history.get_mut(&address) {
+                Some(history) => history,
+                None => continue,
+            };
+            while matches!(history.last(), Some((_, at)) if *at == height) {
+                history.pop();
+            }
+            if history.is_empty() {
+                self.history.remove(&address);
+                self.script_hashes.remove(&script_hash(&address));
+            }
+        }
+    }
+}
+
+// Hash light wallets look addresses up by, the sha256 of its 32 bytes.
+pub fn script_hash(address: &Address) -> Hash {
+    hash(address)
+}
+
+// Every address the transaction spends from or pays to, each one once.
+// Inputs the chain doesn't know the owner of are left out.
+pub fn transaction_addresses(
+    blockchain: &BlockChain<Signature, Output>,
+    transaction: &Transaction<Signature, Output>,
+) -> Vec<Address> {
+    let inputs = transaction.inputs.iter().filter_map(|outpoint| {
+        if let Some(output) = blockchain.outputs.get(outpoint) {
+            Some(output.address)
+        } else if let Some(deposit) = blockchain.peg.deposit_outputs.get(outpoint) {
+            Some(deposit.address)
+        } else {
+            blockchain
+                .peg
+                .withdrawal_outputs
+                .get(outpoint)
+                .map(|refund| refund.side_address)
+        }
+    });
+    let outputs = transaction.outputs.iter().map(|output| output.address);
+    let withdrawals = transaction
+        .withdrawal_outputs
+        .iter()
+        .map(|withdrawal| withdrawal.side_address);
+    let mut seen = HashSet::new();
+    inputs
+        .chain(outputs)
+        .chain(withdrawals)
+        .filter(|address| seen.insert(*address))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn index_follows_reorgs() {
+        let mut wallet = Wallet::default();
+        let from = wallet.generate_address();
+        let to: Address = [1; 32].into();
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(bitcoin::OutPoint::default()),
+                DepositOutput {
+                    address: from,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+        wallet.add_deposit_outputs(&blockchain.peg.deposit_outputs);
+        let transaction = wallet
+            .create_transaction(
+                vec![Output {
+                    address: to,
+                    value: Amount::from_sat(100),
+                }],
+                Amount::ZERO,
+            )
+            .unwrap();
+        let txid = transaction.txid();
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![transaction],
+        };
+        let header = Header::new(&Hash::default().into(), &body);
+        blockchain.connect_block(&header, &body, None).unwrap();
+
+        let mut index = AddressIndex::build(&blockchain);
+        assert_eq!(index.history(&from), [(txid, 1)]);
+        assert_eq!(index.history(&to), [(txid, 1)]);
+        assert_eq!(index.address(&script_hash(&to)), Some(to));
+        // Nothing changed, nothing is indexed twice.
+        index.sync(&blockchain);
+        assert_eq!(index.history(&to), [(txid, 1)]);
+
+        blockchain.disconnect_block(&header, &body);
+        let empty = Body {
+            coinbase: vec![Output {
+                address: to,
+                value: Amount::ZERO,
+            }],
+            transactions: vec![],
+        };
+        blockchain
+            .connect_block(&Header::new(&Hash::default().into(), &empty), &empty, None)
+            .unwrap();
+        index.sync(&blockchain);
+        assert!(index.history(&from).is_empty());
+        assert!(index.history(&to).is_empty());
+        assert_eq!(index.address(&script_hash(&to)), None);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::p2p::{Message, Network};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_SCORE: i32 = 100;
+// Addresses at or below this score are never tried again.
+const BAN_SCORE: i32 = -20;
+// Unreachable addresses are retried after 1, 2, 4, ... minutes, up to a day.
+const MIN_RETRY_DELAY: u64 = 60;
+const MAX_RETRY_DELAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub score: i32,
+    // Seconds since the unix epoch.
+    pub last_success: Option<u64>,
+    pub last_attempt: Option<u64>,
+    // Failed connection attempts since the last successful one.
+    pub failures: u32,
+}
+
+impl PeerInfo {
+    fn can_retry(&self, now: u64) -> bool {
+        let last_attempt = match self.last_attempt {
+            Some(last_attempt) => last_attempt,
+            None => return true,
+        };
+        let delay = MIN_RETRY_DELAY
+            .saturating_mul(1 << self.failures.min(20))
+            .min(MAX_RETRY_DELAY);
+        self.failures == 0 || now >= last_attempt + delay
+    }
+}
+
+// Known peer addresses with a score of how well connecting to them worked,
+// so nodes find each other without every address being configured by hand.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AddrMan {
+    peers: HashMap<SocketAddr, PeerInfo>,
+}
+
+impl AddrMan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
+    // Returns true if the address wasn't known yet.
+    pub fn add(&mut self, addr: SocketAddr) -> bool {
+        if self.peers.contains_key(&addr) {
+            return false;
+        }
+        self.peers.insert(addr, PeerInfo::default());
+        true
+    }
+
+    pub fn add_many(&mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> usize {
+        addrs.into_iter().filter(|addr| self.add(*addr)).count()
+    }
+
+    pub fn mark_good(&mut self, addr: SocketAddr) {
+        let now = now();
+        let info = self.peers.entry(addr).or_default();
+        info.score = (info.score + 1).min(MAX_SCORE);
+        info.last_attempt = Some(now);
+        info.last_success = Some(now);
+        info.failures = 0;
+    }
+
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        let info = self.peers.entry(addr).or_default();
+        info.score = (info.score - 1).max(BAN_SCORE);
+        info.last_attempt = Some(now());
+        info.failures += 1;
+    }
+
+    // Misbehaving peers are banned right away.
+    pub fn ban(&mut self, addr: SocketAddr) {
+        self.peers.entry(addr).or_default().score = BAN_SCORE;
+    }
+
+    // Up to `n` addresses worth connecting to, best scores first.
+    pub fn select(&self, n: usize, exclude: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let now = now();
+        let mut candidates: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(addr, info)| {
+                info.score > BAN_SCORE && !exclude.contains(addr) && info.can_retry(now)
+            })
+            .collect();
+        candidates
+            .sort_by(|(a_addr, a), (b_addr, b)| b.score.cmp(&a.score).then(a_addr.cmp(b_addr)));
+        candidates
+            .into_iter()
+            .take(n)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    // Addresses to answer a GetAddr with, only ones that worked before.
+    pub fn sample(&self, n: usize) -> Vec<SocketAddr> {
+        let mut good: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, info)| info.last_success.is_some() && info.score > BAN_SCORE)
+            .collect();
+        good.sort_by(|(a_addr, a), (b_addr, b)| b.score.cmp(&a.score).then(a_addr.cmp(b_addr)));
+        good.into_iter().take(n).map(|(addr, _)| *addr).collect()
+    }
+
+    // Written to a temporary file first so a crash can't leave a torn file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bincode::serialize(self)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    // Returns an empty address manager if nothing was saved yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(bincode::deserialize(&bytes)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+// Looks up the addresses behind DNS seeds, seeds that fail to resolve are
+// skipped. Seeds can name their own port as host:port, otherwise `port` is
+// used.
+pub fn resolve_dns_seeds(seeds: &[&str], port: u16) -> Vec<SocketAddr> {
+    let mut addrs = vec![];
+    for seed in seeds {
+        let resolved = seed
+            .to_socket_addrs()
+            .or_else(|_| (*seed, port).to_socket_addrs());
+        match resolved {
+            Ok(resolved) => addrs.extend(resolved),
+            Err(err) => log::debug!("failed to resolve seed {}: {}", seed, err),
+        }
+    }
+    addrs
+}
+
+// Opens outbound connections to the best known addresses until there are
+// `target` of them, asking every new peer for more addresses. Returns the
+// number of new connections.
+pub fn maintain_connections<S, O>(
+    network: &Network<S, O>,
+    addrman: &mut AddrMan,
+    target: usize,
+) -> usize
+where
+    S: Serialize + DeserializeOwned + Send + 'static,
+    O: Serialize + DeserializeOwned + Send + 'static,
+{
+    let missing = target.saturating_sub(network.outbound_count());
+    if missing == 0 {
+        return 0;
+    }
+    let mut connected: HashSet<SocketAddr> = HashSet::new();
+    for (peer, addr, _) in network.peers() {
+        connected.insert(addr);
+        connected.extend(network.listen_addr(peer));
+    }
+    let mut connections = 0;
+    for addr in addrman.select(missing, &connected) {
+        match network.connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(peer) => {
+                addrman.mark_good(addr);
+                let _ = network.send(peer, &Message::GetAddr);
+                connections += 1;
+            }
+            Err(err) => {
+                log::debug!("failed to connect to {}: {}", addr, err);
+                addrman.mark_failed(addr);
+            }
+        }
+    }
+    connections
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concrete::{Output, Signature};
+    use crate::p2p::{Version, PROTOCOL_VERSION};
+    use std::net::TcpListener;
+
+    fn network() -> Network<Signature, Output> {
+        Network::new(Version {
+            version: PROTOCOL_VERSION,
+            genesis: [0; 32].into(),
+            best_height: 0,
+            user_agent: "test".into(),
+            listen_port: 0,
+        })
+    }
+
+    #[test]
+    fn unreachable_addresses_are_backed_off() {
+        let listening = network();
+        let addr = listening.listen("127.0.0.1:0").unwrap();
+        // Bound and dropped right away, so nothing listens there.
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut addrman = AddrMan::new();
+        assert_eq!(addrman.add_many([addr, dead, addr]), 2);
+
+        let node = network();
+        assert_eq!(maintain_connections(&node, &mut addrman, 2), 1);
+        assert_eq!(node.outbound_count(), 1);
+        assert_eq!(addrman.get(&addr).unwrap().score, 1);
+        assert_eq!(addrman.get(&dead).unwrap().failures, 1);
+        assert!(addrman.select(2, &HashSet::new()).contains(&addr));
+        assert!(!addrman.select(2, &HashSet::new()).contains(&dead));
+        assert_eq!(addrman.sample(10), [addr]);
+        assert_eq!(maintain_connections(&node, &mut addrman, 2), 0);
+    }
+
+    #[test]
+    fn seeds_may_name_a_port() {
+        let seeds = resolve_dns_seeds(&["127.0.0.1", "127.0.0.1:1", "::1"], 18445);
+        assert_eq!(
+            seeds,
+            [
+                "127.0.0.1:18445".parse().unwrap(),
+                "127.0.0.1:1".parse().unwrap(),
+                "[::1]:18445".parse().unwrap(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use alloc::format;
+use alloc::string::String;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use serde::{Deserialize, Serialize};
+
+// Satoshis in a coin.
+pub const COIN: u64 = 100_000_000;
+// No amount on the sidechain can be larger than every bitcoin there will
+// ever be.
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+const DECIMALS: usize = 8;
+
+// An amount of satoshis. Encodes exactly like the u64 it wraps, so txids
+// and RPC values don't change.
+//
+// Values coming from transactions and users go through the checked methods,
+// which also reject anything above MAX_MONEY. The operators are for sums
+// that can't overflow, like ones over outputs that were already validated,
+// and panic if they do.
+#[derive(
+    Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_sat(sat: u64) -> Self {
+        Self(sat)
+    }
+
+    pub const fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_valid(self) -> bool {
+        self <= MAX_MONEY
+    }
+
+    // None on overflow or if the result is above MAX_MONEY.
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0
+            .checked_add(other.0)
+            .map(Amount)
+            .filter(|sum| sum.is_valid())
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn saturating_sub(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_sub(other.0))
+    }
+
+    // None if any amount or the total is above MAX_MONEY.
+    pub fn checked_sum(amounts: impl IntoIterator<Item = Amount>) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+
+    // Parses a coin denominated string like "1.5" or "0.00000001".
+    pub fn from_coins(s: &str) -> Result<Self, Error> {
+        let (coins, fraction) = s.split_once('.').unwrap_or((s, ""));
+        let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if !digits(coins) || !(fraction.is_empty() || digits(fraction)) || s.ends_with('.') {
+            return Err(Error::Invalid(s.into()));
+        }
+        if fraction.len() > DECIMALS {
+            return Err(Error::TooPrecise(s.into()));
+        }
+        let coins: u64 = coins.parse().map_err(|_| Error::TooLarge(s.into()))?;
+        let fraction: u64 = format!("{:0<width$}", fraction, width = DECIMALS)
+            .parse()
+            .expect("fraction is at most 8 digits");
+        let amount = coins
+            .checked_mul(COIN)
+            .and_then(|sat| sat.checked_add(fraction))
+            .map(Amount)
+            .filter(|amount| amount.is_valid())
+            .ok_or_else(|| Error::TooLarge(s.into()))?;
+        Ok(amount)
+    }
+
+    // Coin denominated with all 8 decimals, like "1.50000000".
+    pub fn to_coins(self) -> String {
+        format!(
+            "{}.{:0width$}",
+            self.0 / COIN,
+            self.0 % COIN,
+            width = DECIMALS
+        )
+    }
+}
+
+impl core::fmt::Display for Amount {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.to_coins())
+    }
+}
+
+impl core::str::FromStr for Amount {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_coins(s)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+    fn add(self, other: Amount) -> Amount {
+        Amount(self.0.checked_add(other.0).expect("amount overflow"))
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Amount) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+    fn sub(self, other: Amount) -> Amount {
+        Amount(self.0.checked_sub(other.0).expect("amount underflow"))
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Amount) {
+        *self = *self - other;
+    }
+}
+
+impl core::iter::Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Self {
+        iter.fold(Amount::ZERO, Add::add)
+    }
+}
+
+impl<'a> core::iter::Sum<&'a Amount> for Amount {
+    fn sum<I: Iterator<Item = &'a Amount>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
+// Fee per 1000 virtual bytes, fine grained enough that small transactions
+// still get a meaningful rate out of integer math.
+#[derive(
+    Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub const ZERO: FeeRate = FeeRate(0);
+
+    pub const fn from_sat_per_kvb(sat: u64) -> Self {
+        Self(sat)
+    }
+
+    pub const fn to_sat_per_kvb(self) -> u64 {
+        self.0
+    }
+
+    // Rate of paying `fee` for `vsize` virtual bytes.
+    pub fn new(fee: Amount, vsize: usize) -> Self {
+        let rate = u128::from(fee.0) * 1000 / (vsize.max(1) as u128);
+        Self(rate.try_into().unwrap_or(u64::MAX))
+    }
+
+    // Fee for `vsize` virtual bytes at this rate, rounded up so the rate of
+    // the result is never lower.
+    pub fn fee(self, vsize: usize) -> Amount {
+        let fee = (u128::from(self.0) * vsize as u128).div_ceil(1000);
+        Amount(fee.try_into().unwrap_or(u64::MAX))
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    Invalid(String),
+    TooPrecise(String),
+    TooLarge(String),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Invalid(s) => write!(f, "invalid amount {:?}", s),
+            Self::TooPrecise(s) => write!(f, "amount {:?} has more than 8 decimals", s),
+            Self::TooLarge(s) => write!(f, "amount {:?} is larger than the maximum", s),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_are_checked_and_formatted() {
+        let one = Amount::from_sat(COIN);
+        assert_eq!(Amount::from_coins("1"), Ok(one));
+        assert_eq!(Amount::from_coins("0.00000001"), Ok(Amount::from_sat(1)));
+        assert_eq!("1.5".parse(), Ok(Amount::from_sat(150_000_000)));
+        assert_eq!(Amount::from_sat(150_000_000).to_string(), "1.50000000");
+        assert_eq!(MAX_MONEY.to_coins(), "21000000.00000000");
+        for invalid in ["", ".", "1.", ".5", "-1", "1e3", "1,5", "0x10"] {
+            assert_eq!(
+                Amount::from_coins(invalid),
+                Err(Error::Invalid(invalid.into()))
+            );
+        }
+        assert!(matches!(
+            Amount::from_coins("0.000000001"),
+            Err(Error::TooPrecise(_))
+        ));
+        assert!(matches!(
+            Amount::from_coins("21000000.00000001"),
+            Err(Error::TooLarge(_))
+        ));
+        assert!(matches!(
+            Amount::from_coins("99999999999999999999"),
+            Err(Error::TooLarge(_))
+        ));
+
+        assert_eq!(MAX_MONEY.checked_add(Amount::from_sat(1)), None);
+        assert_eq!(Amount::ZERO.checked_sub(Amount::from_sat(1)), None);
+        assert_eq!(
+            Amount::checked_sum([one, one, Amount::from_sat(u64::MAX)]),
+            None
+        );
+        assert_eq!(
+            Amount::checked_sum([one, one]),
+            Some(Amount::from_sat(2 * COIN))
+        );
+        // Encodes like a plain u64.
+        assert_eq!(
+            crate::encode::to_vec(&one).unwrap(),
+            COIN.to_le_bytes().to_vec()
+        );
+        assert_eq!(serde_json::to_string(&one).unwrap(), COIN.to_string());
+
+        let rate = FeeRate::new(Amount::from_sat(150), 100);
+        assert_eq!(rate, FeeRate::from_sat_per_kvb(1500));
+        assert_eq!(rate.fee(100), Amount::from_sat(150));
+        assert_eq!(rate.fee(101), Amount::from_sat(152));
+        assert!(FeeRate::new(rate.fee(333), 333) >= rate);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::batch::{BatchRequest, BatchResponse, JsonRpcResponse};
+use crate::client::{
+    block_headers, block_headers_batch, deposit_block_hashes, deposits_chunk, deposits_params,
+    filter_confirmed, Client, ConnectionConfig, Error, FailedWithdrawal, JsonDeposit,
+    JsonFeeEstimate, JsonVerifiedBMM, MainBlockHeader, SpentWithdrawal, TlsConfig, VerifiedBMM,
+    RPC_ID,
+};
+use crate::types::{BlockHash, Deposit, DepositsChunk, FeeRate};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::collections::HashMap;
+
+// Non-blocking counterpart of Client for use inside async node loops.
+#[derive(Clone)]
+pub struct AsyncClient {
+    pub this_sidechain: usize,
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    tls: Option<TlsConfig>,
+    connection: ConnectionConfig,
+    min_confirmations: u32,
+    http: reqwest::Client,
+}
+
+impl AsyncClient {
+    pub fn new(this_sidechain: usize, host: &str, port: u16, user: &str, password: &str) -> Self {
+        Self {
+            this_sidechain,
+            host: host.into(),
+            port,
+            user: user.into(),
+            password: password.into(),
+            tls: None,
+            connection: ConnectionConfig::default(),
+            min_confirmations: 0,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_min_confirmations(mut self, min_confirmations: u32) -> Self {
+        self.min_confirmations = min_confirmations;
+        self
+    }
+
+    pub fn with_connection(mut self, connection: ConnectionConfig) -> Result<Self, Error> {
+        self.connection = connection;
+        self.http = self.build_http()?;
+        Ok(self)
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> Result<Self, Error> {
+        self.tls = Some(tls);
+        self.http = self.build_http()?;
+        Ok(self)
+    }
+
+    fn build_http(&self) -> Result<reqwest::Client, Error> {
+        let connection = &self.connection;
+        let mut builder = reqwest::Client::builder().connect_timeout(connection.connect_timeout);
+        if let Some(timeout) = connection.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder = builder.pool_max_idle_per_host(if connection.keep_alive {
+            connection.max_idle_connections
+        } else {
+            0
+        });
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            builder = builder.danger_accept_invalid_certs(tls.accept_invalid_certs);
+            if let Some(ca_certificate) = &tls.ca_certificate {
+                builder =
+                    builder.add_root_certificate(reqwest::Certificate::from_pem(ca_certificate)?);
+            }
+        }
+        Ok(builder.build()?)
+    }
+
+    async fn post<T: DeserializeOwned>(&self, body: &serde_json::Value) -> Result<T, Error> {
+        let mut response = self
+            .http
+            .post(self.url())
+            .basic_auth(&self.user, Some(&self.password))
+            .json(body)
+            .send()
+            .await?;
+        let max_response_size = self.connection.max_response_size;
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() as u64 > max_response_size {
+                return Err(Error::ResponseTooLarge(max_response_size));
+            }
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    fn url(&self) -> String {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, self.host, self.port)
+    }
+
+    pub async fn send_request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[serde_json::Value],
+    ) -> Result<T, Error> {
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": RPC_ID,
+            "method": method,
+            "params": params,
+        });
+        self.post::<JsonRpcResponse>(&request).await?.into_result()
+    }
+
+    pub async fn send_batch(&self, batch: &BatchRequest) -> Result<BatchResponse, Error> {
+        let responses = self.post::<Vec<JsonRpcResponse>>(&batch.to_json()).await?;
+        BatchResponse::new(batch, responses)
+    }
+
+    pub async fn get_deposits(
+        &self,
+        last_deposit: Option<Deposit>,
+    ) -> Result<DepositsChunk, Error> {
+        self.fetch_deposits(last_deposit, None).await
+    }
+
+    pub async fn get_deposits_page(
+        &self,
+        last_deposit: Option<Deposit>,
+        limit: usize,
+    ) -> Result<DepositsChunk, Error> {
+        self.fetch_deposits(last_deposit, Some(limit)).await
+    }
+
+    async fn fetch_deposits(
+        &self,
+        last_deposit: Option<Deposit>,
+        limit: Option<usize>,
+    ) -> Result<DepositsChunk, Error> {
+        let (params, prev_value) = deposits_params(self.this_sidechain, last_deposit, limit);
+        let json_deposits = self
+            .send_request::<Vec<JsonDeposit>>("listsidechaindeposits", &params)
+            .await?;
+        // Headers of the blocks the deposits are in, for their heights.
+        let block_hashes = deposit_block_hashes(&json_deposits);
+        let headers = match block_hashes.is_empty() {
+            true => HashMap::new(),
+            false => {
+                let response = self.send_batch(&block_headers_batch(&block_hashes)).await?;
+                block_headers(&block_hashes, &response)?
+            }
+        };
+        let json_deposits = filter_confirmed(json_deposits, &headers, self.min_confirmations);
+        deposits_chunk(json_deposits, prev_value, &headers)
+    }
+
+    pub async fn verify_bmm(
+        &self,
+        main_block_hash: &bitcoin::BlockHash,
+        critical_hash: &BlockHash,
+    ) -> Result<VerifiedBMM, Error> {
+        let params = &[
+            json!(main_block_hash),
+            json!(critical_hash.to_string()),
+            json!(self.this_sidechain),
+        ];
+        let response = self
+            .send_request::<JsonVerifiedBMM>("verifybmm", params)
+            .await?;
+        Ok(response.bmm)
+    }
+
+    pub async fn get_block_count(&self) -> Result<usize, Error> {
+        self.send_request("getblockcount", &[]).await
+    }
+
+    pub async fn get_block_hash(&self, height: usize) -> Result<bitcoin::BlockHash, Error> {
+        self.send_request("getblockhash", &[json!(height)]).await
+    }
+
+    pub async fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error> {
+        self.send_request("getbestblockhash", &[]).await
+    }
+
+    pub async fn get_block_header(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+    ) -> Result<MainBlockHeader, Error> {
+        self.send_request("getblockheader", &[json!(block_hash), json!(true)])
+            .await
+    }
+
+    pub async fn create_deposit(
+        &self,
+        deposit_address: &str,
+        amount: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<bitcoin::Txid, Error> {
+        let params = &[
+            json!(self.this_sidechain),
+            json!(deposit_address),
+            json!(amount.to_btc()),
+            json!(fee.to_btc()),
+        ];
+        self.send_request("createsidechaindeposit", params).await
+    }
+
+    pub async fn get_block(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::Block, Error> {
+        let block: String = self
+            .send_request("getblock", &[json!(block_hash), json!(0)])
+            .await?;
+        Ok(bitcoin::consensus::deserialize(&hex::decode(block)?)?)
+    }
+
+    pub async fn get_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error> {
+        let spent_withdrawals = self
+            .send_request::<Vec<SpentWithdrawal>>("listspentwithdrawals", &[])
+            .await?;
+        Ok(spent_withdrawals
+            .into_iter()
+            .filter(|withdrawal| withdrawal.nsidechain == self.this_sidechain)
+            .collect())
+    }
+
+    pub async fn get_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error> {
+        let failed_withdrawals = self
+            .send_request::<Vec<FailedWithdrawal>>("listfailedwithdrawals", &[])
+            .await?;
+        Ok(failed_withdrawals
+            .into_iter()
+            .filter(|withdrawal| withdrawal.nsidechain == self.this_sidechain)
+            .collect())
+    }
+
+    pub async fn estimate_smart_fee(&self, conf_target: u16) -> Result<Option<FeeRate>, Error> {
+        let estimate: JsonFeeEstimate = self
+            .send_request("estimatesmartfee", &[json!(conf_target)])
+            .await?;
+        Ok(estimate.fee_rate())
+    }
+}
+
+impl TryFrom<&Client> for AsyncClient {
+    type Error = Error;
+
+    fn try_from(other: &Client) -> Result<Self, Error> {
+        let client = Self::new(
+            other.this_sidechain,
+            &other.host,
+            other.port,
+            &other.user,
+            &other.password,
+        )
+        .with_min_confirmations(other.min_confirmations)
+        .with_connection(other.connection.clone())?;
+        match &other.tls {
+            #[cfg(feature = "tls")]
+            Some(tls) => client.with_tls(tls.clone()),
+            _ => Ok(client),
+        }
+    }
+}
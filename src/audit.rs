@@ -0,0 +1,111 @@
+use crate::amount::Amount;
+use crate::types::Txid;
+
+// Breakdown of where the coins that entered the sidechain through deposits
+// or the genesis block are now. Every such coin is either in an unspent
+// output, was paid out to the mainchain, or was burned as a transaction fee.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AuditReport {
+    pub height: usize,
+    pub total_deposited: Amount,
+    pub premined: Amount,
+    pub total_paid_out: Amount,
+    pub fees: Amount,
+    pub regular_utxos: u64,
+    pub regular_utxo_value: Amount,
+    pub deposit_utxos: u64,
+    pub deposit_utxo_value: Amount,
+    pub withdrawal_utxos: u64,
+    pub withdrawal_utxo_value: Amount,
+    // Confirmed transactions whose fee couldn't be worked out, so the fees
+    // burned are missing theirs.
+    pub fee_errors: Vec<Txid>,
+}
+
+impl AuditReport {
+    pub fn utxo_value(&self) -> Amount {
+        self.regular_utxo_value + self.deposit_utxo_value + self.withdrawal_utxo_value
+    }
+
+    // Positive if there are more coins on the sidechain than were deposited
+    // and not paid out, negative if coins went missing.
+    pub fn discrepancy(&self) -> i128 {
+        let accounted = self.utxo_value().to_sat() as i128 + self.fees.to_sat() as i128;
+        let expected = self.total_deposited.to_sat() as i128 + self.premined.to_sat() as i128
+            - self.total_paid_out.to_sat() as i128;
+        accounted - expected
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.discrepancy() == 0 && self.fee_errors.is_empty()
+    }
+}
+
+impl std::fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "peg audit at height {}", self.height)?;
+        writeln!(f, "  deposited:   {}", self.total_deposited)?;
+        writeln!(f, "  premined:    {}", self.premined)?;
+        writeln!(f, "  paid out:    {}", self.total_paid_out)?;
+        writeln!(f, "  fees:        {}", self.fees)?;
+        writeln!(
+            f,
+            "  utxos:       {} regular ({}), {} deposit ({}), {} withdrawal ({})",
+            self.regular_utxos,
+            self.regular_utxo_value,
+            self.deposit_utxos,
+            self.deposit_utxo_value,
+            self.withdrawal_utxos,
+            self.withdrawal_utxo_value
+        )?;
+        for txid in &self.fee_errors {
+            writeln!(f, "  fee error:   {}", txid)?;
+        }
+        write!(f, "  discrepancy: {}", self.discrepancy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AuditReport;
+    use crate::blockchain::BlockChain;
+    use crate::concrete::{Output, Signature};
+    use crate::types::*;
+    use bitcoin::hashes::Hash;
+    use std::collections::HashMap;
+
+    #[test]
+    fn deposits_are_accounted_for() {
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        let outpoint = bitcoin::OutPoint::default();
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(outpoint),
+                DepositOutput {
+                    address: [1; 32].into(),
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![Deposit {
+                outpoint,
+                total: 100,
+                main_block_hash: bitcoin::BlockHash::all_zeros(),
+                main_height: 0,
+            }],
+        });
+        let report = blockchain.audit();
+        assert!(report.is_balanced(), "{}", report);
+        assert_eq!(report.deposit_utxo_value, Amount::from_sat(100));
+        blockchain.unspent_outpoints.clear();
+        assert_eq!(blockchain.audit().discrepancy(), -100);
+
+        // A fee that couldn't be worked out leaves the fees unaccounted for.
+        let report = AuditReport {
+            fee_errors: vec![[1; 32].into()],
+            ..AuditReport::default()
+        };
+        assert_eq!(report.discrepancy(), 0);
+        assert!(!report.is_balanced());
+        assert!(report.to_string().contains("fee error"));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::bundle::Bundle;
+use crate::client::{Error, FailedWithdrawal, MainBlockHeader, SpentWithdrawal, VerifiedBMM};
+use crate::types::{BlockHash, Deposit, DepositsChunk, FeeRate};
+
+// Everything a sidechain needs from the mainchain. Client talks to a
+// drivechain node over RPC, other implementations can serve a simulated
+// chain or a fake in tests.
+pub trait MainchainBackend {
+    fn get_deposits(&self, last_deposit: Option<Deposit>) -> Result<DepositsChunk, Error>;
+    // At most `limit` deposits following `last_deposit`.
+    fn get_deposits_page(
+        &self,
+        last_deposit: Option<Deposit>,
+        limit: usize,
+    ) -> Result<DepositsChunk, Error>;
+    fn verify_bmm(
+        &self,
+        main_block_hash: &bitcoin::BlockHash,
+        critical_hash: &BlockHash,
+    ) -> Result<VerifiedBMM, Error>;
+    // Asks the mainchain node's wallet to commit to the sidechain block
+    // `critical_hash` in mainchain block `height`, which has to follow
+    // `prev_main_block_hash`, paying `amount` to the mainchain miner.
+    fn create_bmm_request(
+        &self,
+        critical_hash: &BlockHash,
+        amount: bitcoin::Amount,
+        height: usize,
+        prev_main_block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::Txid, Error>;
+    fn get_block_count(&self) -> Result<usize, Error>;
+    fn get_block_hash(&self, height: usize) -> Result<bitcoin::BlockHash, Error>;
+    fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error>;
+    fn get_block_header(&self, block_hash: &bitcoin::BlockHash) -> Result<MainBlockHeader, Error>;
+    // Bundles that were paid out on the mainchain.
+    fn get_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error>;
+    // Bundles that were rejected by miners.
+    fn get_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error>;
+    // Puts a bundle up for miner votes, returns the hash it is known by.
+    // Submitting one the mainchain already has changes nothing.
+    fn submit_bundle(&self, bundle: &Bundle) -> Result<bitcoin::Txid, Error>;
+    // Mainchain fee rate for confirming within `conf_target` blocks, None if
+    // the node doesn't have enough data to estimate one yet.
+    fn estimate_smart_fee(&self, conf_target: u16) -> Result<Option<FeeRate>, Error>;
+
+    fn verify_bmm_batch(
+        &self,
+        commitments: &[(bitcoin::BlockHash, BlockHash)],
+    ) -> Result<Vec<Result<VerifiedBMM, Error>>, Error> {
+        Ok(commitments
+            .iter()
+            .map(|(main_block_hash, critical_hash)| self.verify_bmm(main_block_hash, critical_hash))
+            .collect())
+    }
+
+    fn deposit_pages(
+        &self,
+        last_deposit: Option<Deposit>,
+        page_size: usize,
+    ) -> DepositPages<'_, Self>
+    where
+        Self: Sized,
+    {
+        DepositPages {
+            backend: self,
+            last_deposit,
+            page_size,
+            done: false,
+        }
+    }
+}
+
+// Iterates over all deposits following a cursor, page by page, so syncing a
+// long deposit history doesn't have to hold all of it in memory at once.
+pub struct DepositPages<'a, B: MainchainBackend> {
+    backend: &'a B,
+    last_deposit: Option<Deposit>,
+    page_size: usize,
+    done: bool,
+}
+
+impl<'a, B: MainchainBackend> DepositPages<'a, B> {
+    // The last deposit that was returned, to resume from later.
+    pub fn last_deposit(&self) -> Option<&Deposit> {
+        self.last_deposit.as_ref()
+    }
+}
+
+impl<'a, B: MainchainBackend> Iterator for DepositPages<'a, B> {
+    type Item = Result<DepositsChunk, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page = match self
+            .backend
+            .get_deposits_page(self.last_deposit.clone(), self.page_size)
+        {
+            Ok(page) => page,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        };
+        if page.deposits.len() < self.page_size {
+            self.done = true;
+        }
+        match page.deposits.last() {
+            Some(last_deposit) => {
+                self.last_deposit = Some(last_deposit.clone());
+                Some(Ok(page))
+            }
+            None => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+impl<B: MainchainBackend + ?Sized> MainchainBackend for Box<B> {
+    fn get_deposits(&self, last_deposit: Option<Deposit>) -> Result<DepositsChunk, Error> {
+        (**self).get_deposits(last_deposit)
+    }
+
+    fn get_deposits_page(
+        &self,
+        last_deposit: Option<Deposit>,
+        limit: usize,
+    ) -> Result<DepositsChunk, Error> {
+        (**self).get_deposits_page(last_deposit, limit)
+    }
+
+    fn verify_bmm(
+        &self,
+        main_block_hash: &bitcoin::BlockHash,
+        critical_hash: &BlockHash,
+    ) -> Result<VerifiedBMM, Error> {
+        (**self).verify_bmm(main_block_hash, critical_hash)
+    }
+
+    fn create_bmm_request(
+        &self,
+        critical_hash: &BlockHash,
+        amount: bitcoin::Amount,
+        height: usize,
+        prev_main_block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::Txid, Error> {
+        (**self).create_bmm_request(critical_hash, amount, height, prev_main_block_hash)
+    }
+
+    fn get_block_count(&self) -> Result<usize, Error> {
+        (**self).get_block_count()
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<bitcoin::BlockHash, Error> {
+        (**self).get_block_hash(height)
+    }
+
+    fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error> {
+        (**self).get_best_block_hash()
+    }
+
+    fn get_block_header(&self, block_hash: &bitcoin::BlockHash) -> Result<MainBlockHeader, Error> {
+        (**self).get_block_header(block_hash)
+    }
+
+    fn get_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error> {
+        (**self).get_spent_withdrawals()
+    }
+
+    fn get_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error> {
+        (**self).get_failed_withdrawals()
+    }
+
+    fn submit_bundle(&self, bundle: &Bundle) -> Result<bitcoin::Txid, Error> {
+        (**self).submit_bundle(bundle)
+    }
+
+    fn estimate_smart_fee(&self, conf_target: u16) -> Result<Option<FeeRate>, Error> {
+        (**self).estimate_smart_fee(conf_target)
+    }
+
+    fn verify_bmm_batch(
+        &self,
+        commitments: &[(bitcoin::BlockHash, BlockHash)],
+    ) -> Result<Vec<Result<VerifiedBMM, Error>>, Error> {
+        (**self).verify_bmm_batch(commitments)
+    }
+}
@@ -0,0 +1,144 @@
+use crate::client::Error;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+// Several JSON-RPC calls that are sent to the mainchain in one HTTP request.
+#[derive(Debug, Clone, Default)]
+pub struct BatchRequest {
+    calls: Vec<Value>,
+}
+
+impl BatchRequest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the index of the call, which is used to get its result out of
+    // the BatchResponse.
+    pub fn push(&mut self, method: &str, params: &[Value]) -> usize {
+        let index = self.calls.len();
+        self.calls.push(json!({
+            "jsonrpc": "1.0",
+            "id": index,
+            "method": method,
+            "params": params,
+        }));
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        Value::Array(self.calls.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct BatchResponse {
+    responses: Vec<Option<JsonRpcResponse>>,
+}
+
+impl BatchResponse {
+    // Servers are free to answer batch calls in any order, so responses are
+    // matched back to calls by id.
+    pub(crate) fn new(
+        request: &BatchRequest,
+        responses: Vec<JsonRpcResponse>,
+    ) -> Result<Self, Error> {
+        let mut ordered: Vec<Option<JsonRpcResponse>> = (0..request.len()).map(|_| None).collect();
+        for response in responses {
+            let slot = response
+                .id
+                .as_u64()
+                .and_then(|id| ordered.get_mut(id as usize))
+                .ok_or(Error::UnexpectedResponseId)?;
+            *slot = Some(response);
+        }
+        Ok(Self { responses: ordered })
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, index: usize) -> Result<T, Error> {
+        let response = self
+            .responses
+            .get(index)
+            .and_then(Option::as_ref)
+            .ok_or(Error::MissingBatchResponse(index))?;
+        response.clone().into_result()
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(crate) struct JsonRpcResponse {
+    #[serde(default)]
+    pub id: Value,
+    #[serde(default)]
+    pub result: Value,
+    pub error: Option<JsonRpcError>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(crate) struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl JsonRpcResponse {
+    pub fn into_result<T: DeserializeOwned>(self) -> Result<T, Error> {
+        if let Some(JsonRpcError { code, message }) = self.error {
+            return Err(Error::from_rpc(code, message));
+        }
+        Ok(serde_json::from_value(self.result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_are_matched_by_id() -> anyhow::Result<()> {
+        let mut batch = BatchRequest::new();
+        let count = batch.push("getblockcount", &[]);
+        let hash = batch.push("getbestblockhash", &[]);
+        let responses = serde_json::from_value(json!([
+            {"id": 1, "result": "00ff", "error": null},
+            {"id": 0, "result": 42, "error": null},
+        ]))?;
+        let response = BatchResponse::new(&batch, responses)?;
+        assert_eq!(response.get::<u64>(count)?, 42);
+        assert_eq!(response.get::<String>(hash)?, "00ff");
+        Ok(())
+    }
+
+    #[test]
+    fn rpc_errors_are_mapped_to_variants() -> anyhow::Result<()> {
+        let mut batch = BatchRequest::new();
+        let warmup = batch.push("getblockcount", &[]);
+        let sidechain = batch.push("listsidechaindeposits", &[json!(255)]);
+        let other = batch.push("verifybmm", &[]);
+        let responses = serde_json::from_value(json!([
+            {"id": 0, "result": null, "error": {"code": -28, "message": "Loading block index..."}},
+            {"id": 1, "result": null, "error": {"code": -8, "message": "Invalid sidechain number"}},
+            {"id": 2, "result": null, "error": {"code": -1, "message": "bmm not found"}},
+        ]))?;
+        let response = BatchResponse::new(&batch, responses)?;
+        let warmup = response.get::<u64>(warmup).unwrap_err();
+        assert!(matches!(warmup, Error::InWarmup(_)));
+        assert!(warmup.is_retryable());
+        assert!(matches!(
+            response.get::<Value>(sidechain),
+            Err(Error::UnknownSidechain(_))
+        ));
+        assert!(matches!(
+            response.get::<Value>(other),
+            Err(Error::Rpc { code: -1, .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use crate::types::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+// Start a new file once the current one grows past this.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockPos {
+    pub file: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+// Block bodies appended to numbered flat files, only their positions are kept
+// in memory. Disconnected blocks are dropped from the index but stay in the
+// files, which are never rewritten.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockFiles {
+    dir: PathBuf,
+    max_file_size: u64,
+    index: HashMap<BlockHash, BlockPos>,
+    current_file: u32,
+}
+
+impl BlockFiles {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            index: HashMap::new(),
+            current_file: 0,
+        })
+    }
+
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.index.contains_key(block_hash)
+    }
+
+    pub fn position(&self, block_hash: &BlockHash) -> Option<BlockPos> {
+        self.index.get(block_hash).copied()
+    }
+
+    pub fn write<S: Serialize, O: Serialize>(
+        &mut self,
+        block_hash: BlockHash,
+        body: &Body<S, O>,
+    ) -> Result<BlockPos, Error> {
+        let data = bincode::serialize(body)?;
+        let mut file = self.open_file(self.current_file)?;
+        // Appending goes after anything written since the index was last
+        // saved, such data is unreachable but harmless.
+        let mut offset = file.metadata()?.len();
+        if offset > 0 && offset + data.len() as u64 > self.max_file_size {
+            self.current_file += 1;
+            file = self.open_file(self.current_file)?;
+            offset = file.metadata()?.len();
+        }
+        file.write_all(&data)?;
+        file.sync_data()?;
+        let pos = BlockPos {
+            file: self.current_file,
+            offset,
+            len: data.len() as u32,
+        };
+        self.index.insert(block_hash, pos);
+        Ok(pos)
+    }
+
+    pub fn read<S: DeserializeOwned, O: DeserializeOwned>(
+        &self,
+        block_hash: &BlockHash,
+    ) -> Result<Option<Body<S, O>>, Error> {
+        match self.read_raw(block_hash)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // The encoded body, as stored.
+    pub fn read_raw(&self, block_hash: &BlockHash) -> Result<Option<Vec<u8>>, Error> {
+        let pos = match self.index.get(block_hash) {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let mut file = File::open(self.file_path(pos.file))?;
+        file.seek(SeekFrom::Start(pos.offset))?;
+        let mut data = vec![0; pos.len as usize];
+        file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    pub fn remove(&mut self, block_hash: &BlockHash) -> Option<BlockPos> {
+        self.index.remove(block_hash)
+    }
+
+    fn open_file(&self, file: u32) -> Result<File, Error> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file_path(file))?)
+    }
+
+    fn file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concrete::{Output, Signature};
+
+    #[test]
+    fn bodies_are_read_back_across_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("sdk-blocks-{}", std::process::id()));
+        let mut block_files = BlockFiles::open(&dir)?.with_max_file_size(1);
+        let body = |value| Body::<Signature, Output> {
+            coinbase: vec![Output {
+                address: [1; 32].into(),
+                value: Amount::from_sat(value),
+            }],
+            transactions: vec![],
+        };
+        let first = BlockHash::from([1; 32]);
+        let second = BlockHash::from([2; 32]);
+        let first_pos = block_files.write(first, &body(1))?;
+        let second_pos = block_files.write(second, &body(2))?;
+        assert_eq!((first_pos.file, first_pos.offset), (0, 0));
+        assert_eq!((second_pos.file, second_pos.offset), (1, 0));
+
+        // The index travels with the chainstate.
+        let block_files: BlockFiles = bincode::deserialize(&bincode::serialize(&block_files)?)?;
+        let read = block_files.read::<Signature, Output>(&second)?.unwrap();
+        assert_eq!(read.coinbase[0].value, Amount::from_sat(2));
+        assert_eq!(bincode::serialize(&read)?, bincode::serialize(&body(2))?);
+        let mut block_files = block_files;
+        assert_eq!(block_files.remove(&first), Some(first_pos));
+        assert!(block_files.read::<Signature, Output>(&first)?.is_none());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
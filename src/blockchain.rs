@@ -1,34 +1,170 @@
+use crate::audit::AuditReport;
+use crate::block_files::{BlockFiles, Error as BlockFilesError};
+use crate::bmm::Anchor;
+use crate::bundle::{cut_bundle, suggested_fee, Bundle, BundleLimits};
+use crate::filter::BlockFilter;
+use crate::genesis::{Error as GenesisError, GenesisConfig};
+use crate::params::SidechainParams;
+use crate::peg::{Error as PegError, TwoWayPegState, WithdrawalStatus};
+#[cfg(feature = "async")]
+use crate::ssm::{AsyncSSM, AsyncValidator};
+use crate::ssm::{StatefulSSM, SSM};
 use crate::types::*;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+// Checks the application specific part of a transaction, see
+// Transaction::extra.
+pub type ExtraValidator<S, O> = fn(&Transaction<S, O>) -> Result<(), String>;
+
+// Outputs spent by a transaction: regular, deposit and withdrawal ones.
+type Inputs<O> = (Vec<O>, Vec<DepositOutput>, Vec<WithdrawalOutput>);
+
+// Blocks with their BMM commitments, if they have one.
+pub type Blocks<S, O> = Vec<(Header, Arc<Body<S, O>>, Option<Anchor>)>;
+
+// Block bodies are kept in memory unless the chain is given block files, see
+// BlockChain::with_block_files.
+#[derive(Debug, Serialize, Deserialize)]
+enum Bodies<S, O> {
+    Memory(HashMap<BlockHash, Arc<Body<S, O>>>),
+    Files(BlockFiles),
+}
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct BlockChain<S, O> {
+pub struct BlockChain<S, O, H = Sha256> {
     block_order: Vec<BlockHash>,
     headers: HashMap<BlockHash, Header>,
-    bodies: HashMap<BlockHash, Body<S, O>>,
-    transactions: HashMap<Txid, Transaction<S, O>>,
+    bodies: Bodies<S, O>,
+    // Block and position in its body of every confirmed transaction, the
+    // transactions themselves are only stored with the bodies.
+    transactions: HashMap<Txid, (BlockHash, u32)>,
+    // Made when a block is connected, for light clients.
+    filters: HashMap<BlockHash, BlockFilter>,
 
     pub outputs: HashMap<OutPoint, O>,
-    pub deposit_outputs: HashMap<OutPoint, DepositOutput>,
-    deposits: Vec<Deposit>,
-    pub withdrawal_outputs: HashMap<OutPoint, WithdrawalOutput>,
+    pub peg: TwoWayPegState,
     pub unspent_outpoints: HashSet<OutPoint>,
+    params: SidechainParams,
+    // Mainchain height at which each deposit became or becomes spendable,
+    // kept after maturing so disconnecting blocks can make deposits immature
+    // again. The chain's mainchain height comes from its BMM commitments, so
+    // every node agrees on it whenever it polled the deposit.
+    deposit_mature_heights: HashMap<OutPoint, usize>,
+    // Verified BMM commitment of each connected block that has one.
+    anchors: HashMap<BlockHash, Anchor>,
+    // Blocks an operator marked invalid, each with the blocks that marking
+    // it disconnected, oldest first, so they can be put back.
+    invalid: HashMap<BlockHash, Blocks<S, O>>,
+    // Run the peg audit every this many blocks, 0 disables it.
+    audit_interval: usize,
+    #[serde(skip, default = "Option::default")]
+    extra_validator: Option<ExtraValidator<S, O>>,
+    // Set once an extra validator is registered. Functions aren't saved, so
+    // a loaded chain needs it registered again, see ChainStore::load.
+    expects_extra_validator: bool,
+    // Value created by the genesis block.
+    premined: Amount,
+    // Fees each block burned, the ones its coinbase didn't take, and their
+    // total, kept as blocks connect so the audit doesn't go over the chain.
+    burned_fees: HashMap<BlockHash, Amount>,
+    total_burned_fees: Amount,
+    // Transactions whose fee couldn't be worked out when their block was
+    // connected, the audit can't account for them.
+    fee_errors: HashMap<Txid, BlockHash>,
+    #[serde(skip)]
+    hasher: PhantomData<H>,
 }
 
-impl<S: Sig + Serialize + Clone, O: Out + Serialize + Clone> BlockChain<S, O> {
+impl<
+        S: Sig + Serialize + DeserializeOwned + Clone,
+        O: Out + Serialize + DeserializeOwned + Clone,
+    > BlockChain<S, O>
+{
     pub fn new() -> Self {
-        BlockChain {
-            block_order: vec![],
-            headers: HashMap::new(),
-            bodies: HashMap::new(),
-            transactions: HashMap::new(),
-            outputs: HashMap::new(),
-            deposit_outputs: HashMap::new(),
-            deposits: vec![],
-            withdrawal_outputs: HashMap::new(),
-            unspent_outpoints: HashSet::new(),
+        Self::default()
+    }
+}
+
+impl<
+        S: Sig + Serialize + DeserializeOwned + Clone,
+        O: Out + Serialize + DeserializeOwned + Clone,
+        H: Hasher,
+    > BlockChain<S, O, H>
+{
+    pub fn with_extra_validator(mut self, extra_validator: ExtraValidator<S, O>) -> Self {
+        self.extra_validator = Some(extra_validator);
+        self.expects_extra_validator = true;
+        self
+    }
+
+    pub fn expects_extra_validator(&self) -> bool {
+        self.expects_extra_validator
+    }
+
+    // Keeps block bodies in append-only files instead of memory, bodies
+    // already connected are moved there. A chain loaded from disk that
+    // already uses block files keeps its own.
+    pub fn with_block_files(
+        mut self,
+        mut block_files: BlockFiles,
+    ) -> Result<Self, BlockFilesError> {
+        if let Bodies::Memory(bodies) = &self.bodies {
+            for block_hash in &self.block_order {
+                block_files.write(*block_hash, &bodies[block_hash])?;
+            }
+            self.bodies = Bodies::Files(block_files);
+        }
+        Ok(self)
+    }
+
+    // Connects the genesis block if the chain is still empty.
+    // The genesis block isn't validated, its premine spends nothing.
+    pub fn with_genesis(mut self, genesis: &GenesisConfig<O>) -> Result<Self, Error> {
+        if self.block_order.is_empty() {
+            let (header, body) = genesis.block::<S, H>();
+            self.apply_block(&header, &body, None)?;
+            self.premined = genesis.premine.iter().map(Out::get_value).sum();
+        }
+        Ok(self)
+    }
+
+    // Run on startup, a chain loaded from disk that was started from a
+    // different genesis block must not be extended.
+    pub fn check_genesis(&self, genesis: &GenesisConfig<O>) -> Result<(), GenesisError> {
+        let found = *self.block_order.first().ok_or(GenesisError::Missing)?;
+        let expected = genesis.block::<S, H>().0.hash_with::<H>();
+        if found != expected {
+            return Err(GenesisError::Mismatch { expected, found });
         }
+        Ok(())
+    }
+
+    pub fn with_audit_interval(mut self, audit_interval: usize) -> Self {
+        self.audit_interval = audit_interval;
+        self
+    }
+
+    pub fn with_params(mut self, params: SidechainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn with_deposit_maturity(mut self, deposit_maturity: usize) -> Self {
+        self.params.deposit_maturity = deposit_maturity;
+        self
+    }
+
+    pub fn params(&self) -> &SidechainParams {
+        &self.params
+    }
+
+    // Number of connected blocks, including the genesis block.
+    pub fn height(&self) -> usize {
+        self.block_order.len()
     }
 
     fn is_spent(&self, outpoint: &OutPoint) -> bool {
@@ -36,14 +172,103 @@ impl<S: Sig + Serialize + Clone, O: Out + Serialize + Clone> BlockChain<S, O> {
     }
 
     pub fn add_deposits(&mut self, deposits_chunk: DepositsChunk) {
-        self.unspent_outpoints
-            .extend(deposits_chunk.outputs.keys().cloned());
-        self.deposit_outputs.extend(deposits_chunk.outputs);
-        self.deposits.extend(deposits_chunk.deposits);
+        // Outputs without a deposit record count from the mainchain genesis.
+        let main_heights: HashMap<OutPoint, usize> = deposits_chunk
+            .deposits
+            .iter()
+            .map(|deposit| (OutPoint::Deposit(deposit.outpoint), deposit.main_height))
+            .collect();
+        let outpoints = self.peg.add_deposits(deposits_chunk);
+        if self.params.deposit_maturity == 0 {
+            self.unspent_outpoints.extend(outpoints);
+            return;
+        }
+        let main_height = self.main_height();
+        for outpoint in outpoints {
+            let main_height_in = main_heights.get(&outpoint).copied().unwrap_or(0);
+            let mature_at = main_height_in + self.params.deposit_maturity;
+            if is_mature(main_height, mature_at) {
+                self.unspent_outpoints.insert(outpoint);
+            }
+            self.deposit_mature_heights.insert(outpoint, mature_at);
+        }
+    }
+
+    // Unwinds deposits whose mainchain block was reorged out. Returns the
+    // deposit outpoints that were already spent on the sidechain, blocks
+    // spending them have to be disconnected by the caller.
+    pub fn disconnect_deposits(&mut self, deposits: &[Deposit]) -> Vec<OutPoint> {
+        let main_height = self.main_height();
+        self.peg
+            .disconnect_deposits(deposits)
+            .into_iter()
+            .filter(
+                |outpoint| match self.deposit_mature_heights.remove(outpoint) {
+                    Some(mature_at) if !is_mature(main_height, mature_at) => false,
+                    _ => !self.unspent_outpoints.remove(outpoint),
+                },
+            )
+            .collect()
     }
 
     pub fn validate_transaction(&self, transaction: &Transaction<S, O>) -> Result<(), String> {
-        let (inputs, deposit_inputs, withdrawal_inputs) = self.get_inputs(transaction);
+        self.validate_transaction_with(transaction, &HashMap::new())
+    }
+
+    // Same as validate_transaction, but the transaction may also spend
+    // `pending` outputs, ones created by unconfirmed transactions like
+    // earlier ones in the same block or package.
+    pub fn validate_transaction_with(
+        &self,
+        transaction: &Transaction<S, O>,
+        pending: &HashMap<OutPoint, O>,
+    ) -> Result<(), String> {
+        self.check_transaction(transaction, pending, true)
+    }
+
+    // Same as validate_transaction, with the extra data checked by an async
+    // validator instead of the extra validator.
+    #[cfg(feature = "async")]
+    pub async fn validate_transaction_async<V: AsyncValidator<S, O>>(
+        &self,
+        validator: &V,
+        transaction: &Transaction<S, O>,
+    ) -> Result<(), String>
+    where
+        S: Sync,
+        O: Sync,
+    {
+        self.check_transaction(transaction, &HashMap::new(), false)?;
+        validate_extra_async(validator, transaction).await
+    }
+
+    // Extra data is left to the caller unless `check_extra`.
+    fn check_transaction(
+        &self,
+        transaction: &Transaction<S, O>,
+        pending: &HashMap<OutPoint, O>,
+        check_extra: bool,
+    ) -> Result<(), String> {
+        if transaction.version == 0 {
+            return Err("invalid transaction version".into());
+        }
+        // Every input is checked against its own signature below, an input
+        // without one would otherwise go unchecked. Signatures past the
+        // inputs are left to the extra validator, e.g. account transfers.
+        if transaction.signatures.len() < transaction.inputs.len() {
+            return Err("missing signatures".into());
+        }
+        // Inputs are looked up one by one, a repeated one would be counted
+        // twice towards the value in.
+        let mut inputs = HashSet::with_capacity(transaction.inputs.len());
+        if !transaction
+            .inputs
+            .iter()
+            .all(|outpoint| inputs.insert(outpoint))
+        {
+            return Err("duplicate input".into());
+        }
+        let (inputs, deposit_inputs, withdrawal_inputs) = self.get_inputs(transaction, pending)?;
         if O::validate(
             &inputs,
             &deposit_inputs,
@@ -53,23 +278,39 @@ impl<S: Sig + Serialize + Clone, O: Out + Serialize + Clone> BlockChain<S, O> {
         ) {
             return Err("value out > value in".into());
         }
-        let txid_without_signatures = transaction.without_signatures().txid();
+        if transaction
+            .outputs
+            .iter()
+            .any(|output| output.get_value() < self.params.dust_limit)
+        {
+            return Err("dust output".into());
+        }
+        if transaction.version > TRANSACTION_VERSION || !check_extra {
+            // Extra data of future versions means nothing to this node yet.
+        } else if let Some(extra_validator) = self.extra_validator {
+            extra_validator(transaction)?;
+        } else if !transaction.extra.is_empty() {
+            return Err("unexpected extra data".into());
+        }
+        let sighash = transaction.sighash();
         for (outpoint, signature) in transaction.inputs.iter().zip(transaction.signatures.iter()) {
-            if self.is_spent(&outpoint) {
+            if self.is_spent(outpoint) && !pending.contains_key(outpoint) {
                 return Err("output spent".into());
             }
-            if !signature.is_valid(txid_without_signatures) {
+            if !signature.is_valid(sighash) {
                 return Err("wrong signature".into());
             }
-            if let Some(spent_output) = self.outputs.get(&outpoint) {
+            if let Some(spent_output) = self.outputs.get(outpoint).or(pending.get(outpoint)) {
                 if spent_output.get_address() != signature.get_address() {
                     return Err("addresses don't match".into());
                 }
-            } else if let Some(spent_output) = self.withdrawal_outputs.get(&outpoint) {
+            } else if let Some(spent_output) = self.peg.withdrawal_outputs.get(outpoint) {
                 if spent_output.side_address != signature.get_address() {
                     return Err("addresses don't match".into());
                 }
-            } else if let Some(spent_output) = self.deposit_outputs.get(&outpoint) {
+            } else if let Some(spent_output) = self.peg.deposit_outputs.get(outpoint) {
+                // Deposits belong to the sidechain address the depositor
+                // named on the mainchain, only its key can spend them.
                 if spent_output.address != signature.get_address() {
                     return Err("addresses don't match".into());
                 }
@@ -80,53 +321,391 @@ impl<S: Sig + Serialize + Clone, O: Out + Serialize + Clone> BlockChain<S, O> {
         Ok(())
     }
 
-    pub fn validate_block(&self, header: &Header, body: &Body<S, O>) -> bool {
+    pub fn validate_block(
+        &self,
+        header: &Header,
+        body: &Body<S, O>,
+        anchor: Option<&Anchor>,
+    ) -> bool {
+        self.check_block(header, body, anchor, true)
+    }
+
+    fn check_block(
+        &self,
+        header: &Header,
+        body: &Body<S, O>,
+        anchor: Option<&Anchor>,
+        check_extra: bool,
+    ) -> bool {
+        let block_hash = header.hash_with::<H>();
+        if self.is_invalid(&block_hash) {
+            return false;
+        }
+        // A mainchain block commits to at most one block of a chain, and the
+        // blocks of a chain are committed to in mainchain order.
+        let anchored = match anchor {
+            Some(anchor) => {
+                anchor.block_hash() == block_hash
+                    && self
+                        .main_height()
+                        .is_none_or(|main_height| anchor.main_height() > main_height)
+            }
+            None => !self.params.bmm,
+        };
+        if !anchored {
+            return false;
+        }
         let best_block = self
             .get_best_block_hash()
             .unwrap_or_else(|| Hash::default().into());
         if header.prev_block_hash != best_block {
             return false;
         }
-        if header.merkle_root != body.compute_merkle_root() {
+        if header.merkle_root != body.compute_merkle_root_with::<H>() {
+            return false;
+        }
+        if body.size() > self.params.max_block_size {
             return false;
         }
+        // Transactions are checked against the chain before the block, so two
+        // of them spending the same coin would both pass on their own. They
+        // may spend the regular outputs of earlier ones.
+        let mut spent = HashSet::new();
+        let mut created = HashMap::new();
+        let mut fees = Some(Amount::ZERO);
         for tx in &body.transactions {
-            if self.validate_transaction(tx).is_err() {
+            if self.check_transaction(tx, &created, check_extra).is_err() {
+                return false;
+            }
+            if !tx.inputs.iter().all(|outpoint| spent.insert(*outpoint)) {
+                return false;
+            }
+            let Ok(fee) = self.get_fee_with(tx, &created) else {
                 return false;
+            };
+            fees = fees.and_then(|fees| fees.checked_add(fee));
+            created.extend(regular_outputs(tx.txid_with::<H>(), tx));
+        }
+        // The coinbase can take the fees and no more.
+        let coinbase = Amount::checked_sum(body.coinbase.iter().map(Out::get_value));
+        matches!((coinbase, fees), (Some(coinbase), Some(fees)) if coinbase <= fees)
+    }
+
+    pub fn get_anchor(&self, block_hash: &BlockHash) -> Option<&Anchor> {
+        self.anchors.get(block_hash)
+    }
+
+    // Mainchain height of the last BMM commitment on the chain, None if no
+    // block has one.
+    pub fn main_height(&self) -> Option<usize> {
+        self.block_order
+            .iter()
+            .rev()
+            .find_map(|block_hash| self.anchors.get(block_hash))
+            .map(Anchor::main_height)
+    }
+
+    // Disconnects the block and every block after it, and keeps it from
+    // being connected until it's reconsidered, for getting off a block that
+    // hit a consensus bug. Returns the disconnected blocks, oldest first. A
+    // block that isn't on the chain is only marked. Application state
+    // machines aren't told about the disconnected blocks.
+    pub fn invalidate_block(&mut self, block_hash: BlockHash) -> Result<Blocks<S, O>, Error> {
+        if self.is_invalid(&block_hash) {
+            return Ok(vec![]);
+        }
+        let height = self.block_order.iter().position(|hash| *hash == block_hash);
+        // Every body is looked up before anything is disconnected, a block
+        // that can't be put back is not taken out.
+        let mut disconnected = vec![];
+        for hash in &self.block_order[height.unwrap_or(self.block_order.len())..] {
+            let body = self.get_body(hash).ok_or(Error::MissingBody(*hash))?;
+            let anchor = self.anchors.get(hash).copied();
+            disconnected.push((self.headers[hash].clone(), body, anchor));
+        }
+        for (header, body, _) in disconnected.iter().rev() {
+            self.disconnect_block(header, body);
+        }
+        self.invalid.insert(block_hash, disconnected.clone());
+        Ok(disconnected)
+    }
+
+    // Takes the mark back and reconnects the blocks it disconnected, as far
+    // as they are still valid on top of the tip. Returns how many were.
+    pub fn reconsider_block(&mut self, block_hash: &BlockHash) -> usize {
+        let Some(blocks) = self.invalid.remove(block_hash) else {
+            return 0;
+        };
+        let mut connected = 0;
+        for (header, body, anchor) in blocks {
+            if self.connect_block(&header, &body, anchor.as_ref()).is_err() {
+                break;
+            }
+            connected += 1;
+        }
+        connected
+    }
+
+    pub fn is_invalid(&self, block_hash: &BlockHash) -> bool {
+        self.invalid.contains_key(block_hash)
+    }
+
+    // Validates the block against the UTXO set, then connects it to both
+    // it and the state machine, which checks each transaction on top of the
+    // ones before it. Nothing is changed if either rejects it.
+    pub fn connect_block_with<M: SSM<S, O>>(
+        &mut self,
+        ssm: &mut M,
+        header: &Header,
+        body: &Body<S, O>,
+        anchor: Option<&Anchor>,
+    ) -> Result<(), String> {
+        if !self.validate_block(header, body, anchor) {
+            return Err("invalid block".into());
+        }
+        ssm.connect_block(header, body)
+            .map_err(|err| err.to_string())?;
+        if let Err(err) = self.apply_block(header, body, anchor) {
+            let _ = ssm.disconnect_block(header, body);
+            return Err(err.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn disconnect_block_with<M: SSM<S, O>>(
+        &mut self,
+        ssm: &mut M,
+        header: &Header,
+        body: &Body<S, O>,
+    ) -> Result<(), String> {
+        if self.get_best_block_hash() != Some(header.hash_with::<H>()) {
+            return Err("block is not the tip".into());
+        }
+        ssm.disconnect_block(header, body)
+            .map_err(|err| err.to_string())?;
+        self.disconnect_block(header, body);
+        Ok(())
+    }
+
+    // Same as connect_block_with, and also checks the state root the header
+    // commits to, if any. Returns the snapshot of the state before the block,
+    // which disconnect_block_stateful needs to roll it back.
+    pub fn connect_block_stateful<M: StatefulSSM<S, O>>(
+        &mut self,
+        ssm: &mut M,
+        header: &Header,
+        body: &Body<S, O>,
+        anchor: Option<&Anchor>,
+    ) -> Result<M::Snapshot, String> {
+        if !self.validate_block(header, body, anchor) {
+            return Err("invalid block".into());
+        }
+        let snapshot = ssm.snapshot();
+        if let Err(err) = ssm.connect_block(header, body) {
+            ssm.restore(snapshot);
+            return Err(err.to_string());
+        }
+        if let Some(state_root) = header.state_root {
+            if ssm.state_root() != state_root {
+                ssm.restore(snapshot);
+                return Err("state root mismatch".into());
             }
         }
-        true
+        if let Err(err) = self.apply_block(header, body, anchor) {
+            ssm.restore(snapshot);
+            return Err(err.to_string());
+        }
+        Ok(snapshot)
+    }
+
+    pub fn disconnect_block_stateful<M: StatefulSSM<S, O>>(
+        &mut self,
+        ssm: &mut M,
+        header: &Header,
+        body: &Body<S, O>,
+        snapshot: M::Snapshot,
+    ) -> Result<(), String> {
+        if self.get_best_block_hash() != Some(header.hash_with::<H>()) {
+            return Err("block is not the tip".into());
+        }
+        ssm.restore(snapshot);
+        self.disconnect_block(header, body);
+        Ok(())
     }
 
-    pub fn connect_block(&mut self, header: &Header, body: &Body<S, O>) {
+    // Same as connect_block_with, with the extra data of the transactions
+    // checked by `validator` instead of the extra validator.
+    #[cfg(feature = "async")]
+    pub async fn connect_block_with_async<M: AsyncSSM<S, O>, V: AsyncValidator<S, O>>(
+        &mut self,
+        ssm: &mut M,
+        validator: &V,
+        header: &Header,
+        body: &Body<S, O>,
+        anchor: Option<&Anchor>,
+    ) -> Result<(), String>
+    where
+        S: Sync,
+        O: Sync,
+    {
         for tx in &body.transactions {
-            let txid = tx.txid();
-            self.transactions.insert(txid, tx.clone());
-            for outpoint in &tx.inputs {
-                self.unspent_outpoints.remove(outpoint);
+            validate_extra_async(validator, tx).await?;
+        }
+        if !self.check_block(header, body, anchor, false) {
+            return Err("invalid block".into());
+        }
+        ssm.connect_block(header, body)
+            .await
+            .map_err(|err| err.to_string())?;
+        if let Err(err) = self.apply_block(header, body, anchor) {
+            let _ = ssm.disconnect_block(header, body).await;
+            return Err(err.to_string());
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn disconnect_block_with_async<M: AsyncSSM<S, O>>(
+        &mut self,
+        ssm: &mut M,
+        header: &Header,
+        body: &Body<S, O>,
+    ) -> Result<(), String>
+    where
+        S: Sync,
+        O: Sync,
+    {
+        if self.get_best_block_hash() != Some(header.hash_with::<H>()) {
+            return Err("block is not the tip".into());
+        }
+        ssm.disconnect_block(header, body)
+            .await
+            .map_err(|err| err.to_string())?;
+        self.disconnect_block(header, body);
+        Ok(())
+    }
+
+    pub fn connect_block(
+        &mut self,
+        header: &Header,
+        body: &Body<S, O>,
+        anchor: Option<&Anchor>,
+    ) -> Result<(), Error> {
+        if !self.validate_block(header, body, anchor) {
+            return Err(Error::InvalidBlock(header.hash_with::<H>()));
+        }
+        self.apply_block(header, body, anchor)
+    }
+
+    // Connects a block that was already validated.
+    fn apply_block(
+        &mut self,
+        header: &Header,
+        body: &Body<S, O>,
+        anchor: Option<&Anchor>,
+    ) -> Result<(), Error> {
+        let block_hash = header.hash_with::<H>();
+        // Work out every change first and store the body, the only step that
+        // can fail, so a block is applied either whole or not at all.
+        let mut spent = vec![];
+        let mut created = vec![];
+        let mut transactions = vec![];
+        for (index, tx) in body.transactions.iter().enumerate() {
+            let txid = tx.txid_with::<H>();
+            transactions.push((txid, (block_hash, index as u32)));
+            spent.extend(tx.inputs.iter().copied());
+            created.extend(regular_outputs(txid, tx));
+        }
+        created.extend(coinbase_outputs(block_hash, body));
+        // The body is the only copy of the block's transactions kept.
+        match &mut self.bodies {
+            Bodies::Memory(bodies) => {
+                bodies.insert(block_hash, Arc::new(body.clone()));
             }
-            for (vout, output) in tx.outputs.iter().enumerate() {
-                let vout = vout as u32;
-                let outpoint = OutPoint::Regular { txid, vout };
-                self.outputs.insert(outpoint, output.clone());
-                self.unspent_outpoints.insert(outpoint);
+            Bodies::Files(block_files) => {
+                block_files.write(block_hash, body)?;
             }
-            for (vout, output) in tx.withdrawal_outputs.iter().enumerate() {
-                let vout = vout as u32;
-                let outpoint = OutPoint::Withdrawal { txid, vout };
-                self.withdrawal_outputs.insert(outpoint, output.clone());
-                self.unspent_outpoints.insert(outpoint);
+        }
+        self.filters
+            .insert(block_hash, BlockFilter::new(&block_hash, body));
+
+        for (txid, location) in &transactions {
+            self.transactions.insert(*txid, *location);
+        }
+        // Outputs go in before inputs come out, an input can only refer to an
+        // earlier transaction.
+        for (outpoint, output) in created {
+            self.outputs.insert(outpoint, output);
+            self.unspent_outpoints.insert(outpoint);
+        }
+        for outpoint in &spent {
+            self.unspent_outpoints.remove(outpoint);
+        }
+        for (tx, (txid, _)) in body.transactions.iter().zip(&transactions) {
+            let withdrawal_outpoints = self.peg.connect_withdrawals(*txid, &tx.withdrawal_outputs);
+            self.unspent_outpoints.extend(withdrawal_outpoints);
+        }
+        // Spent outputs are kept, so fees can be worked out once every output
+        // of the block is in. The only transaction without inputs is the
+        // genesis premine.
+        let mut fees = Amount::ZERO;
+        for (tx, (txid, _)) in body.transactions.iter().zip(&transactions) {
+            if tx.inputs.is_empty() {
+                continue;
+            }
+            match self.get_fee(tx) {
+                Ok(fee) => fees += fee,
+                Err(_) => {
+                    self.fee_errors.insert(*txid, block_hash);
+                }
             }
-            let block_hash = header.hash();
-            self.headers.insert(block_hash, header.clone());
-            self.bodies.insert(block_hash, body.clone());
-            self.block_order.push(block_hash);
         }
+        let coinbase = body.coinbase.iter().map(Out::get_value).sum();
+        let burned = fees.checked_sub(coinbase).unwrap_or(Amount::ZERO);
+        self.burned_fees.insert(block_hash, burned);
+        self.total_burned_fees += burned;
+        let prev_main_height = self.main_height();
+        if let Some(anchor) = anchor {
+            self.anchors.insert(block_hash, *anchor);
+        }
+        self.headers.insert(block_hash, header.clone());
+        self.block_order.push(block_hash);
+        let height = self.height();
+        let main_height = self.main_height();
+        self.unspent_outpoints.extend(
+            self.deposit_mature_heights
+                .iter()
+                .filter(|(_, mature_at)| {
+                    !is_mature(prev_main_height, **mature_at) && is_mature(main_height, **mature_at)
+                })
+                .map(|(outpoint, _)| *outpoint),
+        );
+        if self.audit_interval != 0 && height.is_multiple_of(self.audit_interval) {
+            let report = self.audit();
+            if !report.is_balanced() {
+                log::error!("{}", report);
+            }
+        }
+        Ok(())
     }
 
     pub fn disconnect_block(&mut self, header: &Header, body: &Body<S, O>) {
-        for tx in &body.transactions {
-            let txid = tx.txid();
+        let block_hash = header.hash_with::<H>();
+        let main_height = self.main_height();
+        let prev_main_height = self
+            .block_order
+            .iter()
+            .rev()
+            .filter(|hash| **hash != block_hash)
+            .find_map(|hash| self.anchors.get(hash))
+            .map(Anchor::main_height);
+        for (outpoint, mature_at) in &self.deposit_mature_heights {
+            if is_mature(main_height, *mature_at) && !is_mature(prev_main_height, *mature_at) {
+                self.unspent_outpoints.remove(outpoint);
+            }
+        }
+        for tx in body.transactions.iter().rev() {
+            let txid = tx.txid_with::<H>();
             for outpoint in &tx.inputs {
                 self.unspent_outpoints.insert(*outpoint);
             }
@@ -136,57 +715,373 @@ impl<S: Sig + Serialize + Clone, O: Out + Serialize + Clone> BlockChain<S, O> {
                 self.outputs.remove(&outpoint);
                 self.unspent_outpoints.remove(&outpoint);
             }
-            for vout in 0..tx.withdrawal_outputs.len() {
-                let vout = vout as u32;
-                let outpoint = OutPoint::Withdrawal { txid, vout };
-                self.withdrawal_outputs.remove(&outpoint);
+            for outpoint in self
+                .peg
+                .disconnect_withdrawals(txid, tx.withdrawal_outputs.len())
+            {
                 self.unspent_outpoints.remove(&outpoint);
             }
             self.transactions.remove(&txid);
         }
-        let block_hash = header.hash();
-        self.bodies.remove(&block_hash);
+        for (outpoint, _) in coinbase_outputs(block_hash, body) {
+            self.outputs.remove(&outpoint);
+            self.unspent_outpoints.remove(&outpoint);
+        }
+        match &mut self.bodies {
+            Bodies::Memory(bodies) => {
+                bodies.remove(&block_hash);
+            }
+            Bodies::Files(block_files) => {
+                block_files.remove(&block_hash);
+            }
+        }
+        if let Some(burned) = self.burned_fees.remove(&block_hash) {
+            self.total_burned_fees -= burned;
+        }
+        self.fee_errors
+            .retain(|_, in_block| *in_block != block_hash);
+        self.anchors.remove(&block_hash);
+        self.filters.remove(&block_hash);
         self.headers.remove(&block_hash);
         self.block_order.pop();
     }
 
-    fn get_best_block_hash(&self) -> Option<BlockHash> {
+    // Checks that the coins on the sidechain add up to what was deposited
+    // minus what was paid out.
+    pub fn audit(&self) -> AuditReport {
+        let mut report = AuditReport {
+            height: self.height(),
+            total_deposited: self.peg.total_deposited(),
+            premined: self.premined,
+            ..AuditReport::default()
+        };
+        for (outpoint, output) in &self.peg.withdrawal_outputs {
+            if let Some(WithdrawalStatus::Paid { .. }) = self.peg.withdrawal_status(outpoint) {
+                report.total_paid_out += output.value;
+            }
+        }
+        report.fees = self.total_burned_fees;
+        report.fee_errors = self.fee_errors.keys().copied().collect();
+        report.fee_errors.sort();
+        for outpoint in &self.unspent_outpoints {
+            if let Some(output) = self.outputs.get(outpoint) {
+                report.regular_utxos += 1;
+                report.regular_utxo_value += output.get_value();
+            } else if let Some(output) = self.peg.deposit_outputs.get(outpoint) {
+                report.deposit_utxos += 1;
+                report.deposit_utxo_value += output.value;
+            } else if let Some(output) = self.peg.withdrawal_outputs.get(outpoint) {
+                // Paid out withdrawals are already counted as gone.
+                if let Some(WithdrawalStatus::Paid { .. }) = self.peg.withdrawal_status(outpoint) {
+                    continue;
+                }
+                report.withdrawal_utxos += 1;
+                report.withdrawal_utxo_value += output.value;
+            }
+        }
+        // Deposits that haven't matured are not spendable yet, but they are
+        // still on the sidechain.
+        let main_height = self.main_height();
+        for (outpoint, mature_at) in &self.deposit_mature_heights {
+            if !is_mature(main_height, *mature_at) {
+                if let Some(output) = self.peg.deposit_outputs.get(outpoint) {
+                    report.deposit_utxos += 1;
+                    report.deposit_utxo_value += output.value;
+                }
+            }
+        }
+        report
+    }
+
+    // Next bundle of withdrawals that haven't been spent on the sidechain
+    // or bundled yet, ready to be broadcast to the mainchain.
+    pub fn next_bundle(&self, limits: &BundleLimits) -> Option<Bundle> {
+        cut_bundle(self.pending_withdrawals(), &self.remaining_limits(limits)?)
+    }
+
+    // Mainchain fee a withdrawal made now should offer to be included in
+    // the next bundle.
+    pub fn suggested_withdrawal_fee(&self, limits: &BundleLimits) -> Amount {
+        match self.remaining_limits(limits) {
+            Some(limits) => suggested_fee(self.pending_withdrawals(), &limits),
+            // Nothing gets bundled until the period is over, so any fee
+            // competes for the next one.
+            None => suggested_fee(self.pending_withdrawals(), limits),
+        }
+    }
+
+    pub fn mark_bundled(&mut self, hash: bitcoin::Txid, bundle: &Bundle) -> Result<(), PegError> {
+        let height = self.height();
+        self.peg.mark_bundled(hash, bundle, height)
+    }
+
+    fn pending_withdrawals(&self) -> impl Iterator<Item = (&OutPoint, &WithdrawalOutput)> {
+        self.peg.withdrawal_outputs.iter().filter(|(outpoint, _)| {
+            self.unspent_outpoints.contains(outpoint)
+                && self
+                    .peg
+                    .withdrawal_status(outpoint)
+                    .is_some_and(|status| status.can_be_bundled())
+        })
+    }
+
+    // Per bundle limits tightened by what is left of the current period,
+    // None if the period is used up.
+    fn remaining_limits(&self, limits: &BundleLimits) -> Option<BundleLimits> {
+        if limits.period == 0 {
+            return Some(limits.clone());
+        }
+        let period_start = (self.height() + 1).saturating_sub(limits.period);
+        let (bundles, value) = self.peg.bundled_since(period_start);
+        let value = value.to_sat();
+        if bundles >= limits.max_bundles_per_period || value >= limits.max_value_per_period {
+            return None;
+        }
+        Some(BundleLimits {
+            max_value: limits.max_value.min(limits.max_value_per_period - value),
+            ..limits.clone()
+        })
+    }
+
+    pub fn get_best_block_hash(&self) -> Option<BlockHash> {
         self.block_order.last().copied()
     }
 
-    pub fn get_fee(&self, transaction: &Transaction<S, O>) -> u64 {
-        let (inputs, deposit_inputs, withdrawal_inputs) = self.get_inputs(transaction);
-        O::get_fee(
+    pub fn get_header(&self, block_hash: &BlockHash) -> Option<&Header> {
+        self.headers.get(block_hash)
+    }
+
+    // Height of a block on the chain, searched from the tip since that's
+    // where forks are.
+    pub fn get_height(&self, block_hash: &BlockHash) -> Option<usize> {
+        self.headers.get(block_hash)?;
+        let index = self
+            .block_order
+            .iter()
+            .rposition(|hash| hash == block_hash)?;
+        Some(index + 1)
+    }
+
+    // Block at `height` on the best chain, the first block is at 1.
+    pub fn get_block_hash(&self, height: usize) -> Option<BlockHash> {
+        self.block_order.get(height.checked_sub(1)?).copied()
+    }
+
+    pub fn get_body(&self, block_hash: &BlockHash) -> Option<Arc<Body<S, O>>> {
+        match &self.bodies {
+            Bodies::Memory(bodies) => bodies.get(block_hash).cloned(),
+            Bodies::Files(block_files) => match block_files.read(block_hash) {
+                Ok(body) => body.map(Arc::new),
+                Err(err) => {
+                    log::error!("failed to read block {}: {}", block_hash, err);
+                    None
+                }
+            },
+        }
+    }
+
+    pub fn get_filter(&self, block_hash: &BlockHash) -> Option<&BlockFilter> {
+        self.filters.get(block_hash)
+    }
+
+    pub fn get_transaction(&self, txid: &Txid) -> Option<Transaction<S, O>> {
+        let (block_hash, index) = self.transactions.get(txid)?;
+        let body = self.get_body(block_hash)?;
+        body.transactions.get(*index as usize).cloned()
+    }
+
+    // Header of the block the transaction is in, with a proof of the
+    // transaction against the header's merkle root, for clients that only
+    // keep headers.
+    pub fn get_merkle_proof(&self, txid: &Txid) -> Option<(Header, MerkleProof)> {
+        let (block_hash, _) = self.transactions.get(txid)?;
+        let body = self.get_body(block_hash)?;
+        let proof = body.merkle_proof_with::<H>(txid)?;
+        Some((self.headers.get(block_hash)?.clone(), proof))
+    }
+
+    // Hashes of the best chain from the tip back to the first block, dense
+    // near the tip and exponentially sparser further back, so a peer can find
+    // where its chain forks off ours from a few dozen hashes.
+    pub fn get_locator(&self) -> Vec<BlockHash> {
+        let mut locator = vec![];
+        let mut index = self.block_order.len();
+        let mut step = 1;
+        while index > 0 {
+            locator.push(self.block_order[index - 1]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        if let Some(first) = self.block_order.first() {
+            if locator.last() != Some(first) {
+                locator.push(*first);
+            }
+        }
+        locator
+    }
+
+    // Up to `max` headers following the first locator hash on our best chain,
+    // from the first block if none of them is.
+    pub fn get_headers_after(&self, locator: &[BlockHash], max: usize) -> Vec<Header> {
+        let start = locator
+            .iter()
+            .find_map(|block_hash| self.block_order.iter().rposition(|hash| hash == block_hash))
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        self.block_order[start..]
+            .iter()
+            .take(max)
+            .map(|block_hash| self.headers[block_hash].clone())
+            .collect()
+    }
+
+    // Fails for transactions that spend unknown outputs or more than they
+    // have, whatever their signatures.
+    pub fn get_fee(&self, transaction: &Transaction<S, O>) -> Result<Amount, String> {
+        self.get_fee_with(transaction, &HashMap::new())
+    }
+
+    // Same as get_fee, with `pending` outputs as in validate_transaction_with.
+    pub fn get_fee_with(
+        &self,
+        transaction: &Transaction<S, O>,
+        pending: &HashMap<OutPoint, O>,
+    ) -> Result<Amount, String> {
+        let (inputs, deposit_inputs, withdrawal_inputs) = self.get_inputs(transaction, pending)?;
+        if O::validate(
+            &inputs,
+            &deposit_inputs,
+            &withdrawal_inputs,
+            &transaction.outputs,
+            &transaction.withdrawal_outputs,
+        ) {
+            return Err("value out > value in".into());
+        }
+        Ok(O::get_fee(
             &inputs,
             &deposit_inputs,
             &withdrawal_inputs,
             &transaction.outputs,
             &transaction.withdrawal_outputs,
-        )
+        ))
     }
 
     fn get_inputs(
         &self,
         transaction: &Transaction<S, O>,
-    ) -> (Vec<O>, Vec<DepositOutput>, Vec<WithdrawalOutput>) {
-        let inputs: Vec<O> = transaction
-            .inputs
-            .iter()
-            .filter(|outpoint| self.outputs.contains_key(outpoint))
-            .map(|outpoint| self.outputs[outpoint].clone())
-            .collect();
-        let deposit_inputs: Vec<DepositOutput> = transaction
-            .inputs
-            .iter()
-            .filter(|outpoint| self.deposit_outputs.contains_key(outpoint))
-            .map(|outpoint| self.deposit_outputs[outpoint].clone())
-            .collect();
-        let withdrawal_inputs: Vec<WithdrawalOutput> = transaction
-            .inputs
-            .iter()
-            .filter(|outpoint| self.withdrawal_outputs.contains_key(outpoint))
-            .map(|outpoint| self.withdrawal_outputs[outpoint].clone())
-            .collect();
-        (inputs, deposit_inputs, withdrawal_inputs)
+        pending: &HashMap<OutPoint, O>,
+    ) -> Result<Inputs<O>, String> {
+        let mut inputs = vec![];
+        let mut deposit_inputs = vec![];
+        let mut withdrawal_inputs = vec![];
+        for outpoint in &transaction.inputs {
+            if let Some(output) = self.outputs.get(outpoint).or(pending.get(outpoint)) {
+                inputs.push(output.clone());
+            } else if let Some(output) = self.peg.deposit_outputs.get(outpoint) {
+                deposit_inputs.push(output.clone());
+            } else if let Some(output) = self.peg.withdrawal_outputs.get(outpoint) {
+                withdrawal_inputs.push(output.clone());
+            } else {
+                return Err("output doesn't exist".into());
+            }
+        }
+        Ok((inputs, deposit_inputs, withdrawal_inputs))
+    }
+}
+
+impl<
+        S: Sig + Serialize + DeserializeOwned + Clone,
+        O: Out + Serialize + DeserializeOwned + Clone,
+        H: Hasher,
+    > Default for BlockChain<S, O, H>
+{
+    fn default() -> Self {
+        BlockChain {
+            block_order: vec![],
+            headers: HashMap::new(),
+            bodies: Bodies::Memory(HashMap::new()),
+            transactions: HashMap::new(),
+            filters: HashMap::new(),
+            outputs: HashMap::new(),
+            peg: TwoWayPegState::new(),
+            unspent_outpoints: HashSet::new(),
+            params: SidechainParams::default(),
+            deposit_mature_heights: HashMap::new(),
+            anchors: HashMap::new(),
+            invalid: HashMap::new(),
+            audit_interval: 0,
+            extra_validator: None,
+            expects_extra_validator: false,
+            premined: Amount::ZERO,
+            burned_fees: HashMap::new(),
+            total_burned_fees: Amount::ZERO,
+            fee_errors: HashMap::new(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+async fn validate_extra_async<S: Sync, O: Sync, V: AsyncValidator<S, O>>(
+    validator: &V,
+    transaction: &Transaction<S, O>,
+) -> Result<(), String> {
+    // Extra data of future versions means nothing to this node yet.
+    if transaction.version > TRANSACTION_VERSION {
+        return Ok(());
     }
+    validator
+        .validate_transaction(transaction)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+// Regular outputs a transaction creates, by outpoint.
+pub(crate) fn regular_outputs<S, O: Clone>(
+    txid: Txid,
+    transaction: &Transaction<S, O>,
+) -> impl Iterator<Item = (OutPoint, O)> + '_ {
+    transaction
+        .outputs
+        .iter()
+        .enumerate()
+        .map(move |(vout, output)| {
+            let outpoint = OutPoint::Regular {
+                txid,
+                vout: vout as u32,
+            };
+            (outpoint, output.clone())
+        })
+}
+
+// Outputs the block's coinbase creates, by outpoint.
+fn coinbase_outputs<S, O: Clone>(
+    block_hash: BlockHash,
+    body: &Body<S, O>,
+) -> impl Iterator<Item = (OutPoint, O)> + '_ {
+    body.coinbase.iter().enumerate().map(move |(vout, output)| {
+        let outpoint = OutPoint::Coinbase {
+            block_hash,
+            vout: vout as u32,
+        };
+        (outpoint, output.clone())
+    })
+}
+
+// A deposit maturing at `mature_at` is spendable on a chain whose last BMM
+// commitment is at `main_height`.
+fn is_mature(main_height: Option<usize>, mature_at: usize) -> bool {
+    main_height.is_some_and(|main_height| main_height >= mature_at)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("block {0} is invalid")]
+    InvalidBlock(BlockHash),
+    #[error("body of block {0} is missing")]
+    MissingBody(BlockHash),
+    #[error("block files error")]
+    BlockFiles(#[from] BlockFilesError),
 }
@@ -0,0 +1,90 @@
+use crate::backend::MainchainBackend;
+use crate::client::Error as ClientError;
+use crate::types::BlockHash;
+use serde::{Deserialize, Serialize};
+
+// A sidechain block's BMM commitment, checked against the mainchain: the
+// block's hash was committed to in a mainchain block on the best mainchain.
+// Chains that require BMM only connect blocks with one, at most one block
+// per mainchain block and in mainchain order.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Anchor {
+    block_hash: BlockHash,
+    main_block_hash: bitcoin::BlockHash,
+    main_height: usize,
+}
+
+impl Anchor {
+    // Asks the mainchain whether `main_block_hash` includes a commitment to
+    // `block_hash`, as hashed by the sidechain the block is for.
+    pub fn verify<B: MainchainBackend + ?Sized>(
+        mainchain: &B,
+        main_block_hash: &bitcoin::BlockHash,
+        block_hash: &BlockHash,
+    ) -> Result<Self, Error> {
+        mainchain.verify_bmm(main_block_hash, block_hash)?;
+        let main_header = mainchain.get_block_header(main_block_hash)?;
+        if main_header.confirmations < 0 {
+            return Err(Error::Stale(*main_block_hash));
+        }
+        Ok(Self {
+            block_hash: *block_hash,
+            main_block_hash: *main_block_hash,
+            main_height: main_header.height,
+        })
+    }
+
+    pub fn block_hash(&self) -> BlockHash {
+        self.block_hash
+    }
+
+    pub fn main_block_hash(&self) -> bitcoin::BlockHash {
+        self.main_block_hash
+    }
+
+    pub fn main_height(&self) -> usize {
+        self.main_height
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("mainchain error")]
+    Mainchain(#[from] ClientError),
+    #[error("mainchain block {0} is not on the best mainchain")]
+    Stale(bitcoin::BlockHash),
+}
+
+impl Error {
+    // The mainchain node couldn't be asked, as opposed to a missing or
+    // reorged out commitment.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Mainchain(err) if err.is_retryable())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_client::MockMainClient;
+
+    #[test]
+    fn anchors_need_a_commitment_on_the_best_mainchain() {
+        let mainchain = MockMainClient::new();
+        let block_hash: BlockHash = [1; 32].into();
+        let prev_main_block_hash = mainchain.get_best_block_hash().unwrap();
+        mainchain
+            .create_bmm_request(&block_hash, bitcoin::Amount::ZERO, 1, &prev_main_block_hash)
+            .unwrap();
+        let main_block_hash = mainchain.mine_block();
+        let anchor = Anchor::verify(&mainchain, &main_block_hash, &block_hash).unwrap();
+        assert_eq!(anchor.main_block_hash(), main_block_hash);
+        assert_eq!(anchor.main_height(), 1);
+        assert!(Anchor::verify(&mainchain, &main_block_hash, &[2; 32].into()).is_err());
+        mainchain.disconnect_block();
+        assert!(matches!(
+            Anchor::verify(&mainchain, &main_block_hash, &block_hash),
+            Err(Error::Stale(_))
+        ));
+    }
+}
@@ -0,0 +1,363 @@
+use crate::blockchain::BlockChain;
+use crate::concrete::{Output, Signature};
+use crate::types::*;
+use ed25519_dalek::Keypair;
+
+// Builders for transactions and blocks with the concrete types, so tests can
+// say which coins to spend and who to pay without going through a wallet.
+
+// Deterministic key for tests, the same seed always gives the same address.
+pub fn keypair(seed: [u8; 32]) -> Keypair {
+    let secret = ed25519_dalek::SecretKey::from_bytes(&seed).expect("seed is 32 bytes");
+    let public = (&secret).into();
+    Keypair { secret, public }
+}
+
+pub struct TxBuilder<'a> {
+    transaction: Transaction<Signature, Output>,
+    // Key for each input, in the same order.
+    keys: Vec<&'a Keypair>,
+}
+
+impl<'a> Default for TxBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> TxBuilder<'a> {
+    pub fn new() -> Self {
+        Self {
+            transaction: Transaction {
+                version: TRANSACTION_VERSION,
+                inputs: vec![],
+                signatures: vec![],
+                outputs: vec![],
+                withdrawal_outputs: vec![],
+                extra: vec![],
+            },
+            keys: vec![],
+        }
+    }
+
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.transaction.version = version;
+        self
+    }
+
+    pub fn with_extra(mut self, extra: Vec<u8>) -> Self {
+        self.transaction.extra = extra;
+        self
+    }
+
+    // Spends `outpoint`, signed for by `keypair`.
+    pub fn spend(mut self, outpoint: OutPoint, keypair: &'a Keypair) -> Self {
+        self.transaction.inputs.push(outpoint);
+        self.keys.push(keypair);
+        self
+    }
+
+    pub fn pay(mut self, address: Address, value: Amount) -> Self {
+        self.transaction.outputs.push(Output { address, value });
+        self
+    }
+
+    // Pays `value` out on the mainchain, refunded to `side_address` if its
+    // bundle fails.
+    pub fn withdraw(
+        mut self,
+        main_address: bitcoin::Address,
+        value: Amount,
+        main_fee: Amount,
+        side_address: Address,
+    ) -> Self {
+        self.transaction.withdrawal_outputs.push(WithdrawalOutput {
+            value,
+            fee: main_fee,
+            side_address,
+            main_address,
+        });
+        self
+    }
+
+    pub fn build_unsigned(self) -> Transaction<Signature, Output> {
+        self.transaction
+    }
+
+    pub fn build(self) -> Transaction<Signature, Output> {
+        let signatures = self
+            .keys
+            .iter()
+            .map(|keypair| Signature::new(keypair, &self.transaction))
+            .collect();
+        Transaction {
+            signatures,
+            ..self.transaction
+        }
+    }
+}
+
+pub struct BlockBuilder {
+    prev_block_hash: BlockHash,
+    body: Body<Signature, Output>,
+    state_root: Option<Hash>,
+}
+
+impl BlockBuilder {
+    pub fn new(prev_block_hash: BlockHash) -> Self {
+        Self {
+            prev_block_hash,
+            body: Body {
+                coinbase: vec![],
+                transactions: vec![],
+            },
+            state_root: None,
+        }
+    }
+
+    // A block on top of the chain's tip.
+    pub fn on(blockchain: &BlockChain<Signature, Output>) -> Self {
+        Self::new(
+            blockchain
+                .get_best_block_hash()
+                .unwrap_or_else(|| Hash::default().into()),
+        )
+    }
+
+    pub fn with_state_root(mut self, state_root: Hash) -> Self {
+        self.state_root = Some(state_root);
+        self
+    }
+
+    pub fn coinbase(mut self, address: Address, value: Amount) -> Self {
+        self.body.coinbase.push(Output { address, value });
+        self
+    }
+
+    pub fn transaction(mut self, transaction: Transaction<Signature, Output>) -> Self {
+        self.body.transactions.push(transaction);
+        self
+    }
+
+    pub fn build(self) -> (Header, Body<Signature, Output>) {
+        let mut header = Header::new(&self.prev_block_hash, &self.body);
+        header.state_root = self.state_root;
+        (header, self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash as _;
+    use std::collections::HashMap;
+
+    #[test]
+    fn reorg_spends_a_deposit_twice() {
+        let alice = keypair([1; 32]);
+        let bob = keypair([2; 32]);
+        let alice_address: Address = alice.public.into();
+        let bob_address: Address = bob.public.into();
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_inner([1; 32]),
+            vout: 0,
+        });
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                deposit,
+                DepositOutput {
+                    address: alice_address,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+
+        let stolen = TxBuilder::new()
+            .spend(deposit, &bob)
+            .pay(bob_address, Amount::from_sat(100))
+            .build();
+        assert_eq!(
+            blockchain.validate_transaction(&stolen),
+            Err("addresses don't match".into())
+        );
+        // Neither of these has a fee, and neither may take the node down.
+        let unknown = TxBuilder::new()
+            .spend(
+                OutPoint::Regular {
+                    txid: Hash::default().into(),
+                    vout: 0,
+                },
+                &alice,
+            )
+            .pay(bob_address, Amount::from_sat(100))
+            .build();
+        assert_eq!(
+            blockchain.validate_transaction(&unknown),
+            Err("output doesn't exist".into())
+        );
+        assert!(blockchain.get_fee(&unknown).is_err());
+        let overspent = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(bob_address, Amount::from_sat(101))
+            .build();
+        assert!(blockchain.get_fee(&overspent).is_err());
+        // Listing the deposit twice would count it twice.
+        let doubled = TxBuilder::new()
+            .spend(deposit, &alice)
+            .spend(deposit, &alice)
+            .pay(bob_address, Amount::from_sat(200))
+            .build();
+        assert_eq!(
+            blockchain.validate_transaction(&doubled),
+            Err("duplicate input".into())
+        );
+        let unsigned = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(bob_address, Amount::from_sat(100))
+            .build_unsigned();
+        assert!(blockchain.validate_transaction(&unsigned).is_err());
+
+        // Each half is fine on its own, together they spend the deposit twice.
+        let half = |address| {
+            TxBuilder::new()
+                .spend(deposit, &alice)
+                .pay(address, Amount::from_sat(50))
+                .build()
+        };
+        let (header, body) = BlockBuilder::on(&blockchain)
+            .transaction(half(alice_address))
+            .transaction(half(bob_address))
+            .build();
+        assert!(!blockchain.validate_block(&header, &body, None));
+        let height = blockchain.height();
+        assert!(blockchain.connect_block(&header, &body, None).is_err());
+        assert_eq!(blockchain.height(), height);
+
+        let pay_bob = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(bob_address, Amount::from_sat(90))
+            .build();
+        let (header, body) = BlockBuilder::on(&blockchain)
+            .coinbase(alice_address, Amount::from_sat(10))
+            .transaction(pay_bob.clone())
+            .build();
+        blockchain.connect_block(&header, &body, None).unwrap();
+        assert!(blockchain.validate_transaction(&pay_bob).is_err());
+
+        // Once the block is reorged out the deposit can go elsewhere.
+        blockchain.disconnect_block(&header, &body);
+        let pay_alice = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(alice_address, Amount::from_sat(100))
+            .build();
+        let (other_header, other_body) = BlockBuilder::on(&blockchain)
+            .transaction(pay_alice.clone())
+            .build();
+        blockchain
+            .connect_block(&other_header, &other_body, None)
+            .unwrap();
+        assert!(blockchain.get_transaction(&pay_alice.txid()).is_some());
+        assert!(blockchain.get_transaction(&pay_bob.txid()).is_none());
+        assert!(!blockchain.validate_block(&header, &body, None));
+    }
+
+    #[test]
+    fn coinbase_takes_at_most_the_fees() -> anyhow::Result<()> {
+        let alice = keypair([1; 32]);
+        let miner = keypair([2; 32]);
+        let alice_address: Address = alice.public.into();
+        let miner_address: Address = miner.public.into();
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint::default());
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                deposit,
+                DepositOutput {
+                    address: alice_address,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let pay = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(alice_address, Amount::from_sat(90))
+            .build();
+        let greedy = BlockBuilder::on(&blockchain)
+            .coinbase(miner_address, Amount::from_sat(11))
+            .transaction(pay.clone())
+            .build();
+        assert!(!blockchain.validate_block(&greedy.0, &greedy.1, None));
+        let empty = BlockBuilder::on(&blockchain)
+            .coinbase(miner_address, Amount::from_sat(1))
+            .build();
+        assert!(!blockchain.validate_block(&empty.0, &empty.1, None));
+
+        // Half the fee goes to the miner, the rest is burned.
+        let (header, body) = BlockBuilder::on(&blockchain)
+            .coinbase(miner_address, Amount::from_sat(5))
+            .transaction(pay)
+            .build();
+        blockchain.connect_block(&header, &body, None)?;
+        let reward = OutPoint::Coinbase {
+            block_hash: header.hash(),
+            vout: 0,
+        };
+        assert!(blockchain.unspent_outpoints.contains(&reward));
+        let report = blockchain.audit();
+        assert!(report.is_balanced(), "{}", report);
+        assert_eq!(report.fees, Amount::from_sat(5));
+
+        let spend_reward = TxBuilder::new()
+            .spend(reward, &miner)
+            .pay(miner_address, Amount::from_sat(5))
+            .build();
+        assert_eq!(blockchain.validate_transaction(&spend_reward), Ok(()));
+        blockchain.disconnect_block(&header, &body);
+        assert!(!blockchain.unspent_outpoints.contains(&reward));
+        assert!(blockchain.validate_transaction(&spend_reward).is_err());
+        assert_eq!(blockchain.audit().fees, Amount::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn deposits_mature_at_the_same_block_on_every_node() -> anyhow::Result<()> {
+        use crate::backend::MainchainBackend;
+        use crate::mock_client::MockMainClient;
+
+        let mainchain = MockMainClient::new();
+        let address: Address = keypair([1; 32]).public.into();
+        let deposit = OutPoint::Deposit(mainchain.add_deposit(address, 100));
+        let chunk = mainchain.get_deposits(None)?;
+        // One node polls the deposit right away, the other only after a
+        // block, they still have to agree on when it can be spent.
+        let mut early = BlockChain::<Signature, Output>::new().with_deposit_maturity(2);
+        let mut late = BlockChain::<Signature, Output>::new().with_deposit_maturity(2);
+        early.add_deposits(chunk.clone());
+        let connect = |early: &mut BlockChain<Signature, Output>,
+                       late: &mut BlockChain<Signature, Output>| {
+            let (header, body) = BlockBuilder::on(early).build();
+            let anchor = mainchain.anchor(&header.hash());
+            early.connect_block(&header, &body, Some(&anchor))?;
+            late.connect_block(&header, &body, Some(&anchor))?;
+            anyhow::Ok((header, body))
+        };
+        connect(&mut early, &mut late)?;
+        late.add_deposits(chunk);
+        assert!(!early.unspent_outpoints.contains(&deposit));
+        assert_eq!(early.unspent_outpoints, late.unspent_outpoints);
+
+        let (header, body) = connect(&mut early, &mut late)?;
+        assert!(early.unspent_outpoints.contains(&deposit));
+        assert_eq!(early.unspent_outpoints, late.unspent_outpoints);
+        assert!(early.audit().is_balanced());
+
+        early.disconnect_block(&header, &body);
+        late.disconnect_block(&header, &body);
+        assert!(!early.unspent_outpoints.contains(&deposit));
+        assert_eq!(early.unspent_outpoints, late.unspent_outpoints);
+        Ok(())
+    }
+}
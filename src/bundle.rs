@@ -0,0 +1,232 @@
+use crate::types::{Amount, FeeRate, OutPoint, WithdrawalOutput};
+use serde::{Deserialize, Serialize};
+
+// Largest transaction weight mainchain nodes relay, bundles above it would
+// never make it into a block.
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+// Weight of a bundle transaction without any withdrawal outputs: version,
+// locktime, the sidechain treasury input and output, and the fee
+// commitment output.
+const BUNDLE_BASE_WEIGHT: u64 = 4 * (4 + 4 + 1 + 41 + 3 + 43 + 43);
+
+// Mainchain blocks a bundle should confirm within, what fee rates are
+// estimated for.
+pub const BUNDLE_CONF_TARGET: u16 = 6;
+
+#[derive(Debug, Clone)]
+pub struct BundleLimits {
+    pub max_weight: u64,
+    pub max_outputs: usize,
+    pub max_value: u64,
+    // Caps on bundles cut within any window of `period` sidechain blocks,
+    // a period of 0 disables them.
+    pub period: usize,
+    pub max_bundles_per_period: usize,
+    pub max_value_per_period: u64,
+    // Mainchain fee rate bundles have to pay to confirm in reasonable time,
+    // usually the mainchain node's estimatesmartfee.
+    pub main_fee_rate: FeeRate,
+}
+
+impl Default for BundleLimits {
+    fn default() -> Self {
+        Self {
+            max_weight: MAX_STANDARD_TX_WEIGHT,
+            max_outputs: usize::MAX,
+            max_value: u64::MAX,
+            period: 0,
+            max_bundles_per_period: usize::MAX,
+            max_value_per_period: u64::MAX,
+            main_fee_rate: FeeRate::ZERO,
+        }
+    }
+}
+
+// Withdrawals that are paid out together in one mainchain transaction.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Bundle {
+    pub outpoints: Vec<OutPoint>,
+    pub outputs: Vec<bitcoin::TxOut>,
+    // Sum of the mainchain fees offered by the withdrawals.
+    pub fee: u64,
+}
+
+impl Bundle {
+    pub fn weight(&self) -> u64 {
+        BUNDLE_BASE_WEIGHT + self.outputs.iter().map(output_weight).sum::<u64>()
+    }
+
+    pub fn value(&self) -> u64 {
+        self.outputs.iter().map(|output| output.value).sum()
+    }
+
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4) as usize
+    }
+
+    // The bundle as the mainchain node is handed it: the fee commitment
+    // followed by the withdrawal outputs, the node adds the treasury input
+    // and output itself. Its txid is the hash the bundle is known by.
+    pub fn transaction(&self) -> bitcoin::Transaction {
+        let fee = bitcoin::TxOut {
+            value: 0,
+            script_pubkey: bitcoin::Script::new_op_return(&self.fee.to_le_bytes()),
+        };
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: bitcoin::PackedLockTime::ZERO,
+            input: vec![],
+            output: [fee].into_iter().chain(self.outputs.clone()).collect(),
+        }
+    }
+}
+
+fn output_weight(output: &bitcoin::TxOut) -> u64 {
+    4 * bitcoin::consensus::serialize(output).len() as u64
+}
+
+// Mainchain fee for the output paying a withdrawal to `main_address` at
+// `main_fee_rate`, the least a withdrawal has to offer to be bundled.
+pub fn withdrawal_cost(main_address: &bitcoin::Address, main_fee_rate: FeeRate) -> Amount {
+    let output = bitcoin::TxOut {
+        value: 0,
+        script_pubkey: main_address.script_pubkey(),
+    };
+    main_fee_rate.fee(output_weight(&output).div_ceil(4) as usize)
+}
+
+// Picks the withdrawals offering the highest mainchain fee that fit into a
+// single bundle. Ties are broken by outpoint, so every node cuts the same
+// bundle from the same withdrawals. Withdrawals that don't pay for their
+// output at the mainchain fee rate are left out, and so is the whole bundle
+// if its fees don't cover its weight, it would never confirm.
+pub fn cut_bundle<'a>(
+    withdrawals: impl IntoIterator<Item = (&'a OutPoint, &'a WithdrawalOutput)>,
+    limits: &BundleLimits,
+) -> Option<Bundle> {
+    let mut withdrawals: Vec<_> = withdrawals.into_iter().collect();
+    withdrawals.sort_by(|(a_outpoint, a), (b_outpoint, b)| {
+        b.fee.cmp(&a.fee).then(a_outpoint.cmp(b_outpoint))
+    });
+    let mut bundle = Bundle {
+        outpoints: vec![],
+        outputs: vec![],
+        fee: 0,
+    };
+    let mut weight = BUNDLE_BASE_WEIGHT;
+    for (outpoint, withdrawal) in withdrawals {
+        if bundle.outputs.len() >= limits.max_outputs {
+            break;
+        }
+        let output = bitcoin::TxOut {
+            value: withdrawal.value.to_sat(),
+            script_pubkey: withdrawal.main_address.script_pubkey(),
+        };
+        let output_weight = output_weight(&output);
+        if withdrawal.fee < withdrawal_cost(&withdrawal.main_address, limits.main_fee_rate)
+            || weight + output_weight > limits.max_weight
+            || bundle.value().saturating_add(withdrawal.value.to_sat()) > limits.max_value
+        {
+            continue;
+        }
+        weight += output_weight;
+        bundle.outpoints.push(*outpoint);
+        bundle.outputs.push(output);
+        bundle.fee += withdrawal.fee.to_sat();
+    }
+    if bundle.outputs.is_empty()
+        || Amount::from_sat(bundle.fee) < limits.main_fee_rate.fee(bundle.vsize())
+    {
+        return None;
+    }
+    Some(bundle)
+}
+
+// Fee a new withdrawal should offer to make it into the next bundle. When
+// every pending withdrawal fits it is 0, otherwise the new one has to
+// outbid the cheapest withdrawal that made it in.
+pub fn suggested_fee<'a>(
+    withdrawals: impl IntoIterator<Item = (&'a OutPoint, &'a WithdrawalOutput)>,
+    limits: &BundleLimits,
+) -> Amount {
+    let withdrawals: Vec<_> = withdrawals.into_iter().collect();
+    let pending = withdrawals.len();
+    let bundle = match cut_bundle(withdrawals.iter().copied(), limits) {
+        Some(bundle) => bundle,
+        None => return Amount::ZERO,
+    };
+    if bundle.outpoints.len() == pending {
+        return Amount::ZERO;
+    }
+    let fees: std::collections::HashMap<_, _> = withdrawals
+        .into_iter()
+        .map(|(outpoint, withdrawal)| (outpoint, withdrawal.fee))
+        .collect();
+    let lowest_fee = bundle
+        .outpoints
+        .iter()
+        .filter_map(|outpoint| fees.get(outpoint))
+        .min()
+        .copied()
+        .unwrap_or_default();
+    lowest_fee + Amount::from_sat(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn highest_fees_are_bundled_first() {
+        let main_address =
+            bitcoin::Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080").unwrap();
+        let withdrawals: Vec<(OutPoint, WithdrawalOutput)> = (0..3)
+            .map(|i| {
+                let outpoint = OutPoint::Withdrawal {
+                    txid: [i; 32].into(),
+                    vout: 0,
+                };
+                let withdrawal = WithdrawalOutput {
+                    value: Amount::from_sat(1000),
+                    fee: Amount::from_sat([10, 30, 20][i as usize]),
+                    side_address: [i; 32].into(),
+                    main_address: main_address.clone(),
+                };
+                (outpoint, withdrawal)
+            })
+            .collect();
+        let limits = BundleLimits {
+            max_outputs: 2,
+            ..BundleLimits::default()
+        };
+        let bundle = cut_bundle(withdrawals.iter().map(|(o, w)| (o, w)), &limits).unwrap();
+        assert_eq!(bundle.outpoints, [withdrawals[1].0, withdrawals[2].0]);
+        assert_eq!(bundle.fee, 50);
+        assert_eq!(bundle.value(), 2000);
+        let fee = suggested_fee(withdrawals.iter().map(|(o, w)| (o, w)), &limits);
+        assert_eq!(fee, Amount::from_sat(21));
+
+        // Each withdrawal pays for its 31 byte output, the bundle for all
+        // of its 232 vbytes.
+        let main_fee_rate = FeeRate::from_sat_per_kvb(200);
+        assert_eq!(
+            withdrawal_cost(&main_address, main_fee_rate),
+            Amount::from_sat(7)
+        );
+        let limits = BundleLimits {
+            main_fee_rate,
+            ..BundleLimits::default()
+        };
+        let bundle = cut_bundle(withdrawals.iter().map(|(o, w)| (o, w)), &limits).unwrap();
+        assert_eq!(bundle.vsize(), 232);
+        assert_eq!(bundle.fee, 60);
+        // At 400 sat/kvB the cheapest one doesn't pay for its output, and the
+        // other two don't pay for the bundle.
+        let limits = BundleLimits {
+            main_fee_rate: FeeRate::from_sat_per_kvb(400),
+            ..BundleLimits::default()
+        };
+        assert!(cut_bundle(withdrawals.iter().map(|(o, w)| (o, w)), &limits).is_none());
+    }
+}
@@ -1,75 +1,565 @@
-use crate::types::{Deposit, DepositOutput, DepositsChunk, OutPoint};
+use crate::backend::MainchainBackend;
+use crate::batch::{BatchRequest, BatchResponse, JsonRpcResponse};
+use crate::bundle::Bundle;
+use crate::retry::RetryConfig;
+use crate::socks::{self, Proxy};
+use crate::spv;
+use crate::types::{
+    AddressError, Amount, BlockHash, Deposit, DepositOutput, DepositsChunk, FeeRate, OutPoint,
+};
+use base64::Engine;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::util::psbt::serialize::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::json;
 use std::collections::HashMap;
-use ureq_jsonrpc::json;
+use std::io::Read;
+use std::time::Duration;
 
-// TODO: Implement mock client for running unit tests.
+pub(crate) const RPC_ID: &str = "sdk";
+
+// Cloning is cheap, clones share the same connection pool.
+#[derive(Clone)]
 pub struct Client {
     pub this_sidechain: usize,
-    pub client: ureq_jsonrpc::Client,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) user: String,
+    pub(crate) password: String,
+    pub(crate) tls: Option<TlsConfig>,
+    pub(crate) connection: ConnectionConfig,
+    pub(crate) min_confirmations: u32,
+    retry: RetryConfig,
+    agent: ureq::Agent,
+    // Local port that tunnels connections through the proxy to the node.
+    forwarder: Option<std::net::SocketAddr>,
+    #[cfg(feature = "tls")]
+    tls_connector: Option<std::sync::Arc<native_tls::TlsConnector>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    pub connect_timeout: Duration,
+    // Limit for a whole call, from connecting to reading the last byte of
+    // the response. None means wait forever.
+    pub timeout: Option<Duration>,
+    // Keep idle connections open and reuse them for later calls.
+    pub keep_alive: bool,
+    // Number of idle connections kept open, enough for every thread that
+    // shares the client to reuse its own.
+    pub max_idle_connections: usize,
+    // Responses larger than this many bytes are rejected instead of being
+    // read into memory.
+    pub max_response_size: u64,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Some(Duration::from_secs(300)),
+            keep_alive: true,
+            max_idle_connections: 8,
+            max_response_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
+// Settings for talking to a mainchain node over https. By default the
+// server certificate is checked against the system trust store.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    // PEM encoded certificate of an additional trusted CA, for nodes that
+    // use a private CA or a self-signed certificate.
+    pub ca_certificate: Option<Vec<u8>>,
+    pub accept_invalid_certs: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct VerifiedBMM {
     pub time: i64,
     pub txid: bitcoin::Txid,
 }
 
 impl Client {
-    pub fn get_deposits(&self, last_deposit: Option<Deposit>) -> Result<DepositsChunk, Error> {
-        let (outpoint, mut prev_value) = match last_deposit {
-            Some(Deposit { outpoint, total }) => {
-                (vec![json!(outpoint.txid), json!(outpoint.vout)], total)
-            }
-            None => (vec![], 0),
+    pub fn new(this_sidechain: usize, host: &str, port: u16, user: &str, password: &str) -> Self {
+        let mut client = Self {
+            this_sidechain,
+            host: host.into(),
+            port,
+            user: user.into(),
+            password: password.into(),
+            tls: None,
+            connection: ConnectionConfig::default(),
+            min_confirmations: 0,
+            retry: RetryConfig::default(),
+            agent: ureq::Agent::new(),
+            forwarder: None,
+            #[cfg(feature = "tls")]
+            tls_connector: None,
         };
-        let params = &[vec![self.this_sidechain.into()], outpoint].concat();
-        let json_deposits = self
-            .client
-            .send_request::<Vec<JsonDeposit>>("listsidechaindeposits", params)?;
-        let mut outputs = HashMap::new();
-        let mut outpoint_to_tx = HashMap::new();
-        for deposit in json_deposits.iter().cloned().rev() {
-            let tx = hex::decode(deposit.txhex)?;
-            let tx = Transaction::deserialize(tx.as_slice())?;
-            let outpoint = OutPoint::Deposit(bitcoin::OutPoint {
-                txid: tx.txid(),
-                vout: deposit.nburnindex as u32,
-            });
-            let value = tx.output[deposit.nburnindex].value;
-            if value < prev_value {
-                continue;
-            }
-            let output = DepositOutput {
-                address: deposit.strdest.parse()?,
-                value: value - prev_value,
-            };
-            prev_value = value;
-            if let OutPoint::Deposit(outpoint) = outpoint {
-                outpoint_to_tx.insert(outpoint, tx);
+        client.agent = client.build_agent();
+        client
+    }
+
+    // Only report deposits that are buried under at least this many
+    // mainchain blocks, so shallow reorgs can't take back credited deposits.
+    pub fn with_min_confirmations(mut self, min_confirmations: u32) -> Self {
+        self.min_confirmations = min_confirmations;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_connection(mut self, connection: ConnectionConfig) -> Self {
+        self.connection = connection;
+        self.agent = self.build_agent();
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> Result<Self, Error> {
+        let mut connector = native_tls::TlsConnector::builder();
+        if let Some(ca_certificate) = &tls.ca_certificate {
+            connector.add_root_certificate(native_tls::Certificate::from_pem(ca_certificate)?);
+        }
+        connector.danger_accept_invalid_certs(tls.accept_invalid_certs);
+        self.tls_connector = Some(std::sync::Arc::new(connector.build()?));
+        self.tls = Some(tls);
+        self.agent = self.build_agent();
+        Ok(self)
+    }
+
+    // Routes calls through a SOCKS5 proxy, like Tor. ureq only connects to
+    // addresses itself, so it's pointed at a local port that does the
+    // tunneling, certificates are still checked against the node's host.
+    pub fn with_proxy(mut self, proxy: Proxy) -> Result<Self, Error> {
+        self.forwarder = Some(socks::forward(proxy, self.host.clone(), self.port)?);
+        self.agent = self.build_agent();
+        Ok(self)
+    }
+
+    fn build_agent(&self) -> ureq::Agent {
+        let connection = &self.connection;
+        let mut builder = ureq::AgentBuilder::new().timeout_connect(connection.connect_timeout);
+        if let Some(timeout) = connection.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder = if connection.keep_alive {
+            builder
+                .max_idle_connections(connection.max_idle_connections)
+                .max_idle_connections_per_host(connection.max_idle_connections)
+        } else {
+            builder.max_idle_connections(0)
+        };
+        #[cfg(feature = "tls")]
+        if let Some(tls_connector) = &self.tls_connector {
+            builder = builder.tls_connector(tls_connector.clone());
+        }
+        if let Some(forwarder) = self.forwarder {
+            builder = builder.resolver(move |_: &str| Ok(vec![forwarder]));
+        }
+        builder.build()
+    }
+
+    pub(crate) fn url(&self) -> String {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, self.host, self.port)
+    }
+
+    fn post<T: DeserializeOwned>(&self, body: &serde_json::Value) -> Result<T, Error> {
+        let auth = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", self.user, self.password));
+        let response = match self
+            .agent
+            .post(&self.url())
+            .set("Authorization", &format!("Basic {}", auth))
+            .send_json(body)
+        {
+            Ok(response) => response,
+            // Failed calls come back with an error status, but the body
+            // still holds the JSON-RPC error.
+            Err(ureq::Error::Status(_, response))
+                if response.content_type() == "application/json" =>
+            {
+                response
             }
-            outputs.insert(outpoint, output);
+            Err(ureq::Error::Status(status, _)) => return Err(Error::HttpStatus(status)),
+            Err(err) => return Err(err.into()),
+        };
+        // The connection only goes back to the pool once the body is read
+        // to the end.
+        let max_response_size = self.connection.max_response_size;
+        let mut body = vec![];
+        response
+            .into_reader()
+            .take(max_response_size.saturating_add(1))
+            .read_to_end(&mut body)?;
+        if body.len() as u64 > max_response_size {
+            return Err(Error::ResponseTooLarge(max_response_size));
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    pub fn send_request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[serde_json::Value],
+    ) -> Result<T, Error> {
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": RPC_ID,
+            "method": method,
+            "params": params,
+        });
+        self.retry.run(Error::is_retryable, || {
+            self.post::<JsonRpcResponse>(&request)?.into_result()
+        })
+    }
+
+    // Sends `amount` from the mainchain node's wallet to a sidechain deposit
+    // address, meant for regtest setups. Returns the deposit txid.
+    pub fn create_deposit(
+        &self,
+        deposit_address: &str,
+        amount: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<bitcoin::Txid, Error> {
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": RPC_ID,
+            "method": "createsidechaindeposit",
+            "params": [self.this_sidechain, deposit_address, amount.to_btc(), fee.to_btc()],
+        });
+        // Not retried, a call that timed out might still have been
+        // processed and retrying it would deposit twice.
+        self.post::<JsonRpcResponse>(&request)?.into_result()
+    }
+
+    pub fn get_block(&self, block_hash: &bitcoin::BlockHash) -> Result<bitcoin::Block, Error> {
+        let block: String = self.send_request("getblock", &[json!(block_hash), json!(0)])?;
+        Ok(bitcoin::consensus::deserialize(&hex::decode(block)?)?)
+    }
+
+    pub fn get_raw_block_header(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::BlockHeader, Error> {
+        let header: String =
+            self.send_request("getblockheader", &[json!(block_hash), json!(false)])?;
+        Ok(bitcoin::consensus::deserialize(&hex::decode(header)?)?)
+    }
+
+    // Merkle proof that `txid` is included in the block `block_hash`.
+    pub fn get_tx_out_proof(
+        &self,
+        txid: &bitcoin::Txid,
+        block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::MerkleBlock, Error> {
+        let proof: String =
+            self.send_request("gettxoutproof", &[json!([txid]), json!(block_hash)])?;
+        Ok(bitcoin::consensus::deserialize(&hex::decode(proof)?)?)
+    }
+
+    // Fetches a proof of the deposit transaction's inclusion in its
+    // mainchain block and checks it against `header`, which the caller got
+    // from somewhere it trusts.
+    pub fn verify_deposit(
+        &self,
+        deposit: &Deposit,
+        header: &bitcoin::BlockHeader,
+    ) -> Result<(), Error> {
+        let proof = self.get_tx_out_proof(&deposit.outpoint.txid, &deposit.main_block_hash)?;
+        Ok(spv::verify_inclusion(
+            &proof,
+            &deposit.outpoint.txid,
+            header,
+        )?)
+    }
+
+    pub fn send_batch(&self, batch: &BatchRequest) -> Result<BatchResponse, Error> {
+        let request = batch.to_json();
+        let responses = self.retry.run(Error::is_retryable, || {
+            self.post::<Vec<JsonRpcResponse>>(&request)
+        })?;
+        BatchResponse::new(batch, responses)
+    }
+
+    fn fetch_deposits(
+        &self,
+        last_deposit: Option<Deposit>,
+        limit: Option<usize>,
+    ) -> Result<DepositsChunk, Error> {
+        let (params, prev_value) = deposits_params(self.this_sidechain, last_deposit, limit);
+        let json_deposits =
+            self.send_request::<Vec<JsonDeposit>>("listsidechaindeposits", &params)?;
+        // Headers of the blocks the deposits are in, for their heights.
+        let block_hashes = deposit_block_hashes(&json_deposits);
+        let headers = match block_hashes.is_empty() {
+            true => HashMap::new(),
+            false => block_headers(
+                &block_hashes,
+                &self.send_batch(&block_headers_batch(&block_hashes))?,
+            )?,
+        };
+        let json_deposits = filter_confirmed(json_deposits, &headers, self.min_confirmations);
+        deposits_chunk(json_deposits, prev_value, &headers)
+    }
+}
+
+impl MainchainBackend for Client {
+    fn get_deposits(&self, last_deposit: Option<Deposit>) -> Result<DepositsChunk, Error> {
+        self.fetch_deposits(last_deposit, None)
+    }
+
+    fn get_deposits_page(
+        &self,
+        last_deposit: Option<Deposit>,
+        limit: usize,
+    ) -> Result<DepositsChunk, Error> {
+        self.fetch_deposits(last_deposit, Some(limit))
+    }
+
+    fn verify_bmm(
+        &self,
+        main_block_hash: &bitcoin::BlockHash,
+        critical_hash: &BlockHash,
+    ) -> Result<VerifiedBMM, Error> {
+        let params = &[
+            json!(main_block_hash),
+            json!(critical_hash.to_string()),
+            json!(self.this_sidechain),
+        ];
+        let response = self.send_request::<JsonVerifiedBMM>("verifybmm", params)?;
+        Ok(response.bmm)
+    }
+
+    fn create_bmm_request(
+        &self,
+        critical_hash: &BlockHash,
+        amount: bitcoin::Amount,
+        height: usize,
+        prev_main_block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::Txid, Error> {
+        // The mainchain only includes the request in a block whose previous
+        // block hash ends with these bytes.
+        let prev_main_block_hash = prev_main_block_hash.to_string();
+        let prev_bytes = &prev_main_block_hash[prev_main_block_hash.len() - 8..];
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": RPC_ID,
+            "method": "createbmmcriticaldatatx",
+            "params": [
+                amount.to_btc(),
+                height,
+                critical_hash.to_string(),
+                self.this_sidechain,
+                prev_bytes,
+            ],
+        });
+        // Not retried, like create_deposit, a retry could pay twice.
+        let response: JsonBmmRequest = self.post::<JsonRpcResponse>(&request)?.into_result()?;
+        Ok(response.txid.into())
+    }
+
+    fn get_block_count(&self) -> Result<usize, Error> {
+        self.send_request("getblockcount", &[])
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<bitcoin::BlockHash, Error> {
+        self.send_request("getblockhash", &[json!(height)])
+    }
+
+    fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error> {
+        self.send_request("getbestblockhash", &[])
+    }
+
+    fn get_block_header(&self, block_hash: &bitcoin::BlockHash) -> Result<MainBlockHeader, Error> {
+        self.send_request("getblockheader", &[json!(block_hash), json!(true)])
+    }
+
+    fn get_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error> {
+        let spent_withdrawals =
+            self.send_request::<Vec<SpentWithdrawal>>("listspentwithdrawals", &[])?;
+        Ok(spent_withdrawals
+            .into_iter()
+            .filter(|withdrawal| withdrawal.nsidechain == self.this_sidechain)
+            .collect())
+    }
+
+    fn get_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error> {
+        let failed_withdrawals =
+            self.send_request::<Vec<FailedWithdrawal>>("listfailedwithdrawals", &[])?;
+        Ok(failed_withdrawals
+            .into_iter()
+            .filter(|withdrawal| withdrawal.nsidechain == self.this_sidechain)
+            .collect())
+    }
+
+    fn submit_bundle(&self, bundle: &Bundle) -> Result<bitcoin::Txid, Error> {
+        let transaction = bundle.transaction();
+        let raw = hex::encode(bitcoin::consensus::serialize(&transaction));
+        self.send_request::<serde_json::Value>(
+            "receivewithdrawalbundle",
+            &[json!(self.this_sidechain), json!(raw)],
+        )?;
+        Ok(transaction.txid())
+    }
+
+    fn estimate_smart_fee(&self, conf_target: u16) -> Result<Option<FeeRate>, Error> {
+        let estimate: JsonFeeEstimate =
+            self.send_request("estimatesmartfee", &[json!(conf_target)])?;
+        Ok(estimate.fee_rate())
+    }
+
+    fn verify_bmm_batch(
+        &self,
+        commitments: &[(bitcoin::BlockHash, BlockHash)],
+    ) -> Result<Vec<Result<VerifiedBMM, Error>>, Error> {
+        if commitments.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut batch = BatchRequest::new();
+        for (main_block_hash, critical_hash) in commitments {
+            batch.push(
+                "verifybmm",
+                &[
+                    json!(main_block_hash),
+                    json!(critical_hash.to_string()),
+                    json!(self.this_sidechain),
+                ],
+            );
         }
-        let deposits = sort_deposits(&outpoint_to_tx);
-        Ok(DepositsChunk { outputs, deposits })
+        let response = self.send_batch(&batch)?;
+        Ok((0..batch.len())
+            .map(|index| Ok(response.get::<JsonVerifiedBMM>(index)?.bmm))
+            .collect())
     }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("ureq error")]
-    Ureq(#[from] ureq_jsonrpc::Error),
     #[error("failed to decode hex value")]
     Hex(#[from] hex::FromHexError),
     #[error("bitcoin encoding error")]
     BitcoinEncode(#[from] bitcoin::consensus::encode::Error),
-    #[error("bs58 decode errro")]
-    Bs58Decode(#[from] bs58::decode::Error),
+    #[error("invalid deposit address: {0}")]
+    Address(#[from] AddressError),
+    #[error("spv proof error")]
+    Spv(#[from] spv::Error),
+    #[error("mock client error: {0}")]
+    Mock(&'static str),
+    #[cfg(feature = "regtest")]
+    #[error("regtest node error: {0}")]
+    Regtest(&'static str),
+    #[error("json rpc error {code}: {message}")]
+    Rpc { code: i64, message: String },
+    #[error("mainchain node is still starting up: {0}")]
+    InWarmup(String),
+    #[error("rpc method not found: {0}")]
+    MethodNotFound(String),
+    #[error("invalid rpc params: {0}")]
+    InvalidParams(String),
+    #[error("sidechain is not active on the mainchain: {0}")]
+    UnknownSidechain(String),
+    #[error("invalid address or key: {0}")]
+    InvalidAddressOrKey(String),
+    #[error("mainchain wallet is locked: {0}")]
+    WalletLocked(String),
+    #[error("insufficient funds in mainchain wallet: {0}")]
+    InsufficientFunds(String),
+    #[error("json error")]
+    Json(#[from] serde_json::Error),
+    #[error("http error")]
+    Http(#[from] Box<ureq::Error>),
+    #[error("http status {0}")]
+    HttpStatus(u16),
+    #[error("response is larger than {0} bytes")]
+    ResponseTooLarge(u64),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("batch response has an unexpected id")]
+    UnexpectedResponseId,
+    #[error("batch response is missing a result for call {0}")]
+    MissingBatchResponse(usize),
+    #[cfg(feature = "async")]
+    #[error("reqwest error")]
+    Reqwest(#[from] reqwest::Error),
+    #[cfg(feature = "tls")]
+    #[error("tls error")]
+    Tls(#[from] native_tls::Error),
+}
+
+// Error codes used by Bitcoin Core and drivechain, see src/rpc/protocol.h.
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+const RPC_INVALID_PARAMS: i64 = -32602;
+const RPC_INVALID_PARAMETER: i64 = -8;
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+const RPC_WALLET_INSUFFICIENT_FUNDS: i64 = -6;
+const RPC_WALLET_UNLOCK_NEEDED: i64 = -13;
+const RPC_IN_WARMUP: i64 = -28;
+
+impl Error {
+    // Maps a JSON-RPC error returned by the node to a variant callers can
+    // match on, codes without a dedicated variant end up in Error::Rpc.
+    pub fn from_rpc(code: i64, message: String) -> Self {
+        match code {
+            RPC_IN_WARMUP => Self::InWarmup(message),
+            RPC_METHOD_NOT_FOUND => Self::MethodNotFound(message),
+            // drivechain doesn't have a separate code for sidechain numbers
+            // that aren't active, only the message tells them apart.
+            RPC_INVALID_PARAMETER if message.to_lowercase().contains("sidechain") => {
+                Self::UnknownSidechain(message)
+            }
+            RPC_INVALID_PARAMS | RPC_INVALID_PARAMETER => Self::InvalidParams(message),
+            RPC_INVALID_ADDRESS_OR_KEY => Self::InvalidAddressOrKey(message),
+            RPC_WALLET_UNLOCK_NEEDED => Self::WalletLocked(message),
+            RPC_WALLET_INSUFFICIENT_FUNDS => Self::InsufficientFunds(message),
+            _ => Self::Rpc { code, message },
+        }
+    }
+
+    // Transient failures that are worth another attempt, as opposed to
+    // permanent errors reported by the node itself.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Http(_) | Self::Io(_) | Self::InWarmup(_))
+            || matches!(self, Self::HttpStatus(status) if *status >= 500)
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(other: ureq::Error) -> Self {
+        Self::Http(Box::new(other))
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct JsonBmmRequest {
+    txid: JsonBmmTxid,
+}
+
+// Depending on the version, drivechain nodes answer with the txid or with an
+// object holding it.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum JsonBmmTxid {
+    Txid(bitcoin::Txid),
+    Object { txid: bitcoin::Txid },
+}
+
+impl From<JsonBmmTxid> for bitcoin::Txid {
+    fn from(txid: JsonBmmTxid) -> Self {
+        match txid {
+            JsonBmmTxid::Txid(txid) | JsonBmmTxid::Object { txid } => txid,
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-struct JsonDeposit {
+pub(crate) struct JsonDeposit {
     hashblock: bitcoin::BlockHash,
     nburnindex: usize,
     nsidechain: usize,
@@ -78,6 +568,52 @@ struct JsonDeposit {
     txhex: String,
 }
 
+// feerate is in BTC/kvB and missing when the node can't estimate yet, the
+// reason is in errors.
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct JsonFeeEstimate {
+    #[serde(default, with = "bitcoin::util::amount::serde::as_btc::opt")]
+    feerate: Option<bitcoin::Amount>,
+}
+
+impl JsonFeeEstimate {
+    pub(crate) fn fee_rate(&self) -> Option<FeeRate> {
+        self.feerate
+            .map(|feerate| FeeRate::from_sat_per_kvb(feerate.to_sat()))
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct JsonVerifiedBMM {
+    pub bmm: VerifiedBMM,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MainBlockHeader {
+    pub hash: bitcoin::BlockHash,
+    // -1 if the block is not in the main chain anymore.
+    pub confirmations: i64,
+    pub height: usize,
+    pub time: u64,
+    pub previousblockhash: Option<bitcoin::BlockHash>,
+    pub nextblockhash: Option<bitcoin::BlockHash>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SpentWithdrawal {
+    pub nsidechain: usize,
+    pub hash: bitcoin::Txid,
+    pub hashblock: bitcoin::BlockHash,
+}
+
+// A withdrawal bundle that didn't gather enough miner acks and was dropped
+// by the mainchain, its withdrawals have to be refunded or bundled again.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FailedWithdrawal {
+    pub nsidechain: usize,
+    pub hash: bitcoin::Txid,
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct MainDeposit {
     address: String,
@@ -90,13 +626,122 @@ pub struct DepositOutpoint {
     index: usize,
 }
 
-fn sort_deposits(deposits: &HashMap<bitcoin::OutPoint, bitcoin::Transaction>) -> Vec<Deposit> {
+pub(crate) fn deposit_block_hashes(json_deposits: &[JsonDeposit]) -> Vec<bitcoin::BlockHash> {
+    let mut block_hashes: Vec<bitcoin::BlockHash> = json_deposits
+        .iter()
+        .map(|deposit| deposit.hashblock)
+        .collect();
+    block_hashes.sort();
+    block_hashes.dedup();
+    block_hashes
+}
+
+pub(crate) fn block_headers_batch(block_hashes: &[bitcoin::BlockHash]) -> BatchRequest {
+    let mut batch = BatchRequest::new();
+    for block_hash in block_hashes {
+        batch.push("getblockheader", &[json!(block_hash), json!(true)]);
+    }
+    batch
+}
+
+pub(crate) fn block_headers(
+    block_hashes: &[bitcoin::BlockHash],
+    response: &BatchResponse,
+) -> Result<HashMap<bitcoin::BlockHash, MainBlockHeader>, Error> {
+    let mut headers = HashMap::new();
+    for (index, block_hash) in block_hashes.iter().enumerate() {
+        headers.insert(*block_hash, response.get::<MainBlockHeader>(index)?);
+    }
+    Ok(headers)
+}
+
+// Deposits form a chain where every deposit spends the previous one, so
+// once a deposit is too shallow all the following ones are too.
+pub(crate) fn filter_confirmed(
+    json_deposits: Vec<JsonDeposit>,
+    headers: &HashMap<bitcoin::BlockHash, MainBlockHeader>,
+    min_confirmations: u32,
+) -> Vec<JsonDeposit> {
+    json_deposits
+        .into_iter()
+        .filter(|deposit| {
+            let confirmations = headers
+                .get(&deposit.hashblock)
+                .map(|header| header.confirmations);
+            confirmations.unwrap_or(0) >= min_confirmations as i64
+        })
+        .collect()
+}
+
+pub(crate) fn deposits_params(
+    this_sidechain: usize,
+    last_deposit: Option<Deposit>,
+    limit: Option<usize>,
+) -> (Vec<serde_json::Value>, u64) {
+    let (outpoint, prev_value) = match last_deposit {
+        Some(Deposit {
+            outpoint, total, ..
+        }) => (vec![json!(outpoint.txid), json!(outpoint.vout)], total),
+        None => (vec![], 0),
+    };
+    let mut params = [vec![this_sidechain.into()], outpoint].concat();
+    if let Some(limit) = limit {
+        // count comes after the optional cursor, so an empty one has to be
+        // passed explicitly.
+        if params.len() == 1 {
+            params.extend([json!(""), json!(0)]);
+        }
+        params.push(json!(limit));
+    }
+    (params, prev_value)
+}
+
+pub(crate) fn deposits_chunk(
+    json_deposits: Vec<JsonDeposit>,
+    mut prev_value: u64,
+    headers: &HashMap<bitcoin::BlockHash, MainBlockHeader>,
+) -> Result<DepositsChunk, Error> {
+    let mut outputs = HashMap::new();
+    let mut outpoint_to_tx = HashMap::new();
+    for deposit in json_deposits.into_iter().rev() {
+        let tx = hex::decode(deposit.txhex)?;
+        let tx = Transaction::deserialize(tx.as_slice())?;
+        let outpoint = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: tx.txid(),
+            vout: deposit.nburnindex as u32,
+        });
+        let value = tx.output[deposit.nburnindex].value;
+        if value < prev_value {
+            continue;
+        }
+        let output = DepositOutput {
+            address: deposit.strdest.parse()?,
+            value: Amount::from_sat(value - prev_value),
+        };
+        prev_value = value;
+        if let OutPoint::Deposit(outpoint) = outpoint {
+            outpoint_to_tx.insert(outpoint, (tx, deposit.hashblock));
+        }
+        outputs.insert(outpoint, output);
+    }
+    let main_heights = headers
+        .iter()
+        .map(|(block_hash, header)| (*block_hash, header.height))
+        .collect();
+    let deposits = sort_deposits(&outpoint_to_tx, &main_heights);
+    Ok(DepositsChunk { outputs, deposits })
+}
+
+fn sort_deposits(
+    deposits: &HashMap<bitcoin::OutPoint, (bitcoin::Transaction, bitcoin::BlockHash)>,
+    main_heights: &HashMap<bitcoin::BlockHash, usize>,
+) -> Vec<Deposit> {
     if deposits.is_empty() {
         return vec![];
     }
     let mut spent_by = HashMap::<bitcoin::OutPoint, bitcoin::OutPoint>::new();
     let mut sorted_deposits = vec![];
-    for (outpoint, tx) in deposits {
+    for (outpoint, (tx, main_block_hash)) in deposits {
         let mut spent = false;
         for input in &tx.input {
             if deposits.contains_key(&input.previous_output) {
@@ -107,19 +752,23 @@ fn sort_deposits(deposits: &HashMap<bitcoin::OutPoint, bitcoin::Transaction>) ->
         if !spent {
             let total = tx.output[outpoint.vout as usize].value;
             sorted_deposits.push(Deposit {
-                outpoint: outpoint.clone(),
+                outpoint: *outpoint,
                 total,
+                main_block_hash: *main_block_hash,
+                main_height: main_heights.get(main_block_hash).copied().unwrap_or(0),
             });
         }
     }
     let mut outpoint = sorted_deposits[0].outpoint;
     while let Some(next) = spent_by.get(&outpoint) {
         if deposits.contains_key(next) {
-            let tx = &deposits[next];
+            let (tx, main_block_hash) = &deposits[next];
             let total = tx.output[next.vout as usize].value;
             sorted_deposits.push(Deposit {
-                outpoint: next.clone(),
+                outpoint: *next,
                 total,
+                main_block_hash: *main_block_hash,
+                main_height: main_heights.get(main_block_hash).copied().unwrap_or(0),
             });
             outpoint = *next;
         }
@@ -131,6 +780,7 @@ fn sort_deposits(deposits: &HashMap<bitcoin::OutPoint, bitcoin::Transaction>) ->
 mod tests {
     use super::*;
 
+    #[allow(dead_code)]
     pub fn format_deposit_address(sidechain_number: usize, address: &str) -> String {
         let deposit_address: String = format!("s{}_{}_", sidechain_number, address);
         let hash = sha256::digest(deposit_address.as_bytes()).to_string();
@@ -139,17 +789,9 @@ mod tests {
     }
 
     #[test]
+    #[ignore = "requires a running drivechain node"]
     fn it_works() -> anyhow::Result<()> {
-        let client = Client {
-            this_sidechain: 0,
-            client: ureq_jsonrpc::Client {
-                host: "localhost".into(),
-                port: 18443,
-                user: "user".into(),
-                password: "password".into(),
-                id: "sdk".into(),
-            },
-        };
+        let client = Client::new(0, "localhost", 18443, "user", "password");
         let deposits = client.get_deposits(None)?;
         dbg!(deposits);
         Ok(())
@@ -1,11 +1,12 @@
 use crate::types::*;
+use bitcoin::secp256k1::{schnorr, KeyPair, Message, Secp256k1, XOnlyPublicKey};
 use ed25519_dalek::{Signer, Verifier};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Output {
     pub address: Address,
-    pub value: u64,
+    pub value: Amount,
 }
 
 impl Out for Output {
@@ -16,13 +17,25 @@ impl Out for Output {
         outputs: &[Self],
         withdrawal_outputs: &[WithdrawalOutput],
     ) -> bool {
-        let regular_in: u64 = inputs.iter().map(|i| i.value).sum();
-        let deposit_in: u64 = deposit_inputs.iter().map(|i| i.value).sum();
-        let refund_in: u64 = withdrawal_inputs.iter().map(|i| i.value).sum();
-
-        let regular_out: u64 = outputs.iter().map(|o| o.value).sum();
-        let withdrawal_out: u64 = withdrawal_outputs.iter().map(|o| o.value).sum();
-        regular_out + withdrawal_out > regular_in + deposit_in + refund_in
+        let value_in = Amount::checked_sum(
+            inputs
+                .iter()
+                .map(|i| i.value)
+                .chain(deposit_inputs.iter().map(|i| i.value))
+                .chain(withdrawal_inputs.iter().map(|i| i.value)),
+        );
+        let value_out = Amount::checked_sum(
+            outputs
+                .iter()
+                .map(|o| o.value)
+                .chain(withdrawal_outputs.iter().map(|o| o.value)),
+        );
+        // Values that don't add up to a valid amount are as bad as spending
+        // more than the inputs.
+        match (value_in, value_out) {
+            (Some(value_in), Some(value_out)) => value_out > value_in,
+            _ => true,
+        }
     }
     fn get_fee(
         inputs: &[Self],
@@ -30,18 +43,21 @@ impl Out for Output {
         withdrawal_inputs: &[WithdrawalOutput],
         outputs: &[Self],
         withdrawal_outputs: &[WithdrawalOutput],
-    ) -> u64 {
-        let regular_in: u64 = inputs.iter().map(|i| i.value).sum();
-        let deposit_in: u64 = deposit_inputs.iter().map(|i| i.value).sum();
-        let withdrawal_in: u64 = withdrawal_inputs.iter().map(|i| i.value).sum();
+    ) -> Amount {
+        let regular_in: Amount = inputs.iter().map(|i| i.value).sum();
+        let deposit_in: Amount = deposit_inputs.iter().map(|i| i.value).sum();
+        let withdrawal_in: Amount = withdrawal_inputs.iter().map(|i| i.value).sum();
 
-        let regular_out: u64 = outputs.iter().map(|o| o.value).sum();
-        let withdrawal_out: u64 = withdrawal_outputs.iter().map(|wo| wo.value).sum();
+        let regular_out: Amount = outputs.iter().map(|o| o.value).sum();
+        let withdrawal_out: Amount = withdrawal_outputs.iter().map(|wo| wo.value).sum();
         (regular_in + deposit_in + withdrawal_in) - (regular_out + withdrawal_out)
     }
     fn get_address(&self) -> Address {
         self.address
     }
+    fn get_value(&self) -> Amount {
+        self.value
+    }
 }
 
 impl Ord for Output {
@@ -52,7 +68,7 @@ impl Ord for Output {
 
 impl PartialOrd for Output {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.value.partial_cmp(&other.value)
+        Some(self.cmp(other))
     }
 }
 
@@ -71,11 +87,11 @@ pub struct Signature {
 }
 
 impl Signature {
-    pub fn new(
+    pub fn new<O: Serialize + Clone>(
         keypair: &ed25519_dalek::Keypair,
-        transaction: &Transaction<Signature, Output>,
+        transaction: &Transaction<Signature, O>,
     ) -> Self {
-        let hash: Hash = transaction.txid().into();
+        let hash = transaction.sighash();
         Self {
             signature: keypair.sign(&hash),
             public_key: keypair.public,
@@ -84,12 +100,93 @@ impl Signature {
 }
 
 impl Sig for Signature {
-    fn is_valid(&self, txid_without_signatures: Txid) -> bool {
-        let hash: Hash = txid_without_signatures.into();
-        self.public_key.verify(&hash, &self.signature).is_ok()
+    fn is_valid(&self, sighash: Hash) -> bool {
+        self.public_key.verify(&sighash, &self.signature).is_ok()
     }
 
     fn get_address(&self) -> Address {
         self.public_key.into()
     }
 }
+
+// BIP340 Schnorr signature over secp256k1, lets sidechain keys be derived
+// from the same seeds as Bitcoin taproot keys.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct SchnorrSignature {
+    public_key: XOnlyPublicKey,
+    signature: schnorr::Signature,
+}
+
+impl SchnorrSignature {
+    pub fn new<O: Serialize + Clone>(
+        keypair: &KeyPair,
+        transaction: &Transaction<SchnorrSignature, O>,
+    ) -> Self {
+        let hash = transaction.sighash();
+        let message = Message::from_slice(&hash).expect("sighash is 32 bytes");
+        Self {
+            signature: Secp256k1::signing_only().sign_schnorr_no_aux_rand(&message, keypair),
+            public_key: keypair.x_only_public_key().0,
+        }
+    }
+}
+
+impl Sig for SchnorrSignature {
+    fn is_valid(&self, sighash: Hash) -> bool {
+        let message = Message::from_slice(&sighash).expect("sighash is 32 bytes");
+        Secp256k1::verification_only()
+            .verify_schnorr(&self.signature, &message, &self.public_key)
+            .is_ok()
+    }
+
+    fn get_address(&self) -> Address {
+        self.public_key.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schnorr_signature_commits_to_sighash() {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let transaction = Transaction::<SchnorrSignature, Output> {
+            version: TRANSACTION_VERSION,
+            inputs: vec![],
+            signatures: vec![],
+            outputs: vec![Output {
+                address: keypair.x_only_public_key().0.into(),
+                value: Amount::from_sat(100),
+            }],
+            withdrawal_outputs: vec![],
+            extra: vec![],
+        };
+        let signature = SchnorrSignature::new(&keypair, &transaction);
+        assert!(signature.is_valid(transaction.sighash()));
+        assert_eq!(signature.get_address(), transaction.outputs[0].address);
+        let other = Transaction {
+            extra: vec![1],
+            ..transaction.clone()
+        };
+        assert!(!signature.is_valid(other.sighash()));
+        // Nor can it be moved onto a spend of other coins.
+        let other = Transaction {
+            inputs: vec![OutPoint::Regular {
+                txid: [1; 32].into(),
+                vout: 0,
+            }],
+            ..transaction.clone()
+        };
+        assert!(!signature.is_valid(other.sighash()));
+        // Signing the bare txid, outside the sighash domain, doesn't count.
+        let txid: Hash = transaction.txid().into();
+        let bare = SchnorrSignature {
+            signature: secp
+                .sign_schnorr_no_aux_rand(&Message::from_slice(&txid).unwrap(), &keypair),
+            ..signature
+        };
+        assert!(!bare.is_valid(transaction.sighash()));
+    }
+}
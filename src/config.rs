@@ -0,0 +1,672 @@
+use crate::params::SidechainParams;
+use crate::types::{FeeRate, THIS_SIDECHAIN};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// Port sidechain nodes accept peers on unless configured otherwise.
+pub const DEFAULT_P2P_PORT: u16 = 18445;
+// Read when no path is given and the file exists.
+pub const DEFAULT_CONFIG_FILE: &str = "sdk.toml";
+// Every setting can be overridden with an environment variable named after
+// it, like SDK_RPC_PORT for rpc.port.
+const ENV_PREFIX: &str = "SDK_";
+
+// Node settings, loaded from a TOML file with every field optional:
+//
+//   data_dir = "/var/lib/sdk"
+//   sidechain = 0
+//   bmm = true
+//
+//   proxy = "127.0.0.1:9050"
+//
+//   [rpc]
+//   port = 18444
+//   password = "secret"
+//
+//   [mainchain]
+//   host = "localhost"
+//   port = 18443
+//
+//   [mempool]
+//   max_size = 300
+//   min_fee_rate = 1000
+//
+//   [p2p]
+//   enabled = true
+//   listen = "0.0.0.0:18445"
+//   peers = ["node.example.com:18445"]
+//   seeds = ["seed.example.com"]
+//   max_outbound = 8
+//
+//   [rest]
+//   enabled = true
+//   port = 18446
+//
+//   [grpc]
+//   enabled = true
+//   host = "0.0.0.0"
+//
+//   [ws]
+//   enabled = true
+//
+//   [electrum]
+//   enabled = true
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub sidechain: usize,
+    // Blocks need a BMM commitment on the mainchain, see
+    // SidechainParams::bmm. Only off for chains mined locally.
+    pub bmm: bool,
+    // Relative paths are inside data_dir, defaults to data_dir/wallet.dat.
+    pub wallet: Option<PathBuf>,
+    // Named wallets loaded next to the default one, kept in
+    // data_dir/wallets and created if they don't exist yet.
+    pub wallets: Vec<String>,
+    // SOCKS5 proxy outbound connections go through, like Tor's
+    // 127.0.0.1:9050.
+    pub proxy: Option<SocketAddr>,
+    pub rpc: RpcConfig,
+    pub mainchain: MainchainConfig,
+    pub mining: MiningConfig,
+    pub mempool: MempoolConfig,
+    pub p2p: P2pConfig,
+    pub rest: RestConfig,
+    pub grpc: GrpcConfig,
+    pub ws: WsConfig,
+    pub electrum: ElectrumConfig,
+}
+
+// The node's own JSON-RPC server.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+}
+
+// The drivechain node deposits and withdrawals are tracked on.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MainchainConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    // Seconds between polls.
+    pub poll_interval: u64,
+    // Mainchain blocks a broadcast bundle may go without being paid out or
+    // failing before it is broadcast again.
+    pub bundle_timeout: usize,
+}
+
+// Block production out of the mempool, with BMM requests to the mainchain
+// node unless bmm is off.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiningConfig {
+    pub enabled: bool,
+    // Seconds between blocks, a block is only made if the mempool isn't
+    // empty.
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MempoolConfig {
+    // Megabytes, once full the minimum fee rate goes up.
+    pub max_size: usize,
+    // Satoshis per 1000 virtual bytes.
+    pub min_fee_rate: u64,
+}
+
+// Connections to other sidechain nodes, blocks and transactions are
+// exchanged with them.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct P2pConfig {
+    pub enabled: bool,
+    // Inbound peers are accepted on it, None only makes outbound
+    // connections.
+    pub listen: Option<SocketAddr>,
+    // Connected to on startup, as host:port.
+    pub peers: Vec<String>,
+    // DNS names or addresses the first peers are looked up from, with
+    // DEFAULT_P2P_PORT unless they name a port. Peers found this way or
+    // learned from other peers are kept in peers.dat in the data directory.
+    pub seeds: Vec<String>,
+    // Outbound connections kept open to known peers.
+    pub max_outbound: usize,
+    // Outbound connections go through the proxy when one is set.
+    pub proxy: bool,
+}
+
+// The read-only REST API, needs a build with the rest feature.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+// The gRPC service, needs a build with the grpc feature.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+// The WebSocket stream of block and transaction events, needs a build with
+// the ws feature.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WsConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+// The Electrum server light wallets follow their addresses through, needs a
+// build with the electrum feature.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ElectrumConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("./data"),
+            sidechain: THIS_SIDECHAIN,
+            bmm: true,
+            wallet: None,
+            wallets: vec![],
+            proxy: None,
+            rpc: RpcConfig::default(),
+            mainchain: MainchainConfig::default(),
+            mining: MiningConfig::default(),
+            mempool: MempoolConfig::default(),
+            p2p: P2pConfig::default(),
+            rest: RestConfig::default(),
+            grpc: GrpcConfig::default(),
+            ws: WsConfig::default(),
+            electrum: ElectrumConfig::default(),
+        }
+    }
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".into(),
+            port: 18444,
+            user: "user".into(),
+            password: "password".into(),
+        }
+    }
+}
+
+impl Default for MainchainConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".into(),
+            port: 18443,
+            user: "user".into(),
+            password: "password".into(),
+            poll_interval: 5,
+            bundle_timeout: 10,
+        }
+    }
+}
+
+impl Default for MiningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 10,
+        }
+    }
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 300,
+            min_fee_rate: 0,
+        }
+    }
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: None,
+            peers: vec![],
+            seeds: vec![],
+            max_outbound: 8,
+            proxy: true,
+        }
+    }
+}
+
+impl Default for RestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".into(),
+            port: 18446,
+        }
+    }
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".into(),
+            port: 18447,
+        }
+    }
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".into(),
+            port: 18448,
+        }
+    }
+}
+
+impl Default for ElectrumConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".into(),
+            port: 50001,
+        }
+    }
+}
+
+impl Config {
+    // Reads the file at `path`, or DEFAULT_CONFIG_FILE if there is one, then
+    // applies the environment overrides and validates the result.
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path).map_err(|source| Error::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|source| Error::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    // Overrides settings with the variables `var` returns a value for.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), Error> {
+        let var = |name: &str| {
+            let name = format!("{}{}", ENV_PREFIX, name);
+            var(&name).map(|value| (name, value))
+        };
+        if let Some((_, value)) = var("DATA_DIR") {
+            self.data_dir = value.into();
+        }
+        if let Some((name, value)) = var("SIDECHAIN") {
+            self.sidechain = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("BMM") {
+            self.bmm = parse_env(name, value)?;
+        }
+        if let Some((_, value)) = var("WALLET") {
+            self.wallet = Some(value.into());
+        }
+        // Comma separated.
+        if let Some((_, value)) = var("WALLETS") {
+            self.wallets = value
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some((name, value)) = var("PROXY") {
+            self.proxy = Some(parse_env(name, value)?);
+        }
+        if let Some((_, value)) = var("RPC_HOST") {
+            self.rpc.host = value;
+        }
+        if let Some((name, value)) = var("RPC_PORT") {
+            self.rpc.port = parse_env(name, value)?;
+        }
+        if let Some((_, value)) = var("RPC_USER") {
+            self.rpc.user = value;
+        }
+        if let Some((_, value)) = var("RPC_PASSWORD") {
+            self.rpc.password = value;
+        }
+        if let Some((_, value)) = var("MAINCHAIN_HOST") {
+            self.mainchain.host = value;
+        }
+        if let Some((name, value)) = var("MAINCHAIN_PORT") {
+            self.mainchain.port = parse_env(name, value)?;
+        }
+        if let Some((_, value)) = var("MAINCHAIN_USER") {
+            self.mainchain.user = value;
+        }
+        if let Some((_, value)) = var("MAINCHAIN_PASSWORD") {
+            self.mainchain.password = value;
+        }
+        if let Some((name, value)) = var("MAINCHAIN_POLL_INTERVAL") {
+            self.mainchain.poll_interval = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("MAINCHAIN_BUNDLE_TIMEOUT") {
+            self.mainchain.bundle_timeout = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("MINING_ENABLED") {
+            self.mining.enabled = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("MINING_INTERVAL") {
+            self.mining.interval = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("MEMPOOL_MAX_SIZE") {
+            self.mempool.max_size = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("MEMPOOL_MIN_FEE_RATE") {
+            self.mempool.min_fee_rate = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("P2P_ENABLED") {
+            self.p2p.enabled = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("P2P_LISTEN") {
+            self.p2p.listen = Some(parse_env(name, value)?);
+        }
+        if let Some((_, value)) = var("P2P_PEERS") {
+            self.p2p.peers = split_list(&value);
+        }
+        if let Some((_, value)) = var("P2P_SEEDS") {
+            self.p2p.seeds = split_list(&value);
+        }
+        if let Some((name, value)) = var("P2P_MAX_OUTBOUND") {
+            self.p2p.max_outbound = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("P2P_PROXY") {
+            self.p2p.proxy = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("REST_ENABLED") {
+            self.rest.enabled = parse_env(name, value)?;
+        }
+        if let Some((_, value)) = var("REST_HOST") {
+            self.rest.host = value;
+        }
+        if let Some((name, value)) = var("REST_PORT") {
+            self.rest.port = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("GRPC_ENABLED") {
+            self.grpc.enabled = parse_env(name, value)?;
+        }
+        if let Some((_, value)) = var("GRPC_HOST") {
+            self.grpc.host = value;
+        }
+        if let Some((name, value)) = var("GRPC_PORT") {
+            self.grpc.port = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("WS_ENABLED") {
+            self.ws.enabled = parse_env(name, value)?;
+        }
+        if let Some((_, value)) = var("WS_HOST") {
+            self.ws.host = value;
+        }
+        if let Some((name, value)) = var("WS_PORT") {
+            self.ws.port = parse_env(name, value)?;
+        }
+        if let Some((name, value)) = var("ELECTRUM_ENABLED") {
+            self.electrum.enabled = parse_env(name, value)?;
+        }
+        if let Some((_, value)) = var("ELECTRUM_HOST") {
+            self.electrum.host = value;
+        }
+        if let Some((name, value)) = var("ELECTRUM_PORT") {
+            self.electrum.port = parse_env(name, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        // The mainchain numbers sidechain slots with a single byte.
+        if self.sidechain > u8::MAX as usize {
+            return Err(Error::Invalid(format!(
+                "sidechain must be at most {}, got {}",
+                u8::MAX,
+                self.sidechain
+            )));
+        }
+        if self.data_dir.as_os_str().is_empty() {
+            return Err(Error::Invalid("data_dir must not be empty".into()));
+        }
+        // Servers the binary wasn't built with can't be started.
+        for (name, enabled, built) in [
+            ("rest", self.rest.enabled, cfg!(feature = "rest")),
+            ("grpc", self.grpc.enabled, cfg!(feature = "grpc")),
+            ("ws", self.ws.enabled, cfg!(feature = "ws")),
+            (
+                "electrum",
+                self.electrum.enabled,
+                cfg!(feature = "electrum"),
+            ),
+        ] {
+            if enabled && !built {
+                return Err(Error::Invalid(format!(
+                    "{}.enabled needs a build with the {} feature",
+                    name, name
+                )));
+            }
+        }
+        let mut servers = vec![
+            ("rpc", &self.rpc.host, self.rpc.port),
+            ("mainchain", &self.mainchain.host, self.mainchain.port),
+        ];
+        if self.rest.enabled {
+            servers.push(("rest", &self.rest.host, self.rest.port));
+        }
+        if self.grpc.enabled {
+            servers.push(("grpc", &self.grpc.host, self.grpc.port));
+        }
+        if self.ws.enabled {
+            servers.push(("ws", &self.ws.host, self.ws.port));
+        }
+        if self.electrum.enabled {
+            servers.push(("electrum", &self.electrum.host, self.electrum.port));
+        }
+        for (name, host, port) in servers {
+            if host.is_empty() {
+                return Err(Error::Invalid(format!("{}.host must not be empty", name)));
+            }
+            if port == 0 {
+                return Err(Error::Invalid(format!("{}.port must not be 0", name)));
+            }
+        }
+        if self.mainchain.poll_interval == 0 {
+            return Err(Error::Invalid(
+                "mainchain.poll_interval must be at least 1 second".into(),
+            ));
+        }
+        if self.mainchain.bundle_timeout == 0 {
+            return Err(Error::Invalid(
+                "mainchain.bundle_timeout must be at least 1 block".into(),
+            ));
+        }
+        if self.mining.interval == 0 {
+            return Err(Error::Invalid(
+                "mining.interval must be at least 1 second".into(),
+            ));
+        }
+        if self.mempool.max_size == 0 {
+            return Err(Error::Invalid(
+                "mempool.max_size must be at least 1 megabyte".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn wallet_path(&self) -> PathBuf {
+        match &self.wallet {
+            Some(wallet) => self.data_dir.join(wallet),
+            None => self.data_dir.join("wallet.dat"),
+        }
+    }
+
+    pub fn params(&self) -> SidechainParams {
+        SidechainParams {
+            sidechain_number: self.sidechain,
+            bmm: self.bmm,
+            ..SidechainParams::default()
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.mainchain.poll_interval)
+    }
+
+    pub fn block_interval(&self) -> Duration {
+        Duration::from_secs(self.mining.interval)
+    }
+
+    // In bytes.
+    pub fn mempool_max_size(&self) -> usize {
+        self.mempool.max_size * 1_000_000
+    }
+
+    pub fn min_fee_rate(&self) -> FeeRate {
+        FeeRate::from_sat_per_kvb(self.mempool.min_fee_rate)
+    }
+}
+
+// Lists are comma separated in the environment.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_env<T: FromStr>(name: String, value: String) -> Result<T, Error> {
+    value.parse().map_err(|_| Error::InvalidEnv { name, value })
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid value {value:?} for {name}")]
+    InvalidEnv { name: String, value: String },
+    #[error("invalid config: {0}")]
+    Invalid(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn file_and_env_override_defaults() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("sdk-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "sidechain = 3\nwallet = \"alice.dat\"\n\n[rpc]\nport = 20000\n\n[mainchain]\nhost = \"node\"\n",
+        )?;
+        let mut config = Config::from_file(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(config.sidechain, 3);
+        assert_eq!(config.rpc.port, 20000);
+        assert_eq!(config.rpc.user, RpcConfig::default().user);
+        assert_eq!(config.mainchain.host, "node");
+        assert_eq!(config.wallet_path(), Path::new("./data/alice.dat"));
+
+        let env = HashMap::from([
+            ("SDK_RPC_PORT", "20001"),
+            ("SDK_DATA_DIR", "/tmp/sdk"),
+            ("SDK_WALLETS", "hot,cold"),
+            ("SDK_PROXY", "127.0.0.1:9050"),
+            ("SDK_MEMPOOL_MIN_FEE_RATE", "1000"),
+            ("SDK_BMM", "false"),
+            ("SDK_MAINCHAIN_BUNDLE_TIMEOUT", "20"),
+            ("SDK_P2P_LISTEN", "0.0.0.0:18445"),
+            ("SDK_P2P_PEERS", "a:18445,b:18445"),
+            ("SDK_P2P_SEEDS", "seed.example.com"),
+            ("SDK_REST_PORT", "20002"),
+            ("SDK_GRPC_HOST", "0.0.0.0"),
+            ("SDK_WS_ENABLED", "true"),
+            ("SDK_ELECTRUM_PORT", "20003"),
+        ]);
+        config.apply_env(|name| env.get(name).map(|value| value.to_string()))?;
+        assert_eq!(config.rpc.port, 20001);
+        assert_eq!(config.wallets, vec!["hot", "cold"]);
+        assert_eq!(config.proxy, Some(SocketAddr::from(([127, 0, 0, 1], 9050))));
+        assert_eq!(config.mempool.min_fee_rate, 1000);
+        assert_eq!(config.mainchain.bundle_timeout, 20);
+        assert_eq!(
+            config.p2p.listen,
+            Some(SocketAddr::from(([0, 0, 0, 0], DEFAULT_P2P_PORT)))
+        );
+        assert_eq!(config.p2p.peers, vec!["a:18445", "b:18445"]);
+        assert_eq!(config.p2p.seeds, vec!["seed.example.com"]);
+        assert_eq!(config.rest.port, 20002);
+        assert_eq!(config.grpc.host, "0.0.0.0");
+        assert!(config.ws.enabled);
+        assert_eq!(config.electrum.port, 20003);
+        assert!(!config.params().bmm);
+        assert_eq!(config.wallet_path(), Path::new("/tmp/sdk/alice.dat"));
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "ws"));
+        config.ws.enabled = false;
+        config.validate()?;
+
+        let env = HashMap::from([("SDK_RPC_PORT", "http")]);
+        let err = config
+            .apply_env(|name| env.get(name).map(|value| value.to_string()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid value \"http\" for SDK_RPC_PORT");
+        config.rest.enabled = true;
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "rest"));
+        config.sidechain = 256;
+        assert!(matches!(config.validate(), Err(Error::Invalid(_))));
+        // Typos aren't silently ignored.
+        assert!(toml::from_str::<Config>("port = 1").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use crate::ssm::StatefulSSM;
+use crate::types::{Body, Hash, Header};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Chance in percent that a step connects a block rather than disconnecting
+// the tip, so chains grow on average while still being unwound often.
+const CONNECT_PERCENT: u32 = 65;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub seed: u64,
+    // Independent connect/disconnect sequences to run.
+    pub cases: usize,
+    pub steps: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            cases: 64,
+            steps: 32,
+        }
+    }
+}
+
+impl Config {
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_cases(mut self, cases: usize) -> Self {
+        self.cases = cases;
+        self
+    }
+
+    pub fn with_steps(mut self, steps: usize) -> Self {
+        self.steps = steps;
+        self
+    }
+}
+
+// Checks a state machine against what BlockChain expects of it by driving
+// random sequences of connects and disconnects:
+// - two instances fed the same blocks end up with the same state root,
+// - disconnecting a block gives back the state root from before it,
+// - unwinding a whole sequence gives back the state of a fresh instance.
+//
+// `new_ssm` makes a fresh instance and `generate_body` a block on top of the
+// state it is given, which the state machine has to accept. Failures name
+// the case and step, the same seed replays them.
+pub fn check<S, O, M: StatefulSSM<S, O>>(
+    config: &Config,
+    new_ssm: impl Fn() -> M,
+    mut generate_body: impl FnMut(&M, &mut StdRng) -> Body<S, O>,
+) -> Result<(), Error>
+where
+    S: serde::Serialize,
+    O: serde::Serialize,
+{
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    for case in 0..config.cases {
+        let mut ssm = new_ssm();
+        let mut replica = new_ssm();
+        let initial_root = ssm.state_root();
+        if replica.state_root() != initial_root {
+            return Err(Error::Nondeterministic { case, step: 0 });
+        }
+        // Connected blocks with the state root from before each of them.
+        let mut connected: Vec<(Header, Body<S, O>, Hash)> = vec![];
+        for step in 0..config.steps {
+            let connect = connected.is_empty() || rng.gen_range(0, 100) < CONNECT_PERCENT;
+            if connect {
+                let body = generate_body(&ssm, &mut rng);
+                let prev_block_hash = connected
+                    .last()
+                    .map_or_else(|| Hash::default().into(), |(header, _, _)| header.hash());
+                let header = Header::new(&prev_block_hash, &body);
+                let root = ssm.state_root();
+                let rejected = |error: String| Error::Rejected { case, step, error };
+                ssm.connect_block(&header, &body)
+                    .map_err(|err| rejected(err.to_string()))?;
+                replica
+                    .connect_block(&header, &body)
+                    .map_err(|err| rejected(err.to_string()))?;
+                if ssm.state_root() != replica.state_root() {
+                    return Err(Error::Nondeterministic { case, step });
+                }
+                connected.push((header, body, root));
+            } else {
+                let (header, body, root) = connected.pop().expect("a block is connected");
+                disconnect(&mut ssm, &header, &body, case, step)?;
+                disconnect(&mut replica, &header, &body, case, step)?;
+                if ssm.state_root() != root {
+                    return Err(Error::NotInverse { case, step });
+                }
+            }
+        }
+        while let Some((header, body, _)) = connected.pop() {
+            disconnect(&mut ssm, &header, &body, case, config.steps)?;
+        }
+        if ssm.state_root() != initial_root {
+            return Err(Error::NotInverse {
+                case,
+                step: config.steps,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn disconnect<S, O, M: StatefulSSM<S, O>>(
+    ssm: &mut M,
+    header: &Header,
+    body: &Body<S, O>,
+    case: usize,
+    step: usize,
+) -> Result<(), Error> {
+    ssm.disconnect_block(header, body)
+        .map_err(|err| Error::Rejected {
+            case,
+            step,
+            error: err.to_string(),
+        })
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("case {case} step {step}: block was rejected: {error}")]
+    Rejected {
+        case: usize,
+        step: usize,
+        error: String,
+    },
+    #[error("case {case} step {step}: same blocks led to different states")]
+    Nondeterministic { case: usize, step: usize },
+    #[error("case {case} step {step}: disconnect didn't restore the state")]
+    NotInverse { case: usize, step: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concrete::{Output, Signature};
+    use crate::ssm::SSM;
+    use crate::types::{Transaction, TRANSACTION_VERSION};
+
+    // Appends the extra data of every transaction to a log.
+    #[derive(Default)]
+    struct Log {
+        entries: Vec<Vec<u8>>,
+        // Forgets to drop entries on disconnect.
+        leaky: bool,
+    }
+
+    impl SSM<Signature, Output> for Log {
+        type Error = String;
+
+        fn validate_transaction(
+            &self,
+            transaction: &Transaction<Signature, Output>,
+        ) -> Result<(), String> {
+            if transaction.extra.is_empty() {
+                return Err("empty entry".into());
+            }
+            Ok(())
+        }
+
+        fn connect_block(
+            &mut self,
+            _header: &Header,
+            body: &Body<Signature, Output>,
+        ) -> Result<(), String> {
+            for transaction in &body.transactions {
+                self.validate_transaction(transaction)?;
+            }
+            for transaction in &body.transactions {
+                self.entries.push(transaction.extra.clone());
+            }
+            Ok(())
+        }
+
+        fn disconnect_block(
+            &mut self,
+            _header: &Header,
+            body: &Body<Signature, Output>,
+        ) -> Result<(), String> {
+            if !self.leaky {
+                let len = self.entries.len() - body.transactions.len();
+                self.entries.truncate(len);
+            }
+            Ok(())
+        }
+    }
+
+    impl StatefulSSM<Signature, Output> for Log {
+        type Snapshot = Vec<Vec<u8>>;
+
+        fn snapshot(&self) -> Self::Snapshot {
+            self.entries.clone()
+        }
+
+        fn restore(&mut self, snapshot: Self::Snapshot) {
+            self.entries = snapshot;
+        }
+
+        fn state_root(&self) -> Hash {
+            crate::types::hash(&self.entries)
+        }
+    }
+
+    fn generate_body(_log: &Log, rng: &mut StdRng) -> Body<Signature, Output> {
+        let transactions = (0..rng.gen_range(0, 4))
+            .map(|_| Transaction {
+                version: TRANSACTION_VERSION,
+                inputs: vec![],
+                signatures: vec![],
+                outputs: vec![],
+                withdrawal_outputs: vec![],
+                extra: vec![rng.gen_range(1, u8::MAX); rng.gen_range(1, 8)],
+            })
+            .collect();
+        Body {
+            coinbase: vec![],
+            transactions,
+        }
+    }
+
+    #[test]
+    fn broken_disconnects_are_caught() {
+        let config = Config::default().with_seed(7);
+        assert_eq!(check(&config, Log::default, generate_body), Ok(()));
+        let leaky = || Log {
+            leaky: true,
+            ..Log::default()
+        };
+        assert!(matches!(
+            check(&config, leaky, generate_body),
+            Err(Error::NotInverse { case: 0, .. })
+        ));
+        let empty = |_: &Log, _: &mut StdRng| Body {
+            coinbase: vec![],
+            transactions: vec![Transaction {
+                version: TRANSACTION_VERSION,
+                inputs: vec![],
+                signatures: vec![],
+                outputs: vec![],
+                withdrawal_outputs: vec![],
+                extra: vec![],
+            }],
+        };
+        assert!(matches!(
+            check(&config, Log::default, empty),
+            Err(Error::Rejected { step: 0, .. })
+        ));
+    }
+}
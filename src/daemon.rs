@@ -0,0 +1,1682 @@
+use crate::addrman::{self, AddrMan};
+use crate::backend::MainchainBackend;
+use crate::block_files::BlockFiles;
+use crate::blockchain::BlockChain;
+use crate::bundle::{BundleLimits, BUNDLE_CONF_TARGET};
+use crate::client::Client;
+use crate::concrete::{Output, Signature};
+use crate::config::{Config, DEFAULT_P2P_PORT};
+use crate::ibd::{self, InitialBlockDownload};
+use crate::mempool::MemPool;
+use crate::miner::{BmmRequest, BmmStatus, Miner};
+use crate::p2p::{Event, Message, Network, PeerId, Version, PROTOCOL_VERSION};
+use crate::relay::{BlockRelay, TransactionRelay};
+use crate::rpc::{NodeState, RpcServer};
+use crate::socks::Proxy;
+use crate::store::ChainStore;
+use crate::types::*;
+use crate::wallet::{Wallet, Wallets, WalletsError};
+use crate::watcher::MainchainWatcher;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// How often the main loop checks whether it was asked to stop.
+const TICK: Duration = Duration::from_millis(100);
+// Most transactions put into a locally mined block.
+const MAX_BLOCK_TRANSACTIONS: usize = 1000;
+// Connected blocks appended to the chain store journal before the chain is
+// saved whole again, which empties it.
+const MAX_JOURNALED_BLOCKS: usize = 100;
+// Most addresses sent in or taken from one Addr message.
+const MAX_ADDRS: usize = 1000;
+// Blocks of the best chain remembered to tell which ones a reorg
+// disconnected.
+const MAX_REORG_DEPTH: usize = 1000;
+
+// A block template whose BMM request is waiting for the mainchain.
+type PendingBlock = (Header, Body<Signature, Output>, BmmRequest);
+
+// A long running node: follows the mainchain for deposits, serves RPC,
+// exchanges blocks and transactions with peers and optionally mines blocks
+// out of the mempool. The chainstate, wallet and
+// mempool are kept in the data directory and written back before run
+// returns.
+pub struct Daemon {
+    config: Config,
+    node: Arc<Mutex<NodeState>>,
+    store: ChainStore,
+    shutdown: Arc<AtomicBool>,
+}
+
+// What run started next to the node loop. The servers and the p2p network
+// watch the shutdown flag, run waits for all of them before the last flush.
+#[derive(Default)]
+struct Services {
+    threads: Vec<JoinHandle<()>>,
+    p2p: Option<P2p>,
+    #[cfg(feature = "grpc")]
+    grpc: Option<crate::grpc::NodeService>,
+    #[cfg(feature = "ws")]
+    events: Option<crate::ws::EventPublisher>,
+    #[cfg(feature = "electrum")]
+    electrum: Option<crate::electrum::Notifier>,
+}
+
+// The connections to other sidechain nodes, what's relayed over them and
+// the addresses of the nodes we could connect to.
+struct P2p {
+    network: Network<Signature, Output>,
+    blocks: BlockRelay,
+    transactions: TransactionRelay,
+    addrman: AddrMan,
+    // Set when a peer with more blocks than us connects.
+    behind: bool,
+}
+
+// The last blocks of the best chain as the daemon last saw it, to work out
+// which blocks were connected and disconnected since, whether by mining,
+// peers, RPC or an invalidated block.
+struct Tip {
+    // Oldest first, the last one is at `height`.
+    recent: VecDeque<BlockHash>,
+    height: usize,
+}
+
+// Blocks that left the best chain, newest first, and the ones that joined
+// it, oldest first, with their heights.
+#[derive(Debug, Default, PartialEq)]
+struct TipChanges {
+    disconnected: Vec<(BlockHash, usize)>,
+    connected: Vec<(BlockHash, usize)>,
+}
+
+impl Tip {
+    fn new(blockchain: &BlockChain<Signature, Output>) -> Self {
+        let height = blockchain.height();
+        let start = height.saturating_sub(MAX_REORG_DEPTH) + 1;
+        Self {
+            recent: (start..=height)
+                .filter_map(|height| blockchain.get_block_hash(height))
+                .collect(),
+            height,
+        }
+    }
+
+    fn get(&self, height: usize) -> Option<BlockHash> {
+        let oldest = self.height + 1 - self.recent.len();
+        self.recent.get(height.checked_sub(oldest)?).copied()
+    }
+
+    // A reorg deeper than MAX_REORG_DEPTH is reported from the oldest block
+    // remembered.
+    fn update(&mut self, blockchain: &BlockChain<Signature, Output>) -> TipChanges {
+        let height = blockchain.height();
+        let oldest = self.height + 1 - self.recent.len();
+        let mut fork = self.height.min(height);
+        while fork >= oldest && fork > 0 && blockchain.get_block_hash(fork) != self.get(fork) {
+            fork -= 1;
+        }
+        let mut changes = TipChanges::default();
+        for height in (fork + 1..=self.height).rev() {
+            if let Some(block_hash) = self.recent.pop_back() {
+                changes.disconnected.push((block_hash, height));
+            }
+        }
+        for height in fork + 1..=height {
+            if let Some(block_hash) = blockchain.get_block_hash(height) {
+                changes.connected.push((block_hash, height));
+                self.recent.push_back(block_hash);
+            }
+        }
+        while self.recent.len() > MAX_REORG_DEPTH {
+            self.recent.pop_front();
+        }
+        self.height = height;
+        changes
+    }
+}
+
+impl P2p {
+    fn new(network: Network<Signature, Output>, addrman: AddrMan) -> Self {
+        Self {
+            network,
+            blocks: BlockRelay::new(),
+            transactions: TransactionRelay::new(),
+            addrman,
+            behind: false,
+        }
+    }
+}
+
+impl Daemon {
+    pub fn open(config: Config) -> Result<Self, Error> {
+        std::fs::create_dir_all(&config.data_dir)?;
+        let params = config.params();
+        let store = ChainStore::new(config.data_dir.join("chain.dat"));
+        let mut blockchain = store
+            .load(None)?
+            .unwrap_or_else(BlockChain::default)
+            .with_block_files(BlockFiles::open(config.data_dir.join("blocks"))?)?
+            .with_params(params.clone());
+        let replayed = store.replay(&mut blockchain)?;
+        if replayed > 0 {
+            log::info!("replayed {} journaled blocks", replayed);
+        }
+        let mempool = load(&mempool_path(&config))?
+            .unwrap_or_else(MemPool::default)
+            .with_params(params.clone())
+            .with_max_size(config.mempool_max_size())
+            .with_min_fee_rate(config.min_fee_rate());
+        let wallet = load(&config.wallet_path())?
+            .unwrap_or_else(Wallet::default)
+            .with_params(params.clone());
+        let mut wallets = Wallets::new(config.data_dir.join("wallets")).with_params(params);
+        for name in &config.wallets {
+            match wallets.load(name) {
+                Err(WalletsError::NotFound(_)) => wallets.create(name).map(drop)?,
+                result => result.map(drop)?,
+            }
+        }
+        Ok(Self {
+            config,
+            node: Arc::new(Mutex::new(NodeState {
+                blockchain,
+                mempool,
+                wallet,
+                wallets,
+                main_fee_rate: None,
+            })),
+            store,
+            shutdown: Arc::default(),
+        })
+    }
+
+    pub fn node(&self) -> Arc<Mutex<NodeState>> {
+        self.node.clone()
+    }
+
+    // Setting the flag makes run stop the servers and peers, flush
+    // everything and return. Signal handlers hold on to it.
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    pub fn run(&self) -> Result<(), Error> {
+        let main = &self.config.mainchain;
+        let mut client = Client::new(
+            self.config.sidechain,
+            &main.host,
+            main.port,
+            &main.user,
+            &main.password,
+        );
+        if let Some(proxy) = self.config.proxy {
+            client = client.with_proxy(Proxy::new(proxy))?;
+        }
+        let mut services = Services::default();
+        let result = self
+            .start(&client, &mut services)
+            .and_then(|()| self.follow(&client, &mut services));
+
+        // Set on errors too, everything started has to stop before the node
+        // is flushed.
+        self.shutdown.store(true, Ordering::SeqCst);
+        log::info!("shutting down");
+        if let Some(p2p) = &services.p2p {
+            p2p.network.shutdown();
+        }
+        for thread in services.threads {
+            if thread.join().is_err() {
+                log::error!("a server thread panicked");
+            }
+        }
+        let flushed = match &services.p2p {
+            Some(p2p) => p2p
+                .addrman
+                .save(peers_path(&self.config))
+                .map_err(Error::from),
+            None => Ok(()),
+        }
+        .and_then(|()| self.flush());
+        result.and(flushed)
+    }
+
+    // Binds the enabled servers and connects to peers. Every thread started
+    // ends once the shutdown flag is set.
+    fn start(&self, client: &Client, services: &mut Services) -> Result<(), Error> {
+        let rpc = &self.config.rpc;
+        let server = RpcServer::bind((rpc.host.as_str(), rpc.port), self.node.clone())?
+            .with_auth(&rpc.user, &rpc.password)
+            .with_mainchain(client.clone())
+            .with_shutdown(self.shutdown.clone());
+        log::info!("rpc server listening on {:?}", server.local_addr());
+        services
+            .threads
+            .push(std::thread::spawn(move || server.run()));
+        #[cfg(feature = "rest")]
+        if self.config.rest.enabled {
+            let rest = &self.config.rest;
+            let server =
+                crate::rest::RestServer::bind((rest.host.as_str(), rest.port), self.node.clone())?
+                    .with_shutdown(self.shutdown.clone());
+            log::info!("rest server listening on {:?}", server.local_addr());
+            services
+                .threads
+                .push(std::thread::spawn(move || server.run()));
+        }
+        #[cfg(feature = "grpc")]
+        {
+            services.grpc = self.start_grpc(&mut services.threads)?;
+        }
+        #[cfg(feature = "ws")]
+        {
+            services.events = self.start_ws(&mut services.threads)?;
+        }
+        #[cfg(feature = "electrum")]
+        {
+            services.electrum = self.start_electrum(&mut services.threads)?;
+        }
+        services.p2p = self.start_p2p()?;
+        Ok(())
+    }
+
+    // The node loop, returns once the shutdown flag is set.
+    fn follow(&self, client: &Client, services: &mut Services) -> Result<(), Error> {
+        let mut watcher = self.watch(client);
+        let mut next_poll = Instant::now();
+        let mut next_block = Instant::now() + self.config.block_interval();
+        let mut pending = None;
+        let mut journaled = 0;
+        let mut tip = Tip::new(&self.node.lock().unwrap().blockchain);
+        // Mempool transactions as of the last tick.
+        let mut mempool = HashSet::new();
+        while !self.shutdown.load(Ordering::SeqCst) {
+            if Instant::now() >= next_poll {
+                match watcher.poll() {
+                    Ok(true) => {
+                        self.update_main_fee_rate(client);
+                        self.save_chain()?;
+                        journaled = 0;
+                    }
+                    Ok(false) => {}
+                    // An unreachable mainchain node is retried on the next poll.
+                    Err(err) => log::warn!("failed to poll the mainchain: {}", err),
+                }
+                match self.broadcast_bundles(client) {
+                    Ok(broadcast) if !broadcast.is_empty() => {
+                        self.save_chain()?;
+                        journaled = 0;
+                    }
+                    Ok(_) => {}
+                    Err(err) => log::warn!("failed to broadcast withdrawal bundles: {}", err),
+                }
+                if let Some(p2p) = &mut services.p2p {
+                    self.maintain_connections(p2p);
+                    p2p.addrman.save(peers_path(&self.config))?;
+                }
+                self.save_wallet_and_mempool()?;
+                next_poll = Instant::now() + self.config.poll_interval();
+            }
+            if let Some(p2p) = &mut services.p2p {
+                self.handle_p2p_events(p2p, client);
+            }
+            if self.config.mining.enabled && Instant::now() >= next_block {
+                let mined = match self.config.bmm {
+                    true => self
+                        .mine_bmm_block(client, &mut pending)
+                        .unwrap_or_else(|err| {
+                            log::warn!("failed to mine a block: {}", err);
+                            None
+                        }),
+                    false => self.mine_block(),
+                };
+                if let Some(block_hash) = mined {
+                    log::info!("mined block {}", block_hash);
+                }
+                next_block = Instant::now() + self.config.block_interval();
+            }
+            let changes = tip.update(&self.node.lock().unwrap().blockchain);
+            self.save_blocks(&changes, &mut journaled)?;
+            let accepted = self.accepted_transactions(&mut mempool);
+            // The relay leaves out peers that already know them.
+            if let Some(p2p) = &mut services.p2p {
+                if !accepted.is_empty() {
+                    p2p.transactions.announce(&p2p.network, None, &accepted);
+                }
+            }
+            #[cfg(feature = "grpc")]
+            if let Some(grpc) = &services.grpc {
+                for (block_hash, height) in &changes.connected {
+                    grpc.notify_block(*block_hash, *height);
+                }
+            }
+            #[cfg(feature = "ws")]
+            if let Some(events) = &services.events {
+                publish(events, &changes, &accepted);
+            }
+            #[cfg(feature = "electrum")]
+            if let Some(electrum) = &services.electrum {
+                if changes != TipChanges::default() || !accepted.is_empty() {
+                    electrum.notify();
+                }
+            }
+            std::thread::sleep(TICK);
+        }
+        Ok(())
+    }
+
+    // Serves the gRPC service on a runtime of its own. The returned service
+    // shares the block subscribers, None if gRPC is disabled.
+    #[cfg(feature = "grpc")]
+    fn start_grpc(
+        &self,
+        threads: &mut Vec<JoinHandle<()>>,
+    ) -> Result<Option<crate::grpc::NodeService>, Error> {
+        let config = &self.config.grpc;
+        if !config.enabled {
+            return Ok(None);
+        }
+        // Bound here so a taken port fails run instead of a thread.
+        let listener = std::net::TcpListener::bind((config.host.as_str(), config.port))?;
+        listener.set_nonblocking(true)?;
+        log::info!("grpc server listening on {}", listener.local_addr()?);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let service = crate::grpc::NodeService::new(self.node.clone());
+        let server = service.clone().into_server();
+        let shutdown = self.shutdown.clone();
+        threads.push(std::thread::spawn(move || {
+            let served = runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let serving = tokio::spawn(
+                    tonic::transport::Server::builder()
+                        .add_service(server)
+                        .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(
+                            listener,
+                        )),
+                );
+                while !serving.is_finished() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        return Ok(());
+                    }
+                    tokio::time::sleep(TICK).await;
+                }
+                serving
+                    .await
+                    .map_err(std::io::Error::other)?
+                    .map_err(std::io::Error::other)
+            });
+            if let Err(err) = served {
+                log::error!("grpc server stopped: {}", err);
+            }
+            // Not a graceful shutdown, block subscriptions never end on
+            // their own. Dropping the runtime closes their connections.
+        }));
+        Ok(Some(service))
+    }
+
+    // None if the event stream is disabled.
+    #[cfg(feature = "ws")]
+    fn start_ws(
+        &self,
+        threads: &mut Vec<JoinHandle<()>>,
+    ) -> Result<Option<crate::ws::EventPublisher>, Error> {
+        let config = &self.config.ws;
+        if !config.enabled {
+            return Ok(None);
+        }
+        let server = crate::ws::EventServer::bind((config.host.as_str(), config.port))?
+            .with_shutdown(self.shutdown.clone());
+        log::info!("event server listening on {}", server.local_addr()?);
+        let publisher = server.publisher();
+        threads.push(std::thread::spawn(move || server.run()));
+        Ok(Some(publisher))
+    }
+
+    // The returned notifier syncs the server's address index with the chain,
+    // None if the Electrum server is disabled.
+    #[cfg(feature = "electrum")]
+    fn start_electrum(
+        &self,
+        threads: &mut Vec<JoinHandle<()>>,
+    ) -> Result<Option<crate::electrum::Notifier>, Error> {
+        let config = &self.config.electrum;
+        if !config.enabled {
+            return Ok(None);
+        }
+        let server = crate::electrum::ElectrumServer::bind(
+            (config.host.as_str(), config.port),
+            self.node.clone(),
+        )?
+        .with_shutdown(self.shutdown.clone());
+        log::info!("electrum server listening on {}", server.local_addr()?);
+        let notifier = server.notifier();
+        threads.push(std::thread::spawn(move || server.run()));
+        Ok(Some(notifier))
+    }
+
+    // Connects to the configured peers and starts accepting inbound ones,
+    // None if p2p is disabled. The addresses behind the seeds join the ones
+    // saved last time, outbound connections to them are opened as the
+    // daemon runs.
+    fn start_p2p(&self) -> Result<Option<P2p>, Error> {
+        let config = &self.config.p2p;
+        if !config.enabled {
+            return Ok(None);
+        }
+        let mut addrman = AddrMan::load(peers_path(&self.config))?;
+        let seeds: Vec<&str> = config.seeds.iter().map(String::as_str).collect();
+        let found = addrman.add_many(addrman::resolve_dns_seeds(&seeds, DEFAULT_P2P_PORT));
+        log::info!(
+            "{} known peer addresses, {} new from seeds",
+            addrman.len(),
+            found
+        );
+        let mut network = Network::new(self.version());
+        if let (true, Some(proxy)) = (config.proxy, self.config.proxy) {
+            network = network.with_proxy(Proxy::new(proxy));
+        }
+        if let Some(listen) = config.listen {
+            let addr = network.listen(listen)?;
+            log::info!("p2p listening on {}", addr);
+        }
+        for peer in &config.peers {
+            if let Err(err) = network.connect(peer.as_str()) {
+                log::warn!("failed to connect to peer {}: {}", peer, err);
+            }
+        }
+        Ok(Some(P2p::new(network, addrman)))
+    }
+
+    // A connection attempt can take seconds, so at most one is made per
+    // call and the rest wait for the next poll.
+    fn maintain_connections(&self, p2p: &mut P2p) {
+        let target = self
+            .config
+            .p2p
+            .max_outbound
+            .min(p2p.network.outbound_count() + 1);
+        addrman::maintain_connections(&p2p.network, &mut p2p.addrman, target);
+    }
+
+    // The daemon's chain starts without a genesis block, so nodes of other
+    // sidechains are told apart by a hash of the sidechain parameters.
+    fn version(&self) -> Version {
+        let node = self.node.lock().unwrap();
+        Version {
+            version: PROTOCOL_VERSION,
+            genesis: hash(&self.config.params()).into(),
+            best_height: node.blockchain.height() as u64,
+            user_agent: format!("sdk/{}", env!("CARGO_PKG_VERSION")),
+            listen_port: self.config.p2p.listen.map_or(0, |addr| addr.port()),
+        }
+    }
+
+    // Hands the blocks and transactions peers sent to the relays, which
+    // connect them and pass them on, and answers requests for blocks,
+    // headers, filters and merkle proofs. Blocks are downloaded first from
+    // peers that are ahead of us.
+    fn handle_p2p_events<B: MainchainBackend>(&self, p2p: &mut P2p, mainchain: &B) {
+        p2p.network.set_best_height(self.height());
+        while let Some(event) = p2p.network.try_recv() {
+            match event {
+                Event::Connected {
+                    peer,
+                    addr,
+                    version,
+                } => {
+                    log::info!(
+                        "peer {} connected from {} ({})",
+                        peer,
+                        addr,
+                        version.user_agent
+                    );
+                    // Inbound peers that accept connections are worth
+                    // telling others about.
+                    if let Some(addr) = p2p.network.listen_addr(peer) {
+                        p2p.addrman.add(addr);
+                    }
+                    p2p.behind |= version.best_height > self.height();
+                }
+                Event::Message { peer, message } => {
+                    self.handle_message(p2p, mainchain, peer, &message)
+                }
+                Event::Disconnected { peer } => {
+                    log::info!("peer {} disconnected", peer);
+                    p2p.transactions.remove_peer(peer);
+                }
+            }
+            if p2p.behind {
+                p2p.behind = false;
+                self.download_blocks(p2p, mainchain);
+            }
+        }
+    }
+
+    // Transactions that entered the mempool since it had `seen`, whether
+    // they came over RPC, from a wallet or from a peer.
+    fn accepted_transactions(&self, seen: &mut HashSet<Txid>) -> Vec<Txid> {
+        let txids = self.node.lock().unwrap().mempool.txids();
+        let accepted = txids
+            .iter()
+            .filter(|txid| !seen.contains(txid))
+            .copied()
+            .collect();
+        *seen = txids.into_iter().collect();
+        accepted
+    }
+
+    // Catches up with the peers ahead of us. The node is held until the
+    // download is done, RPC calls wait for it.
+    fn download_blocks<B: MainchainBackend>(&self, p2p: &P2p, mainchain: &B) {
+        let mut node = self.node.lock().unwrap();
+        let NodeState {
+            blockchain,
+            mempool,
+            ..
+        } = &mut *node;
+        match InitialBlockDownload::new().run(&p2p.network, mainchain, blockchain, &mut ()) {
+            Ok(connected) => log::info!("downloaded {} blocks from peers", connected),
+            // The blocks connected before the failure are kept.
+            Err(err) => log::warn!("block download failed: {}", err),
+        }
+        mempool.revalidate(blockchain);
+    }
+
+    fn handle_message<B: MainchainBackend>(
+        &self,
+        p2p: &mut P2p,
+        mainchain: &B,
+        peer: PeerId,
+        message: &Message<Signature, Output>,
+    ) {
+        let network = &p2p.network;
+        match message {
+            Message::GetAddr => {
+                let addrs = p2p.addrman.sample(MAX_ADDRS);
+                if let Err(err) = network.send(peer, &Message::Addr(addrs)) {
+                    log::debug!("failed to send addresses to {}: {}", peer, err);
+                }
+                return;
+            }
+            Message::Addr(addrs) => {
+                p2p.addrman.add_many(addrs.iter().take(MAX_ADDRS).copied());
+                return;
+            }
+            _ => {}
+        }
+        let mut node = self.node.lock().unwrap();
+        let NodeState {
+            blockchain,
+            mempool,
+            ..
+        } = &mut *node;
+        if p2p
+            .blocks
+            .handle(network, mainchain, blockchain, mempool, peer, message)
+            || p2p
+                .transactions
+                .handle(network, blockchain, mempool, peer, message)
+        {
+            return;
+        }
+        // Requests of peers catching up with us, anything else is dropped.
+        if let Err(err) = ibd::serve(network, blockchain, peer, message) {
+            log::debug!("failed to answer peer {}: {}", peer, err);
+        }
+    }
+
+    // A watcher that connects the deposits it finds on the mainchain to the
+    // chainstate and disconnects the ones reorged out, picking up after the
+    // ones the saved chainstate already has.
+    pub fn watch<'a, B: MainchainBackend>(&'a self, mainchain: &'a B) -> MainchainWatcher<'a, B> {
+        let last_deposit = {
+            let node = self.node.lock().unwrap();
+            node.blockchain.peg.deposits().last().cloned()
+        };
+        let mut watcher = MainchainWatcher::new(mainchain, last_deposit);
+        watcher.on_deposits(|deposits| {
+            let mut node = self.node.lock().unwrap();
+            node.blockchain.add_deposits(deposits.clone());
+        });
+        watcher.on_deposits_disconnected(|deposits| {
+            let mut node = self.node.lock().unwrap();
+            node.blockchain.disconnect_deposits(deposits);
+        });
+        watcher
+    }
+
+    // Withdrawals are priced so their bundles confirm at the mainchain fee
+    // rate, a failed estimate keeps the last one.
+    fn update_main_fee_rate(&self, client: &Client) {
+        match client.estimate_smart_fee(BUNDLE_CONF_TARGET) {
+            Ok(main_fee_rate) => self.node.lock().unwrap().main_fee_rate = main_fee_rate,
+            Err(err) => log::warn!("failed to estimate the mainchain fee rate: {}", err),
+        }
+    }
+
+    // Settles the bundles the mainchain paid out or failed, then broadcasts
+    // the next bundle of pending withdrawals along with the ones that went
+    // mainchain.bundle_timeout blocks without either, so a bundle the
+    // mainchain node lost doesn't hold its withdrawals forever. Returns the
+    // hashes of the bundles broadcast.
+    pub fn broadcast_bundles<B: MainchainBackend>(
+        &self,
+        mainchain: &B,
+    ) -> Result<Vec<bitcoin::Txid>, Error> {
+        let main_height = mainchain.get_block_count()?;
+        let spent_withdrawals = mainchain.get_spent_withdrawals()?;
+        let failed_withdrawals = mainchain.get_failed_withdrawals()?;
+        let (stale, next) = {
+            let mut node = self.node.lock().unwrap();
+            let limits = BundleLimits {
+                main_fee_rate: node.main_fee_rate.unwrap_or_default(),
+                ..BundleLimits::default()
+            };
+            let peg = &mut node.blockchain.peg;
+            // The mainchain lists every bundle it ever settled.
+            for spent_withdrawal in &spent_withdrawals {
+                if peg.is_outstanding(&spent_withdrawal.hash) {
+                    peg.mark_paid(spent_withdrawal)?;
+                }
+            }
+            for failed_withdrawal in &failed_withdrawals {
+                if peg.is_outstanding(&failed_withdrawal.hash) {
+                    peg.mark_failed(failed_withdrawal)?;
+                }
+            }
+            let stale = peg.stale_bundles(main_height, self.config.mainchain.bundle_timeout);
+            (stale, node.blockchain.next_bundle(&limits))
+        };
+        // The node isn't held while the mainchain is asked.
+        let mut broadcast = vec![];
+        for (hash, bundle) in &stale {
+            mainchain.submit_bundle(bundle)?;
+            broadcast.push(*hash);
+        }
+        let next = match next {
+            Some(bundle) => Some((mainchain.submit_bundle(&bundle)?, bundle)),
+            None => None,
+        };
+        let mut node = self.node.lock().unwrap();
+        if let Some((hash, bundle)) = next {
+            node.blockchain.mark_bundled(hash, &bundle)?;
+            broadcast.push(hash);
+        }
+        for hash in &broadcast {
+            node.blockchain.peg.mark_broadcast(*hash, main_height)?;
+        }
+        Ok(broadcast)
+    }
+
+    // One step of mining with BMM: connects the pending block once a
+    // mainchain block includes its commitment, otherwise commits to a new
+    // template if the last one missed its window or there was none.
+    // Returns the connected block, if any.
+    pub fn mine_bmm_block<B: MainchainBackend>(
+        &self,
+        mainchain: &B,
+        pending: &mut Option<PendingBlock>,
+    ) -> Result<Option<BlockHash>, Error> {
+        let miner = Miner::new().with_max_transactions(MAX_BLOCK_TRANSACTIONS);
+        if let Some((header, body, request)) = pending.take() {
+            match miner.check_bmm(mainchain, &request)? {
+                BmmStatus::Included(anchor) => {
+                    let mut node = self.node.lock().unwrap();
+                    let NodeState {
+                        blockchain,
+                        mempool,
+                        ..
+                    } = &mut *node;
+                    let anchor = Some(anchor);
+                    match miner.connect_block(blockchain, mempool, None, header, body, anchor) {
+                        Ok(block_hash) => return Ok(Some(block_hash)),
+                        // The chain moved on while the request was waiting.
+                        Err(err) => log::warn!("failed to connect mined block: {}", err),
+                    }
+                }
+                BmmStatus::Pending => {
+                    *pending = Some((header, body, request));
+                    return Ok(None);
+                }
+                BmmStatus::Missed => log::info!("bmm request {} wasn't included", request.txid),
+            }
+        }
+        let (header, body) = {
+            let mut node = self.node.lock().unwrap();
+            let NodeState {
+                blockchain,
+                mempool,
+                wallet,
+                ..
+            } = &mut *node;
+            mempool.revalidate(blockchain);
+            if mempool.is_empty() {
+                return Ok(None);
+            }
+            miner.block_template(blockchain, mempool, wallet.generate_address())
+        };
+        let request = miner.request_bmm(mainchain, &header)?;
+        *pending = Some((header, body, request));
+        Ok(None)
+    }
+
+    // Connects a block with the highest fee mempool transactions, paying the
+    // fees to a fresh wallet address, for chains without BMM. Returns None if
+    // the mempool is empty.
+    pub fn mine_block(&self) -> Option<BlockHash> {
+        let mut node = self.node.lock().unwrap();
+        let NodeState {
+            blockchain,
+            mempool,
+            wallet,
+            ..
+        } = &mut *node;
+        // Transactions that went invalid since they were accepted, like ones
+        // spending a deposit that was disconnected, would spoil the block.
+        mempool.revalidate(blockchain);
+        if mempool.is_empty() {
+            return None;
+        }
+        mempool.set_max_template_transactions(MAX_BLOCK_TRANSACTIONS);
+        let body = mempool.get_block_template(wallet.generate_address());
+        let prev_block_hash = blockchain
+            .get_best_block_hash()
+            .unwrap_or_else(|| Hash::default().into());
+        let header = Header::new(&prev_block_hash, &body);
+        if let Err(err) = blockchain.connect_block(&header, &body, None) {
+            log::warn!("mempool transactions don't make a block: {}", err);
+            return None;
+        }
+        // Drops what the block confirmed and whatever conflicts with it.
+        mempool.revalidate(blockchain);
+        blockchain.get_best_block_hash()
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        self.save_chain()?;
+        self.save_wallet_and_mempool()
+    }
+
+    fn save_chain(&self) -> Result<(), Error> {
+        let node = self.node.lock().unwrap();
+        self.store.save(&node.blockchain)?;
+        Ok(())
+    }
+
+    fn height(&self) -> u64 {
+        self.node.lock().unwrap().blockchain.height() as u64
+    }
+
+    // Saves the blocks the best chain changed by. They're journaled while
+    // the journal has room, after a reorg or with a full journal the chain
+    // is saved whole.
+    fn save_blocks(&self, changes: &TipChanges, journaled: &mut usize) -> Result<(), Error> {
+        let connected = &changes.connected;
+        if !changes.disconnected.is_empty() || *journaled + connected.len() > MAX_JOURNALED_BLOCKS {
+            self.save_chain()?;
+            *journaled = 0;
+            return Ok(());
+        }
+        for (block_hash, _) in connected {
+            self.journal_block(block_hash)?;
+        }
+        *journaled += connected.len();
+        Ok(())
+    }
+
+    // Saves a connected block without rewriting the whole chain.
+    fn journal_block(&self, block_hash: &BlockHash) -> Result<(), Error> {
+        let node = self.node.lock().unwrap();
+        let blockchain = &node.blockchain;
+        let header = blockchain.get_header(block_hash);
+        let body = blockchain.get_body(block_hash);
+        let (Some(header), Some(body)) = (header, body) else {
+            // Reorged out already, nothing to journal.
+            return Ok(());
+        };
+        let anchor = blockchain.get_anchor(block_hash);
+        self.store.append_block(header, &body, anchor)?;
+        Ok(())
+    }
+
+    fn save_wallet_and_mempool(&self) -> Result<(), Error> {
+        let node = self.node.lock().unwrap();
+        save(&self.config.wallet_path(), &node.wallet)?;
+        node.wallets.save()?;
+        save(&mempool_path(&self.config), &node.mempool)
+    }
+}
+
+// Blocks that left the chain go out before the ones that replaced them.
+#[cfg(feature = "ws")]
+fn publish(events: &crate::ws::EventPublisher, changes: &TipChanges, accepted: &[Txid]) {
+    use crate::ws::Event;
+
+    for (block_hash, height) in &changes.disconnected {
+        events.publish(&Event::block_disconnected(*block_hash, *height));
+    }
+    for (block_hash, height) in &changes.connected {
+        events.publish(&Event::block_connected(*block_hash, *height));
+    }
+    for txid in accepted {
+        events.publish(&Event::tx_accepted(*txid));
+    }
+}
+
+fn mempool_path(config: &Config) -> PathBuf {
+    config.data_dir.join("mempool.dat")
+}
+
+fn peers_path(config: &Config) -> PathBuf {
+    config.data_dir.join("peers.dat")
+}
+
+// Returns None if the file doesn't exist yet.
+fn load<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(Some(bincode::deserialize_from(BufReader::new(file))?))
+}
+
+// Written to a temporary file first, so a crash mid write keeps the old file.
+fn save<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    bincode::serialize_into(&mut writer, value)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+    #[error("chain store error")]
+    Store(#[from] crate::store::Error),
+    #[error("rpc error")]
+    Rpc(#[from] crate::rpc::Error),
+    #[error("block files error")]
+    BlockFiles(#[from] crate::block_files::Error),
+    #[error("wallet error")]
+    Wallets(#[from] WalletsError),
+    #[error("mainchain client error")]
+    Client(#[from] crate::client::Error),
+    #[error("miner error")]
+    Miner(#[from] crate::miner::Error),
+    #[error("peg error")]
+    Peg(#[from] crate::peg::Error),
+    #[error("p2p error")]
+    P2p(#[from] crate::p2p::Error),
+    #[error("address manager error")]
+    AddrMan(#[from] addrman::Error),
+    #[cfg(feature = "rest")]
+    #[error("rest server error")]
+    Rest(#[from] crate::rest::Error),
+    #[cfg(feature = "ws")]
+    #[error("event server error")]
+    Ws(#[from] crate::ws::Error),
+    #[cfg(feature = "electrum")]
+    #[error("electrum server error")]
+    Electrum(#[from] crate::electrum::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::SpentWithdrawal;
+    use crate::mock_client::MockMainClient;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    // Pays a deposit to the node's wallet and spends it into the mempool.
+    fn submit_payment(daemon: &Daemon) -> Txid {
+        let node = daemon.node();
+        let mut node = node.lock().unwrap();
+        let address = node.wallet.generate_address();
+        node.blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(bitcoin::OutPoint::default()),
+                DepositOutput {
+                    address,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+        node.sync_wallet();
+        let output = Output {
+            address,
+            value: Amount::from_sat(60),
+        };
+        let transaction = node
+            .wallet
+            .create_transaction(vec![output], Amount::ZERO)
+            .unwrap();
+        node.submit(transaction).unwrap()
+    }
+
+    #[test]
+    fn state_survives_restart() -> anyhow::Result<()> {
+        let data_dir = std::env::temp_dir().join(format!("sdk-daemon-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        let daemon = Daemon::open(config.clone())?;
+        assert_eq!(daemon.mine_block(), None);
+        let txid = submit_payment(&daemon);
+        let block_hash = daemon.mine_block().unwrap();
+        // A shutdown before run got to do anything still flushes.
+        daemon.shutdown_handle().store(true, Ordering::SeqCst);
+        daemon.run()?;
+        drop(daemon);
+
+        let daemon = Daemon::open(config.clone())?;
+        let node = daemon.node();
+        let mut node = node.lock().unwrap();
+        assert_eq!(node.blockchain.get_best_block_hash(), Some(block_hash));
+        assert!(node.blockchain.get_transaction(&txid).is_some());
+        assert!(node.mempool.is_empty());
+        node.sync_wallet();
+        assert_eq!(node.wallet.get_balance(), Amount::from_sat(100));
+        drop(node);
+
+        // A journaled block survives a crash without a flush.
+        let txid = submit_payment(&daemon);
+        let block_hash = daemon.mine_block().unwrap();
+        daemon.journal_block(&block_hash)?;
+        drop(daemon);
+        let daemon = Daemon::open(config)?;
+        let node = daemon.node();
+        let node = node.lock().unwrap();
+        assert_eq!(node.blockchain.get_best_block_hash(), Some(block_hash));
+        assert!(node.blockchain.get_transaction(&txid).is_some());
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_stops_peers_before_flushing() -> anyhow::Result<()> {
+        let data_dir =
+            std::env::temp_dir().join(format!("sdk-daemon-shutdown-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        config.p2p.enabled = true;
+        // Bound and dropped, so the daemon can listen there.
+        let listen = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        config.p2p.listen = Some(listen);
+        let daemon = Daemon::open(config.clone())?;
+        let txid = submit_payment(&daemon);
+        let peer = Network::<Signature, Output>::new(daemon.version());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        std::thread::scope(|scope| {
+            let run = scope.spawn(|| daemon.run());
+            while peer.connect(listen).is_err() {
+                assert!(Instant::now() < deadline);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            daemon.shutdown_handle().store(true, Ordering::SeqCst);
+            run.join().unwrap()?;
+            // run only returns once the peer is gone and nobody is listening.
+            while !matches!(
+                peer.recv_timeout(Duration::from_millis(10)),
+                Some(Event::Disconnected { .. })
+            ) {
+                assert!(Instant::now() < deadline);
+            }
+            assert!(std::net::TcpStream::connect(listen).is_err());
+            Ok::<_, anyhow::Error>(())
+        })?;
+        drop(daemon);
+
+        let daemon = Daemon::open(config)?;
+        assert!(daemon.node().lock().unwrap().mempool.get(&txid).is_some());
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_are_mined_with_bmm() -> anyhow::Result<()> {
+        let data_dir = std::env::temp_dir().join(format!("sdk-daemon-bmm-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        let daemon = Daemon::open(config)?;
+        let mainchain = MockMainClient::new();
+        let mut pending = None;
+        let txid = submit_payment(&daemon);
+        // Without a commitment the block isn't valid.
+        assert_eq!(daemon.mine_block(), None);
+        assert_eq!(daemon.mine_bmm_block(&mainchain, &mut pending)?, None);
+        assert!(pending.is_some());
+        assert_eq!(daemon.mine_bmm_block(&mainchain, &mut pending)?, None);
+        mainchain.mine_block();
+        let block_hash = daemon.mine_bmm_block(&mainchain, &mut pending)?.unwrap();
+        assert!(pending.is_none());
+        let node = daemon.node();
+        let node = node.lock().unwrap();
+        assert!(node.blockchain.get_anchor(&block_hash).is_some());
+        assert!(node.blockchain.get_transaction(&txid).is_some());
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn deposits_follow_the_mainchain() -> anyhow::Result<()> {
+        let data_dir =
+            std::env::temp_dir().join(format!("sdk-daemon-deposits-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        let daemon = Daemon::open(config)?;
+        let mainchain = MockMainClient::new();
+        let address: Address = [1; 32].into();
+        let kept = mainchain.add_deposit(address, 100);
+        mainchain.mine_block();
+        let reorged = mainchain.add_deposit(address, 50);
+        let mut watcher = daemon.watch(&mainchain);
+        watcher.poll()?;
+        let connected = |outpoint| {
+            let node = daemon.node();
+            let node = node.lock().unwrap();
+            node.blockchain
+                .peg
+                .deposit_outputs
+                .contains_key(&OutPoint::Deposit(outpoint))
+        };
+        assert!(connected(kept) && connected(reorged));
+        mainchain.disconnect_block();
+        mainchain.mine_block();
+        mainchain.mine_block();
+        watcher.poll()?;
+        assert!(connected(kept));
+        assert!(!connected(reorged));
+        drop(watcher);
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn stalled_bundles_are_broadcast_again() -> anyhow::Result<()> {
+        let data_dir =
+            std::env::temp_dir().join(format!("sdk-daemon-bundles-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        config.mainchain.bundle_timeout = 3;
+        let daemon = Daemon::open(config)?;
+        let mainchain = MockMainClient::new();
+        assert!(daemon.broadcast_bundles(&mainchain)?.is_empty());
+        submit_payment(&daemon);
+        daemon.mine_block().unwrap();
+        {
+            let node = daemon.node();
+            let mut node = node.lock().unwrap();
+            node.sync_wallet();
+            let main_address: bitcoin::Address =
+                "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080".parse()?;
+            let withdrawal = node
+                .wallet
+                .create_withdrawal(
+                    main_address,
+                    Amount::from_sat(30),
+                    Amount::from_sat(10),
+                    Amount::ZERO,
+                )
+                .unwrap();
+            node.submit(withdrawal).unwrap();
+        }
+        daemon.mine_block().unwrap();
+
+        let broadcast = daemon.broadcast_bundles(&mainchain)?;
+        assert_eq!(broadcast.len(), 1);
+        let hash = broadcast[0];
+        assert_eq!(mainchain.submitted_bundles(), [hash]);
+        // The mainchain node loses it, and it goes out again once it has
+        // been quiet for the timeout.
+        for _ in 0..2 {
+            mainchain.mine_block();
+            assert!(daemon.broadcast_bundles(&mainchain)?.is_empty());
+        }
+        mainchain.mine_block();
+        assert_eq!(daemon.broadcast_bundles(&mainchain)?, [hash]);
+        assert_eq!(mainchain.submitted_bundles(), [hash, hash]);
+
+        // Once paid out it is left alone.
+        mainchain.add_spent_withdrawal(SpentWithdrawal {
+            nsidechain: THIS_SIDECHAIN,
+            hash,
+            hashblock: mainchain.mine_block(),
+        });
+        for _ in 0..3 {
+            mainchain.mine_block();
+        }
+        assert!(daemon.broadcast_bundles(&mainchain)?.is_empty());
+        let node = daemon.node();
+        let node = node.lock().unwrap();
+        assert!(!node.blockchain.peg.is_outstanding(&hash));
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn peers_feed_the_chain_and_mempool() -> anyhow::Result<()> {
+        let data_dir = std::env::temp_dir().join(format!("sdk-daemon-p2p-{}", std::process::id()));
+        let open = |name: &str| {
+            let mut config = Config {
+                data_dir: data_dir.join(name),
+                bmm: false,
+                ..Config::default()
+            };
+            config.rpc.port = 0;
+            Daemon::open(config)
+        };
+        let (a, b) = (open("a")?, open("b")?);
+        let mainchain = MockMainClient::new();
+        let txid = submit_payment(&b);
+        let (transaction, deposits) = {
+            let node = b.node();
+            let node = node.lock().unwrap();
+            let deposits = DepositsChunk {
+                outputs: node.blockchain.peg.deposit_outputs.clone(),
+                deposits: vec![],
+            };
+            (node.mempool.get(&txid).unwrap().clone(), deposits)
+        };
+        a.node().lock().unwrap().blockchain.add_deposits(deposits);
+
+        let mut p2p = P2p::new(Network::new(a.version()), AddrMan::new());
+        let addr = p2p.network.listen("127.0.0.1:0")?;
+        let peer = Network::new(b.version());
+        let id = peer.connect(addr)?;
+        let mut wait_for = |done: &dyn Fn(&NodeState) -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !done(&a.node.lock().unwrap()) {
+                assert!(Instant::now() < deadline);
+                a.handle_p2p_events(&mut p2p, &mainchain);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        peer.send(id, &Message::Transaction(transaction))?;
+        wait_for(&|node| node.mempool.contains(&txid));
+        let block_hash = b.mine_block().unwrap();
+        let (header, body) = {
+            let node = b.node();
+            let node = node.lock().unwrap();
+            let header = node.blockchain.get_header(&block_hash).unwrap().clone();
+            (header, node.blockchain.get_body(&block_hash).unwrap())
+        };
+        peer.send(
+            id,
+            &Message::Block {
+                header,
+                body: Arc::unwrap_or_clone(body),
+                main_block_hash: None,
+            },
+        )?;
+        wait_for(&|node| node.blockchain.get_best_block_hash() == Some(block_hash));
+        assert!(a.node.lock().unwrap().mempool.is_empty());
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn daemons_sync_over_loopback() -> anyhow::Result<()> {
+        let data_dir = std::env::temp_dir().join(format!("sdk-daemon-sync-{}", std::process::id()));
+        // Bound and dropped, so the first daemon can listen there.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let config = |name: &str| {
+            let mut config = Config {
+                data_dir: data_dir.join(name),
+                bmm: false,
+                ..Config::default()
+            };
+            config.rpc.port = 0;
+            config.p2p.enabled = true;
+            config
+        };
+        let mut a = config("a");
+        a.p2p.listen = Some(addr);
+        let a = Daemon::open(a)?;
+        let mut b = config("b");
+        b.p2p.peers = vec![addr.to_string()];
+        let b = Daemon::open(b)?;
+        submit_payment(&a);
+        let block_hash = a.mine_block().unwrap();
+        let deposits = DepositsChunk {
+            outputs: a
+                .node
+                .lock()
+                .unwrap()
+                .blockchain
+                .peg
+                .deposit_outputs
+                .clone(),
+            deposits: vec![],
+        };
+        b.node.lock().unwrap().blockchain.add_deposits(deposits);
+
+        std::thread::scope(|scope| {
+            let a_run = scope.spawn(|| a.run());
+            while std::net::TcpStream::connect(addr).is_err() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let b_run = scope.spawn(|| b.run());
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while b.node.lock().unwrap().blockchain.get_best_block_hash() != Some(block_hash)
+                && Instant::now() < deadline
+            {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            a.shutdown_handle().store(true, Ordering::SeqCst);
+            b.shutdown_handle().store(true, Ordering::SeqCst);
+            a_run.join().unwrap()?;
+            b_run.join().unwrap()
+        })?;
+        assert_eq!(
+            b.node.lock().unwrap().blockchain.get_best_block_hash(),
+            Some(block_hash)
+        );
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn accepted_transactions_are_announced() -> anyhow::Result<()> {
+        let data_dir =
+            std::env::temp_dir().join(format!("sdk-daemon-announce-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        let daemon = Daemon::open(config)?;
+        let mainchain = MockMainClient::new();
+        let mut p2p = P2p::new(Network::new(daemon.version()), AddrMan::new());
+        let addr = p2p.network.listen("127.0.0.1:0")?;
+        let peer = Network::<Signature, Output>::new(daemon.version());
+        let id = peer.connect(addr)?;
+        let mut mempool = HashSet::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        // Transactions accepted before the handshake aren't announced to
+        // the peer.
+        while p2p.network.peers().is_empty() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let mut next_message = || loop {
+            assert!(Instant::now() < deadline);
+            daemon.handle_p2p_events(&mut p2p, &mainchain);
+            let accepted = daemon.accepted_transactions(&mut mempool);
+            p2p.transactions.announce(&p2p.network, None, &accepted);
+            match peer.recv_timeout(Duration::from_millis(10)) {
+                Some(Event::Message { message, .. }) => return message,
+                _ => continue,
+            }
+        };
+
+        let txid = submit_payment(&daemon);
+        assert!(matches!(next_message(), Message::Inv(txids) if txids == [txid]));
+        peer.send(id, &Message::GetData(vec![txid]))?;
+        assert!(matches!(
+            next_message(),
+            Message::Transaction(transaction) if transaction.txid() == txid
+        ));
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "rest")]
+    #[test]
+    fn rest_is_served_while_running() -> anyhow::Result<()> {
+        let data_dir = std::env::temp_dir().join(format!("sdk-daemon-rest-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        config.rest.enabled = true;
+        // Bound and dropped, so the daemon can listen there.
+        config.rest.port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let url = format!("http://127.0.0.1:{}/mempool", config.rest.port);
+        let daemon = Daemon::open(config)?;
+        let txid = submit_payment(&daemon);
+        let mempool = std::thread::scope(|scope| {
+            let run = scope.spawn(|| daemon.run());
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let response = loop {
+                match ureq::get(&url).call() {
+                    Ok(response) => break response,
+                    Err(_) if Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(10))
+                    }
+                    Err(err) => panic!("rest server not reachable: {}", err),
+                }
+            };
+            daemon.shutdown_handle().store(true, Ordering::SeqCst);
+            run.join().unwrap()?;
+            Ok::<_, anyhow::Error>(response.into_string()?)
+        })?;
+        assert!(mempool.contains(&txid.to_string()));
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn tip_changes_follow_reorgs() -> anyhow::Result<()> {
+        let data_dir = std::env::temp_dir().join(format!("sdk-daemon-tip-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        let daemon = Daemon::open(config)?;
+        let node = daemon.node();
+        submit_payment(&daemon);
+        let first = daemon.mine_block().unwrap();
+        let mut tip = Tip::new(&node.lock().unwrap().blockchain);
+        assert_eq!(
+            tip.update(&node.lock().unwrap().blockchain),
+            TipChanges::default()
+        );
+
+        submit_payment(&daemon);
+        let second = daemon.mine_block().unwrap();
+        let changes = tip.update(&node.lock().unwrap().blockchain);
+        assert_eq!(changes.connected, [(second, 2)]);
+        assert!(changes.disconnected.is_empty());
+
+        // Replaced by a block with a different coinbase address.
+        node.lock().unwrap().invalidate_block(second)?;
+        let third = daemon.mine_block().unwrap();
+        let changes = tip.update(&node.lock().unwrap().blockchain);
+        assert_eq!(changes.disconnected, [(second, 2)]);
+        assert_eq!(changes.connected, [(third, 2)]);
+
+        node.lock().unwrap().invalidate_block(first)?;
+        let changes = tip.update(&node.lock().unwrap().blockchain);
+        assert_eq!(changes.disconnected, [(third, 2), (first, 1)]);
+        assert!(changes.connected.is_empty());
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn grpc_is_served_while_running() -> anyhow::Result<()> {
+        use crate::grpc::proto::{node_client::NodeClient, Empty};
+
+        let data_dir = std::env::temp_dir().join(format!("sdk-daemon-grpc-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        config.grpc.enabled = true;
+        // Bound and dropped, so the daemon can listen there.
+        config.grpc.port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let url = format!("http://127.0.0.1:{}", config.grpc.port);
+        let daemon = Daemon::open(config)?;
+        submit_payment(&daemon);
+        let (block_hash, notification) = std::thread::scope(|scope| {
+            let run = scope.spawn(|| daemon.run());
+            let runtime = tokio::runtime::Runtime::new()?;
+            let mined = runtime.block_on(async {
+                let deadline = Instant::now() + Duration::from_secs(10);
+                let mut client = loop {
+                    match NodeClient::connect(url.clone()).await {
+                        Ok(client) => break client,
+                        Err(_) if Instant::now() < deadline => {
+                            std::thread::sleep(Duration::from_millis(10))
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
+                };
+                let mut blocks = client.subscribe_blocks(Empty {}).await?.into_inner();
+                let block_hash = daemon.mine_block().unwrap();
+                let notification = blocks.message().await?.unwrap();
+                Ok::<_, anyhow::Error>((block_hash, notification))
+            });
+            daemon.shutdown_handle().store(true, Ordering::SeqCst);
+            run.join().unwrap()?;
+            mined
+        })?;
+        assert_eq!(notification.hash, block_hash.to_string());
+        assert_eq!(notification.height, 1);
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ws")]
+    #[test]
+    fn events_are_published_while_running() -> anyhow::Result<()> {
+        use serde_json::{json, Value};
+
+        let data_dir = std::env::temp_dir().join(format!("sdk-daemon-ws-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        config.ws.enabled = true;
+        // Bound and dropped, so the daemon can listen there.
+        config.ws.port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let url = format!("ws://127.0.0.1:{}", config.ws.port);
+        let daemon = Daemon::open(config)?;
+        let node = daemon.node();
+        let (txid, block_hash, events) = std::thread::scope(|scope| {
+            let run = scope.spawn(|| daemon.run());
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let (mut websocket, _) = loop {
+                match tungstenite::connect(&url) {
+                    Ok(connected) => break connected,
+                    Err(_) if Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(10))
+                    }
+                    Err(err) => panic!("event server not reachable: {}", err),
+                }
+            };
+            let mut next_event = || -> anyhow::Result<Value> {
+                Ok(serde_json::from_str(websocket.read()?.to_text()?)?)
+            };
+            let txid = submit_payment(&daemon);
+            let mut events = vec![next_event()?];
+            let block_hash = daemon.mine_block().unwrap();
+            events.push(next_event()?);
+            // The transaction goes back to the mempool.
+            node.lock().unwrap().invalidate_block(block_hash)?;
+            events.push(next_event()?);
+            events.push(next_event()?);
+            daemon.shutdown_handle().store(true, Ordering::SeqCst);
+            run.join().unwrap()?;
+            Ok::<_, anyhow::Error>((txid, block_hash, events))
+        })?;
+        assert_eq!(
+            events,
+            [
+                json!({ "type": "tx_accepted", "txid": txid.to_string() }),
+                json!({ "type": "block_connected", "hash": block_hash.to_string(), "height": 1 }),
+                json!({ "type": "block_disconnected", "hash": block_hash.to_string(), "height": 1 }),
+                json!({ "type": "tx_accepted", "txid": txid.to_string() }),
+            ]
+        );
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "electrum")]
+    #[test]
+    fn electrum_follows_the_chain_while_running() -> anyhow::Result<()> {
+        use serde_json::{json, Value};
+        use std::io::BufRead;
+
+        let data_dir =
+            std::env::temp_dir().join(format!("sdk-daemon-electrum-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        config.electrum.enabled = true;
+        // Bound and dropped, so the daemon can listen there.
+        config.electrum.port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let addr = ("127.0.0.1", config.electrum.port);
+        let daemon = Daemon::open(config)?;
+        let txid = submit_payment(&daemon);
+        let address = daemon
+            .node()
+            .lock()
+            .unwrap()
+            .mempool
+            .get(&txid)
+            .unwrap()
+            .outputs[0]
+            .address;
+        let script_hash = crate::electrum::address_script_hash(&address);
+        let (block_hash, notification, history) = std::thread::scope(|scope| {
+            let run = scope.spawn(|| daemon.run());
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let stream = loop {
+                match std::net::TcpStream::connect(addr) {
+                    Ok(stream) => break stream,
+                    Err(_) if Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(10))
+                    }
+                    Err(err) => panic!("electrum server not reachable: {}", err),
+                }
+            };
+            let mut writer = stream.try_clone()?;
+            let mut reader = std::io::BufReader::new(stream);
+            let mut read = || -> anyhow::Result<Value> {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                Ok(serde_json::from_str(&line)?)
+            };
+            let subscribe = json!({
+                "id": 1,
+                "method": "blockchain.scripthash.subscribe",
+                "params": [script_hash],
+            });
+            writeln!(writer, "{}", subscribe)?;
+            read()?;
+            let block_hash = daemon.mine_block().unwrap();
+            let notification = read()?;
+            let get_history = json!({
+                "id": 2,
+                "method": "blockchain.scripthash.get_history",
+                "params": [script_hash],
+            });
+            writeln!(writer, "{}", get_history)?;
+            let history = read()?;
+            daemon.shutdown_handle().store(true, Ordering::SeqCst);
+            run.join().unwrap()?;
+            Ok::<_, anyhow::Error>((block_hash, notification, history))
+        })?;
+        assert_eq!(notification["method"], "blockchain.scripthash.subscribe");
+        assert_eq!(notification["params"][0], script_hash);
+        assert_eq!(
+            history["result"],
+            json!([{ "tx_hash": txid.to_string(), "height": 1 }])
+        );
+        let node = daemon.node();
+        assert_eq!(
+            node.lock().unwrap().blockchain.get_best_block_hash(),
+            Some(block_hash)
+        );
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn peers_are_found_through_seeds() -> anyhow::Result<()> {
+        let data_dir =
+            std::env::temp_dir().join(format!("sdk-daemon-seeds-{}", std::process::id()));
+        let mut config = Config {
+            data_dir: data_dir.clone(),
+            bmm: false,
+            ..Config::default()
+        };
+        config.rpc.port = 0;
+        let version = Daemon::open(config.clone())?.version();
+        let seed = Network::<Signature, Output>::new(version);
+        config.p2p.enabled = true;
+        config.p2p.seeds = vec![seed.listen("127.0.0.1:0")?.to_string()];
+        let daemon = Daemon::open(config.clone())?;
+        let mainchain = MockMainClient::new();
+
+        let mut p2p = daemon.start_p2p()?.unwrap();
+        daemon.maintain_connections(&mut p2p);
+        assert_eq!(p2p.network.outbound_count(), 1);
+        // The seed is asked for the peers it knows.
+        let other: SocketAddr = "127.0.0.2:18445".parse()?;
+        loop {
+            match seed.recv_timeout(Duration::from_secs(10)).unwrap() {
+                Event::Message {
+                    peer,
+                    message: Message::GetAddr,
+                } => {
+                    seed.send(peer, &Message::Addr(vec![other]))?;
+                    break;
+                }
+                _ => continue,
+            }
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while p2p.addrman.get(&other).is_none() {
+            assert!(Instant::now() < deadline);
+            daemon.handle_p2p_events(&mut p2p, &mainchain);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        p2p.addrman.save(peers_path(&config))?;
+        assert_eq!(AddrMan::load(peers_path(&config))?.len(), 2);
+        std::fs::remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use crate::types::{hash, Address, AddressError};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+// Descriptors say which outputs a wallet owns, in a form that can be moved
+// between wallets and read by people:
+//
+//   key(<hex public key>)   outputs to the address of an ed25519 or x-only
+//                           secp256k1 key, both derive addresses alike
+//   addr(<base58 address>)  outputs to an address, watched without a key
+//
+// Either may end in #<checksum>, the first 8 hex digits of the sha256 of the
+// rest, which export always adds and import checks when present. Private
+// keys are never part of a descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Descriptor {
+    Key([u8; 32]),
+    Addr(Address),
+}
+
+impl Descriptor {
+    pub fn address(&self) -> Address {
+        match self {
+            Descriptor::Key(key) => hash(key).into(),
+            Descriptor::Addr(address) => *address,
+        }
+    }
+
+    fn body(&self) -> String {
+        match self {
+            Descriptor::Key(key) => format!("key({})", hex::encode(key)),
+            Descriptor::Addr(address) => format!("addr({})", address),
+        }
+    }
+}
+
+fn checksum(body: &str) -> String {
+    hex::encode(&sha2::Sha256::digest(body.as_bytes())[..4])
+}
+
+impl core::fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let body = self.body();
+        write!(f, "{}#{}", body, checksum(&body))
+    }
+}
+
+impl core::str::FromStr for Descriptor {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let body = match s.split_once('#') {
+            Some((body, expected)) => {
+                if checksum(body) != expected {
+                    return Err(Error::Checksum);
+                }
+                body
+            }
+            None => s,
+        };
+        let (function, argument) = body
+            .strip_suffix(')')
+            .and_then(|body| body.split_once('('))
+            .ok_or_else(|| Error::Syntax(body.into()))?;
+        match function {
+            "key" => {
+                let key = hex::decode(argument).map_err(|_| Error::Key(argument.into()))?;
+                let key = key.try_into().map_err(|_| Error::Key(argument.into()))?;
+                Ok(Descriptor::Key(key))
+            }
+            "addr" => Ok(Descriptor::Addr(argument.parse()?)),
+            // Signatures are checked against the single key an output pays
+            // to, there is nothing a multisig descriptor could describe.
+            "multi" | "sortedmulti" => Err(Error::Multisig),
+            _ => Err(Error::Unknown(function.into())),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("invalid descriptor {0:?}")]
+    Syntax(String),
+    #[error("descriptor checksum doesn't match")]
+    Checksum,
+    #[error("unknown descriptor function {0}")]
+    Unknown(String),
+    #[error("public keys are 32 bytes of hex, got {0:?}")]
+    Key(String),
+    #[error(transparent)]
+    Address(#[from] AddressError),
+    #[error("multisig outputs aren't supported, outputs pay to a single key")]
+    Multisig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptors_round_trip_with_checksums() {
+        let keypair = crate::builder::keypair([1; 32]);
+        let key = Descriptor::Key(keypair.public.to_bytes());
+        assert_eq!(key.address(), Address::from(keypair.public));
+        let watched = Descriptor::Addr([2; 32].into());
+        for descriptor in [key, watched] {
+            let string = descriptor.to_string();
+            assert_eq!(string.parse::<Descriptor>().unwrap(), descriptor);
+            let (body, _) = string.split_once('#').unwrap();
+            assert_eq!(body.parse::<Descriptor>().unwrap(), descriptor);
+        }
+
+        let mut typo = key.to_string();
+        typo.replace_range(4..5, if &typo[4..5] == "0" { "1" } else { "0" });
+        assert!(matches!(typo.parse::<Descriptor>(), Err(Error::Checksum)));
+        let multi = format!("multi(1,{})", hex::encode([1; 32]));
+        assert!(matches!(multi.parse::<Descriptor>(), Err(Error::Multisig)));
+        assert!(matches!(
+            "key(00)".parse::<Descriptor>(),
+            Err(Error::Key(_))
+        ));
+        assert!(matches!(
+            "pkh(00)".parse::<Descriptor>(),
+            Err(Error::Unknown(_))
+        ));
+    }
+}
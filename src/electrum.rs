@@ -0,0 +1,523 @@
+use crate::address_index::{self, transaction_addresses, AddressIndex};
+use crate::amount::COIN;
+use crate::concrete::{Output, Signature};
+use crate::rpc::NodeState;
+use crate::types::*;
+use serde_json::{json, Value};
+use sha2::Digest;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const PROTOCOL_VERSION: &str = "1.4";
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How long run sleeps when no client is connecting.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+// Error codes, the same ones ElectrumX uses.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const BAD_REQUEST: i64 = 1;
+
+// The subset of the Electrum protocol light wallets need to follow their
+// addresses and send transactions, served as newline delimited JSON-RPC over
+// TCP:
+//
+//   server.version, server.ping
+//   blockchain.scripthash.subscribe, blockchain.scripthash.unsubscribe
+//   blockchain.scripthash.get_history
+//   blockchain.transaction.get, blockchain.transaction.broadcast
+//
+// Script hashes are address_index::script_hash of an address, hex encoded in
+// reverse byte order like Electrum's. Raw transactions are hex encoded
+// bincode.
+pub struct ElectrumServer {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+    poll_interval: Duration,
+    shutdown: Arc<AtomicBool>,
+}
+
+struct Shared {
+    node: Arc<Mutex<NodeState>>,
+    index: Mutex<AddressIndex>,
+    sessions: Mutex<Vec<Arc<Session>>>,
+}
+
+struct Session {
+    writer: Mutex<TcpStream>,
+    // Subscribed script hashes with the status the client was last told.
+    subscriptions: Mutex<HashMap<Hash, Option<String>>>,
+}
+
+impl ElectrumServer {
+    pub fn bind(addr: impl ToSocketAddrs, node: Arc<Mutex<NodeState>>) -> Result<Self, Error> {
+        let index = AddressIndex::build(&node.lock().unwrap().blockchain);
+        let listener = TcpListener::bind(addr)?;
+        // Accepting doesn't block, so run notices the shutdown flag.
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            shared: Arc::new(Shared {
+                node,
+                index: Mutex::new(index),
+                sessions: Mutex::default(),
+            }),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            shutdown: Arc::default(),
+        })
+    }
+
+    // How often subscribed script hashes are checked for changes.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    // Setting the flag makes run disconnect every client and return.
+    pub fn with_shutdown(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn notifier(&self) -> Notifier {
+        Notifier {
+            shared: self.shared.clone(),
+        }
+    }
+
+    // Accepts clients, each one is served by its own thread while another one
+    // notifies them of status changes.
+    pub fn run(&self) {
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !self.shutdown.load(Ordering::SeqCst) {
+                    std::thread::sleep(self.poll_interval);
+                    self.shared.notify();
+                }
+            });
+            while !self.shutdown.load(Ordering::SeqCst) {
+                let stream = match self.listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        std::thread::sleep(ACCEPT_INTERVAL);
+                        continue;
+                    }
+                    Err(err) => {
+                        log::debug!("failed to accept electrum client: {}", err);
+                        continue;
+                    }
+                };
+                if let Err(err) = stream.set_nonblocking(false) {
+                    log::debug!("failed to accept electrum client: {}", err);
+                    continue;
+                }
+                let shared = self.shared.clone();
+                std::thread::spawn(move || {
+                    if let Err(err) = shared.serve(stream) {
+                        log::debug!("electrum client disconnected: {}", err);
+                    }
+                });
+            }
+        });
+        // Their threads end once the connections are closed.
+        for session in self.shared.sessions.lock().unwrap().iter() {
+            let _ = session.writer.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// Brings the address index up to date and tells clients about it right away,
+// instead of on the next poll.
+#[derive(Clone)]
+pub struct Notifier {
+    shared: Arc<Shared>,
+}
+
+impl Notifier {
+    pub fn notify(&self) {
+        self.shared.notify();
+    }
+}
+
+impl Shared {
+    fn serve(&self, stream: TcpStream) -> Result<(), Error> {
+        let session = Arc::new(Session {
+            writer: Mutex::new(stream.try_clone()?),
+            subscriptions: Mutex::default(),
+        });
+        self.sessions.lock().unwrap().push(session.clone());
+        let result = self.answer(&session, BufReader::new(stream));
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|other| !Arc::ptr_eq(other, &session));
+        result
+    }
+
+    fn answer(&self, session: &Session, reader: BufReader<TcpStream>) -> Result<(), Error> {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Value>(&line) {
+                // Batch requests get an array with a response for every call.
+                Ok(Value::Array(calls)) => Value::Array(
+                    calls
+                        .into_iter()
+                        .map(|call| self.call(session, call))
+                        .collect(),
+                ),
+                Ok(call) => self.call(session, call),
+                Err(err) => response(Value::Null, Err((PARSE_ERROR, err.to_string()))),
+            };
+            session.send(&response)?;
+        }
+        Ok(())
+    }
+
+    fn call(&self, session: &Session, call: Value) -> Value {
+        let id = call.get("id").cloned().unwrap_or_default();
+        let method = match call.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => return response(id, Err((INVALID_REQUEST, "missing method".into()))),
+        };
+        let params = match call.get("params") {
+            Some(Value::Array(params)) => params.as_slice(),
+            _ => &[],
+        };
+        let mut node = self.node.lock().unwrap();
+        let mut index = self.index.lock().unwrap();
+        index.sync(&node.blockchain);
+        let result = match method {
+            "server.version" => Ok(json!([
+                concat!("sdk ", env!("CARGO_PKG_VERSION")),
+                PROTOCOL_VERSION
+            ])),
+            "server.ping" => Ok(Value::Null),
+            // Coins per 1000 virtual bytes.
+            "blockchain.relayfee" => Ok(json!(
+                node.mempool.min_fee_rate().to_sat_per_kvb() as f64 / COIN as f64
+            )),
+            "blockchain.scripthash.subscribe" => param_script_hash(params).map(|script_hash| {
+                let status = status(&node, &index, &script_hash);
+                session
+                    .subscriptions
+                    .lock()
+                    .unwrap()
+                    .insert(script_hash, status.clone());
+                json!(status)
+            }),
+            "blockchain.scripthash.unsubscribe" => param_script_hash(params).map(|script_hash| {
+                let subscriptions = &mut session.subscriptions.lock().unwrap();
+                json!(subscriptions.remove(&script_hash).is_some())
+            }),
+            "blockchain.scripthash.get_history" => param_script_hash(params).map(|script_hash| {
+                let history = history(&node, &index, &script_hash)
+                    .into_iter()
+                    .map(|(txid, height, fee)| match fee {
+                        Some(fee) => {
+                            json!({ "tx_hash": txid.to_string(), "height": height, "fee": fee })
+                        }
+                        None => json!({ "tx_hash": txid.to_string(), "height": height }),
+                    })
+                    .collect::<Vec<_>>();
+                json!(history)
+            }),
+            "blockchain.transaction.get" => param_txid(params).and_then(|txid| {
+                let transaction = node
+                    .blockchain
+                    .get_transaction(&txid)
+                    .or_else(|| node.mempool.get(&txid).cloned())
+                    .ok_or_else(|| (BAD_REQUEST, format!("transaction {} not found", txid)))?;
+                let raw =
+                    bincode::serialize(&transaction).expect("failed to serialize transaction");
+                Ok(json!(hex::encode(raw)))
+            }),
+            "blockchain.transaction.broadcast" => {
+                param_transaction(params).and_then(|transaction| {
+                    let txid = node.submit(transaction).map_err(|err| (BAD_REQUEST, err))?;
+                    Ok(json!(txid.to_string()))
+                })
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        };
+        response(id, result)
+    }
+
+    // Tells every client about subscribed script hashes whose status changed.
+    fn notify(&self) {
+        let sessions = self.sessions.lock().unwrap().clone();
+        let mut notifications = vec![];
+        {
+            let node = self.node.lock().unwrap();
+            let mut index = self.index.lock().unwrap();
+            index.sync(&node.blockchain);
+            for session in &sessions {
+                let mut subscriptions = session.subscriptions.lock().unwrap();
+                for (script_hash, last_status) in subscriptions.iter_mut() {
+                    let status = status(&node, &index, script_hash);
+                    if status != *last_status {
+                        *last_status = status.clone();
+                        notifications.push((
+                            session.clone(),
+                            json!({
+                                "jsonrpc": "2.0",
+                                "method": "blockchain.scripthash.subscribe",
+                                "params": [encode_script_hash(script_hash), status],
+                            }),
+                        ));
+                    }
+                }
+            }
+        }
+        // Sent without holding the node, a slow client doesn't stall it.
+        for (session, notification) in notifications {
+            if let Err(err) = session.send(&notification) {
+                log::debug!("failed to notify electrum client: {}", err);
+            }
+        }
+    }
+}
+
+impl Session {
+    fn send(&self, message: &Value) -> Result<(), Error> {
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(writer, "{}", message)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+type CallResult = Result<Value, (i64, String)>;
+
+fn response(id: Value, result: CallResult) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+// Confirmed transactions first, oldest first, then the mempool ones with
+// height 0 and their fee.
+fn history(
+    node: &NodeState,
+    index: &AddressIndex,
+    script_hash: &Hash,
+) -> Vec<(Txid, usize, Option<Amount>)> {
+    let mut history: Vec<_> = index
+        .address(script_hash)
+        .map(|address| index.history(&address))
+        .unwrap_or_default()
+        .iter()
+        .map(|(txid, height)| (*txid, *height, None))
+        .collect();
+    for (fee, transaction) in node.mempool.iter() {
+        let addresses = transaction_addresses(&node.blockchain, transaction);
+        if addresses
+            .iter()
+            .any(|address| address_index::script_hash(address) == *script_hash)
+        {
+            history.push((transaction.txid(), 0, Some(fee)));
+        }
+    }
+    history
+}
+
+// The Electrum status of a script hash, None if it has no history.
+fn status(node: &NodeState, index: &AddressIndex, script_hash: &Hash) -> Option<String> {
+    let history = history(node, index, script_hash);
+    if history.is_empty() {
+        return None;
+    }
+    let mut hasher = sha2::Sha256::new();
+    for (txid, height, _) in history {
+        hasher.update(format!("{}:{}:", txid, height));
+    }
+    Some(hex::encode(hasher.finalize()))
+}
+
+// Electrum shows hashes in reverse byte order.
+pub fn encode_script_hash(script_hash: &Hash) -> String {
+    let mut bytes = *script_hash;
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+pub fn address_script_hash(address: &Address) -> String {
+    encode_script_hash(&address_index::script_hash(address))
+}
+
+fn param_script_hash(params: &[Value]) -> Result<Hash, (i64, String)> {
+    let mut bytes: Hash = param_hex(params)?
+        .try_into()
+        .map_err(|_| (BAD_REQUEST, "script hash must be 32 bytes".to_string()))?;
+    bytes.reverse();
+    Ok(bytes)
+}
+
+fn param_txid(params: &[Value]) -> Result<Txid, (i64, String)> {
+    let txid = params.first().and_then(Value::as_str).unwrap_or_default();
+    txid.parse()
+        .map_err(|_| (BAD_REQUEST, format!("invalid txid {:?}", txid)))
+}
+
+fn param_transaction(params: &[Value]) -> Result<Transaction<Signature, Output>, (i64, String)> {
+    bincode::deserialize(&param_hex(params)?)
+        .map_err(|err| (BAD_REQUEST, format!("invalid transaction: {}", err)))
+}
+
+fn param_hex(params: &[Value]) -> Result<Vec<u8>, (i64, String)> {
+    let param = params
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| (BAD_REQUEST, "missing param 0".to_string()))?;
+    hex::decode(param).map_err(|_| (BAD_REQUEST, format!("invalid hex {:?}", param)))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::mempool::MemPool;
+    use crate::wallet::{Wallet, Wallets};
+
+    struct Client {
+        writer: TcpStream,
+        reader: BufReader<TcpStream>,
+        // Notifications read while waiting for a response.
+        notifications: Vec<Value>,
+    }
+
+    impl Client {
+        fn read(&mut self) -> Value {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        fn call(&mut self, method: &str, params: Value) -> Value {
+            let request = json!({ "id": 1, "method": method, "params": params });
+            writeln!(self.writer, "{}", request).unwrap();
+            loop {
+                let message = self.read();
+                if message.get("id").is_some() {
+                    return message;
+                }
+                self.notifications.push(message);
+            }
+        }
+
+        fn notification(&mut self) -> Value {
+            if self.notifications.is_empty() {
+                return self.read();
+            }
+            self.notifications.remove(0)
+        }
+    }
+
+    #[test]
+    fn light_wallet_follows_an_address() -> anyhow::Result<()> {
+        let mut node = NodeState {
+            blockchain: BlockChain::new(),
+            mempool: MemPool::default(),
+            wallet: Wallet::default(),
+            wallets: Wallets::default(),
+            main_fee_rate: None,
+        };
+        let from = node.wallet.generate_address();
+        node.blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(bitcoin::OutPoint::default()),
+                DepositOutput {
+                    address: from,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+        node.sync_wallet();
+        let to: Address = [1; 32].into();
+        let output = Output {
+            address: to,
+            value: Amount::from_sat(90),
+        };
+        let transaction = node
+            .wallet
+            .create_transaction(vec![output], Amount::from_sat(10))
+            .unwrap();
+        let txid = transaction.txid();
+        let node = Arc::new(Mutex::new(node));
+        let server = ElectrumServer::bind("127.0.0.1:0", node.clone())?
+            .with_poll_interval(Duration::from_millis(10));
+        let stream = TcpStream::connect(server.local_addr()?)?;
+        std::thread::spawn(move || server.run());
+        let mut client = Client {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+            notifications: vec![],
+        };
+
+        let version = client.call("server.version", json!(["test", "1.4"]));
+        assert_eq!(version["result"][1], PROTOCOL_VERSION);
+        let relay_fee = client.call("blockchain.relayfee", json!([]));
+        assert_eq!(relay_fee["result"], 0.0);
+        let script_hash = address_script_hash(&to);
+        let subscribed = client.call("blockchain.scripthash.subscribe", json!([script_hash]));
+        assert_eq!(subscribed["result"], Value::Null);
+
+        let raw = hex::encode(bincode::serialize(&transaction)?);
+        let broadcast = client.call("blockchain.transaction.broadcast", json!([raw]));
+        assert_eq!(broadcast["result"], txid.to_string());
+        let notification = client.notification();
+        assert_eq!(notification["method"], "blockchain.scripthash.subscribe");
+        assert_eq!(notification["params"][0], script_hash);
+        let unconfirmed_status = notification["params"][1].clone();
+        assert!(unconfirmed_status.is_string());
+        let history = client.call("blockchain.scripthash.get_history", json!([script_hash]));
+        assert_eq!(
+            history["result"],
+            json!([{ "tx_hash": txid.to_string(), "height": 0, "fee": 10 }])
+        );
+
+        {
+            let mut node = node.lock().unwrap();
+            let body = node.mempool.create_body(from, 10);
+            let header = Header::new(&Hash::default().into(), &body);
+            node.blockchain.connect_block(&header, &body, None).unwrap();
+            node.mempool.retain(|_| false);
+        }
+        // Confirming the transaction changes the status once more.
+        let notification = client.notification();
+        assert_ne!(notification["params"][1], unconfirmed_status);
+        let history = client.call("blockchain.scripthash.get_history", json!([script_hash]));
+        assert_eq!(
+            history["result"],
+            json!([{ "tx_hash": txid.to_string(), "height": 1 }])
+        );
+        let raw_transaction = client.call("blockchain.transaction.get", json!([txid.to_string()]));
+        assert_eq!(raw_transaction["result"], raw);
+        let unknown = client.call("blockchain.block.header", json!([0]));
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+        Ok(())
+    }
+}
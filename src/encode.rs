@@ -0,0 +1,515 @@
+use alloc::vec::Vec;
+use core::fmt;
+use serde::ser::{self, Serialize};
+
+// Consensus encoding txids, block hashes and merkle roots are computed over,
+// version 1. Values are encoded as:
+//
+//   bool, u8, i8            1 byte, bools as 0 or 1
+//   other integers          fixed width little endian
+//   f32, f64                IEEE 754 bits, little endian
+//   char                    its UTF-8 bytes
+//   str, bytes, seq, map    u64 length, then the items
+//   arrays, tuples          the items back to back, no length
+//   structs                 the fields in declaration order
+//   Option                  0 for None, 1 followed by the value for Some
+//   enums                   u32 variant index, then the fields
+//   unit, unit structs      nothing
+//
+// which for the consensus types gives:
+//
+//   Txid, BlockHash, Hash,  32 bytes
+//   MerkleRoot, Address
+//   Amount                  u64 satoshis
+//   OutPoint                u32 variant, then
+//                             0 Regular     txid, u32 vout
+//                             1 Coinbase    block hash, u32 vout
+//                             2 Withdrawal  txid, u32 vout
+//                             3 Deposit     u64 length 32, the mainchain
+//                                           txid, u32 vout
+//   Output                  address, amount
+//   WithdrawalOutput        value, fee, side address, mainchain address as
+//                           a u64 length and its string form
+//   Transaction             u32 version, inputs, signatures, outputs,
+//                           withdrawal outputs, extra, each a u64 count or
+//                           length followed by the items
+//   Body                    coinbase outputs, transactions, each a u64
+//                           count followed by the items
+//   Header                  prev block hash, merkle root, Option state root
+//
+// The version is fixed for the life of a chain: nothing on the wire or in a
+// hash says which one was used, so any change to the layout above, field and
+// variant order included, silently changes every txid and block hash. The
+// golden vectors in the tests pin it. A new encoding would have to be
+// selected by a new TRANSACTION_VERSION activated at a fork height, never
+// by changing this one.
+pub const ENCODING_VERSION: u32 = 1;
+
+// Encodes a value with the consensus encoding. It only needs core and
+// alloc, so hashing works in no_std builds too.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder { output: Vec::new() };
+    value.serialize(&mut encoder)?;
+    Ok(encoder.output)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // The length of a sequence is written before its items.
+    UnknownLength,
+    Custom,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownLength => write!(f, "sequences must have a known length"),
+            Self::Custom => write!(f, "failed to serialize"),
+        }
+    }
+}
+
+impl ser::StdError for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        Self::Custom
+    }
+}
+
+struct Encoder {
+    output: Vec<u8>,
+}
+
+impl Encoder {
+    fn write_len(&mut self, len: Option<usize>) -> Result<(), Error> {
+        let len = len.ok_or(Error::UnknownLength)?;
+        self.output.extend_from_slice(&(len as u64).to_le_bytes());
+        Ok(())
+    }
+}
+
+impl ser::Serializer for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.output.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.output.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.output.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    // Chars are written as their UTF-8 bytes, without a length.
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        let mut buf = [0; 4];
+        self.output
+            .extend_from_slice(v.encode_utf8(&mut buf).as_bytes());
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_len(Some(v.len()))?;
+        self.output.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.output.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        self.output.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), Error> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, Error> {
+        self.write_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, Error> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, Error> {
+        self.write_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, Error> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concrete::{Output, Signature};
+    use crate::types::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn layout_of_the_other_types() -> anyhow::Result<()> {
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: bitcoin::hashes::Hash::from_inner([1; 32]),
+            vout: 3,
+        });
+        assert_eq!(
+            hex::encode(to_vec(&deposit)?),
+            concat!(
+                "03000000",                                                         // Deposit
+                "2000000000000000",                                                 // length
+                "0101010101010101010101010101010101010101010101010101010101010101", // txid
+                "03000000",                                                         // vout
+            )
+        );
+        let withdrawal = WithdrawalOutput {
+            value: Amount::from_sat(5),
+            fee: Amount::from_sat(6),
+            side_address: [7; 32].into(),
+            main_address: bitcoin::Address::from_str(
+                "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+            )?,
+        };
+        assert_eq!(
+            hex::encode(to_vec(&withdrawal)?),
+            [
+                "0500000000000000",
+                "0600000000000000",
+                "0707070707070707070707070707070707070707070707070707070707070707",
+                "2c00000000000000",
+                &hex::encode("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"),
+            ]
+            .concat()
+        );
+        let other = ('\u{e9}', "str", -1i64, Some(1.5f64), None::<u8>, ());
+        assert_eq!(
+            hex::encode(to_vec(&other)?),
+            concat!(
+                "c3a9", // char
+                "0300000000000000",
+                "737472",           // str
+                "ffffffffffffffff", // i64
+                "01",
+                "000000000000f83f", // Some(f64)
+                "00",               // None
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn golden_vectors() -> anyhow::Result<()> {
+        let transaction = Transaction::<Signature, Output> {
+            version: 1,
+            inputs: vec![OutPoint::Regular {
+                txid: [1; 32].into(),
+                vout: 2,
+            }],
+            signatures: vec![],
+            outputs: vec![Output {
+                address: [3; 32].into(),
+                value: Amount::from_sat(4),
+            }],
+            withdrawal_outputs: vec![],
+            extra: vec![5],
+        };
+        let body = Body::<Signature, Output> {
+            coinbase: vec![Output {
+                address: [6; 32].into(),
+                value: Amount::from_sat(7),
+            }],
+            transactions: vec![transaction.clone()],
+        };
+        let header = Header::new(&[8; 32].into(), &body).with_state_root([9; 32]);
+        // Changing any of these changes the txids and block hashes of
+        // existing chains.
+        let encoded_transaction = concat!(
+            "01000000",                                                         // version
+            "0100000000000000",                                                 // inputs
+            "00000000",                                                         // Regular
+            "0101010101010101010101010101010101010101010101010101010101010101", // txid
+            "02000000",                                                         // vout
+            "0000000000000000",                                                 // signatures
+            "0100000000000000",                                                 // outputs
+            "0303030303030303030303030303030303030303030303030303030303030303", // address
+            "0400000000000000",                                                 // value
+            "0000000000000000",                                                 // withdrawals
+            "010000000000000005",                                               // extra
+        );
+        assert_eq!(hex::encode(to_vec(&transaction)?), encoded_transaction);
+        assert_eq!(
+            hex::encode(to_vec(&body)?),
+            [
+                "0100000000000000",
+                "0606060606060606060606060606060606060606060606060606060606060606",
+                "0700000000000000",
+                "0100000000000000",
+                encoded_transaction,
+            ]
+            .concat()
+        );
+        assert_eq!(
+            hex::encode(to_vec(&header)?),
+            concat!(
+                "0808080808080808080808080808080808080808080808080808080808080808",
+                "cedcb479a66f63e036d818d04d9f5a07f7445d5d6738a0f36131a9bc130df26a",
+                "01",
+                "0909090909090909090909090909090909090909090909090909090909090909",
+            )
+        );
+        assert_eq!(
+            transaction.txid().to_string(),
+            "8c0c438c2e67a725bb9a5a10d51c9fcde43b4aebedbed0ce14baa9249f5f355d"
+        );
+        assert_eq!(
+            body.compute_merkle_root().to_string(),
+            "cedcb479a66f63e036d818d04d9f5a07f7445d5d6738a0f36131a9bc130df26a"
+        );
+        assert_eq!(
+            header.hash().to_string(),
+            "678f26925c767608e456a9342c740823210dfef77b086a4aa66aec473d9b44c2"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use crate::types::*;
+use bitcoin::util::bip158::{self, GCSFilterReader, GCSFilterWriter};
+use serde::{Deserialize, Serialize};
+
+// The parameters of BIP158 basic filters: a false positive about once in M
+// lookups, at a little over P + 1 bits per element.
+const P: u8 = 19;
+const M: u64 = 784931;
+
+// BIP158 style filter of a block, a Golomb coded set of the address of
+// every output the block creates and every outpoint it spends. Light
+// wallets test their addresses and coins against it and only download the
+// blocks that match. The set is keyed with the block hash, so elements that
+// collide in one block don't in the next.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockFilter {
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn new<S, O: Out>(block_hash: &BlockHash, body: &Body<S, O>) -> Self {
+        let (k0, k1) = keys(block_hash);
+        let mut content = vec![];
+        let mut writer = GCSFilterWriter::new(&mut content, k0, k1, M, P);
+        for output in &body.coinbase {
+            writer.add_element(&address_element(&output.get_address()));
+        }
+        for transaction in &body.transactions {
+            for outpoint in &transaction.inputs {
+                writer.add_element(&outpoint_element(outpoint));
+            }
+            for output in &transaction.outputs {
+                writer.add_element(&address_element(&output.get_address()));
+            }
+            // Refunded to the side address if the withdrawal fails.
+            for output in &transaction.withdrawal_outputs {
+                writer.add_element(&address_element(&output.side_address));
+            }
+        }
+        writer.finish().expect("writing to a vec can't fail");
+        Self { content }
+    }
+
+    // Whether the block may pay to one of the addresses or spend one of the
+    // outpoints. Fails only for filters that weren't made with new, like a
+    // malformed one from a peer.
+    pub fn match_any(
+        &self,
+        block_hash: &BlockHash,
+        addresses: &[Address],
+        outpoints: &[OutPoint],
+    ) -> Result<bool, Error> {
+        let query: Vec<Vec<u8>> = addresses
+            .iter()
+            .map(address_element)
+            .chain(outpoints.iter().map(outpoint_element))
+            .collect();
+        let (k0, k1) = keys(block_hash);
+        let matched = GCSFilterReader::new(k0, k1, M, P).match_any(
+            &mut self.content.as_slice(),
+            &mut query.iter().map(Vec::as_slice),
+        )?;
+        Ok(matched)
+    }
+}
+
+// SipHash keys from the first 16 bytes of the block hash, as in BIP158.
+fn keys(block_hash: &BlockHash) -> (u64, u64) {
+    let hash = Hash::from(*block_hash);
+    let k0 = u64::from_le_bytes(hash[0..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(hash[8..16].try_into().unwrap());
+    (k0, k1)
+}
+
+fn address_element(address: &Address) -> Vec<u8> {
+    Hash::from(*address).to_vec()
+}
+
+fn outpoint_element(outpoint: &OutPoint) -> Vec<u8> {
+    outpoint.to_string().into_bytes()
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("malformed block filter")]
+    Malformed(#[from] bip158::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{keypair, BlockBuilder, TxBuilder};
+
+    #[test]
+    fn filters_match_what_blocks_touch() {
+        let alice = keypair([1; 32]);
+        let bob: Address = [2; 32].into();
+        let miner: Address = [3; 32].into();
+        let stranger: Address = [4; 32].into();
+        let spent = OutPoint::Deposit(bitcoin::OutPoint::default());
+        let transaction = TxBuilder::new()
+            .spend(spent, &alice)
+            .pay(bob, Amount::from_sat(90))
+            .build();
+        let (header, body) = BlockBuilder::new(Hash::default().into())
+            .coinbase(miner, Amount::from_sat(10))
+            .transaction(transaction)
+            .build();
+        let block_hash = header.hash();
+        let filter = BlockFilter::new(&block_hash, &body);
+
+        for address in [bob, miner] {
+            assert!(filter.match_any(&block_hash, &[address], &[]).unwrap());
+        }
+        assert!(filter.match_any(&block_hash, &[], &[spent]).unwrap());
+        let unspent = OutPoint::Regular {
+            txid: [5; 32].into(),
+            vout: 0,
+        };
+        assert!(!filter
+            .match_any(&block_hash, &[stranger], &[unspent])
+            .unwrap());
+        // The set is keyed with the block hash.
+        let other_block: BlockHash = [6; 32].into();
+        assert!(!filter.match_any(&other_block, &[bob], &[]).unwrap());
+    }
+}
@@ -0,0 +1,336 @@
+use crate::blockchain::BlockChain;
+use crate::bmm::Anchor;
+use crate::types::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+
+// Every block a node has heard of, on its chain or not, and the choice
+// between them. The longest valid chain wins. Between tips at the same
+// height the one whose BMM commitment the mainchain included first wins, so
+// every node picks the same one no matter which it heard of first, and
+// nodes stay on their tip when neither commitment can be verified. Blocks
+// without a commitment are still accepted.
+pub struct BlockTree<S, O, H = Sha256> {
+    blocks: HashMap<BlockHash, (Header, Body<S, O>)>,
+    heights: HashMap<BlockHash, usize>,
+    // BMM commitments of the blocks whose commitment could be verified.
+    anchors: HashMap<BlockHash, Anchor>,
+    invalid: HashSet<BlockHash>,
+    hasher: PhantomData<H>,
+}
+
+// Blocks a switch to the best chain took off the chain and put on it,
+// oldest first.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Reorg {
+    pub disconnected: Vec<BlockHash>,
+    pub connected: Vec<BlockHash>,
+}
+
+impl<S, O, H> Default for BlockTree<S, O, H> {
+    fn default() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            heights: HashMap::new(),
+            anchors: HashMap::new(),
+            invalid: HashSet::new(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<S, O, H> BlockTree<S, O, H>
+where
+    S: Sig + Serialize + DeserializeOwned + Clone,
+    O: Out + Serialize + DeserializeOwned + Clone,
+    H: Hasher,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.blocks.contains_key(block_hash)
+    }
+
+    pub fn get(&self, block_hash: &BlockHash) -> Option<&(Header, Body<S, O>)> {
+        self.blocks.get(block_hash)
+    }
+
+    pub fn get_anchor(&self, block_hash: &BlockHash) -> Option<&Anchor> {
+        self.anchors.get(block_hash)
+    }
+
+    pub fn is_invalid(&self, block_hash: &BlockHash) -> bool {
+        self.invalid.contains(block_hash)
+    }
+
+    // Remembers the block, returns false if it was already known. Its parent
+    // may be on `blockchain` without being in the tree.
+    pub fn insert(
+        &mut self,
+        blockchain: &BlockChain<S, O, H>,
+        header: Header,
+        body: Body<S, O>,
+        anchor: Option<Anchor>,
+    ) -> bool {
+        let block_hash = header.hash_with::<H>();
+        if self.blocks.contains_key(&block_hash) {
+            return false;
+        }
+        let prev_block_hash = header.prev_block_hash;
+        self.blocks.insert(block_hash, (header, body));
+        if let Some(anchor) = anchor {
+            self.anchors.insert(block_hash, anchor);
+        }
+        if self.invalid.contains(&prev_block_hash) {
+            self.invalidate(block_hash);
+        }
+        self.update_heights(blockchain, block_hash);
+        true
+    }
+
+    // Switches `blockchain` to the best chain the tree knows of. A block
+    // that fails to connect is marked invalid together with every block
+    // building on it, and the next best chain is tried. Application state
+    // machines aren't told about the switch.
+    pub fn reorganize(&mut self, blockchain: &mut BlockChain<S, O, H>) -> Reorg {
+        let mut reorg = Reorg::default();
+        loop {
+            let tip = blockchain.get_best_block_hash();
+            let best = match self.best_tip(blockchain) {
+                Some(best) if Some(best) != tip => best,
+                _ => return reorg,
+            };
+            // Blocks of the new chain past the fork point, tip first.
+            let mut path = vec![];
+            let mut block_hash = best;
+            while blockchain.get_header(&block_hash).is_none()
+                && block_hash != Hash::default().into()
+            {
+                path.push(block_hash);
+                block_hash = self.blocks[&block_hash].0.prev_block_hash;
+            }
+            let mut disconnected = vec![];
+            while let Some(tip) = blockchain.get_best_block_hash() {
+                if tip == block_hash {
+                    break;
+                }
+                disconnected.push(self.disconnect_tip(blockchain, tip));
+            }
+            let mut connected = vec![];
+            let mut failed = None;
+            for block_hash in path.into_iter().rev() {
+                let (header, body) = &self.blocks[&block_hash];
+                let anchor = self.anchors.get(&block_hash);
+                if blockchain.connect_block(header, body, anchor).is_err() {
+                    failed = Some(block_hash);
+                    break;
+                }
+                connected.push(block_hash);
+            }
+            if let Some(failed) = failed {
+                self.invalidate(failed);
+                for block_hash in connected.into_iter().rev() {
+                    self.disconnect_tip(blockchain, block_hash);
+                }
+                for block_hash in disconnected.into_iter().rev() {
+                    let (header, body) = &self.blocks[&block_hash];
+                    let anchor = self.anchors.get(&block_hash);
+                    blockchain
+                        .connect_block(header, body, anchor)
+                        .expect("disconnected block no longer connects");
+                }
+                continue;
+            }
+            // A block switched away from and back to in one call was never
+            // really gone.
+            for block_hash in disconnected.into_iter().rev() {
+                match reorg.connected.iter().position(|hash| *hash == block_hash) {
+                    Some(index) => {
+                        reorg.connected.remove(index);
+                    }
+                    None => reorg.disconnected.insert(0, block_hash),
+                }
+            }
+            reorg.connected.extend(connected);
+        }
+    }
+
+    // The tip of the best valid chain, `blockchain`'s own tip included.
+    fn best_tip(&self, blockchain: &BlockChain<S, O, H>) -> Option<BlockHash> {
+        let height = blockchain.height();
+        let tip = blockchain.get_best_block_hash();
+        let tip_anchor = tip.and_then(|tip| blockchain.get_anchor(&tip));
+        let candidates = self
+            .heights
+            .iter()
+            .filter(|(block_hash, block_height)| {
+                **block_height >= height && !self.invalid.contains(*block_hash)
+            })
+            .map(|(block_hash, block_height)| {
+                let anchor = self.anchors.get(block_hash);
+                (*block_hash, *block_height, anchor)
+            })
+            .chain(tip.map(|tip| (tip, height, tip_anchor)));
+        candidates
+            .max_by_key(|(block_hash, block_height, anchor)| {
+                rank(
+                    *block_height,
+                    anchor.copied(),
+                    Some(*block_hash) == tip,
+                    *block_hash,
+                )
+            })
+            .map(|(block_hash, _, _)| block_hash)
+    }
+
+    // Disconnects `blockchain`'s tip, keeping the block so it can be
+    // connected again.
+    fn disconnect_tip(
+        &mut self,
+        blockchain: &mut BlockChain<S, O, H>,
+        block_hash: BlockHash,
+    ) -> BlockHash {
+        let (header, body) = self.blocks.entry(block_hash).or_insert_with(|| {
+            let header = blockchain
+                .get_header(&block_hash)
+                .expect("tip has a header");
+            let body = blockchain.get_body(&block_hash).expect("tip has a body");
+            (header.clone(), (*body).clone())
+        });
+        self.heights.insert(block_hash, blockchain.height());
+        if let Some(anchor) = blockchain.get_anchor(&block_hash) {
+            self.anchors.insert(block_hash, *anchor);
+        }
+        blockchain.disconnect_block(header, body);
+        block_hash
+    }
+
+    // Heights of the block and every orphan that now connects through it.
+    fn update_heights(&mut self, blockchain: &BlockChain<S, O, H>, block_hash: BlockHash) {
+        let mut pending = vec![block_hash];
+        while let Some(block_hash) = pending.pop() {
+            let prev_block_hash = self.blocks[&block_hash].0.prev_block_hash;
+            let prev_height = match self.heights.get(&prev_block_hash) {
+                Some(height) => *height,
+                None if prev_block_hash == Hash::default().into() => 0,
+                None => match blockchain.get_height(&prev_block_hash) {
+                    Some(height) => height,
+                    None => continue,
+                },
+            };
+            self.heights.insert(block_hash, prev_height + 1);
+            pending.extend(self.children(&block_hash));
+        }
+    }
+
+    // The block and every known block building on it.
+    fn invalidate(&mut self, block_hash: BlockHash) {
+        let mut pending = vec![block_hash];
+        while let Some(block_hash) = pending.pop() {
+            self.invalid.insert(block_hash);
+            pending.extend(self.children(&block_hash));
+        }
+    }
+
+    fn children(&self, block_hash: &BlockHash) -> Vec<BlockHash> {
+        self.blocks
+            .iter()
+            .filter(|(_, (header, _))| header.prev_block_hash == *block_hash)
+            .map(|(child, _)| *child)
+            .collect()
+    }
+}
+
+// Order of chain tips, the best one is the greatest: the highest, then the
+// one committed to in the earliest mainchain block, with tips that have no
+// verified commitment last, then the node's own tip and last the hash, so
+// nodes that know the same tips agree.
+fn rank(height: usize, anchor: Option<Anchor>, is_tip: bool, block_hash: BlockHash) -> impl Ord {
+    let main_height = anchor.map_or(usize::MAX, |anchor| anchor.main_height());
+    (height, Reverse(main_height), is_tip, block_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::BlockBuilder;
+    use crate::concrete::{Output, Signature};
+    use crate::mock_client::MockMainClient;
+
+    #[test]
+    fn the_first_commitment_on_the_mainchain_wins() -> anyhow::Result<()> {
+        let mainchain = MockMainClient::new();
+        let base = BlockChain::<Signature, Output>::new();
+        let miner: Address = [1; 32].into();
+        let (first, first_body) = BlockBuilder::on(&base).build();
+        let (second, second_body) = BlockBuilder::on(&base)
+            .coinbase(miner, Amount::ZERO)
+            .build();
+        let first_anchor = mainchain.anchor(&first.hash());
+        let second_anchor = mainchain.anchor(&second.hash());
+
+        // Heard of in either order, both nodes end up on the block committed
+        // to first.
+        let mut early = BlockChain::<Signature, Output>::new();
+        let mut early_tree = BlockTree::new();
+        early_tree.insert(
+            &early,
+            first.clone(),
+            first_body.clone(),
+            Some(first_anchor),
+        );
+        early_tree.reorganize(&mut early);
+        early_tree.insert(
+            &early,
+            second.clone(),
+            second_body.clone(),
+            Some(second_anchor),
+        );
+        assert_eq!(early_tree.reorganize(&mut early), Reorg::default());
+        let mut late = BlockChain::<Signature, Output>::new();
+        let mut late_tree = BlockTree::new();
+        late_tree.insert(
+            &late,
+            second.clone(),
+            second_body.clone(),
+            Some(second_anchor),
+        );
+        late_tree.reorganize(&mut late);
+        late_tree.insert(&late, first.clone(), first_body.clone(), Some(first_anchor));
+        let reorg = late_tree.reorganize(&mut late);
+        assert_eq!(reorg.disconnected, [second.hash()]);
+        assert_eq!(reorg.connected, [first.hash()]);
+        assert_eq!(early.get_best_block_hash(), Some(first.hash()));
+        assert_eq!(late.get_best_block_hash(), Some(first.hash()));
+
+        // A block without a verified commitment doesn't take the tip.
+        let (unanchored, unanchored_body) = BlockBuilder::on(&base)
+            .coinbase(miner, Amount::ZERO)
+            .coinbase(miner, Amount::ZERO)
+            .build();
+        late_tree.insert(&late, unanchored, unanchored_body, None);
+        assert_eq!(late_tree.reorganize(&mut late), Reorg::default());
+
+        // A longer chain wins over an earlier commitment, and the node falls
+        // back to the best valid chain when one of its blocks is invalid.
+        let (child, child_body) = BlockBuilder::on(&late).build();
+        let mut on_second = BlockChain::<Signature, Output>::new();
+        on_second.connect_block(&second, &second_body, Some(&second_anchor))?;
+        let (invalid_child, invalid_child_body) = BlockBuilder::on(&on_second)
+            .coinbase(miner, Amount::from_sat(1))
+            .build();
+        late_tree.insert(&late, invalid_child.clone(), invalid_child_body, None);
+        assert_eq!(late_tree.reorganize(&mut late), Reorg::default());
+        assert!(late_tree.is_invalid(&invalid_child.hash()));
+        late_tree.insert(&late, child.clone(), child_body, None);
+        let reorg = late_tree.reorganize(&mut late);
+        assert_eq!(reorg.connected, [child.hash()]);
+        assert_eq!(late.height(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+use crate::builder::{keypair, TxBuilder};
+use crate::concrete::{Output, Signature};
+use crate::types::*;
+use arbitrary::{Arbitrary, Unstructured};
+use bitcoin::hashes::Hash as _;
+use ed25519_dalek::Keypair;
+use std::collections::HashMap;
+
+// Arbitrary values of the core types, for fuzzing validators. They are
+// well formed but otherwise random, so a transaction is almost never valid,
+// the builders below make ones that are.
+
+impl<'a> Arbitrary<'a> for Amount {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Amount::from_sat(u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Address {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Hash::arbitrary(u)?.into())
+    }
+}
+
+impl<'a> Arbitrary<'a> for Txid {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Hash::arbitrary(u)?.into())
+    }
+}
+
+impl<'a> Arbitrary<'a> for BlockHash {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Hash::arbitrary(u)?.into())
+    }
+}
+
+impl<'a> Arbitrary<'a> for MerkleRoot {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Hash::arbitrary(u)?.into())
+    }
+}
+
+impl<'a> Arbitrary<'a> for OutPoint {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let vout = u.arbitrary()?;
+        Ok(match u.int_in_range(0..=3)? {
+            0 => OutPoint::Regular {
+                txid: u.arbitrary()?,
+                vout,
+            },
+            1 => OutPoint::Coinbase {
+                block_hash: u.arbitrary()?,
+                vout,
+            },
+            2 => OutPoint::Withdrawal {
+                txid: u.arbitrary()?,
+                vout,
+            },
+            _ => OutPoint::Deposit(bitcoin::OutPoint {
+                txid: bitcoin::Txid::from_inner(u.arbitrary()?),
+                vout,
+            }),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Output {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Output {
+            address: u.arbitrary()?,
+            value: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for WithdrawalOutput {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let payload = match u.int_in_range(0..=2)? {
+            0 => bitcoin::util::address::Payload::PubkeyHash(bitcoin::PubkeyHash::from_inner(
+                u.arbitrary()?,
+            )),
+            1 => bitcoin::util::address::Payload::ScriptHash(bitcoin::ScriptHash::from_inner(
+                u.arbitrary()?,
+            )),
+            _ => bitcoin::util::address::Payload::WitnessProgram {
+                version: bitcoin::util::address::WitnessVersion::V0,
+                program: <[u8; 20]>::arbitrary(u)?.to_vec(),
+            },
+        };
+        Ok(WithdrawalOutput {
+            value: u.arbitrary()?,
+            fee: u.arbitrary()?,
+            side_address: u.arbitrary()?,
+            main_address: bitcoin::Address {
+                payload,
+                network: bitcoin::Network::Regtest,
+            },
+        })
+    }
+}
+
+// A real signature by a random key over a random message, so it parses but
+// doesn't sign anything it is attached to.
+impl<'a> Arbitrary<'a> for Signature {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let keypair = keypair(u.arbitrary()?);
+        let message = Transaction::<Signature, Output> {
+            version: TRANSACTION_VERSION,
+            inputs: vec![],
+            signatures: vec![],
+            outputs: vec![],
+            withdrawal_outputs: vec![],
+            extra: u.arbitrary()?,
+        };
+        Ok(Signature::new(&keypair, &message))
+    }
+}
+
+impl<'a, S: Arbitrary<'a>, O: Arbitrary<'a>> Arbitrary<'a> for Transaction<S, O> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Transaction {
+            // Mostly the current version, the others are rejected outright
+            // or skip the extra data checks.
+            version: match u.ratio(7, 8)? {
+                true => TRANSACTION_VERSION,
+                false => u.arbitrary()?,
+            },
+            inputs: u.arbitrary()?,
+            signatures: u.arbitrary()?,
+            outputs: u.arbitrary()?,
+            withdrawal_outputs: u.arbitrary()?,
+            extra: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a, S: Arbitrary<'a>, O: Arbitrary<'a>> Arbitrary<'a> for Body<S, O> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Body {
+            coinbase: u.arbitrary()?,
+            transactions: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Header {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Header {
+            prev_block_hash: u.arbitrary()?,
+            merkle_root: u.arbitrary()?,
+            state_root: u.arbitrary()?,
+        })
+    }
+}
+
+// An arbitrary body under a header that commits to it, so only the body
+// decides whether the block is valid on top of `prev_block_hash`.
+pub fn arbitrary_block<'a, S, O>(
+    u: &mut Unstructured<'a>,
+    prev_block_hash: &BlockHash,
+) -> arbitrary::Result<(Header, Body<S, O>)>
+where
+    S: Arbitrary<'a> + serde::Serialize,
+    O: Arbitrary<'a> + serde::Serialize,
+{
+    let body: Body<S, O> = u.arbitrary()?;
+    Ok((Header::new(prev_block_hash, &body), body))
+}
+
+// Spends a nonempty subset of `coins` to arbitrary addresses, whatever isn't
+// paid out is the fee. Coins are signed for with the key of their address
+// from `keypairs`, so the transaction is valid as long as they are unspent.
+pub fn arbitrary_spend(
+    u: &mut Unstructured,
+    coins: &[(OutPoint, Output)],
+    keypairs: &HashMap<Address, Keypair>,
+) -> arbitrary::Result<Transaction<Signature, Output>> {
+    let mut spent: Vec<&(OutPoint, Output)> = vec![];
+    for coin in coins {
+        if u.arbitrary()? {
+            spent.push(coin);
+        }
+    }
+    if spent.is_empty() {
+        spent.push(u.choose(coins)?);
+    }
+    let mut remaining = spent.iter().map(|(_, output)| output.value.to_sat()).sum();
+    let mut builder = TxBuilder::new();
+    for (outpoint, output) in spent {
+        builder = builder.spend(*outpoint, &keypairs[&output.address]);
+    }
+    for _ in 0..u.int_in_range(1..=4)? {
+        let value = u.int_in_range(0..=remaining)?;
+        remaining -= value;
+        builder = builder.pay(u.arbitrary()?, Amount::from_sat(value));
+    }
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn only_owners_can_spend() -> anyhow::Result<()> {
+        let keypairs: HashMap<Address, Keypair> = (1..=3)
+            .map(|n| {
+                let keypair = keypair([n; 32]);
+                (keypair.public.into(), keypair)
+            })
+            .collect();
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        let coins: Vec<(OutPoint, Output)> = keypairs
+            .keys()
+            .enumerate()
+            .map(|(n, address)| {
+                let outpoint = OutPoint::Deposit(bitcoin::OutPoint {
+                    txid: bitcoin::Txid::from_inner([n as u8; 32]),
+                    vout: 0,
+                });
+                let output = Output {
+                    address: *address,
+                    value: Amount::from_sat(1000 * (n as u64 + 1)),
+                };
+                (outpoint, output)
+            })
+            .collect();
+        blockchain.add_deposits(DepositsChunk {
+            outputs: coins
+                .iter()
+                .map(|(outpoint, output)| {
+                    let output = DepositOutput {
+                        address: output.address,
+                        value: output.value,
+                    };
+                    (*outpoint, output)
+                })
+                .collect(),
+            deposits: vec![],
+        });
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut data = vec![0; 4096];
+        for _ in 0..256 {
+            rng.fill(&mut data[..]);
+            let mut u = Unstructured::new(&data);
+            let transaction: Transaction<Signature, Output> = u.arbitrary()?;
+            // Nobody else holds the keys, so only a transaction that spends
+            // nothing can be valid.
+            if blockchain.validate_transaction(&transaction).is_ok() {
+                assert!(transaction.inputs.is_empty());
+            }
+            let (header, body) =
+                arbitrary_block::<Signature, Output>(&mut u, &Hash::default().into())?;
+            if blockchain.validate_block(&header, &body, None) {
+                assert!(body.transactions.iter().all(|tx| tx.inputs.is_empty()));
+            }
+
+            let spend = arbitrary_spend(&mut u, &coins, &keypairs)?;
+            assert_eq!(blockchain.validate_transaction(&spend), Ok(()));
+            let mut unsigned = spend.clone();
+            unsigned.signatures.pop();
+            assert!(blockchain.validate_transaction(&unsigned).is_err());
+            let mut forged = spend;
+            forged.signatures[0] = u.arbitrary()?;
+            assert!(blockchain.validate_transaction(&forged).is_err());
+        }
+        Ok(())
+    }
+}
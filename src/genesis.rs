@@ -0,0 +1,96 @@
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+
+// Everything that goes into a sidechain's first block. Nodes started with
+// different configs end up with different genesis hashes, so they can tell
+// they are not on the same chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisConfig<O> {
+    pub chain_id: String,
+    // Seconds since the unix epoch.
+    pub timestamp: u64,
+    pub premine: Vec<O>,
+}
+
+impl<O: Serialize + Clone> GenesisConfig<O> {
+    pub fn new(chain_id: impl Into<String>, timestamp: u64) -> Self {
+        Self {
+            chain_id: chain_id.into(),
+            timestamp,
+            premine: vec![],
+        }
+    }
+
+    pub fn with_premine(mut self, premine: Vec<O>) -> Self {
+        self.premine = premine;
+        self
+    }
+
+    // The genesis block has a single transaction without inputs that creates
+    // the premine, the chain id and timestamp go into its extra data. That
+    // way the merkle root, and so the block hash, commits to the whole
+    // config.
+    pub fn block<S: Serialize, H: Hasher>(&self) -> (Header, Body<S, O>) {
+        let transaction = Transaction {
+            version: TRANSACTION_VERSION,
+            inputs: vec![],
+            signatures: vec![],
+            outputs: self.premine.clone(),
+            withdrawal_outputs: vec![],
+            extra: bincode::serialize(&(&self.chain_id, self.timestamp))
+                .expect("failed to serialize genesis config"),
+        };
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![transaction],
+        };
+        let header = Header::new_with::<H, S, O>(&Hash::default().into(), &body);
+        (header, body)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("chain has no genesis block")]
+    Missing,
+    #[error("genesis block {found} doesn't match the configured genesis block {expected}")]
+    Mismatch {
+        expected: BlockHash,
+        found: BlockHash,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::concrete::{Output, Signature};
+
+    #[test]
+    fn nodes_with_different_genesis_disagree() {
+        let genesis = GenesisConfig::new("test", 1_600_000_000).with_premine(vec![Output {
+            address: [1; 32].into(),
+            value: Amount::from_sat(50),
+        }]);
+        let blockchain = BlockChain::<Signature, Output>::new()
+            .with_genesis(&genesis)
+            .unwrap();
+        assert!(blockchain.check_genesis(&genesis).is_ok());
+        let audit = blockchain.audit();
+        assert_eq!(audit.premined, Amount::from_sat(50));
+        assert!(audit.is_balanced());
+
+        let other = GenesisConfig {
+            chain_id: "other".into(),
+            ..genesis
+        };
+        assert!(matches!(
+            blockchain.check_genesis(&other),
+            Err(Error::Mismatch { .. })
+        ));
+        assert!(matches!(
+            BlockChain::<Signature, Output>::new().check_genesis(&other),
+            Err(Error::Missing)
+        ));
+    }
+}
@@ -0,0 +1,433 @@
+use crate::concrete::Output;
+use crate::rpc::NodeState;
+use crate::types::{Address, Amount, BlockHash, OutPoint, Txid};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+// Blocks a slow subscriber can fall behind before it misses some.
+const NOTIFICATION_BUFFER: usize = 64;
+
+// Messages of proto/sdk.proto, the two have to be kept in sync.
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Empty {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BlockCount {
+        #[prost(uint64, tag = "1")]
+        pub height: u64,
+        #[prost(string, optional, tag = "2")]
+        pub best_block_hash: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BlockRequest {
+        #[prost(string, tag = "1")]
+        pub hash: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Block {
+        #[prost(string, tag = "1")]
+        pub hash: String,
+        #[prost(string, tag = "2")]
+        pub prev_block_hash: String,
+        #[prost(string, tag = "3")]
+        pub merkle_root: String,
+        #[prost(string, repeated, tag = "4")]
+        pub txids: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TransactionRequest {
+        #[prost(string, tag = "1")]
+        pub txid: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Output {
+        #[prost(string, tag = "1")]
+        pub address: String,
+        #[prost(uint64, tag = "2")]
+        pub value: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WithdrawalOutput {
+        #[prost(uint64, tag = "1")]
+        pub value: u64,
+        #[prost(uint64, tag = "2")]
+        pub fee: u64,
+        #[prost(string, tag = "3")]
+        pub side_address: String,
+        #[prost(string, tag = "4")]
+        pub main_address: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Transaction {
+        #[prost(string, tag = "1")]
+        pub txid: String,
+        #[prost(bool, tag = "2")]
+        pub confirmed: bool,
+        #[prost(message, repeated, tag = "3")]
+        pub outputs: Vec<Output>,
+        #[prost(message, repeated, tag = "4")]
+        pub withdrawal_outputs: Vec<WithdrawalOutput>,
+        #[prost(uint32, tag = "5")]
+        pub version: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Balance {
+        #[prost(uint64, tag = "1")]
+        pub value: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct NewAddress {
+        #[prost(string, tag = "1")]
+        pub address: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SendRequest {
+        #[prost(string, tag = "1")]
+        pub address: String,
+        #[prost(uint64, tag = "2")]
+        pub value: u64,
+        #[prost(uint64, tag = "3")]
+        pub fee: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WithdrawalRequest {
+        #[prost(string, tag = "1")]
+        pub main_address: String,
+        #[prost(uint64, tag = "2")]
+        pub value: u64,
+        #[prost(uint64, tag = "3")]
+        pub main_fee: u64,
+        #[prost(uint64, tag = "4")]
+        pub fee: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Txid {
+        #[prost(string, tag = "1")]
+        pub txid: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Withdrawal {
+        #[prost(string, tag = "1")]
+        pub txid: String,
+        #[prost(uint32, tag = "2")]
+        pub vout: u32,
+        #[prost(string, tag = "3")]
+        pub status: String,
+        #[prost(string, optional, tag = "4")]
+        pub bundle: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PegStatus {
+        #[prost(uint64, tag = "1")]
+        pub deposits: u64,
+        #[prost(message, repeated, tag = "2")]
+        pub withdrawals: Vec<Withdrawal>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BlockNotification {
+        #[prost(string, tag = "1")]
+        pub hash: String,
+        #[prost(uint64, tag = "2")]
+        pub height: u64,
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/sdk.Node.rs"));
+}
+
+type BlockStream = Pin<Box<dyn Stream<Item = Result<proto::BlockNotification, Status>> + Send>>;
+
+// The node API over gRPC, for integrators using other languages. Clones
+// share the node and the block subscribers, keep one to call notify_block
+// from the node loop.
+#[derive(Clone)]
+pub struct NodeService {
+    node: Arc<Mutex<NodeState>>,
+    blocks: broadcast::Sender<proto::BlockNotification>,
+}
+
+impl NodeService {
+    pub fn new(node: Arc<Mutex<NodeState>>) -> Self {
+        let (blocks, _) = broadcast::channel(NOTIFICATION_BUFFER);
+        Self { node, blocks }
+    }
+
+    pub fn into_server(self) -> proto::node_server::NodeServer<Self> {
+        proto::node_server::NodeServer::new(self)
+    }
+
+    // Sends a newly connected block to every SubscribeBlocks stream.
+    pub fn notify_block(&self, block_hash: BlockHash, height: usize) {
+        // Fails only when nobody is subscribed.
+        let _ = self.blocks.send(proto::BlockNotification {
+            hash: block_hash.to_string(),
+            height: height as u64,
+        });
+    }
+}
+
+#[tonic::async_trait]
+impl proto::node_server::Node for NodeService {
+    type SubscribeBlocksStream = BlockStream;
+
+    async fn get_block_count(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::BlockCount>, Status> {
+        let node = self.node.lock().unwrap();
+        Ok(Response::new(proto::BlockCount {
+            height: node.blockchain.height() as u64,
+            best_block_hash: node
+                .blockchain
+                .get_best_block_hash()
+                .map(|block_hash| block_hash.to_string()),
+        }))
+    }
+
+    async fn get_block(
+        &self,
+        request: Request<proto::BlockRequest>,
+    ) -> Result<Response<proto::Block>, Status> {
+        let block_hash = BlockHash::from_str(&request.get_ref().hash)
+            .map_err(|_| Status::invalid_argument("invalid block hash"))?;
+        let node = self.node.lock().unwrap();
+        let (header, body) = match (
+            node.blockchain.get_header(&block_hash),
+            node.blockchain.get_body(&block_hash),
+        ) {
+            (Some(header), Some(body)) => (header, body),
+            _ => return Err(Status::not_found("block not found")),
+        };
+        Ok(Response::new(proto::Block {
+            hash: block_hash.to_string(),
+            prev_block_hash: header.prev_block_hash.to_string(),
+            merkle_root: header.merkle_root.to_string(),
+            txids: body
+                .transactions
+                .iter()
+                .map(|transaction| transaction.txid().to_string())
+                .collect(),
+        }))
+    }
+
+    async fn get_transaction(
+        &self,
+        request: Request<proto::TransactionRequest>,
+    ) -> Result<Response<proto::Transaction>, Status> {
+        let txid = Txid::from_str(&request.get_ref().txid)
+            .map_err(|_| Status::invalid_argument("invalid txid"))?;
+        let node = self.node.lock().unwrap();
+        let (transaction, confirmed) = match node.blockchain.get_transaction(&txid) {
+            Some(transaction) => (transaction, true),
+            None => match node.mempool.get(&txid) {
+                Some(transaction) => (transaction.clone(), false),
+                None => return Err(Status::not_found("transaction not found")),
+            },
+        };
+        Ok(Response::new(proto::Transaction {
+            txid: txid.to_string(),
+            confirmed,
+            version: transaction.version,
+            outputs: transaction
+                .outputs
+                .iter()
+                .map(|output| proto::Output {
+                    address: output.address.to_string(),
+                    value: output.value.to_sat(),
+                })
+                .collect(),
+            withdrawal_outputs: transaction
+                .withdrawal_outputs
+                .iter()
+                .map(|withdrawal| proto::WithdrawalOutput {
+                    value: withdrawal.value.to_sat(),
+                    fee: withdrawal.fee.to_sat(),
+                    side_address: withdrawal.side_address.to_string(),
+                    main_address: withdrawal.main_address.to_string(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn get_balance(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Balance>, Status> {
+        let mut node = self.node.lock().unwrap();
+        node.sync_wallet();
+        Ok(Response::new(proto::Balance {
+            value: node.wallet.get_balance().to_sat(),
+        }))
+    }
+
+    async fn get_new_address(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::NewAddress>, Status> {
+        let mut node = self.node.lock().unwrap();
+        Ok(Response::new(proto::NewAddress {
+            address: node.wallet.generate_address().to_string(),
+        }))
+    }
+
+    async fn send_to_address(
+        &self,
+        request: Request<proto::SendRequest>,
+    ) -> Result<Response<proto::Txid>, Status> {
+        let request = request.into_inner();
+        let mut node = self.node.lock().unwrap();
+        let hrp = node.blockchain.params().address_hrp();
+        let address = Address::parse(&request.address, &hrp)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        node.sync_wallet();
+        let transaction = node
+            .wallet
+            .create_transaction(
+                vec![Output {
+                    address,
+                    value: Amount::from_sat(request.value),
+                }],
+                Amount::from_sat(request.fee),
+            )
+            .ok_or_else(|| Status::failed_precondition("insufficient funds"))?;
+        let txid = node
+            .submit_from_wallet(None, transaction)
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(proto::Txid {
+            txid: txid.to_string(),
+        }))
+    }
+
+    async fn create_withdrawal(
+        &self,
+        request: Request<proto::WithdrawalRequest>,
+    ) -> Result<Response<proto::Txid>, Status> {
+        let request = request.into_inner();
+        let main_address = bitcoin::Address::from_str(&request.main_address)
+            .map_err(|_| Status::invalid_argument("invalid mainchain address"))?;
+        let mut node = self.node.lock().unwrap();
+        node.sync_wallet();
+        let transaction = node
+            .wallet
+            .create_withdrawal(
+                main_address,
+                Amount::from_sat(request.value),
+                Amount::from_sat(request.main_fee),
+                Amount::from_sat(request.fee),
+            )
+            .ok_or_else(|| Status::failed_precondition("insufficient funds"))?;
+        let txid = node
+            .submit_from_wallet(None, transaction)
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(proto::Txid {
+            txid: txid.to_string(),
+        }))
+    }
+
+    async fn get_peg_status(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::PegStatus>, Status> {
+        let node = self.node.lock().unwrap();
+        let peg = &node.blockchain.peg;
+        let withdrawals = peg
+            .withdrawals_with_status(|_| true)
+            .into_iter()
+            .filter_map(|(outpoint, status)| match outpoint {
+                OutPoint::Withdrawal { txid, vout } => Some(proto::Withdrawal {
+                    txid: txid.to_string(),
+                    vout,
+                    status: status.name().into(),
+                    bundle: status.bundle().map(|bundle| bundle.to_string()),
+                }),
+                _ => None,
+            })
+            .collect();
+        Ok(Response::new(proto::PegStatus {
+            deposits: peg.deposits().len() as u64,
+            withdrawals,
+        }))
+    }
+
+    async fn subscribe_blocks(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<BlockStream>, Status> {
+        // Subscribers that fell behind skip the blocks they missed.
+        let stream = BroadcastStream::new(self.blocks.subscribe())
+            .filter_map(|notification| notification.ok().map(Ok));
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::mempool::MemPool;
+    use crate::wallet::{Wallet, Wallets};
+    use proto::node_client::NodeClient;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    #[tokio::test]
+    async fn node_is_queried_over_grpc() -> anyhow::Result<()> {
+        let node = NodeState {
+            blockchain: BlockChain::new(),
+            mempool: MemPool::default(),
+            wallet: Wallet::default(),
+            wallets: Wallets::default(),
+            main_fee_rate: None,
+        };
+        let service = NodeService::new(Arc::new(Mutex::new(node)));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = service.clone().into_server();
+        tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(server)
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+        });
+
+        let mut client = NodeClient::connect(format!("http://{}", addr)).await?;
+        let count = client.get_block_count(proto::Empty {}).await?.into_inner();
+        assert_eq!(count.height, 0);
+        assert_eq!(count.best_block_hash, None);
+        let status = client
+            .send_to_address(proto::SendRequest {
+                address: Address::from([1; 32]).to_string(),
+                value: 1,
+                fee: 0,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        let mut blocks = client.subscribe_blocks(proto::Empty {}).await?.into_inner();
+        let block_hash = BlockHash::from([2; 32]);
+        service.notify_block(block_hash, 1);
+        let notification = blocks.message().await?.unwrap();
+        assert_eq!(notification.hash, block_hash.to_string());
+        assert_eq!(notification.height, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use crate::backend::MainchainBackend;
+use crate::client::{Client, Error as ClientError};
+use bitcoin::consensus::params::Params;
+use bitcoin::util::uint::Uint256;
+use bitcoin::BlockHeader;
+use std::collections::HashMap;
+
+// Mainchain headers downloaded and checked by the sidechain itself: every
+// header has to extend the previous one and carry the proof of work the
+// difficulty rules require. This is enough to count confirmations and check
+// deposit proofs without trusting the RPC endpoint for either.
+pub struct HeaderChain {
+    params: Params,
+    // Height of headers[0], the chain can start from a trusted checkpoint
+    // instead of the genesis block.
+    start_height: usize,
+    headers: Vec<BlockHeader>,
+    heights: HashMap<bitcoin::BlockHash, usize>,
+}
+
+impl HeaderChain {
+    pub fn new(params: Params, start_height: usize, start_header: BlockHeader) -> Self {
+        Self {
+            params,
+            start_height,
+            heights: HashMap::from([(start_header.block_hash(), start_height)]),
+            headers: vec![start_header],
+        }
+    }
+
+    pub fn from_genesis(network: bitcoin::Network) -> Self {
+        let genesis = bitcoin::blockdata::constants::genesis_block(network);
+        Self::new(Params::new(network), 0, genesis.header)
+    }
+
+    pub fn tip_height(&self) -> usize {
+        self.start_height + self.headers.len() - 1
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        self.headers.last().expect("header chain is never empty")
+    }
+
+    pub fn header_at(&self, height: usize) -> Option<&BlockHeader> {
+        self.headers.get(height.checked_sub(self.start_height)?)
+    }
+
+    pub fn height_of(&self, block_hash: &bitcoin::BlockHash) -> Option<usize> {
+        self.heights.get(block_hash).copied()
+    }
+
+    pub fn get_header(&self, block_hash: &bitcoin::BlockHash) -> Option<&BlockHeader> {
+        self.header_at(self.height_of(block_hash)?)
+    }
+
+    // None if the block is not in this chain.
+    pub fn confirmations(&self, block_hash: &bitcoin::BlockHash) -> Option<usize> {
+        Some(self.tip_height() - self.height_of(block_hash)? + 1)
+    }
+
+    // Returns the height of the new tip.
+    pub fn connect(&mut self, header: BlockHeader) -> Result<usize, Error> {
+        let tip = self.tip();
+        if header.prev_blockhash != tip.block_hash() {
+            return Err(Error::Disconnected(header.block_hash()));
+        }
+        let height = self.tip_height() + 1;
+        let target = header.target();
+        if target > self.params.pow_limit {
+            return Err(Error::TargetAboveLimit(height));
+        }
+        if let Some(required_bits) = self.required_bits(height) {
+            if header.bits != required_bits {
+                return Err(Error::WrongDifficulty(height));
+            }
+        }
+        let block_hash = header
+            .validate_pow(&target)
+            .map_err(|_| Error::InvalidPow(height))?;
+        self.headers.push(header);
+        self.heights.insert(block_hash, height);
+        Ok(height)
+    }
+
+    // The start header can't be disconnected, it is trusted.
+    pub fn disconnect_tip(&mut self) -> Option<BlockHeader> {
+        if self.headers.len() == 1 {
+            return None;
+        }
+        let header = self.headers.pop()?;
+        self.heights.remove(&header.block_hash());
+        Some(header)
+    }
+
+    // Difficulty bits the header at `height` must have, None if they can't be
+    // checked: on networks that allow min difficulty blocks, or when the
+    // headers of the previous period are before the start of this chain.
+    fn required_bits(&self, height: usize) -> Option<u32> {
+        let params = &self.params;
+        let prev = self.tip();
+        if params.no_pow_retargeting {
+            return Some(prev.bits);
+        }
+        let interval = params.difficulty_adjustment_interval() as usize;
+        if !height.is_multiple_of(interval) {
+            if params.allow_min_difficulty_blocks {
+                return None;
+            }
+            return Some(prev.bits);
+        }
+        let first = self.header_at(height - interval)?;
+        let target_timespan = params.pow_target_timespan;
+        let actual_timespan = (prev.time.saturating_sub(first.time) as u64)
+            .clamp(target_timespan / 4, target_timespan * 4);
+        let target = prev.target().mul_u32(actual_timespan as u32)
+            / Uint256::from_u64(target_timespan).expect("fits into u256");
+        let target = if target > params.pow_limit {
+            params.pow_limit
+        } else {
+            target
+        };
+        Some(BlockHeader::compact_target_from_u256(&target))
+    }
+
+    // Downloads headers from the mainchain node until this chain has the
+    // same tip, following reorgs back to the fork point. Returns the new tip
+    // height.
+    pub fn sync(&mut self, client: &Client) -> Result<usize, Error> {
+        let best_height = client.get_block_count()?;
+        // Walk back to the last header that is still in the node's main
+        // chain.
+        loop {
+            let height = self.tip_height();
+            if height <= best_height && client.get_block_hash(height)? == self.tip().block_hash() {
+                break;
+            }
+            if self.disconnect_tip().is_none() {
+                return Err(Error::CheckpointReorged);
+            }
+        }
+        for height in self.tip_height() + 1..=best_height {
+            let block_hash = client.get_block_hash(height)?;
+            let header = client.get_raw_block_header(&block_hash)?;
+            self.connect(header)?;
+        }
+        Ok(self.tip_height())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("header {0} doesn't extend the tip")]
+    Disconnected(bitcoin::BlockHash),
+    #[error("target of header at height {0} is above the proof of work limit")]
+    TargetAboveLimit(usize),
+    #[error("header at height {0} has the wrong difficulty")]
+    WrongDifficulty(usize),
+    #[error("header at height {0} doesn't have enough proof of work")]
+    InvalidPow(usize),
+    #[error("mainchain node doesn't have the start header in its main chain")]
+    CheckpointReorged,
+    #[error("client error")]
+    Client(#[from] ClientError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_mainnet_block_connects() -> anyhow::Result<()> {
+        let mut chain = HeaderChain::from_genesis(bitcoin::Network::Bitcoin);
+        // Header of mainnet block 1.
+        let header: BlockHeader = bitcoin::consensus::deserialize(&hex::decode(
+            "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000\
+             982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e\
+             61bc6649ffff001d01e36299",
+        )?)?;
+        assert_eq!(chain.connect(header)?, 1);
+        let genesis_hash = chain.header_at(0).unwrap().block_hash();
+        assert_eq!(chain.confirmations(&genesis_hash), Some(2));
+        let mut tampered = header;
+        tampered.nonce += 1;
+        chain.disconnect_tip();
+        assert!(matches!(chain.connect(tampered), Err(Error::InvalidPow(1))));
+        Ok(())
+    }
+}
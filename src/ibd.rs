@@ -0,0 +1,491 @@
+use crate::backend::MainchainBackend;
+use crate::blockchain::BlockChain;
+use crate::bmm::{Anchor, Error as BmmError};
+use crate::p2p::{self, Event, Message, Network, PeerId};
+use crate::ssm::SSM;
+use crate::types::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Most headers sent in one Headers message.
+pub const MAX_HEADERS: usize = 2000;
+// Bodies are only requested for this many headers past the tip, so a slow
+// peer holding up the next block can't make the others fill our memory.
+const DOWNLOAD_WINDOW: usize = 1024;
+const TICK: Duration = Duration::from_millis(100);
+
+// Answers the requests other peers make while downloading blocks from us.
+// Returns false for any other message.
+pub fn serve<S, O, H>(
+    network: &Network<S, O>,
+    blockchain: &BlockChain<S, O, H>,
+    peer: PeerId,
+    message: &Message<S, O>,
+) -> Result<bool, p2p::Error>
+where
+    S: Sig + Serialize + DeserializeOwned + Clone + Send + 'static,
+    O: Out + Serialize + DeserializeOwned + Clone + Send + 'static,
+    H: Hasher,
+{
+    match message {
+        Message::GetHeaders(locator) => {
+            let headers = blockchain.get_headers_after(locator, MAX_HEADERS);
+            network.send(peer, &Message::Headers(headers))?;
+        }
+        Message::GetBlocks(block_hashes) => {
+            let mut not_found = vec![];
+            for block_hash in block_hashes {
+                match (
+                    blockchain.get_header(block_hash),
+                    blockchain.get_body(block_hash),
+                ) {
+                    (Some(header), Some(body)) => network.send(
+                        peer,
+                        &Message::Block {
+                            header: header.clone(),
+                            body: Arc::unwrap_or_clone(body),
+                            main_block_hash: blockchain
+                                .get_anchor(block_hash)
+                                .map(Anchor::main_block_hash),
+                        },
+                    )?,
+                    _ => not_found.push(*block_hash),
+                }
+            }
+            if !not_found.is_empty() {
+                network.send(peer, &Message::NotFound(not_found))?;
+            }
+        }
+        Message::GetFilters(block_hashes) => {
+            let filters = block_hashes
+                .iter()
+                .filter_map(|block_hash| {
+                    let filter = blockchain.get_filter(block_hash)?;
+                    Some((*block_hash, filter.clone()))
+                })
+                .collect();
+            network.send(peer, &Message::Filters(filters))?;
+        }
+        Message::GetMerkleProofs(txids) => {
+            let proofs = txids
+                .iter()
+                .filter_map(|txid| {
+                    let (header, proof) = blockchain.get_merkle_proof(txid)?;
+                    Some((*txid, header, proof))
+                })
+                .collect();
+            network.send(peer, &Message::MerkleProofs(proofs))?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+// Catches up with peers that are ahead of us. Headers are fetched from the
+// peer with the best chain, then the bodies are downloaded in parallel from
+// every peer that has them and connected in order as they arrive. Peers that
+// don't answer within the stall timeout are disconnected and their requests
+// go to other peers.
+//
+// Only extends our best chain, a peer whose headers don't build on it is
+// dropped and reorgs are left to relay::BlockRelay.
+pub struct InitialBlockDownload {
+    stall_timeout: Duration,
+    max_blocks_in_flight: usize,
+}
+
+impl Default for InitialBlockDownload {
+    fn default() -> Self {
+        Self {
+            stall_timeout: Duration::from_secs(10),
+            max_blocks_in_flight: 16,
+        }
+    }
+}
+
+impl InitialBlockDownload {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_stall_timeout(mut self, stall_timeout: Duration) -> Self {
+        self.stall_timeout = stall_timeout;
+        self
+    }
+
+    // Most blocks requested from one peer at a time.
+    pub fn with_max_blocks_in_flight(mut self, max_blocks_in_flight: usize) -> Self {
+        self.max_blocks_in_flight = max_blocks_in_flight.max(1);
+        self
+    }
+
+    // Returns the number of blocks connected. Requests from other peers are
+    // answered while downloading, other messages are dropped. The BMM
+    // commitments peers send with blocks are checked against `mainchain`.
+    pub fn run<S, O, H, M, B>(
+        &self,
+        network: &Network<S, O>,
+        mainchain: &B,
+        blockchain: &mut BlockChain<S, O, H>,
+        ssm: &mut M,
+    ) -> Result<usize, Error>
+    where
+        S: Sig + Serialize + DeserializeOwned + Clone + Send + 'static,
+        O: Out + Serialize + DeserializeOwned + Clone + Send + 'static,
+        H: Hasher,
+        M: SSM<S, O>,
+        B: MainchainBackend + ?Sized,
+    {
+        let height = blockchain.height() as u64;
+        let mut download = Download {
+            peers: network
+                .peers()
+                .into_iter()
+                .filter(|(_, _, version)| version.best_height > height)
+                .map(|(peer, _, version)| (peer, version.best_height))
+                .collect(),
+            headers_request: None,
+            headers_done: false,
+            last_header: blockchain
+                .get_best_block_hash()
+                .unwrap_or_else(|| Hash::default().into()),
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            received: HashMap::new(),
+        };
+        let mut connected = 0;
+        loop {
+            if download.peers.is_empty() {
+                return match download.queue.is_empty() {
+                    true => Ok(connected),
+                    false => Err(Error::NoPeers),
+                };
+            }
+            if download.headers_done && download.queue.is_empty() {
+                return Ok(connected);
+            }
+            if !download.headers_done && download.headers_request.is_none() {
+                download.request_headers(network, blockchain);
+            }
+            self.request_blocks(network, &mut download, blockchain.height());
+
+            if let Some(event) = network.recv_timeout(TICK) {
+                download.handle(network, blockchain, event);
+            }
+
+            let stalled: Vec<PeerId> = download
+                .in_flight
+                .values()
+                .chain(download.headers_request.iter())
+                .filter(|(_, requested_at)| requested_at.elapsed() > self.stall_timeout)
+                .map(|(peer, _)| *peer)
+                .collect();
+            for peer in stalled {
+                log::debug!("peer {} stalled the block download", peer);
+                network.disconnect(peer);
+                download.remove_peer(peer);
+            }
+
+            while let Some((block_hash, header)) = download.queue.front() {
+                let (peer, body, main_block_hash) = match download.received.remove(block_hash) {
+                    Some(received) => received,
+                    None => break,
+                };
+                let anchor = match main_block_hash {
+                    Some(main_block_hash) => {
+                        match Anchor::verify(mainchain, &main_block_hash, block_hash) {
+                            Ok(anchor) => Some(anchor),
+                            Err(err) if err.is_retryable() => return Err(err.into()),
+                            Err(err) => {
+                                network.disconnect(peer);
+                                return Err(Error::InvalidBlock(*block_hash, err.to_string()));
+                            }
+                        }
+                    }
+                    None => None,
+                };
+                let anchor = anchor.as_ref();
+                if let Err(err) = blockchain.connect_block_with(ssm, header, &body, anchor) {
+                    network.disconnect(peer);
+                    return Err(Error::InvalidBlock(*block_hash, err));
+                }
+                download.queue.pop_front();
+                connected += 1;
+            }
+        }
+    }
+
+    fn request_blocks<S, O>(
+        &self,
+        network: &Network<S, O>,
+        download: &mut Download<S, O>,
+        height: usize,
+    ) where
+        S: Serialize + DeserializeOwned + Send + 'static,
+        O: Serialize + DeserializeOwned + Send + 'static,
+    {
+        let mut load: HashMap<PeerId, usize> =
+            download.peers.keys().map(|peer| (*peer, 0)).collect();
+        for (peer, _) in download.in_flight.values() {
+            *load.entry(*peer).or_default() += 1;
+        }
+        let mut requests: HashMap<PeerId, Vec<BlockHash>> = HashMap::new();
+        for (index, (block_hash, _)) in download.queue.iter().take(DOWNLOAD_WINDOW).enumerate() {
+            if download.in_flight.contains_key(block_hash)
+                || download.received.contains_key(block_hash)
+            {
+                continue;
+            }
+            let block_height = (height + index + 1) as u64;
+            // The least busy peer that has the block.
+            let peer = load
+                .iter()
+                .filter(|(peer, load)| {
+                    **load < self.max_blocks_in_flight && download.peers[*peer] >= block_height
+                })
+                .min_by_key(|(peer, load)| (**load, **peer))
+                .map(|(peer, _)| *peer);
+            let peer = match peer {
+                Some(peer) => peer,
+                None => continue,
+            };
+            *load.get_mut(&peer).unwrap() += 1;
+            download
+                .in_flight
+                .insert(*block_hash, (peer, Instant::now()));
+            requests.entry(peer).or_default().push(*block_hash);
+        }
+        for (peer, block_hashes) in requests {
+            if network
+                .send(peer, &Message::GetBlocks(block_hashes))
+                .is_err()
+            {
+                network.disconnect(peer);
+                download.remove_peer(peer);
+            }
+        }
+    }
+}
+
+// A downloaded block's sender, body and the mainchain block its BMM
+// commitment is in.
+type Received<S, O> = (PeerId, Body<S, O>, Option<bitcoin::BlockHash>);
+
+struct Download<S, O> {
+    // Peers we download from and the height of their best block.
+    peers: HashMap<PeerId, u64>,
+    headers_request: Option<(PeerId, Instant)>,
+    // Set once the peer with the best chain has no more headers for us.
+    headers_done: bool,
+    // Newly received headers have to build on this one.
+    last_header: BlockHash,
+    // Headers whose blocks still have to be connected, in chain order.
+    queue: VecDeque<(BlockHash, Header)>,
+    in_flight: HashMap<BlockHash, (PeerId, Instant)>,
+    // Blocks that arrived ahead of the ones before them.
+    received: HashMap<BlockHash, Received<S, O>>,
+}
+
+impl<S, O> Download<S, O>
+where
+    S: Sig + Serialize + DeserializeOwned + Clone + Send + 'static,
+    O: Out + Serialize + DeserializeOwned + Clone + Send + 'static,
+{
+    fn request_headers<H: Hasher>(
+        &mut self,
+        network: &Network<S, O>,
+        blockchain: &BlockChain<S, O, H>,
+    ) {
+        let peer = match self
+            .peers
+            .iter()
+            .max_by_key(|(peer, best_height)| (**best_height, **peer))
+        {
+            Some((peer, _)) => *peer,
+            None => return,
+        };
+        let mut locator = vec![self.last_header];
+        locator.extend(blockchain.get_locator());
+        match network.send(peer, &Message::GetHeaders(locator)) {
+            Ok(()) => self.headers_request = Some((peer, Instant::now())),
+            Err(_) => {
+                network.disconnect(peer);
+                self.remove_peer(peer);
+            }
+        }
+    }
+
+    fn handle<H: Hasher>(
+        &mut self,
+        network: &Network<S, O>,
+        blockchain: &BlockChain<S, O, H>,
+        event: Event<S, O>,
+    ) {
+        let (peer, message) = match event {
+            Event::Connected { peer, version, .. } => {
+                if version.best_height > blockchain.height() as u64 {
+                    self.peers.insert(peer, version.best_height);
+                }
+                return;
+            }
+            Event::Disconnected { peer } => {
+                self.remove_peer(peer);
+                return;
+            }
+            Event::Message { peer, message } => (peer, message),
+        };
+        match message {
+            Message::Headers(headers) if self.headers_request.map(|(p, _)| p) == Some(peer) => {
+                self.headers_request = None;
+                if headers.len() < MAX_HEADERS {
+                    self.headers_done = true;
+                }
+                for header in headers {
+                    if header.prev_block_hash != self.last_header {
+                        log::debug!("peer {} sent headers that don't connect", peer);
+                        network.disconnect(peer);
+                        self.remove_peer(peer);
+                        self.headers_done = false;
+                        return;
+                    }
+                    self.last_header = header.hash_with::<H>();
+                    self.queue.push_back((self.last_header, header));
+                }
+            }
+            Message::Block {
+                header,
+                body,
+                main_block_hash,
+            } => {
+                let block_hash = header.hash_with::<H>();
+                if self.in_flight.get(&block_hash).map(|(p, _)| *p) == Some(peer) {
+                    self.in_flight.remove(&block_hash);
+                    self.received
+                        .insert(block_hash, (peer, body, main_block_hash));
+                }
+            }
+            Message::NotFound(block_hashes) => {
+                // The peer doesn't have the blocks it claimed to, stop
+                // downloading from it.
+                for block_hash in block_hashes {
+                    if self.in_flight.get(&block_hash).map(|(p, _)| *p) == Some(peer) {
+                        self.in_flight.remove(&block_hash);
+                    }
+                }
+                self.remove_peer(peer);
+            }
+            message => {
+                if let Err(err) = serve(network, blockchain, peer, &message) {
+                    log::debug!("failed to answer peer {}: {}", peer, err);
+                }
+            }
+        }
+    }
+}
+
+impl<S, O> Download<S, O> {
+    fn remove_peer(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+        self.in_flight.retain(|_, (p, _)| *p != peer);
+        if self.headers_request.map(|(p, _)| p) == Some(peer) {
+            self.headers_request = None;
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("all peers disconnected before the download finished")]
+    NoPeers,
+    #[error("block {0} is invalid: {1}")]
+    InvalidBlock(BlockHash, String),
+    #[error("failed to check a bmm commitment")]
+    Bmm(#[from] BmmError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::AccountState;
+    use crate::concrete::{Output, Signature};
+    use crate::mock_client::MockMainClient;
+    use crate::p2p::{Version, PROTOCOL_VERSION};
+    use crate::params::SidechainParams;
+
+    fn network(best_height: u64) -> Network<Signature, Output> {
+        Network::new(Version {
+            version: PROTOCOL_VERSION,
+            genesis: [0; 32].into(),
+            best_height,
+            user_agent: "test".into(),
+            listen_port: 0,
+        })
+    }
+
+    // Serves `blockchain` to everyone connecting to the returned address.
+    fn server(blockchain: BlockChain<Signature, Output>) -> std::net::SocketAddr {
+        let network = network(blockchain.height() as u64);
+        let addr = network.listen("127.0.0.1:0").unwrap();
+        std::thread::spawn(move || {
+            while let Some(event) = network.recv() {
+                if let Event::Message { peer, message } = event {
+                    serve(&network, &blockchain, peer, &message).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn blocks_are_downloaded_from_several_peers() -> anyhow::Result<()> {
+        let mainchain = MockMainClient::new();
+        let params = SidechainParams {
+            bmm: true,
+            ..SidechainParams::default()
+        };
+        let mut blockchain = BlockChain::<Signature, Output>::new().with_params(params.clone());
+        for _ in 0..20 {
+            let body = Body {
+                coinbase: vec![],
+                transactions: vec![],
+            };
+            let prev = blockchain
+                .get_best_block_hash()
+                .unwrap_or_else(|| Hash::default().into());
+            let header = Header::new(&prev, &body);
+            let prev_main_block_hash = mainchain.get_best_block_hash()?;
+            let amount = bitcoin::Amount::ZERO;
+            mainchain.create_bmm_request(&header.hash(), amount, 0, &prev_main_block_hash)?;
+            let main_block_hash = mainchain.mine_block();
+            let anchor = Anchor::verify(&mainchain, &main_block_hash, &header.hash())?;
+            blockchain.connect_block(&header, &body, Some(&anchor))?;
+        }
+        let best_block_hash = blockchain.get_best_block_hash();
+        let bytes = bincode::serialize(&blockchain)?;
+
+        let node = network(0);
+        node.connect(server(bincode::deserialize(&bytes)?))?;
+        node.connect(server(bincode::deserialize(&bytes)?))?;
+        // Claims to have the blocks but never answers.
+        let staller = network(20);
+        node.connect(staller.listen("127.0.0.1:0")?)?;
+
+        let mut synced = BlockChain::<Signature, Output>::new().with_params(params);
+        let connected = InitialBlockDownload::new()
+            .with_stall_timeout(Duration::from_millis(500))
+            .with_max_blocks_in_flight(2)
+            .run(&node, &mainchain, &mut synced, &mut AccountState::default())?;
+        assert_eq!(connected, 20);
+        assert_eq!(synced.get_best_block_hash(), best_block_hash);
+        let best_block_hash = best_block_hash.unwrap();
+        assert_eq!(
+            synced.get_anchor(&best_block_hash),
+            blockchain.get_anchor(&best_block_hash)
+        );
+        assert_eq!(node.peers().len(), 2);
+        Ok(())
+    }
+}
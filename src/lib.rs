@@ -0,0 +1,107 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod account;
+#[cfg(feature = "std")]
+pub mod address_index;
+#[cfg(feature = "std")]
+pub mod addrman;
+pub mod amount;
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod backend;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod block_files;
+#[cfg(feature = "std")]
+pub mod blockchain;
+#[cfg(feature = "std")]
+pub mod bmm;
+#[cfg(feature = "std")]
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod bundle;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "std")]
+pub mod concrete;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod conformance;
+#[cfg(feature = "cli")]
+pub mod daemon;
+#[cfg(feature = "std")]
+pub mod descriptor;
+#[cfg(feature = "electrum")]
+pub mod electrum;
+pub mod encode;
+#[cfg(feature = "std")]
+pub mod filter;
+#[cfg(feature = "std")]
+pub mod fork_choice;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(feature = "std")]
+pub mod genesis;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "std")]
+pub mod headers;
+#[cfg(feature = "std")]
+pub mod ibd;
+#[cfg(feature = "std")]
+pub mod mempool;
+#[cfg(feature = "std")]
+pub mod miner;
+#[cfg(feature = "std")]
+pub mod mock_client;
+#[cfg(feature = "std")]
+pub mod p2p;
+#[cfg(feature = "std")]
+pub mod params;
+#[cfg(feature = "std")]
+pub mod peg;
+pub mod primitives;
+#[cfg(feature = "regtest")]
+pub mod regtest;
+#[cfg(feature = "std")]
+pub mod relay;
+#[cfg(feature = "rest")]
+pub mod rest;
+#[cfg(feature = "std")]
+pub mod retry;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+#[cfg(feature = "std")]
+pub mod simulated;
+#[cfg(feature = "std")]
+pub mod simulation;
+#[cfg(feature = "std")]
+pub mod socks;
+#[cfg(feature = "std")]
+pub mod spv;
+#[cfg(feature = "std")]
+pub mod ssm;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "example-token")]
+pub mod token;
+#[cfg(feature = "std")]
+pub mod types;
+#[cfg(feature = "zero-copy")]
+pub mod view;
+#[cfg(feature = "std")]
+pub mod wallet;
+#[cfg(feature = "std")]
+pub mod watcher;
+#[cfg(feature = "ws")]
+pub mod ws;
+#[cfg(feature = "zmq")]
+pub mod zmq_listener;
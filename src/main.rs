@@ -1,54 +1,183 @@
-mod blockchain;
-mod client;
-mod mempool;
-mod types;
-mod wallet;
-mod concrete;
-
-use blockchain::*;
-use client::Client;
-use mempool::*;
-use types::*;
-use wallet::*;
+use sdk::client::Client;
+use sdk::config::{Config, RpcConfig};
+use sdk::daemon::Daemon;
 
 use anyhow::Result;
+use clap::{Args, Parser, Subcommand};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+#[derive(Parser)]
+#[command(about = "Drivechain sidechain node and wallet")]
+struct Cli {
+    // Defaults to sdk.toml in the working directory, if there is one.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    rpc: RpcArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Overrides for where the node's JSON-RPC server listens, used by `node` to
+// serve and by every other command to connect.
+#[derive(Args)]
+struct RpcArgs {
+    #[arg(long, global = true)]
+    rpc_host: Option<String>,
+    #[arg(long, global = true)]
+    rpc_port: Option<u16>,
+    #[arg(long, global = true)]
+    rpc_user: Option<String>,
+    #[arg(long, global = true)]
+    rpc_password: Option<String>,
+}
+
+impl RpcArgs {
+    fn apply(self, config: &mut RpcConfig) {
+        if let Some(host) = self.rpc_host {
+            config.host = host;
+        }
+        if let Some(port) = self.rpc_port {
+            config.port = port;
+        }
+        if let Some(user) = self.rpc_user {
+            config.user = user;
+        }
+        if let Some(password) = self.rpc_password {
+            config.password = password;
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Run the node, following the mainchain and serving RPC")]
+    Node,
+    #[command(about = "Print chain, sync, mempool, peg and wallet status")]
+    Status,
+    #[command(subcommand, about = "Wallet commands")]
+    Wallet(WalletCommand),
+    #[command(subcommand, about = "Chain queries")]
+    Chain(ChainCommand),
+    #[command(subcommand, about = "Mainchain deposits")]
+    Deposit(DepositCommand),
+}
+
+#[derive(Subcommand)]
+enum WalletCommand {
+    #[command(about = "Print a fresh address")]
+    New {
+        // Bech32 instead of base58.
+        #[arg(long)]
+        bech32: bool,
+    },
+    #[command(about = "Print the wallet balance")]
+    Balance,
+    #[command(about = "Send to a sidechain address")]
+    Send {
+        address: String,
+        value: u64,
+        #[arg(long, default_value_t = 0)]
+        fee: u64,
+    },
+    #[command(about = "Withdraw to a mainchain address")]
+    Withdraw {
+        main_address: String,
+        value: u64,
+        // Estimated from the mainchain fee rate when not given.
+        #[arg(long)]
+        main_fee: Option<u64>,
+        #[arg(long, default_value_t = 0)]
+        fee: u64,
+    },
+}
+
+#[derive(Subcommand)]
+enum ChainCommand {
+    #[command(about = "Print the chain tip and mempool size")]
+    Info,
+}
+
+#[derive(Subcommand)]
+enum DepositCommand {
+    #[command(about = "Print a fresh address to deposit to from the mainchain")]
+    Address,
+}
 
 fn main() -> Result<()> {
-    let mut blockchain = BlockChain::new();
-    let mut mempool = MemPool::default();
-    let mut wallet = Wallet::load("./fake_wallet.dat").unwrap_or_default();
-    // for address in wallet.get_addresses() {
-    //     dbg!(address.to_deposit_string());
-    // }
-    let client = Client {
-        this_sidechain: 0,
-        client: ureq_jsonrpc::Client {
-            host: "localhost".into(),
-            port: 18443,
-            user: "user".into(),
-            password: "password".into(),
-            id: "sdk".into(),
-        },
+    let cli = Cli::parse();
+    let mut config = Config::load(cli.config.as_deref())?;
+    cli.rpc.apply(&mut config.rpc);
+    config.validate()?;
+    let rpc = &config.rpc;
+    let node = Client::new(
+        config.sidechain,
+        &rpc.host,
+        rpc.port,
+        &rpc.user,
+        &rpc.password,
+    );
+    let output = match cli.command {
+        Command::Node => return run_node(&config),
+        Command::Status => call::<Value>(&node, "getnodeinfo", &[])?,
+        Command::Wallet(WalletCommand::New { bech32 }) => {
+            let address_type = if bech32 { "bech32" } else { "base58" };
+            call::<Value>(&node, "getnewaddress", &[json!(address_type)])?
+        }
+        Command::Wallet(WalletCommand::Balance) => call::<Value>(&node, "getbalance", &[])?,
+        Command::Wallet(WalletCommand::Send {
+            address,
+            value,
+            fee,
+        }) => call::<Value>(
+            &node,
+            "sendtoaddress",
+            &[json!(address), json!(value), json!(fee)],
+        )?,
+        Command::Wallet(WalletCommand::Withdraw {
+            main_address,
+            value,
+            main_fee,
+            fee,
+        }) => call::<Value>(
+            &node,
+            "createwithdrawal",
+            &[
+                json!(main_address),
+                json!(value),
+                json!(main_fee),
+                json!(fee),
+            ],
+        )?,
+        Command::Chain(ChainCommand::Info) => json!({
+            "blocks": call::<u64>(&node, "getblockcount", &[])?,
+            "best_block_hash": call::<Value>(&node, "getbestblockhash", &[])?,
+            "mempool": call::<Value>(&node, "getmempoolinfo", &[])?,
+        }),
+        Command::Deposit(DepositCommand::Address) => {
+            call::<Value>(&node, "getdepositaddress", &[])?
+        }
     };
-    let deposits = client.get_deposits(None)?;
-    blockchain.add_deposits(deposits);
-    wallet.add_outputs(&blockchain.outputs);
-    dbg!(&blockchain.outputs);
-    dbg!(&wallet.outputs);
-
-    let output = wallet.create_output(100);
-    let transaction = wallet.create_transaction(vec![output], 1).unwrap();
-    let fee = blockchain.get_fee(&transaction);
-    mempool.insert(fee, transaction);
-    let body = mempool.create_body(wallet.generate_address(), 1);
-    let header = Header::new(&Hash::default().into(), &body);
-    dbg!(blockchain.validate_block(&header, &body));
-
-    dbg!(&blockchain.unspent_outpoints);
-    dbg!(blockchain.connect_block(&header, &body));
-    dbg!(&blockchain.unspent_outpoints);
-
-    dbg!(&header, &body);
-    wallet.save("./fake_wallet.dat")?;
+    match output {
+        Value::String(output) => println!("{}", output),
+        output => println!("{}", serde_json::to_string_pretty(&output)?),
+    }
+    Ok(())
+}
+
+fn call<T: DeserializeOwned>(node: &Client, method: &str, params: &[Value]) -> Result<T> {
+    node.send_request(method, params)
+        .map_err(|err| anyhow::anyhow!("{} failed: {}", method, err))
+}
+
+fn run_node(config: &Config) -> Result<()> {
+    let daemon = Daemon::open(config.clone())?;
+    let shutdown = daemon.shutdown_handle();
+    // SIGINT and SIGTERM both stop the node cleanly.
+    ctrlc::set_handler(move || shutdown.store(true, Ordering::SeqCst))?;
+    daemon.run()?;
     Ok(())
 }
@@ -1,28 +1,924 @@
-use crate::types::*;
+use crate::blockchain::{regular_outputs, BlockChain};
 use crate::concrete::*;
-use std::collections::BTreeMap;
+use crate::params::SidechainParams;
+use crate::types::*;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::{Bound, Index, RangeBounds};
+use std::time::{Duration, Instant};
+
+// Most transactions put into the block template by default.
+pub const DEFAULT_MAX_TEMPLATE_TRANSACTIONS: usize = 1000;
+// Largest encoded size of all the transactions together by default.
+pub const DEFAULT_MAX_SIZE: usize = 300_000_000;
+// How far the minimum fee rate is raised above the transactions evicted to
+// make room, so they can't come right back.
+const INCREMENTAL_FEE_RATE: FeeRate = FeeRate::from_sat_per_kvb(1000);
+// A raised minimum fee rate halves every this long.
+const FLOOR_HALF_LIFE: Duration = Duration::from_secs(12 * 60 * 60);
+
+// Transactions are keyed by the fee rate of the transaction together with
+// its mempool ancestors, ties broken by txid. A child paying enough lifts
+// its parents into the block with it.
+type Key = (FeeRate, Txid);
+
+// The transactions by key, with what finding them by txid, by the outputs
+// they spend and by their parents takes, kept up to date as they come and
+// go so none of it walks the whole mempool.
+#[derive(Debug, Default)]
+struct Entries {
+    entries: BTreeMap<Key, Entry>,
+    keys: HashMap<Txid, Key>,
+    spenders: HashMap<OutPoint, Txid>,
+    children: HashMap<Txid, HashSet<Txid>>,
+    // Encoded size of all the transactions.
+    size: usize,
+}
+
+impl Entries {
+    fn insert(&mut self, key: Key, entry: Entry) {
+        let (_, txid) = key;
+        for outpoint in &entry.transaction.inputs {
+            self.spenders.insert(*outpoint, txid);
+        }
+        for (_, parent) in &entry.parents {
+            self.children.entry(*parent).or_default().insert(txid);
+        }
+        self.size += entry.size;
+        self.keys.insert(txid, key);
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        let (_, txid) = key;
+        for outpoint in &entry.transaction.inputs {
+            if self.spenders.get(outpoint) == Some(txid) {
+                self.spenders.remove(outpoint);
+            }
+        }
+        for (_, parent) in &entry.parents {
+            if let Some(children) = self.children.get_mut(parent) {
+                children.remove(txid);
+                if children.is_empty() {
+                    self.children.remove(parent);
+                }
+            }
+        }
+        self.size -= entry.size;
+        self.keys.remove(txid);
+        Some(entry)
+    }
+
+    fn get(&self, key: &Key) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+
+    fn key(&self, txid: &Txid) -> Option<Key> {
+        self.keys.get(txid).copied()
+    }
+
+    fn spender(&self, outpoint: &OutPoint) -> Option<Key> {
+        self.spenders.get(outpoint).and_then(|txid| self.key(txid))
+    }
+
+    fn children(&self, key: &Key) -> impl Iterator<Item = Key> + '_ {
+        let (_, txid) = key;
+        self.children
+            .get(txid)
+            .into_iter()
+            .flatten()
+            .filter_map(|child| self.key(child))
+    }
+
+    fn keys(&self) -> btree_map::Keys<'_, Key, Entry> {
+        self.entries.keys()
+    }
+
+    fn values(&self) -> btree_map::Values<'_, Key, Entry> {
+        self.entries.values()
+    }
+
+    fn iter(&self) -> btree_map::Iter<'_, Key, Entry> {
+        self.entries.iter()
+    }
+
+    fn range(&self, range: impl RangeBounds<Key>) -> btree_map::Range<'_, Key, Entry> {
+        self.entries.range(range)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Index<&Key> for Entries {
+    type Output = Entry;
+
+    fn index(&self, key: &Key) -> &Entry {
+        &self.entries[key]
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    fee: Amount,
+    size: usize,
+    transaction: Transaction<Signature, Output>,
+    // Mempool transactions it spends outputs of, a block has to have them
+    // first.
+    parents: Vec<Key>,
+}
+
+// A transaction picked for the template with the ancestors it brought in,
+// parents before children and itself last.
+#[derive(Debug)]
+struct Pick {
+    keys: Vec<Key>,
+    size: usize,
+    fees: Amount,
+}
 
-#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+// The transactions create_body would pick, kept up to date as transactions
+// come and go so a miner refreshing its template every few seconds gets it
+// right away.
+#[derive(Debug, Default)]
+struct Template {
+    selected: BTreeSet<Key>,
+    picks: BTreeMap<Key, Pick>,
+    // Encoded size of the selected transactions.
+    size: usize,
+    fees: Amount,
+}
+
+impl Template {
+    fn select(&mut self, key: Key, pick: Pick) {
+        self.selected.extend(&pick.keys);
+        self.size += pick.size;
+        self.fees += pick.fees;
+        self.picks.insert(key, pick);
+    }
+
+    fn unselect(&mut self, key: &Key) {
+        if let Some(pick) = self.picks.remove(key) {
+            for key in &pick.keys {
+                self.selected.remove(key);
+            }
+            self.size -= pick.size;
+            self.fees -= pick.fees;
+        }
+    }
+}
+
+// A mempool transaction as of a Snapshot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SnapshotEntry {
+    pub txid: Txid,
+    pub size: usize,
+    pub vsize: usize,
+    pub fee: Amount,
+    pub fee_rate: FeeRate,
+    // Of the transaction together with its mempool ancestors, the rate it
+    // is picked for block templates by.
+    pub ancestor_fee_rate: FeeRate,
+    // Mempool transactions it spends outputs of.
+    pub parents: Vec<Txid>,
+}
+
+// Everything in the mempool at one point in time, for looking into why
+// block templates came out the way they did.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snapshot {
+    // Highest ancestor fee rate first.
+    pub entries: Vec<SnapshotEntry>,
+    pub size: usize,
+    pub vsize: usize,
+    pub fees: Amount,
+    // Of the transactions on their own, zero for an empty mempool.
+    pub median_fee_rate: FeeRate,
+    pub min_fee_rate: FeeRate,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct MemPool {
-    transactions: BTreeMap<u64, Transaction<Signature, Output>>,
+    #[serde(with = "entries")]
+    transactions: Entries,
+    #[serde(skip)]
+    params: SidechainParams,
+    // None until built, a loaded mempool builds it on first use.
+    #[serde(skip)]
+    template: Option<Template>,
+    #[serde(skip, default = "default_max_template_transactions")]
+    max_template_transactions: usize,
+    #[serde(skip)]
+    min_fee_rate: FeeRate,
+    #[serde(skip, default = "default_max_size")]
+    max_size: usize,
+    // Raised by trim and when it was, decays back to min_fee_rate.
+    #[serde(skip)]
+    floor: Option<(FeeRate, Instant)>,
+}
+
+fn default_max_template_transactions() -> usize {
+    DEFAULT_MAX_TEMPLATE_TRANSACTIONS
+}
+
+fn default_max_size() -> usize {
+    DEFAULT_MAX_SIZE
+}
+
+impl Default for MemPool {
+    fn default() -> Self {
+        Self {
+            transactions: Entries::default(),
+            params: SidechainParams::default(),
+            template: Some(Template::default()),
+            max_template_transactions: DEFAULT_MAX_TEMPLATE_TRANSACTIONS,
+            min_fee_rate: FeeRate::ZERO,
+            max_size: DEFAULT_MAX_SIZE,
+            floor: None,
+        }
+    }
 }
 
 impl MemPool {
+    pub fn with_params(mut self, params: SidechainParams) -> Self {
+        self.params = params;
+        self.rebuild_template();
+        self
+    }
+
+    pub fn with_max_template_transactions(mut self, max_template_transactions: usize) -> Self {
+        self.set_max_template_transactions(max_template_transactions);
+        self
+    }
+
+    // Transactions paying less are turned away, unless they come in a
+    // package that pays enough as a whole, see NodeState::submit_package.
+    pub fn with_min_fee_rate(mut self, min_fee_rate: FeeRate) -> Self {
+        self.min_fee_rate = min_fee_rate;
+        self
+    }
+
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    // The configured minimum fee rate, or more for a while after the
+    // mempool was full, see trim.
+    pub fn min_fee_rate(&self) -> FeeRate {
+        let floor = self
+            .floor
+            .map(|(floor, raised_at)| decay(floor, raised_at.elapsed()))
+            .unwrap_or(FeeRate::ZERO);
+        self.min_fee_rate.max(floor)
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    // Encoded size of all the transactions.
+    pub fn size(&self) -> usize {
+        self.transactions.size
+    }
+
+    // Evicts the lowest fee rate transactions, along with the ones spending
+    // their outputs, until the rest fit into the maximum size. The minimum
+    // fee rate goes up past the evicted ones and decays back over the
+    // following hours. Returns the txids of the evicted transactions.
+    pub fn trim(&mut self) -> Vec<Txid> {
+        let mut size = self.size();
+        if size <= self.max_size {
+            return vec![];
+        }
+        let mut evicted = BTreeSet::new();
+        let mut floor = FeeRate::ZERO;
+        for key in self.transactions.keys() {
+            if size <= self.max_size {
+                break;
+            }
+            if evicted.contains(key) {
+                continue;
+            }
+            floor = floor.max(key.0);
+            for key in self.descendants(key) {
+                if evicted.insert(key) {
+                    size -= self.transactions[&key].size;
+                }
+            }
+        }
+        if evicted.is_empty() {
+            return vec![];
+        }
+        let floor = FeeRate::from_sat_per_kvb(
+            floor.to_sat_per_kvb() + INCREMENTAL_FEE_RATE.to_sat_per_kvb(),
+        );
+        self.floor = Some((self.min_fee_rate().max(floor), Instant::now()));
+        self.remove(&evicted);
+        evicted.iter().map(|(_, txid)| *txid).collect()
+    }
+
+    pub fn set_max_template_transactions(&mut self, max_template_transactions: usize) {
+        if self.max_template_transactions != max_template_transactions {
+            self.max_template_transactions = max_template_transactions;
+            self.rebuild_template();
+        }
+    }
+
+    // Takes up to `num` of the highest fee rate transactions that fit into a
+    // block of the maximum size.
     pub fn create_body(&self, coinbase_address: Address, num: usize) -> Body<Signature, Output> {
-        let transactions = self.transactions.iter().rev().take(num);
-        let fee: u64 = transactions.clone().map(|(fee, _)| fee).sum();
-        let transactions = transactions.map(|(_, tx)| tx.clone()).collect();
-        let coinbase = vec![Output {
-            address: coinbase_address,
-            value: fee,
-        }];
+        let mut template = Template::default();
+        self.fill(&mut template, Bound::Unbounded, num);
+        self.template_body(&template, coinbase_address)
+    }
+
+    // Same as create_body with the maximum number of template transactions,
+    // without going through the whole mempool.
+    pub fn get_block_template(&self, coinbase_address: Address) -> Body<Signature, Output> {
+        match &self.template {
+            Some(template) => self.template_body(template, coinbase_address),
+            None => self.create_body(coinbase_address, self.max_template_transactions),
+        }
+    }
+
+    pub fn insert(&mut self, fee: Amount, transaction: Transaction<Signature, Output>) -> bool {
+        if self.contains(&transaction.txid()) {
+            return true;
+        }
+        let (key, entry) = entry(&self.transactions, fee, transaction);
+        self.transactions.insert(key, entry);
+        self.update_template(key);
+        false
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&Transaction<Signature, Output>> {
+        let key = self.transactions.key(txid)?;
+        Some(&self.transactions[&key].transaction)
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.get(txid).is_some()
+    }
+
+    // True if a transaction in the mempool spends `outpoint`.
+    pub fn spends(&self, outpoint: &OutPoint) -> bool {
+        self.transactions.spender(outpoint).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    // Keeps only the transactions `f` returns true for, like dropping the
+    // ones a newly connected block confirmed or conflicts with.
+    pub fn retain(&mut self, mut f: impl FnMut(&Transaction<Signature, Output>) -> bool) {
+        let removed: BTreeSet<Key> = self
+            .transactions
+            .iter()
+            .filter(|(_, entry)| !f(&entry.transaction))
+            .map(|(key, _)| *key)
+            .collect();
+        self.remove(&removed);
+    }
+
+    fn remove(&mut self, removed: &BTreeSet<Key>) {
+        // Transactions that stay but spent outputs of removed ones lose
+        // those parents and get a new ancestor fee rate, like a child whose
+        // parent was confirmed. They are keyed again parents first.
+        let mut stale = BTreeSet::new();
+        for key in removed {
+            stale.extend(self.descendants(key));
+        }
+        let stale: BTreeSet<Key> = stale.difference(removed).copied().collect();
+        let mut order = vec![];
+        for key in &stale {
+            ancestors(&self.transactions, key, removed, &mut order);
+        }
+        order.retain(|key| stale.contains(key));
+        for key in removed {
+            self.transactions.remove(key);
+        }
+        let stale_entries: Vec<Entry> = order
+            .iter()
+            .filter_map(|key| self.transactions.remove(key))
+            .collect();
+        let mut rekeyed = vec![];
+        for stale_entry in stale_entries {
+            let (key, entry) = entry(&self.transactions, stale_entry.fee, stale_entry.transaction);
+            self.transactions.insert(key, entry);
+            rekeyed.push(key);
+        }
+        // Dropping transactions that weren't picked doesn't change which
+        // ones are, the picks that had one of them are redone, and so are
+        // the ones from the highest new key down.
+        let picked = self.template.as_ref().and_then(|template| {
+            template
+                .picks
+                .iter()
+                .filter(|(_, pick)| {
+                    pick.keys
+                        .iter()
+                        .any(|key| removed.contains(key) || stale.contains(key))
+                })
+                .map(|(key, _)| *key)
+                .max()
+        });
+        if let Some(key) = picked.into_iter().chain(rekeyed).max() {
+            self.update_template(key);
+        }
+    }
+
+    // Drops the transactions that aren't valid on top of the chain anymore,
+    // like the ones a new block confirmed or conflicts with, and then the
+    // ones spending outputs of the dropped ones.
+    pub fn revalidate(&mut self, blockchain: &BlockChain<Signature, Output>) {
+        loop {
+            let len = self.len();
+            let pending = self.outputs();
+            self.retain(|transaction| {
+                blockchain
+                    .validate_transaction_with(transaction, &pending)
+                    .is_ok()
+            });
+            if self.len() == len {
+                break;
+            }
+        }
+    }
+
+    // Regular outputs of the mempool transactions, for validating the ones
+    // spending them, see BlockChain::validate_transaction_with.
+    pub fn outputs(&self) -> HashMap<OutPoint, Output> {
+        self.transactions
+            .iter()
+            .flat_map(|((_, txid), entry)| regular_outputs(*txid, &entry.transaction))
+            .collect()
+    }
+
+    // Transactions with their fees, highest fee rate last.
+    pub fn iter(&self) -> impl Iterator<Item = (Amount, &Transaction<Signature, Output>)> {
+        self.transactions
+            .values()
+            .map(|entry| (entry.fee, &entry.transaction))
+    }
+
+    pub fn txids(&self) -> Vec<Txid> {
+        self.transactions.keys().map(|(_, txid)| *txid).collect()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let entries: Vec<SnapshotEntry> = self
+            .transactions
+            .iter()
+            .rev()
+            .map(|((ancestor_fee_rate, txid), entry)| {
+                let vsize = entry.transaction.vsize();
+                SnapshotEntry {
+                    txid: *txid,
+                    size: entry.size,
+                    vsize,
+                    fee: entry.fee,
+                    fee_rate: FeeRate::new(entry.fee, vsize),
+                    ancestor_fee_rate: *ancestor_fee_rate,
+                    parents: entry.parents.iter().map(|(_, txid)| *txid).collect(),
+                }
+            })
+            .collect();
+        let mut fee_rates: Vec<u64> = entries
+            .iter()
+            .map(|entry| entry.fee_rate.to_sat_per_kvb())
+            .collect();
+        fee_rates.sort_unstable();
+        let middle = fee_rates.len() / 2;
+        let median_fee_rate = match fee_rates.len() {
+            0 => 0,
+            len if len % 2 == 0 => (fee_rates[middle - 1] + fee_rates[middle]) / 2,
+            _ => fee_rates[middle],
+        };
+        Snapshot {
+            size: entries.iter().map(|entry| entry.size).sum(),
+            vsize: entries.iter().map(|entry| entry.vsize).sum(),
+            fees: entries.iter().map(|entry| entry.fee).sum(),
+            median_fee_rate: FeeRate::from_sat_per_kvb(median_fee_rate),
+            min_fee_rate: self.min_fee_rate(),
+            entries,
+        }
+    }
+
+    // The transaction at `key` and the mempool transactions spending its
+    // outputs, and theirs.
+    fn descendants(&self, key: &Key) -> BTreeSet<Key> {
+        let mut descendants = BTreeSet::from([*key]);
+        let mut queue = vec![*key];
+        while let Some(key) = queue.pop() {
+            for child in self.transactions.children(&key) {
+                if descendants.insert(child) {
+                    queue.push(child);
+                }
+            }
+        }
+        descendants
+    }
+
+    fn rebuild_template(&mut self) {
+        let mut template = Template::default();
+        self.fill(
+            &mut template,
+            Bound::Unbounded,
+            self.max_template_transactions,
+        );
+        self.template = Some(template);
+    }
+
+    // Which transactions are picked only depends on the ones with a higher
+    // fee rate, so after a change at `from` only the picks from there on down
+    // are redone. Ancestors a higher pick brought in stay, the ones lower
+    // picks brought in are only part of those.
+    fn update_template(&mut self, from: Key) {
+        let mut template = match self.template.take() {
+            Some(template) => template,
+            None => return self.rebuild_template(),
+        };
+        let full = template.selected.len() >= self.max_template_transactions;
+        if full
+            && template
+                .selected
+                .first()
+                .is_some_and(|lowest| from < *lowest)
+        {
+            self.template = Some(template);
+            return;
+        }
+        let below: Vec<Key> = template.picks.range(..=from).map(|(key, _)| *key).collect();
+        for key in below {
+            template.unselect(&key);
+        }
+        self.fill(
+            &mut template,
+            Bound::Included(from),
+            self.max_template_transactions,
+        );
+        self.template = Some(template);
+    }
+
+    // Picks transactions up to `upper` with the ancestors they need,
+    // highest fee rate first, skipping the ones that don't fit into the
+    // block anymore and the ones with an ancestor that left the mempool.
+    fn fill(&self, template: &mut Template, upper: Bound<Key>, num: usize) {
+        let max_size = self
+            .params
+            .max_block_size
+            .saturating_sub(template_body_size());
+        for (key, _) in self.transactions.range((Bound::Unbounded, upper)).rev() {
+            if template.selected.len() >= num {
+                break;
+            }
+            if template.selected.contains(key) {
+                continue;
+            }
+            let mut keys = vec![];
+            if !ancestors(&self.transactions, key, &template.selected, &mut keys) {
+                continue;
+            }
+            let entries = keys.iter().map(|key| &self.transactions[key]);
+            let size = entries.clone().map(|entry| entry.size).sum::<usize>();
+            if template.selected.len() + keys.len() <= num && template.size + size <= max_size {
+                let fees = entries.map(|entry| entry.fee).sum();
+                template.select(*key, Pick { keys, size, fees });
+            }
+        }
+    }
+
+    fn template_body(
+        &self,
+        template: &Template,
+        coinbase_address: Address,
+    ) -> Body<Signature, Output> {
         Body {
-            coinbase,
-            transactions,
+            coinbase: vec![Output {
+                address: coinbase_address,
+                value: template.fees,
+            }],
+            transactions: template
+                .picks
+                .values()
+                .rev()
+                .flat_map(|pick| &pick.keys)
+                .map(|key| self.transactions[key].transaction.clone())
+                .collect(),
+        }
+    }
+}
+
+// The entry for a transaction whose mempool ancestors are in `entries`,
+// with its key.
+fn entry(
+    entries: &Entries,
+    fee: Amount,
+    transaction: Transaction<Signature, Output>,
+) -> (Key, Entry) {
+    let parents = parents(entries, &transaction);
+    let mut keys = vec![];
+    for parent in &parents {
+        ancestors(entries, parent, &BTreeSet::new(), &mut keys);
+    }
+    let (fees, vsize) = keys
+        .iter()
+        .map(|key| &entries[key])
+        .fold((fee, transaction.vsize()), |(fees, vsize), entry| {
+            (fees + entry.fee, vsize + entry.transaction.vsize())
+        });
+    let key = (FeeRate::new(fees, vsize), transaction.txid());
+    let entry = Entry {
+        fee,
+        size: transaction.size(),
+        transaction,
+        parents,
+    };
+    (key, entry)
+}
+
+// Adds the transaction at `key` and its ancestors to `keys`, parents before
+// children, leaving out the ones in `skip` or already in `keys`. False if
+// one of them left the mempool.
+fn ancestors(entries: &Entries, key: &Key, skip: &BTreeSet<Key>, keys: &mut Vec<Key>) -> bool {
+    if skip.contains(key) || keys.contains(key) {
+        return true;
+    }
+    let Some(entry) = entries.get(key) else {
+        return false;
+    };
+    let mut complete = true;
+    for parent in &entry.parents {
+        complete &= ancestors(entries, parent, skip, keys);
+    }
+    keys.push(*key);
+    complete
+}
+
+// `floor` halved for every half life in `elapsed`, down to zero once it is
+// below half the incremental fee rate.
+fn decay(floor: FeeRate, elapsed: Duration) -> FeeRate {
+    let halvings = elapsed.as_secs_f64() / FLOOR_HALF_LIFE.as_secs_f64();
+    let floor = (floor.to_sat_per_kvb() as f64 / halvings.exp2()).round() as u64;
+    if floor < INCREMENTAL_FEE_RATE.to_sat_per_kvb() / 2 {
+        return FeeRate::ZERO;
+    }
+    FeeRate::from_sat_per_kvb(floor)
+}
+
+// Keys of the transactions in `entries` whose outputs `transaction` spends.
+fn parents(entries: &Entries, transaction: &Transaction<Signature, Output>) -> Vec<Key> {
+    let txids: HashSet<Txid> = transaction
+        .inputs
+        .iter()
+        .filter_map(|outpoint| match outpoint {
+            OutPoint::Regular { txid, .. } => Some(*txid),
+            _ => None,
+        })
+        .collect();
+    let mut parents: Vec<Key> = txids.iter().filter_map(|txid| entries.key(txid)).collect();
+    parents.sort();
+    parents
+}
+
+// Size of a template body without transactions, the coinbase output is
+// always the same size.
+fn template_body_size() -> usize {
+    Body::<Signature, Output> {
+        coinbase: vec![Output {
+            address: Hash::default().into(),
+            value: Amount::ZERO,
+        }],
+        transactions: vec![],
+    }
+    .size()
+}
+
+// Saved as a sequence of (fee, transaction) pairs, the same bytes the map
+// keyed by fee older versions saved encodes to, so their mempools still load.
+// Parents are saved before their children, so they are there to be found
+// when the children are loaded.
+mod entries {
+    use super::*;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        transactions: &Entries,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut saved = BTreeSet::new();
+        let mut keys = vec![];
+        for key in transactions.keys() {
+            let mut package = vec![];
+            ancestors(transactions, key, &saved, &mut package);
+            saved.extend(&package);
+            keys.extend(package);
         }
+        serializer.collect_seq(keys.iter().map(|key| {
+            let entry = &transactions[key];
+            (entry.fee, &entry.transaction)
+        }))
     }
 
-    pub fn insert(&mut self, fee: u64, transaction: Transaction<Signature, Output>) -> bool {
-        self.transactions.insert(fee, transaction).is_some()
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Entries, D::Error> {
+        let mut entries = Entries::default();
+        for (fee, transaction) in
+            Vec::<(Amount, Transaction<Signature, Output>)>::deserialize(deserializer)?
+        {
+            let (key, entry) = entry(&entries, fee, transaction);
+            entries.insert(key, entry);
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{keypair, TxBuilder};
+    use crate::wallet::Wallet;
+    use std::collections::HashMap;
+
+    #[test]
+    fn transactions_are_ordered_by_fee_rate() {
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        let mut small = Wallet::default();
+        let mut large = Wallet::default();
+        let mut outputs = HashMap::new();
+        // The wallet tells outputs apart by value.
+        for (vout, (address, value)) in [
+            (small.generate_address(), 1000),
+            (large.generate_address(), 1000),
+            (large.generate_address(), 1100),
+            (large.generate_address(), 1200),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            outputs.insert(
+                OutPoint::Deposit(bitcoin::OutPoint {
+                    vout: vout as u32,
+                    ..Default::default()
+                }),
+                DepositOutput {
+                    address,
+                    value: Amount::from_sat(value),
+                },
+            );
+        }
+        blockchain.add_deposits(DepositsChunk {
+            outputs,
+            deposits: vec![],
+        });
+        small.add_deposit_outputs(&blockchain.peg.deposit_outputs);
+        large.add_deposit_outputs(&blockchain.peg.deposit_outputs);
+        let to: Address = [1; 32].into();
+        let output = |value| Output {
+            address: to,
+            value: Amount::from_sat(value),
+        };
+
+        // Higher fee, but spread over three inputs.
+        let large = large
+            .create_transaction(vec![output(2500)], Amount::from_sat(300))
+            .unwrap();
+        let fee_rate = FeeRate::from_sat_per_kvb(1000);
+        let small = small
+            .create_transaction_with_fee_rate(vec![output(500)], fee_rate)
+            .unwrap();
+        let small_fee = blockchain.get_fee(&small).unwrap();
+        assert!(FeeRate::new(small_fee, small.vsize()) >= fee_rate);
+        assert!(small_fee < Amount::from_sat(300));
+        assert_eq!(
+            small.weight(),
+            small.without_signatures().size() * 3 + small.size()
+        );
+        assert!(small.vsize() < small.size());
+
+        let mut mempool = MemPool::default();
+        mempool.insert(Amount::from_sat(300), large.clone());
+        mempool.insert(small_fee, small.clone());
+        assert_eq!(mempool.txids(), [large.txid(), small.txid()]);
+        let body = mempool.create_body(to, 2);
+        assert_eq!(body.transactions.len(), 2);
+        assert_eq!(body.transactions[0].txid(), small.txid());
+        assert_eq!(body.size() as u64, bincode::serialized_size(&body).unwrap());
+        assert!(body.weight() < body.size() * WITNESS_SCALE_FACTOR);
+
+        // Only the higher rate one fits.
+        let params = SidechainParams {
+            max_block_size: mempool.create_body(to, 1).size(),
+            ..Default::default()
+        };
+        let mut mempool = MemPool::default().with_params(params);
+        mempool.insert(Amount::from_sat(300), large.clone());
+        assert!(mempool.get_block_template(to).transactions.is_empty());
+        mempool.insert(small_fee, small.clone());
+        let body = mempool.create_body(to, 2);
+        assert_eq!(body.transactions.len(), 1);
+        assert_eq!(body.transactions[0].txid(), small.txid());
+        assert_eq!(body.coinbase[0].value, small_fee);
+
+        // The template follows the mempool without being rebuilt.
+        let template = |mempool: &MemPool| {
+            let template = mempool.get_block_template(to);
+            assert_eq!(
+                template.compute_merkle_root(),
+                mempool.create_body(to, 2).compute_merkle_root()
+            );
+            template
+        };
+        assert_eq!(template(&mempool).transactions[0].txid(), small.txid());
+        let mut mempool = mempool.with_params(SidechainParams::default());
+        assert_eq!(template(&mempool).transactions.len(), 2);
+        mempool.retain(|transaction| transaction.txid() != small.txid());
+        let body = template(&mempool);
+        assert_eq!(body.transactions.len(), 1);
+        assert_eq!(body.transactions[0].txid(), large.txid());
+        assert_eq!(body.coinbase[0].value, Amount::from_sat(300));
+        let mempool = mempool.with_max_template_transactions(0);
+        assert!(mempool.get_block_template(to).transactions.is_empty());
+    }
+
+    #[test]
+    fn full_mempools_raise_the_minimum_fee_rate() {
+        let key = keypair([1; 32]);
+        let to: Address = [2; 32].into();
+        let transaction = |vout| {
+            let deposit = OutPoint::Deposit(bitcoin::OutPoint {
+                vout,
+                ..Default::default()
+            });
+            TxBuilder::new()
+                .spend(deposit, &key)
+                .pay(to, Amount::from_sat(1000))
+                .build()
+        };
+        let (cheap, middle, rich) = (transaction(0), transaction(1), transaction(2));
+        let child = TxBuilder::new()
+            .spend(
+                OutPoint::Regular {
+                    txid: cheap.txid(),
+                    vout: 0,
+                },
+                &key,
+            )
+            .pay(to, Amount::from_sat(1000))
+            .build();
+        let mut mempool = MemPool::default().with_max_size(3 * cheap.size());
+        mempool.insert(Amount::from_sat(100), cheap.clone());
+        mempool.insert(Amount::from_sat(300), rich.clone());
+        mempool.insert(Amount::from_sat(10_000), child.clone());
+        assert!(mempool.trim().is_empty());
+        assert_eq!(mempool.min_fee_rate(), FeeRate::ZERO);
+        let snapshot = mempool.snapshot();
+        let txids: Vec<Txid> = snapshot.entries.iter().map(|entry| entry.txid).collect();
+        assert_eq!(txids, [child.txid(), rich.txid(), cheap.txid()]);
+        assert_eq!(snapshot.entries[0].parents, [cheap.txid()]);
+        assert!(snapshot.entries[0].ancestor_fee_rate < snapshot.entries[0].fee_rate);
+        assert_eq!(snapshot.median_fee_rate, snapshot.entries[1].fee_rate);
+        assert_eq!(snapshot.fees, Amount::from_sat(10_400));
+        assert_eq!(snapshot.vsize, cheap.vsize() + rich.vsize() + child.vsize());
+
+        // The cheap parent goes and takes its child with it, although the
+        // child alone would pay more than the one coming in.
+        mempool.insert(Amount::from_sat(200), middle.clone());
+        let mut evicted = mempool.trim();
+        evicted.sort();
+        let mut expected = vec![cheap.txid(), child.txid()];
+        expected.sort();
+        assert_eq!(evicted, expected);
+        assert_eq!(mempool.txids(), [middle.txid(), rich.txid()]);
+        assert_eq!(mempool.size(), middle.size() + rich.size());
+        assert!(mempool.get(&child.txid()).is_none());
+        assert!(!mempool.spends(&cheap.inputs[0]));
+        assert!(mempool.spends(&rich.inputs[0]));
+        // Known transactions aren't added again, whatever fee they come with.
+        assert!(mempool.insert(Amount::from_sat(1), rich.clone()));
+        assert_eq!(mempool.len(), 2);
+        let floor = FeeRate::from_sat_per_kvb(
+            FeeRate::new(Amount::from_sat(100), cheap.vsize()).to_sat_per_kvb() + 1000,
+        );
+        assert_eq!(mempool.min_fee_rate(), floor);
+        let template = mempool.get_block_template(to);
+        assert_eq!(template.coinbase[0].value, Amount::from_sat(500));
+
+        let floor = FeeRate::from_sat_per_kvb(4000);
+        assert_eq!(
+            decay(floor, FLOOR_HALF_LIFE),
+            FeeRate::from_sat_per_kvb(2000)
+        );
+        assert_eq!(decay(floor, 4 * FLOOR_HALF_LIFE), FeeRate::ZERO);
     }
 }
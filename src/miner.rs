@@ -0,0 +1,414 @@
+use crate::backend::MainchainBackend;
+use crate::blockchain::{BlockChain, Error as BlockChainError};
+use crate::bmm::{Anchor, Error as BmmError};
+use crate::client::Error as ClientError;
+use crate::concrete::{Output, Signature};
+use crate::mempool::MemPool;
+use crate::p2p::{Message, Network};
+use crate::types::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const DEFAULT_BMM_AMOUNT: bitcoin::Amount = bitcoin::Amount::from_sat(1000);
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Mainchain blocks a BMM request is waited for before the template is
+// given up on.
+const DEFAULT_INCLUSION_WINDOW: usize = 3;
+const DEFAULT_MAX_TRANSACTIONS: usize = 1000;
+
+// A BMM request made on the mainchain for a block template.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BmmRequest {
+    pub critical_hash: BlockHash,
+    pub txid: bitcoin::Txid,
+    // First mainchain block the commitment can be included in.
+    pub main_height: usize,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BmmStatus {
+    Included(Anchor),
+    // Blocks of the inclusion window are still to be mined.
+    Pending,
+    Missed,
+}
+
+// Everything a block producer in another process needs: the block to mine
+// and the critical hash to commit to with a BMM request.
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub header: Header,
+    pub body: Body<Signature, Output>,
+    pub height: usize,
+    pub fees: Amount,
+    pub critical_hash: BlockHash,
+}
+
+// Produces blocks with blind merged mining: a block template is built from
+// the mempool, a commitment to its hash is submitted to the mainchain and
+// once a mainchain block includes it the block is connected and announced.
+//
+// The steps are separate methods, so callers sharing the chain between
+// threads don't have to hold it while waiting for the mainchain.
+#[derive(Debug, Clone)]
+pub struct Miner {
+    bmm_amount: bitcoin::Amount,
+    poll_interval: Duration,
+    inclusion_window: usize,
+    max_transactions: usize,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        Self {
+            bmm_amount: DEFAULT_BMM_AMOUNT,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            inclusion_window: DEFAULT_INCLUSION_WINDOW,
+            max_transactions: DEFAULT_MAX_TRANSACTIONS,
+        }
+    }
+}
+
+impl Miner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Paid to the mainchain miner that includes the commitment.
+    pub fn with_bmm_amount(mut self, bmm_amount: bitcoin::Amount) -> Self {
+        self.bmm_amount = bmm_amount;
+        self
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn with_inclusion_window(mut self, inclusion_window: usize) -> Self {
+        self.inclusion_window = inclusion_window;
+        self
+    }
+
+    pub fn with_max_transactions(mut self, max_transactions: usize) -> Self {
+        self.max_transactions = max_transactions;
+        self
+    }
+
+    // A block on top of the current tip with the highest fee mempool
+    // transactions, paying the fees to `coinbase_address`.
+    pub fn block_template(
+        &self,
+        blockchain: &BlockChain<Signature, Output>,
+        mempool: &mut MemPool,
+        coinbase_address: Address,
+    ) -> (Header, Body<Signature, Output>) {
+        // Transactions that went invalid since they were accepted would
+        // spoil the block.
+        mempool.revalidate(blockchain);
+        mempool.set_max_template_transactions(self.max_transactions);
+        let body = mempool.get_block_template(coinbase_address);
+        let prev_block_hash = blockchain
+            .get_best_block_hash()
+            .unwrap_or_else(|| Hash::default().into());
+        (Header::new(&prev_block_hash, &body), body)
+    }
+
+    pub fn get_block_template(
+        &self,
+        blockchain: &BlockChain<Signature, Output>,
+        mempool: &mut MemPool,
+        coinbase_address: Address,
+    ) -> BlockTemplate {
+        let (header, body) = self.block_template(blockchain, mempool, coinbase_address);
+        BlockTemplate {
+            height: blockchain.height() + 1,
+            fees: body.coinbase.iter().map(|output| output.value).sum(),
+            critical_hash: header.hash(),
+            header,
+            body,
+        }
+    }
+
+    // Commits to the block in the next mainchain block.
+    pub fn request_bmm<B: MainchainBackend>(
+        &self,
+        mainchain: &B,
+        header: &Header,
+    ) -> Result<BmmRequest, Error> {
+        let critical_hash = header.hash();
+        let height = mainchain.get_block_count()?;
+        let prev_main_block_hash = mainchain.get_block_hash(height)?;
+        let txid = mainchain.create_bmm_request(
+            &critical_hash,
+            self.bmm_amount,
+            height + 1,
+            &prev_main_block_hash,
+        )?;
+        Ok(BmmRequest {
+            critical_hash,
+            txid,
+            main_height: height + 1,
+        })
+    }
+
+    // Looks for the commitment in the mainchain blocks of the inclusion
+    // window mined so far, without waiting for the rest.
+    pub fn check_bmm<B: MainchainBackend>(
+        &self,
+        mainchain: &B,
+        request: &BmmRequest,
+    ) -> Result<BmmStatus, Error> {
+        let last_height = request.main_height + self.inclusion_window.saturating_sub(1);
+        let height = mainchain.get_block_count()?;
+        for main_height in request.main_height..=height.min(last_height) {
+            let main_block_hash = mainchain.get_block_hash(main_height)?;
+            match Anchor::verify(mainchain, &main_block_hash, &request.critical_hash) {
+                Ok(anchor) => return Ok(BmmStatus::Included(anchor)),
+                // The node couldn't be asked, not a missing commitment.
+                Err(err) if err.is_retryable() => return Err(err.into()),
+                Err(_) => {}
+            }
+        }
+        match height >= last_height {
+            true => Ok(BmmStatus::Missed),
+            false => Ok(BmmStatus::Pending),
+        }
+    }
+
+    // Blocks until one of the mainchain blocks in the inclusion window
+    // includes the commitment, None if all of them were mined without it.
+    pub fn wait_for_bmm<B: MainchainBackend>(
+        &self,
+        mainchain: &B,
+        request: &BmmRequest,
+    ) -> Result<Option<Anchor>, Error> {
+        loop {
+            match self.check_bmm(mainchain, request)? {
+                BmmStatus::Included(anchor) => return Ok(Some(anchor)),
+                BmmStatus::Missed => return Ok(None),
+                BmmStatus::Pending => std::thread::sleep(self.poll_interval),
+            }
+        }
+    }
+
+    // Connects a block with its verified BMM commitment, drops the
+    // transactions it confirmed or conflicts with from the mempool and
+    // announces it to every peer.
+    pub fn connect_block(
+        &self,
+        blockchain: &mut BlockChain<Signature, Output>,
+        mempool: &mut MemPool,
+        network: Option<&Network<Signature, Output>>,
+        header: Header,
+        body: Body<Signature, Output>,
+        anchor: Option<Anchor>,
+    ) -> Result<BlockHash, Error> {
+        let block_hash = header.hash();
+        // Another block may have been connected while waiting, or anchored
+        // to the same mainchain block.
+        if !blockchain.validate_block(&header, &body, anchor.as_ref()) {
+            return Err(Error::InvalidBlock(block_hash));
+        }
+        blockchain.connect_block(&header, &body, anchor.as_ref())?;
+        mempool.revalidate(blockchain);
+        if let Some(network) = network {
+            let main_block_hash = anchor.map(|anchor| anchor.main_block_hash());
+            network.relay(
+                None,
+                &Message::Block {
+                    header,
+                    body,
+                    main_block_hash,
+                },
+            );
+        }
+        Ok(block_hash)
+    }
+
+    // Runs every step once, returns None if the commitment wasn't included.
+    pub fn mine_block<B: MainchainBackend>(
+        &self,
+        mainchain: &B,
+        blockchain: &mut BlockChain<Signature, Output>,
+        mempool: &mut MemPool,
+        network: Option<&Network<Signature, Output>>,
+        coinbase_address: Address,
+    ) -> Result<Option<BlockHash>, Error> {
+        let (header, body) = self.block_template(blockchain, mempool, coinbase_address);
+        let request = self.request_bmm(mainchain, &header)?;
+        let Some(anchor) = self.wait_for_bmm(mainchain, &request)? else {
+            log::info!("bmm request {} wasn't included", request.txid);
+            return Ok(None);
+        };
+        let block_hash =
+            self.connect_block(blockchain, mempool, network, header, body, Some(anchor))?;
+        Ok(Some(block_hash))
+    }
+
+    // Mines blocks one after another until `shutdown` is set. Failures are
+    // logged and the next template is tried, the mainchain node may just be
+    // restarting.
+    pub fn run<B: MainchainBackend>(
+        &self,
+        mainchain: &B,
+        blockchain: &mut BlockChain<Signature, Output>,
+        mempool: &mut MemPool,
+        network: Option<&Network<Signature, Output>>,
+        coinbase_address: Address,
+        shutdown: &AtomicBool,
+    ) {
+        while !shutdown.load(Ordering::SeqCst) {
+            match self.mine_block(mainchain, blockchain, mempool, network, coinbase_address) {
+                Ok(Some(block_hash)) => log::info!("mined block {}", block_hash),
+                Ok(None) => {}
+                Err(err) => {
+                    log::warn!("failed to mine a block: {}", err);
+                    std::thread::sleep(self.poll_interval);
+                }
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("mainchain error")]
+    Mainchain(#[from] ClientError),
+    #[error("block {0} is no longer valid on top of the current tip")]
+    InvalidBlock(BlockHash),
+    #[error("bmm error")]
+    Bmm(#[from] BmmError),
+    #[error("blockchain error")]
+    BlockChain(#[from] BlockChainError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::VerifiedBMM;
+    use crate::mock_client::MockMainClient;
+    use crate::params::SidechainParams;
+    use crate::wallet::Wallet;
+    use std::collections::HashMap;
+
+    #[test]
+    fn blocks_are_connected_once_bmm_is_included() -> anyhow::Result<()> {
+        let mainchain = MockMainClient::new();
+        let miner = Miner::new()
+            .with_poll_interval(Duration::from_millis(1))
+            .with_inclusion_window(2);
+        let mut wallet = Wallet::default();
+        let address = wallet.generate_address();
+        let mut blockchain = BlockChain::<Signature, Output>::new().with_params(SidechainParams {
+            bmm: true,
+            ..SidechainParams::default()
+        });
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(bitcoin::OutPoint::default()),
+                DepositOutput {
+                    address,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+        wallet.add_deposit_outputs(&blockchain.peg.deposit_outputs);
+        let transaction = wallet.create_transaction(
+            vec![Output {
+                address,
+                value: Amount::from_sat(90),
+            }],
+            Amount::from_sat(10),
+        );
+        let transaction = transaction.unwrap();
+        let txid = transaction.txid();
+        let mut mempool = MemPool::default();
+        mempool.insert(blockchain.get_fee(&transaction).unwrap(), transaction);
+
+        let BlockTemplate {
+            header,
+            body,
+            height,
+            fees,
+            critical_hash,
+        } = miner.get_block_template(&blockchain, &mut mempool, address);
+        assert_eq!((height, fees), (1, Amount::from_sat(10)));
+        assert_eq!(critical_hash, header.hash());
+        let request = miner.request_bmm(&mainchain, &header)?;
+        assert_eq!(miner.check_bmm(&mainchain, &request)?, BmmStatus::Pending);
+        let main_block_hash = mainchain.mine_block();
+        let first_anchor = miner.wait_for_bmm(&mainchain, &request)?.unwrap();
+        assert_eq!(first_anchor.main_block_hash(), main_block_hash);
+        // A chain that requires BMM takes no block without a commitment.
+        assert!(!blockchain.validate_block(&header, &body, None));
+        let block_hash = miner.connect_block(
+            &mut blockchain,
+            &mut mempool,
+            None,
+            header,
+            body,
+            Some(first_anchor),
+        )?;
+        assert_eq!(blockchain.get_best_block_hash(), Some(block_hash));
+        assert_eq!(blockchain.get_anchor(&block_hash), Some(&first_anchor));
+        assert!(blockchain.get_transaction(&txid).is_some());
+        assert!(mempool.is_empty());
+
+        // A request made on a tip that got reorged out is never included.
+        let (header, body) = miner.block_template(&blockchain, &mut mempool, address);
+        mainchain.mine_block();
+        let request = miner.request_bmm(&mainchain, &header)?;
+        mainchain.disconnect_block();
+        mainchain.mine_block();
+        mainchain.mine_block();
+        mainchain.mine_block();
+        assert_eq!(miner.check_bmm(&mainchain, &request)?, BmmStatus::Missed);
+        assert_eq!(miner.wait_for_bmm(&mainchain, &request)?, None);
+        // Nor can a mainchain block anchor two sidechain blocks.
+        let (other_header, other_body) = miner.block_template(&blockchain, &mut mempool, address);
+        let verified_bmm = VerifiedBMM {
+            time: 0,
+            txid: request.txid,
+        };
+        mainchain.add_bmm(main_block_hash, other_header.hash(), verified_bmm);
+        let reused = Anchor::verify(&mainchain, &main_block_hash, &other_header.hash())?;
+        assert!(matches!(
+            miner.connect_block(
+                &mut blockchain,
+                &mut mempool,
+                None,
+                other_header.clone(),
+                other_body.clone(),
+                Some(reused),
+            ),
+            Err(Error::InvalidBlock(_))
+        ));
+        let request = miner.request_bmm(&mainchain, &other_header)?;
+        mainchain.mine_block();
+        let anchor = miner.wait_for_bmm(&mainchain, &request)?;
+        miner.connect_block(
+            &mut blockchain,
+            &mut mempool,
+            None,
+            other_header,
+            other_body,
+            anchor,
+        )?;
+        // The old template doesn't fit on a chain that moved on.
+        assert!(matches!(
+            miner.connect_block(&mut blockchain, &mut mempool, None, header, body, None),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        // Blocks an operator took out come back with their commitments.
+        let tip = blockchain.get_best_block_hash();
+        assert_eq!(blockchain.invalidate_block(block_hash)?.len(), 2);
+        assert_eq!(blockchain.get_anchor(&block_hash), None);
+        assert_eq!(blockchain.reconsider_block(&block_hash), 2);
+        assert_eq!(blockchain.get_best_block_hash(), tip);
+        assert_eq!(blockchain.get_anchor(&block_hash), Some(&first_anchor));
+        Ok(())
+    }
+}
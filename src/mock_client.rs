@@ -0,0 +1,357 @@
+use crate::backend::MainchainBackend;
+use crate::bmm::Anchor;
+use crate::bundle::Bundle;
+use crate::client::{Error, FailedWithdrawal, MainBlockHeader, SpentWithdrawal, VerifiedBMM};
+use crate::types::{
+    Address, Amount, BlockHash, Deposit, DepositOutput, DepositsChunk, FeeRate, OutPoint,
+};
+use bitcoin::hashes::Hash;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// In-memory stand in for a drivechain node. Everything is seeded by hand,
+// so unit tests can exercise code that talks to the mainchain without
+// running one.
+#[derive(Debug, Default)]
+pub struct MockMainClient {
+    state: RefCell<MockState>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    blocks: Vec<bitcoin::BlockHash>,
+    // Blocks that were reorged out, by height.
+    stale_blocks: HashMap<bitcoin::BlockHash, usize>,
+    mined: usize,
+    deposits: Vec<(Deposit, DepositOutput)>,
+    bmm: HashMap<(bitcoin::BlockHash, BlockHash), VerifiedBMM>,
+    // BMM requests for the next block: critical hash, request txid and the
+    // tip they were made on.
+    bmm_requests: Vec<(BlockHash, bitcoin::Txid, bitcoin::BlockHash)>,
+    spent_withdrawals: Vec<SpentWithdrawal>,
+    failed_withdrawals: Vec<FailedWithdrawal>,
+    // Hashes of the submitted bundles, once per submission.
+    submitted_bundles: Vec<bitcoin::Txid>,
+    fee_rate: Option<FeeRate>,
+}
+
+impl MockMainClient {
+    pub fn new() -> Self {
+        let client = Self::default();
+        client.mine_block();
+        client
+    }
+
+    pub fn mine_block(&self) -> bitcoin::BlockHash {
+        let mut state = self.state.borrow_mut();
+        let preimage = [b"block".as_slice(), &state.mined.to_le_bytes()].concat();
+        let block_hash = bitcoin::BlockHash::hash(&preimage);
+        state.mined += 1;
+        // Requests made on another tip can't go into this block.
+        let prev_block_hash = state.blocks.last().copied();
+        let height = state.blocks.len();
+        for (critical_hash, txid, prev) in std::mem::take(&mut state.bmm_requests) {
+            if Some(prev) == prev_block_hash {
+                let verified_bmm = VerifiedBMM {
+                    time: height as i64,
+                    txid,
+                };
+                state.bmm.insert((block_hash, critical_hash), verified_bmm);
+            }
+        }
+        state.blocks.push(block_hash);
+        block_hash
+    }
+
+    // Reorgs out the tip block together with the deposits included in it.
+    pub fn disconnect_block(&self) -> Option<bitcoin::BlockHash> {
+        let mut state = self.state.borrow_mut();
+        let block_hash = state.blocks.pop()?;
+        let height = state.blocks.len();
+        state.stale_blocks.insert(block_hash, height);
+        state
+            .deposits
+            .retain(|(deposit, _)| deposit.main_block_hash != block_hash);
+        Some(block_hash)
+    }
+
+    // The deposit is included in the current tip block.
+    pub fn add_deposit(&self, address: Address, value: u64) -> bitcoin::OutPoint {
+        let mut state = self.state.borrow_mut();
+        let main_block_hash = *state.blocks.last().expect("no blocks");
+        let preimage = [
+            b"deposit".as_slice(),
+            &state.deposits.len().to_le_bytes(),
+            &main_block_hash,
+        ]
+        .concat();
+        let outpoint = bitcoin::OutPoint {
+            txid: bitcoin::Txid::hash(&preimage),
+            vout: 0,
+        };
+        let prev_total = state
+            .deposits
+            .last()
+            .map_or(0, |(deposit, _)| deposit.total);
+        let deposit = Deposit {
+            outpoint,
+            total: prev_total + value,
+            main_block_hash,
+            main_height: state.blocks.len() - 1,
+        };
+        state.deposits.push((
+            deposit,
+            DepositOutput {
+                address,
+                value: Amount::from_sat(value),
+            },
+        ));
+        outpoint
+    }
+
+    // Mines a block with a BMM commitment to `block_hash` and returns the
+    // verified anchor for it.
+    pub fn anchor(&self, block_hash: &BlockHash) -> Anchor {
+        let prev_main_block_hash = *self.state.borrow().blocks.last().expect("no blocks");
+        self.create_bmm_request(block_hash, bitcoin::Amount::ZERO, 0, &prev_main_block_hash)
+            .expect("mock bmm requests can't fail");
+        let main_block_hash = self.mine_block();
+        Anchor::verify(self, &main_block_hash, block_hash).expect("commitment was just mined")
+    }
+
+    pub fn add_bmm(
+        &self,
+        main_block_hash: bitcoin::BlockHash,
+        critical_hash: BlockHash,
+        verified_bmm: VerifiedBMM,
+    ) {
+        self.state
+            .borrow_mut()
+            .bmm
+            .insert((main_block_hash, critical_hash), verified_bmm);
+    }
+
+    pub fn add_spent_withdrawal(&self, spent_withdrawal: SpentWithdrawal) {
+        self.state
+            .borrow_mut()
+            .spent_withdrawals
+            .push(spent_withdrawal);
+    }
+
+    pub fn add_failed_withdrawal(&self, failed_withdrawal: FailedWithdrawal) {
+        self.state
+            .borrow_mut()
+            .failed_withdrawals
+            .push(failed_withdrawal);
+    }
+
+    pub fn set_fee_rate(&self, fee_rate: Option<FeeRate>) {
+        self.state.borrow_mut().fee_rate = fee_rate;
+    }
+
+    pub fn submitted_bundles(&self) -> Vec<bitcoin::Txid> {
+        self.state.borrow().submitted_bundles.clone()
+    }
+}
+
+impl MainchainBackend for MockMainClient {
+    fn get_deposits(&self, last_deposit: Option<Deposit>) -> Result<DepositsChunk, Error> {
+        self.get_deposits_page(last_deposit, usize::MAX)
+    }
+
+    fn get_deposits_page(
+        &self,
+        last_deposit: Option<Deposit>,
+        limit: usize,
+    ) -> Result<DepositsChunk, Error> {
+        let state = self.state.borrow();
+        let start = match last_deposit {
+            Some(last_deposit) => state
+                .deposits
+                .iter()
+                .position(|(deposit, _)| *deposit == last_deposit)
+                .map_or(0, |position| position + 1),
+            None => 0,
+        };
+        let end = start.saturating_add(limit).min(state.deposits.len());
+        let new_deposits = &state.deposits[start..end];
+        let outputs = new_deposits
+            .iter()
+            .map(|(deposit, output)| (OutPoint::Deposit(deposit.outpoint), output.clone()))
+            .collect();
+        let deposits = new_deposits
+            .iter()
+            .map(|(deposit, _)| deposit.clone())
+            .collect();
+        Ok(DepositsChunk { outputs, deposits })
+    }
+
+    fn verify_bmm(
+        &self,
+        main_block_hash: &bitcoin::BlockHash,
+        critical_hash: &BlockHash,
+    ) -> Result<VerifiedBMM, Error> {
+        self.state
+            .borrow()
+            .bmm
+            .get(&(*main_block_hash, *critical_hash))
+            .cloned()
+            .ok_or(Error::Mock("bmm commitment not found"))
+    }
+
+    fn create_bmm_request(
+        &self,
+        critical_hash: &BlockHash,
+        _amount: bitcoin::Amount,
+        _height: usize,
+        prev_main_block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::Txid, Error> {
+        let mut state = self.state.borrow_mut();
+        let preimage = [
+            b"bmm".as_slice(),
+            &state.bmm_requests.len().to_le_bytes(),
+            &state.mined.to_le_bytes(),
+        ]
+        .concat();
+        let txid = bitcoin::Txid::hash(&preimage);
+        state
+            .bmm_requests
+            .push((*critical_hash, txid, *prev_main_block_hash));
+        Ok(txid)
+    }
+
+    fn get_block_count(&self) -> Result<usize, Error> {
+        Ok(self.state.borrow().blocks.len() - 1)
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<bitcoin::BlockHash, Error> {
+        self.state
+            .borrow()
+            .blocks
+            .get(height)
+            .copied()
+            .ok_or(Error::Mock("block height out of range"))
+    }
+
+    fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error> {
+        self.state
+            .borrow()
+            .blocks
+            .last()
+            .copied()
+            .ok_or(Error::Mock("no blocks"))
+    }
+
+    fn get_block_header(&self, block_hash: &bitcoin::BlockHash) -> Result<MainBlockHeader, Error> {
+        let state = self.state.borrow();
+        if let Some(&height) = state.stale_blocks.get(block_hash) {
+            return Ok(MainBlockHeader {
+                hash: *block_hash,
+                confirmations: -1,
+                height,
+                time: height as u64,
+                previousblockhash: height.checked_sub(1).map(|prev| state.blocks[prev]),
+                nextblockhash: None,
+            });
+        }
+        let height = state
+            .blocks
+            .iter()
+            .position(|hash| hash == block_hash)
+            .ok_or(Error::Mock("block not found"))?;
+        Ok(MainBlockHeader {
+            hash: *block_hash,
+            confirmations: (state.blocks.len() - height) as i64,
+            height,
+            time: height as u64,
+            previousblockhash: height.checked_sub(1).map(|prev| state.blocks[prev]),
+            nextblockhash: state.blocks.get(height + 1).copied(),
+        })
+    }
+
+    fn get_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error> {
+        Ok(self.state.borrow().spent_withdrawals.clone())
+    }
+
+    fn get_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error> {
+        Ok(self.state.borrow().failed_withdrawals.clone())
+    }
+
+    fn submit_bundle(&self, bundle: &Bundle) -> Result<bitcoin::Txid, Error> {
+        let hash = bundle.transaction().txid();
+        self.state.borrow_mut().submitted_bundles.push(hash);
+        Ok(hash)
+    }
+
+    fn estimate_smart_fee(&self, _conf_target: u16) -> Result<Option<FeeRate>, Error> {
+        Ok(self.state.borrow().fee_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::MainchainWatcher;
+
+    #[test]
+    fn watcher_receives_new_deposits() -> anyhow::Result<()> {
+        let client = MockMainClient::new();
+        let address: Address = [1; 32].into();
+        client.add_deposit(address, 100);
+        let mut received = vec![];
+        let mut watcher = MainchainWatcher::new(&client, None);
+        watcher.on_deposits(|deposits| received.push(deposits.clone()));
+        assert!(watcher.poll()?);
+        assert!(!watcher.poll()?);
+        client.add_deposit(address, 50);
+        client.mine_block();
+        assert!(watcher.poll()?);
+        drop(watcher);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].deposits.len(), 1);
+        assert_eq!(received[1].deposits[0].total, 150);
+        Ok(())
+    }
+
+    #[test]
+    fn deposit_pages_resume_from_cursor() -> anyhow::Result<()> {
+        let client = MockMainClient::new();
+        let address: Address = [1; 32].into();
+        for value in 1..=5 {
+            client.add_deposit(address, value);
+        }
+        let pages = client
+            .deposit_pages(None, 2)
+            .collect::<Result<Vec<_>, _>>()?;
+        let sizes: Vec<usize> = pages.iter().map(|page| page.deposits.len()).collect();
+        assert_eq!(sizes, [2, 2, 1]);
+        assert_eq!(pages[2].deposits[0].total, 15);
+        Ok(())
+    }
+
+    #[test]
+    fn watcher_disconnects_reorged_deposits() -> anyhow::Result<()> {
+        let client = MockMainClient::new();
+        let address: Address = [1; 32].into();
+        client.add_deposit(address, 100);
+        client.mine_block();
+        client.add_deposit(address, 50);
+        let mut disconnected = vec![];
+        let mut received = vec![];
+        let mut watcher = MainchainWatcher::new(&client, None);
+        watcher.on_deposits_disconnected(|deposits| disconnected.extend(deposits.clone()));
+        watcher.on_deposits(|deposits| received.extend(deposits.deposits.clone()));
+        watcher.poll()?;
+        client.disconnect_block();
+        client.mine_block();
+        client.add_deposit(address, 70);
+        client.mine_block();
+        watcher.poll()?;
+        drop(watcher);
+        assert_eq!(disconnected.len(), 1);
+        assert_eq!(disconnected[0].total, 150);
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[2].total, 170);
+        Ok(())
+    }
+}
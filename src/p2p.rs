@@ -0,0 +1,574 @@
+use crate::filter::BlockFilter;
+use crate::socks::{self, Proxy};
+use crate::types::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub const PROTOCOL_VERSION: u32 = 2;
+// Every message starts with these bytes, so nodes notice right away when
+// something else is talking to them.
+const MAGIC: [u8; 4] = *b"sdk\x01";
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long listeners sleep when no peer is connecting.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub type PeerId = u64;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub version: u32,
+    // Peers with a different genesis block are on a different chain and
+    // are disconnected during the handshake.
+    pub genesis: BlockHash,
+    pub best_height: u64,
+    pub user_agent: String,
+    // Port the peer accepts connections on, 0 if it doesn't. Together with
+    // the address it connected from this is what gets gossiped to others.
+    pub listen_port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message<S, O> {
+    Version(Version),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    // In the compact header encoding, so the size of a Headers message only
+    // depends on how many headers it has.
+    Headers(#[serde(with = "compact_headers")] Vec<Header>),
+    // With the mainchain block the block's BMM commitment is in, if it has
+    // one, receivers check it themselves.
+    Block {
+        header: Header,
+        body: Body<S, O>,
+        main_block_hash: Option<bitcoin::BlockHash>,
+    },
+    Transaction(Transaction<S, O>),
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    // Asks for the headers following the first hash of the locator the peer
+    // knows, see BlockChain::get_locator.
+    GetHeaders(Vec<BlockHash>),
+    // Asks for the blocks with these hashes, answered with a Block message
+    // each and NotFound for the ones the peer doesn't have.
+    GetBlocks(Vec<BlockHash>),
+    NotFound(Vec<BlockHash>),
+    // Announces transactions by txid, peers that don't have them yet ask
+    // for them with GetData and get a Transaction message each.
+    Inv(Vec<Txid>),
+    GetData(Vec<Txid>),
+    // Asks for the compact filters of these blocks, answered with the ones
+    // the peer has.
+    GetFilters(Vec<BlockHash>),
+    Filters(Vec<(BlockHash, BlockFilter)>),
+    // Asks for the header of the block each transaction is in with a merkle
+    // proof of it, answered for the transactions on the peer's best chain.
+    GetMerkleProofs(Vec<Txid>),
+    MerkleProofs(Vec<(Txid, Header, MerkleProof)>),
+}
+
+mod compact_headers {
+    use crate::types::{decode_compact_headers, encode_compact_headers, Header};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(headers: &[Header], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&encode_compact_headers(headers))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Header>, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        decode_compact_headers(&bytes).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug)]
+pub enum Event<S, O> {
+    Connected {
+        peer: PeerId,
+        addr: SocketAddr,
+        version: Version,
+    },
+    Message {
+        peer: PeerId,
+        message: Message<S, O>,
+    },
+    Disconnected {
+        peer: PeerId,
+    },
+}
+
+struct Peer {
+    addr: SocketAddr,
+    version: Version,
+    outbound: bool,
+    // Write half, the read half is owned by the peer's thread.
+    stream: TcpStream,
+}
+
+// TCP connections to other sidechain nodes. Every peer gets a thread that
+// reads its messages and answers pings, everything else is handed to the
+// node loop as events.
+pub struct Network<S, O> {
+    // Shared with the peer threads, so handshakes send the latest height.
+    version: Arc<Mutex<Version>>,
+    peers: Arc<Mutex<HashMap<PeerId, Peer>>>,
+    next_peer: Arc<AtomicU64>,
+    sender: Sender<Event<S, O>>,
+    receiver: Receiver<Event<S, O>>,
+    // Outbound connections go through it when set, inbound ones are
+    // unaffected.
+    proxy: Option<Proxy>,
+    // Set by shutdown, no peers are added after that.
+    closed: Arc<AtomicBool>,
+    listeners: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<S, O> Network<S, O>
+where
+    S: Serialize + DeserializeOwned + Send + 'static,
+    O: Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn new(version: Version) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            version: Arc::new(Mutex::new(version)),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            next_peer: Arc::new(AtomicU64::new(0)),
+            sender,
+            receiver,
+            proxy: None,
+            closed: Arc::default(),
+            listeners: Mutex::default(),
+        }
+    }
+
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    // Peers that connect from now on are told this height, so they know
+    // whether to download blocks from us.
+    pub fn set_best_height(&self, best_height: u64) {
+        self.version.lock().unwrap().best_height = best_height;
+    }
+
+    // Accepts inbound peers on a background thread. Returns the address
+    // actually bound, useful when binding to port 0.
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> Result<SocketAddr, Error> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        // Accepting doesn't block, so the thread notices shutdown.
+        listener.set_nonblocking(true)?;
+        let network = self.handle();
+        let listening = std::thread::spawn(move || {
+            while !network.closed.load(Ordering::SeqCst) {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        std::thread::sleep(ACCEPT_INTERVAL);
+                        continue;
+                    }
+                    Err(err) => {
+                        log::debug!("failed to accept peer: {}", err);
+                        continue;
+                    }
+                };
+                if let Err(err) = stream.set_nonblocking(false) {
+                    log::debug!("failed to accept peer: {}", err);
+                    continue;
+                }
+                let network = network.clone();
+                std::thread::spawn(move || {
+                    let added = stream
+                        .peer_addr()
+                        .map_err(Error::from)
+                        .and_then(|addr| network.add_peer(stream, addr, false));
+                    if let Err(err) = added {
+                        log::debug!("inbound handshake failed: {}", err);
+                    }
+                });
+            }
+        });
+        self.listeners.lock().unwrap().push(listening);
+        Ok(local_addr)
+    }
+
+    // Stops accepting peers and disconnects every one, returns once the
+    // listeners are closed.
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for listening in self.listeners.lock().unwrap().drain(..) {
+            let _ = listening.join();
+        }
+        for (_, peer) in self.peers.lock().unwrap().drain() {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    // With a proxy, host names are still resolved locally, peers are known
+    // by their socket address.
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> Result<PeerId, Error> {
+        let (stream, addr) = match &self.proxy {
+            Some(proxy) => {
+                let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "no address to connect to",
+                    )
+                })?;
+                (proxy.connect(&addr.ip().to_string(), addr.port())?, addr)
+            }
+            None => {
+                let stream = TcpStream::connect(addr)?;
+                let addr = stream.peer_addr()?;
+                (stream, addr)
+            }
+        };
+        self.handle().add_peer(stream, addr, true)
+    }
+
+    pub fn connect_timeout(&self, addr: &SocketAddr, timeout: Duration) -> Result<PeerId, Error> {
+        let stream = match &self.proxy {
+            Some(proxy) => proxy
+                .clone()
+                .with_connect_timeout(timeout)
+                .connect(&addr.ip().to_string(), addr.port())?,
+            None => TcpStream::connect_timeout(addr, timeout)?,
+        };
+        self.handle().add_peer(stream, *addr, true)
+    }
+
+    pub fn disconnect(&self, peer: PeerId) {
+        if let Some(peer) = self.peers.lock().unwrap().remove(&peer) {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn peers(&self) -> Vec<(PeerId, SocketAddr, Version)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, peer)| (*id, peer.addr, peer.version.clone()))
+            .collect()
+    }
+
+    pub fn outbound_count(&self) -> usize {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.outbound)
+            .count()
+    }
+
+    // Address other nodes can reach the peer at, None if it doesn't accept
+    // connections.
+    pub fn listen_addr(&self, peer: PeerId) -> Option<SocketAddr> {
+        let peers = self.peers.lock().unwrap();
+        let peer = peers.get(&peer)?;
+        if peer.version.listen_port == 0 {
+            return None;
+        }
+        Some(SocketAddr::new(peer.addr.ip(), peer.version.listen_port))
+    }
+
+    pub fn send(&self, peer: PeerId, message: &Message<S, O>) -> Result<(), Error> {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.get_mut(&peer).ok_or(Error::UnknownPeer(peer))?;
+        write_message(&mut peer.stream, message)
+    }
+
+    // Sends to every peer except `source`, the peer the message came from.
+    // Peers that can't be written to are disconnected.
+    pub fn relay(&self, source: Option<PeerId>, message: &Message<S, O>) {
+        let mut peers = self.peers.lock().unwrap();
+        let mut failed = vec![];
+        for (id, peer) in peers.iter_mut() {
+            if Some(*id) == source {
+                continue;
+            }
+            if let Err(err) = write_message(&mut peer.stream, message) {
+                log::debug!("failed to send to peer {}: {}", id, err);
+                failed.push(*id);
+            }
+        }
+        for id in failed {
+            if let Some(peer) = peers.remove(&id) {
+                let _ = peer.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    pub fn recv(&self) -> Option<Event<S, O>> {
+        self.receiver.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Event<S, O>> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    pub fn try_recv(&self) -> Option<Event<S, O>> {
+        self.receiver.try_recv().ok()
+    }
+
+    fn handle(&self) -> Handle<S, O> {
+        Handle {
+            version: self.version.clone(),
+            peers: self.peers.clone(),
+            next_peer: self.next_peer.clone(),
+            sender: self.sender.clone(),
+            closed: self.closed.clone(),
+        }
+    }
+}
+
+// The parts of Network peer threads need.
+struct Handle<S, O> {
+    version: Arc<Mutex<Version>>,
+    peers: Arc<Mutex<HashMap<PeerId, Peer>>>,
+    next_peer: Arc<AtomicU64>,
+    sender: Sender<Event<S, O>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl<S, O> Clone for Handle<S, O> {
+    fn clone(&self) -> Self {
+        Self {
+            version: self.version.clone(),
+            peers: self.peers.clone(),
+            next_peer: self.next_peer.clone(),
+            sender: self.sender.clone(),
+            closed: self.closed.clone(),
+        }
+    }
+}
+
+impl<S, O> Handle<S, O>
+where
+    S: Serialize + DeserializeOwned + Send + 'static,
+    O: Serialize + DeserializeOwned + Send + 'static,
+{
+    // Takes the address instead of asking the stream, which only knows the
+    // proxy's when there is one.
+    fn add_peer(
+        self,
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        outbound: bool,
+    ) -> Result<PeerId, Error> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let ours = self.version.lock().unwrap().clone();
+        let version = handshake::<S, O>(&mut stream, &ours)?;
+        stream.set_read_timeout(None)?;
+        let id = self.next_peer.fetch_add(1, Ordering::SeqCst);
+        let peer = Peer {
+            addr,
+            version: version.clone(),
+            outbound,
+            stream: stream.try_clone()?,
+        };
+        {
+            // Checked under the lock, so shutdown can't miss the peer.
+            let mut peers = self.peers.lock().unwrap();
+            if self.closed.load(Ordering::SeqCst) {
+                let _ = stream.shutdown(Shutdown::Both);
+                return Err(Error::Closed);
+            }
+            peers.insert(id, peer);
+        }
+        let _ = self.sender.send(Event::Connected {
+            peer: id,
+            addr,
+            version,
+        });
+        std::thread::spawn(move || {
+            if let Err(err) = self.read_messages(id, stream) {
+                log::debug!("peer {} disconnected: {}", id, err);
+            }
+            self.peers.lock().unwrap().remove(&id);
+            let _ = self.sender.send(Event::Disconnected { peer: id });
+        });
+        Ok(id)
+    }
+
+    fn read_messages(&self, id: PeerId, mut stream: TcpStream) -> Result<(), Error> {
+        loop {
+            let message = read_message::<S, O>(&mut stream)?;
+            match message {
+                Message::Ping(nonce) => {
+                    let mut peers = self.peers.lock().unwrap();
+                    if let Some(peer) = peers.get_mut(&id) {
+                        write_message::<S, O>(&mut peer.stream, &Message::Pong(nonce))?;
+                    }
+                }
+                Message::Version(_) | Message::Verack => return Err(Error::UnexpectedMessage),
+                message => {
+                    if self
+                        .sender
+                        .send(Event::Message { peer: id, message })
+                        .is_err()
+                    {
+                        // The network was dropped.
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Both sides send their version, check the other one and acknowledge it.
+fn handshake<S, O>(stream: &mut TcpStream, ours: &Version) -> Result<Version, Error>
+where
+    S: Serialize + DeserializeOwned,
+    O: Serialize + DeserializeOwned,
+{
+    write_message::<S, O>(stream, &Message::Version(ours.clone()))?;
+    let theirs = match read_message::<S, O>(stream)? {
+        Message::Version(version) => version,
+        _ => return Err(Error::UnexpectedMessage),
+    };
+    if theirs.version != ours.version {
+        return Err(Error::IncompatibleVersion(theirs.version));
+    }
+    if theirs.genesis != ours.genesis {
+        return Err(Error::GenesisMismatch(theirs.genesis));
+    }
+    write_message::<S, O>(stream, &Message::Verack)?;
+    match read_message::<S, O>(stream)? {
+        Message::Verack => Ok(theirs),
+        _ => Err(Error::UnexpectedMessage),
+    }
+}
+
+fn write_message<S: Serialize, O: Serialize>(
+    stream: &mut TcpStream,
+    message: &Message<S, O>,
+) -> Result<(), Error> {
+    let payload = bincode::serialize(message)?;
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(Error::MessageTooLarge(payload.len()));
+    }
+    let mut frame = Vec::with_capacity(8 + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    stream.write_all(&frame)?;
+    Ok(())
+}
+
+fn read_message<S: DeserializeOwned, O: DeserializeOwned>(
+    stream: &mut TcpStream,
+) -> Result<Message<S, O>, Error> {
+    let mut prefix = [0; 8];
+    stream.read_exact(&mut prefix)?;
+    if prefix[..4] != MAGIC {
+        return Err(Error::WrongMagic);
+    }
+    let len = u32::from_le_bytes(prefix[4..].try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(Error::MessageTooLarge(len));
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok(bincode::deserialize(&payload)?)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+    #[error("message of {0} bytes is too large")]
+    MessageTooLarge(usize),
+    #[error("peer is not speaking the sidechain protocol")]
+    WrongMagic,
+    #[error("unexpected message")]
+    UnexpectedMessage,
+    #[error("peer uses protocol version {0}")]
+    IncompatibleVersion(u32),
+    #[error("peer is on a chain with genesis block {0}")]
+    GenesisMismatch(BlockHash),
+    #[error("unknown peer {0}")]
+    UnknownPeer(PeerId),
+    #[error("proxy error")]
+    Proxy(#[from] socks::Error),
+    #[error("network is shut down")]
+    Closed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concrete::{Output, Signature};
+
+    fn version(genesis: Hash) -> Version {
+        Version {
+            version: PROTOCOL_VERSION,
+            genesis: genesis.into(),
+            best_height: 0,
+            user_agent: "test".into(),
+            listen_port: 0,
+        }
+    }
+
+    #[test]
+    fn transactions_are_relayed_between_peers() -> anyhow::Result<()> {
+        let timeout = Duration::from_secs(5);
+        let a = Network::<Signature, Output>::new(version([0; 32]));
+        let b = Network::<Signature, Output>::new(version([0; 32]));
+        let addr = a.listen("127.0.0.1:0")?;
+        let peer = b.connect(addr)?;
+        assert!(matches!(
+            a.recv_timeout(timeout),
+            Some(Event::Connected { .. })
+        ));
+        assert!(matches!(
+            b.recv_timeout(timeout),
+            Some(Event::Connected { .. })
+        ));
+        let transaction = Transaction {
+            version: TRANSACTION_VERSION,
+            inputs: vec![],
+            signatures: vec![],
+            outputs: vec![],
+            withdrawal_outputs: vec![],
+            extra: vec![1, 2, 3],
+        };
+        b.send(peer, &Message::Ping(7))?;
+        b.relay(None, &Message::Transaction(transaction.clone()));
+        assert!(matches!(
+            b.recv_timeout(timeout),
+            Some(Event::Message {
+                message: Message::Pong(7),
+                ..
+            })
+        ));
+        match a.recv_timeout(timeout) {
+            Some(Event::Message {
+                message: Message::Transaction(received),
+                ..
+            }) => assert_eq!(received.txid(), transaction.txid()),
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        let c = Network::<Signature, Output>::new(version([1; 32]));
+        assert!(matches!(c.connect(addr), Err(Error::GenesisMismatch(_))));
+        Ok(())
+    }
+}
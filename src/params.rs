@@ -0,0 +1,65 @@
+use crate::types::{Amount, THIS_SIDECHAIN};
+use serde::{Deserialize, Serialize};
+
+// Consensus constants of one sidechain deployment. Every node of a
+// sidechain has to run with the same values.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SidechainParams {
+    // Slot the sidechain occupies on the mainchain.
+    pub sidechain_number: usize,
+    // Number of mainchain blocks a deposit has to wait before it can be
+    // spent, so a shallow mainchain reorg can't take back coins that were
+    // already moved on. Counted up to the mainchain block of the sidechain
+    // tip's BMM commitment, so chains without BMM never mature deposits.
+    pub deposit_maturity: usize,
+    // Largest serialized block body.
+    pub max_block_size: usize,
+    // Outputs below this value are rejected, 0 allows any value.
+    pub dust_limit: Amount,
+    // Blocks have to carry a BMM commitment verified on the mainchain, see
+    // bmm::Anchor. Off for chains mined locally, like in tests.
+    pub bmm: bool,
+}
+
+impl Default for SidechainParams {
+    fn default() -> Self {
+        Self {
+            sidechain_number: THIS_SIDECHAIN,
+            deposit_maturity: 0,
+            max_block_size: 1_000_000,
+            dust_limit: Amount::ZERO,
+            bmm: false,
+        }
+    }
+}
+
+impl SidechainParams {
+    // Human readable part of bech32 addresses, sc0 for sidechain 0.
+    pub fn address_hrp(&self) -> String {
+        format!("sc{}", self.sidechain_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::concrete::{Output, Signature};
+    use crate::types::*;
+
+    #[test]
+    fn blocks_over_the_size_limit_are_rejected() {
+        let body = Body::<Signature, Output> {
+            coinbase: vec![],
+            transactions: vec![],
+        };
+        let header = Header::new(&Hash::default().into(), &body);
+        let blockchain = BlockChain::<Signature, Output>::new();
+        assert!(blockchain.validate_block(&header, &body, None));
+        let blockchain = BlockChain::<Signature, Output>::new().with_params(SidechainParams {
+            max_block_size: 1,
+            ..SidechainParams::default()
+        });
+        assert!(!blockchain.validate_block(&header, &body, None));
+    }
+}
@@ -0,0 +1,443 @@
+use crate::bundle::Bundle;
+use crate::client::{FailedWithdrawal, SpentWithdrawal};
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Where a withdrawal stands on its way to the mainchain. Bundles are
+// identified by the hash the mainchain tracks them under.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum WithdrawalStatus {
+    Created,
+    Bundled {
+        bundle: bitcoin::Txid,
+    },
+    Broadcast {
+        bundle: bitcoin::Txid,
+    },
+    Paid {
+        bundle: bitcoin::Txid,
+        main_block_hash: bitcoin::BlockHash,
+    },
+    // Miners rejected the bundle, the withdrawal can be bundled again.
+    Failed {
+        bundle: bitcoin::Txid,
+    },
+}
+
+impl WithdrawalStatus {
+    pub fn can_be_bundled(&self) -> bool {
+        matches!(self, Self::Created | Self::Failed { .. })
+    }
+
+    pub fn bundle(&self) -> Option<bitcoin::Txid> {
+        match self {
+            Self::Created => None,
+            Self::Bundled { bundle }
+            | Self::Broadcast { bundle }
+            | Self::Paid { bundle, .. }
+            | Self::Failed { bundle } => Some(*bundle),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Bundled { .. } => "bundled",
+            Self::Broadcast { .. } => "broadcast",
+            Self::Paid { .. } => "paid",
+            Self::Failed { .. } => "failed",
+        }
+    }
+}
+
+// Sidechain side of the two way peg: deposits coming in from the mainchain
+// and withdrawal outputs going back to it. BlockChain updates it while
+// connecting and disconnecting blocks.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TwoWayPegState {
+    // Deposits in the order they were made on the mainchain.
+    deposits: Vec<Deposit>,
+    pub deposit_outputs: HashMap<OutPoint, DepositOutput>,
+    pub withdrawal_outputs: HashMap<OutPoint, WithdrawalOutput>,
+    withdrawal_statuses: HashMap<OutPoint, WithdrawalStatus>,
+    bundles: HashMap<bitcoin::Txid, Vec<OutPoint>>,
+    // Sidechain height and value of every bundle that was cut.
+    bundle_history: Vec<(usize, bitcoin::Txid, Amount)>,
+    // Mainchain height every bundle waiting for its outcome was last
+    // broadcast at.
+    broadcasts: HashMap<bitcoin::Txid, usize>,
+}
+
+impl TwoWayPegState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn deposits(&self) -> &[Deposit] {
+        &self.deposits
+    }
+
+    // Cursor to pass to MainchainBackend::get_deposits.
+    pub fn last_deposit(&self) -> Option<&Deposit> {
+        self.deposits.last()
+    }
+
+    // Returns the outpoints of the new deposit outputs.
+    pub fn add_deposits(&mut self, deposits_chunk: DepositsChunk) -> Vec<OutPoint> {
+        let outpoints = deposits_chunk.outputs.keys().copied().collect();
+        self.deposit_outputs.extend(deposits_chunk.outputs);
+        self.deposits.extend(deposits_chunk.deposits);
+        outpoints
+    }
+
+    // Returns the outpoints of the removed deposit outputs.
+    pub fn disconnect_deposits(&mut self, deposits: &[Deposit]) -> Vec<OutPoint> {
+        let outpoints = deposits
+            .iter()
+            .map(|deposit| OutPoint::Deposit(deposit.outpoint))
+            .filter(|outpoint| self.deposit_outputs.remove(outpoint).is_some())
+            .collect();
+        self.deposits.retain(|deposit| !deposits.contains(deposit));
+        outpoints
+    }
+
+    pub fn connect_withdrawals(
+        &mut self,
+        txid: Txid,
+        withdrawal_outputs: &[WithdrawalOutput],
+    ) -> Vec<OutPoint> {
+        withdrawal_outputs
+            .iter()
+            .enumerate()
+            .map(|(vout, output)| {
+                let outpoint = OutPoint::Withdrawal {
+                    txid,
+                    vout: vout as u32,
+                };
+                self.withdrawal_outputs.insert(outpoint, output.clone());
+                self.withdrawal_statuses
+                    .insert(outpoint, WithdrawalStatus::Created);
+                outpoint
+            })
+            .collect()
+    }
+
+    // Bundles lose the disconnected withdrawals, and are forgotten once
+    // none are left.
+    pub fn disconnect_withdrawals(&mut self, txid: Txid, count: usize) -> Vec<OutPoint> {
+        let outpoints: Vec<OutPoint> = (0..count as u32)
+            .map(|vout| OutPoint::Withdrawal { txid, vout })
+            .filter(|outpoint| {
+                self.withdrawal_statuses.remove(outpoint);
+                self.withdrawal_outputs.remove(outpoint).is_some()
+            })
+            .collect();
+        for bundled in self.bundles.values_mut() {
+            bundled.retain(|outpoint| !outpoints.contains(outpoint));
+        }
+        let emptied: Vec<bitcoin::Txid> = self
+            .bundles
+            .iter()
+            .filter(|(_, bundled)| bundled.is_empty())
+            .map(|(bundle, _)| *bundle)
+            .collect();
+        for bundle in emptied {
+            self.bundles.remove(&bundle);
+            self.broadcasts.remove(&bundle);
+        }
+        outpoints
+    }
+
+    pub fn withdrawal_status(&self, outpoint: &OutPoint) -> Option<WithdrawalStatus> {
+        self.withdrawal_statuses.get(outpoint).copied()
+    }
+
+    pub fn withdrawals_with_status(
+        &self,
+        filter: impl Fn(&WithdrawalStatus) -> bool,
+    ) -> Vec<(OutPoint, WithdrawalStatus)> {
+        self.withdrawal_statuses
+            .iter()
+            .filter(|(_, status)| filter(status))
+            .map(|(outpoint, status)| (*outpoint, *status))
+            .collect()
+    }
+
+    pub fn bundle_withdrawals(&self, bundle: &bitcoin::Txid) -> Option<&[OutPoint]> {
+        self.bundles.get(bundle).map(Vec::as_slice)
+    }
+
+    pub fn mark_bundled(
+        &mut self,
+        hash: bitcoin::Txid,
+        bundle: &Bundle,
+        height: usize,
+    ) -> Result<(), Error> {
+        for outpoint in &bundle.outpoints {
+            let status = self
+                .withdrawal_statuses
+                .get(outpoint)
+                .ok_or(Error::UnknownWithdrawal(*outpoint))?;
+            if !status.can_be_bundled() {
+                return Err(Error::InvalidTransition(*outpoint, *status));
+            }
+        }
+        for outpoint in &bundle.outpoints {
+            self.withdrawal_statuses
+                .insert(*outpoint, WithdrawalStatus::Bundled { bundle: hash });
+        }
+        self.bundles.insert(hash, bundle.outpoints.clone());
+        self.bundle_history
+            .push((height, hash, Amount::from_sat(bundle.value())));
+        Ok(())
+    }
+
+    // Number and total value of bundles cut at or after `height`.
+    pub fn bundled_since(&self, height: usize) -> (usize, Amount) {
+        self.bundle_history
+            .iter()
+            .rev()
+            .take_while(|(bundled_at, _, _)| *bundled_at >= height)
+            .fold((0, Amount::ZERO), |(count, total), (_, _, value)| {
+                (count + 1, total + *value)
+            })
+    }
+
+    // Broadcasting a bundle again only restarts its clock.
+    pub fn mark_broadcast(
+        &mut self,
+        bundle: bitcoin::Txid,
+        main_height: usize,
+    ) -> Result<(), Error> {
+        self.transition(bundle, |status| match status {
+            WithdrawalStatus::Bundled { bundle } | WithdrawalStatus::Broadcast { bundle } => {
+                Some(WithdrawalStatus::Broadcast { bundle })
+            }
+            _ => None,
+        })?;
+        self.broadcasts.insert(bundle, main_height);
+        Ok(())
+    }
+
+    // Bundles that were broadcast at least `timeout` mainchain blocks before
+    // `main_height` and neither paid out nor failed since, the mainchain
+    // node most likely never got them. They are rebroadcast as they are:
+    // withdrawals offer fixed fees, so a rebuilt bundle can't pay more, and
+    // it would pay out twice if the lost one turned up after all.
+    pub fn stale_bundles(
+        &self,
+        main_height: usize,
+        timeout: usize,
+    ) -> Vec<(bitcoin::Txid, Bundle)> {
+        let mut stale: Vec<_> = self
+            .broadcasts
+            .iter()
+            .filter(|(_, broadcast_at)| main_height >= *broadcast_at + timeout)
+            .filter_map(|(hash, _)| Some((*hash, self.bundle(hash)?)))
+            .collect();
+        stale.sort_by_key(|(hash, _)| *hash);
+        stale
+    }
+
+    // Bundled or broadcast, and neither paid out nor failed since.
+    pub fn is_outstanding(&self, bundle: &bitcoin::Txid) -> bool {
+        self.bundle_withdrawals(bundle)
+            .unwrap_or_default()
+            .iter()
+            .any(|outpoint| match self.withdrawal_status(outpoint) {
+                Some(WithdrawalStatus::Bundled { bundle: hash })
+                | Some(WithdrawalStatus::Broadcast { bundle: hash }) => hash == *bundle,
+                _ => false,
+            })
+    }
+
+    // The bundle as it was cut, from the withdrawals it pays out.
+    pub fn bundle(&self, hash: &bitcoin::Txid) -> Option<Bundle> {
+        let outpoints = self.bundles.get(hash)?.clone();
+        let mut outputs = vec![];
+        let mut fee = 0;
+        for outpoint in &outpoints {
+            let withdrawal = self.withdrawal_outputs.get(outpoint)?;
+            outputs.push(bitcoin::TxOut {
+                value: withdrawal.value.to_sat(),
+                script_pubkey: withdrawal.main_address.script_pubkey(),
+            });
+            fee += withdrawal.fee.to_sat();
+        }
+        Some(Bundle {
+            outpoints,
+            outputs,
+            fee,
+        })
+    }
+
+    pub fn mark_paid(&mut self, spent_withdrawal: &SpentWithdrawal) -> Result<(), Error> {
+        self.transition(spent_withdrawal.hash, |status| match status {
+            WithdrawalStatus::Bundled { bundle } | WithdrawalStatus::Broadcast { bundle } => {
+                Some(WithdrawalStatus::Paid {
+                    bundle,
+                    main_block_hash: spent_withdrawal.hashblock,
+                })
+            }
+            _ => None,
+        })?;
+        self.broadcasts.remove(&spent_withdrawal.hash);
+        Ok(())
+    }
+
+    pub fn mark_failed(&mut self, failed_withdrawal: &FailedWithdrawal) -> Result<(), Error> {
+        self.transition(failed_withdrawal.hash, |status| match status {
+            WithdrawalStatus::Bundled { bundle } | WithdrawalStatus::Broadcast { bundle } => {
+                Some(WithdrawalStatus::Failed { bundle })
+            }
+            _ => None,
+        })?;
+        self.broadcasts.remove(&failed_withdrawal.hash);
+        Ok(())
+    }
+
+    fn transition(
+        &mut self,
+        bundle: bitcoin::Txid,
+        next: impl Fn(WithdrawalStatus) -> Option<WithdrawalStatus>,
+    ) -> Result<(), Error> {
+        let outpoints = self
+            .bundles
+            .get(&bundle)
+            .ok_or(Error::UnknownBundle(bundle))?;
+        let mut updates = vec![];
+        for outpoint in outpoints {
+            let status = self
+                .withdrawal_statuses
+                .get(outpoint)
+                .copied()
+                .ok_or(Error::UnknownWithdrawal(*outpoint))?;
+            // A withdrawal that failed in this bundle may already be part of
+            // a newer one.
+            if status.bundle() != Some(bundle) {
+                continue;
+            }
+            let next = next(status).ok_or(Error::InvalidTransition(*outpoint, status))?;
+            updates.push((*outpoint, next));
+        }
+        self.withdrawal_statuses.extend(updates);
+        Ok(())
+    }
+
+    // Total value deposited from the mainchain, including deposits that were
+    // already spent on the sidechain.
+    pub fn total_deposited(&self) -> Amount {
+        self.deposit_outputs
+            .values()
+            .map(|output| output.value)
+            .sum()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("unknown withdrawal {0:?}")]
+    UnknownWithdrawal(OutPoint),
+    #[error("unknown bundle {0}")]
+    UnknownBundle(bitcoin::Txid),
+    #[error("withdrawal {0:?} can't leave state {1:?}")]
+    InvalidTransition(OutPoint, WithdrawalStatus),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::{cut_bundle, BundleLimits};
+    use bitcoin::hashes::Hash;
+    use std::str::FromStr;
+
+    #[test]
+    fn failed_withdrawals_can_be_bundled_again() -> anyhow::Result<()> {
+        let mut peg = TwoWayPegState::new();
+        let withdrawal = WithdrawalOutput {
+            value: Amount::from_sat(1000),
+            fee: Amount::from_sat(10),
+            side_address: [1; 32].into(),
+            main_address: bitcoin::Address::from_str(
+                "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+            )?,
+        };
+        let outpoints = peg.connect_withdrawals([1; 32].into(), &[withdrawal]);
+        let bundle = cut_bundle(&peg.withdrawal_outputs, &BundleLimits::default()).unwrap();
+        let hash = bitcoin::Txid::hash(b"bundle");
+        peg.mark_bundled(hash, &bundle, 1)?;
+        peg.mark_broadcast(hash, 100)?;
+        assert!(peg.mark_bundled(hash, &bundle, 1).is_err());
+        assert!(peg.stale_bundles(105, 6).is_empty());
+        assert_eq!(peg.stale_bundles(106, 6), [(hash, bundle.clone())]);
+        peg.mark_broadcast(hash, 106)?;
+        assert!(peg.stale_bundles(106, 6).is_empty());
+        peg.mark_failed(&FailedWithdrawal {
+            nsidechain: THIS_SIDECHAIN,
+            hash,
+        })?;
+        assert!(peg.stale_bundles(200, 6).is_empty());
+        assert_eq!(
+            peg.withdrawal_status(&outpoints[0]),
+            Some(WithdrawalStatus::Failed { bundle: hash })
+        );
+        let retry = bitcoin::Txid::hash(b"retry");
+        peg.mark_bundled(retry, &bundle, 2)?;
+        let main_block_hash = bitcoin::BlockHash::hash(b"block");
+        peg.mark_paid(&SpentWithdrawal {
+            nsidechain: THIS_SIDECHAIN,
+            hash: retry,
+            hashblock: main_block_hash,
+        })?;
+        assert_eq!(
+            peg.withdrawal_status(&outpoints[0]),
+            Some(WithdrawalStatus::Paid {
+                bundle: retry,
+                main_block_hash
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reorged_withdrawals_leave_their_bundle() -> anyhow::Result<()> {
+        let mut peg = TwoWayPegState::new();
+        let withdrawal = WithdrawalOutput {
+            value: Amount::from_sat(1000),
+            fee: Amount::from_sat(10),
+            side_address: [1; 32].into(),
+            main_address: bitcoin::Address::from_str(
+                "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+            )?,
+        };
+        let kept = peg.connect_withdrawals([1; 32].into(), std::slice::from_ref(&withdrawal));
+        let reorged = peg.connect_withdrawals([2; 32].into(), &[withdrawal]);
+        let bundle = cut_bundle(&peg.withdrawal_outputs, &BundleLimits::default()).unwrap();
+        let hash = bitcoin::Txid::hash(b"bundle");
+        peg.mark_bundled(hash, &bundle, 1)?;
+        peg.mark_broadcast(hash, 100)?;
+
+        assert_eq!(peg.disconnect_withdrawals([2; 32].into(), 1), reorged);
+        assert_eq!(peg.bundle_withdrawals(&hash), Some(kept.as_slice()));
+        let main_block_hash = bitcoin::BlockHash::hash(b"block");
+        peg.mark_paid(&SpentWithdrawal {
+            nsidechain: THIS_SIDECHAIN,
+            hash,
+            hashblock: main_block_hash,
+        })?;
+        assert_eq!(
+            peg.withdrawal_status(&kept[0]),
+            Some(WithdrawalStatus::Paid {
+                bundle: hash,
+                main_block_hash
+            })
+        );
+        assert_eq!(peg.withdrawal_status(&reorged[0]), None);
+
+        // A bundle without any withdrawals left is gone.
+        peg.disconnect_withdrawals([1; 32].into(), 1);
+        assert_eq!(peg.bundle_withdrawals(&hash), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,686 @@
+// Hashes, addresses, headers and merkle proofs, everything needed to check
+// that a transaction is committed to by a block. Only needs core and alloc,
+// so it builds without the std feature, e.g. for wasm32-unknown-unknown.
+// Transactions aren't here yet: their outpoints and withdrawal outputs embed
+// bitcoin types, and bitcoin 0.29 can't be built without std.
+use crate::encode;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use bech32::{FromBase32, ToBase32};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+pub const THIS_SIDECHAIN: usize = 0;
+
+const SHA256_LENGTH: usize = 32;
+// First character after the separator of bech32 addresses, so other kinds of
+// addresses can be told apart later on.
+const BECH32_ADDRESS_VERSION: u8 = 0;
+pub type Hash = [u8; SHA256_LENGTH];
+
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BlockHash(Hash);
+
+impl From<Hash> for BlockHash {
+    fn from(other: Hash) -> Self {
+        Self(other)
+    }
+}
+
+impl From<BlockHash> for Hash {
+    fn from(other: BlockHash) -> Self {
+        other.0
+    }
+}
+
+impl core::fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl core::fmt::Debug for BlockHash {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl core::str::FromStr for BlockHash {
+    type Err = hex::FromHexError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(hex::FromHex::from_hex(s)?))
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub struct MerkleRoot(Hash);
+
+impl From<Hash> for MerkleRoot {
+    fn from(other: Hash) -> Self {
+        Self(other)
+    }
+}
+
+impl core::fmt::Display for MerkleRoot {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl core::fmt::Debug for MerkleRoot {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl core::str::FromStr for MerkleRoot {
+    type Err = hex::FromHexError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(hex::FromHex::from_hex(s)?))
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Txid(Hash);
+
+impl From<Hash> for Txid {
+    fn from(other: Hash) -> Self {
+        Self(other)
+    }
+}
+
+impl From<Txid> for Hash {
+    fn from(other: Txid) -> Self {
+        other.0
+    }
+}
+
+impl core::fmt::Display for Txid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl core::fmt::Debug for Txid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl core::str::FromStr for Txid {
+    type Err = hex::FromHexError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(hex::FromHex::from_hex(s)?))
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Address(Hash);
+
+impl From<Hash> for Address {
+    fn from(other: Hash) -> Self {
+        Self(other)
+    }
+}
+
+impl From<Address> for Hash {
+    fn from(other: Address) -> Self {
+        other.0
+    }
+}
+
+impl Address {
+    pub fn to_deposit_string(self) -> String {
+        self.to_deposit_string_for(THIS_SIDECHAIN)
+    }
+
+    pub fn to_deposit_string_for(self, sidechain_number: usize) -> String {
+        format_deposit_address(sidechain_number, &self.to_string())
+    }
+
+    // Bech32m form, like sc01q... for sidechain 0. The hrp comes from
+    // SidechainParams::address_hrp, an invalid one, like an empty or mixed
+    // case one, is an error.
+    pub fn to_bech32(self, hrp: &str) -> Result<String, AddressError> {
+        let mut data = vec![bech32::u5::try_from_u8(BECH32_ADDRESS_VERSION).unwrap()];
+        data.extend(self.0.to_base32());
+        Ok(bech32::encode(hrp, data, bech32::Variant::Bech32m)?)
+    }
+
+    pub fn from_bech32(s: &str, hrp: &str) -> Result<Self, AddressError> {
+        let (found, data, variant) = bech32::decode(s)?;
+        if found != hrp {
+            return Err(AddressError::WrongHrp {
+                expected: hrp.into(),
+                found,
+            });
+        }
+        match data.split_first() {
+            Some((version, data))
+                if version.to_u8() == BECH32_ADDRESS_VERSION
+                    && variant == bech32::Variant::Bech32m =>
+            {
+                let bytes = Vec::<u8>::from_base32(data)?;
+                let len = bytes.len();
+                let address: Hash = bytes
+                    .try_into()
+                    .map_err(|_| AddressError::InvalidLength(len))?;
+                Ok(Address(address))
+            }
+            _ => Err(AddressError::UnsupportedVersion),
+        }
+    }
+
+    // Accepts both the base58 and the bech32 form, bech32 addresses have to
+    // be for `hrp`.
+    pub fn parse(s: &str, hrp: &str) -> Result<Self, AddressError> {
+        let is_bech32 = match s.rsplit_once('1') {
+            Some((prefix, _)) => prefix.eq_ignore_ascii_case(hrp),
+            None => false,
+        };
+        if is_bech32 {
+            return Self::from_bech32(&s.to_lowercase(), hrp);
+        }
+        match <Self as core::str::FromStr>::from_str(s) {
+            Ok(address) => Ok(address),
+            // Most likely an address of another sidechain.
+            Err(err) => match bech32::decode(s) {
+                Ok((found, _, _)) => Err(AddressError::WrongHrp {
+                    expected: hrp.into(),
+                    found,
+                }),
+                Err(_) => Err(err),
+            },
+        }
+    }
+}
+
+// Hashes and addresses are strings in human readable formats like JSON, the
+// same ones Display gives. Everything else, including the consensus encoding,
+// gets the raw bytes.
+fn serialize_bytes<S: serde::Serializer>(
+    value: &impl core::fmt::Display,
+    bytes: &Hash,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.collect_str(value)
+    } else {
+        bytes.serialize(serializer)
+    }
+}
+
+fn deserialize_bytes<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: core::str::FromStr + From<Hash>,
+    T::Err: core::fmt::Display,
+{
+    if deserializer.is_human_readable() {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    } else {
+        Hash::deserialize(deserializer).map(T::from)
+    }
+}
+
+impl Serialize for BlockHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_bytes(self, &self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_bytes(deserializer)
+    }
+}
+
+impl Serialize for MerkleRoot {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_bytes(self, &self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MerkleRoot {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_bytes(deserializer)
+    }
+}
+
+impl Serialize for Txid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_bytes(self, &self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Txid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_bytes(deserializer)
+    }
+}
+
+// Base58, addresses in the bech32 form need the hrp of the sidechain to
+// parse, see Address::parse.
+impl Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_bytes(self, &self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_bytes(deserializer)
+    }
+}
+
+// Errors here are written out by hand, thiserror 1 needs std.
+#[derive(Debug)]
+pub enum AddressError {
+    Base58(bs58::decode::Error),
+    Bech32(bech32::Error),
+    WrongHrp { expected: String, found: String },
+    UnsupportedVersion,
+    InvalidLength(usize),
+}
+
+impl core::fmt::Display for AddressError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Base58(err) => write!(f, "invalid base58 address: {}", err),
+            Self::Bech32(err) => write!(f, "invalid bech32 address: {}", err),
+            Self::WrongHrp { expected, found } => {
+                write!(f, "address is for {}, expected {}", found, expected)
+            }
+            Self::UnsupportedVersion => write!(f, "unsupported address version"),
+            Self::InvalidLength(len) => write!(f, "address must be 32 bytes, got {}", len),
+        }
+    }
+}
+
+impl core::error::Error for AddressError {}
+
+impl From<bs58::decode::Error> for AddressError {
+    fn from(err: bs58::decode::Error) -> Self {
+        Self::Base58(err)
+    }
+}
+
+impl From<bech32::Error> for AddressError {
+    fn from(err: bech32::Error) -> Self {
+        Self::Bech32(err)
+    }
+}
+
+fn format_deposit_address(sidechain_number: usize, address: &str) -> String {
+    let deposit_address: String = format!("s{}_{}_", sidechain_number, address);
+    let hash = hex::encode(sha2::Sha256::digest(deposit_address.as_bytes()));
+    let hash: String = hash[..6].into();
+    format!("{}{}", deposit_address, hash)
+}
+
+impl core::fmt::Display for Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let address = bs58::encode(self.0)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .with_check()
+            .into_string();
+        write!(f, "{}", address)
+    }
+}
+
+impl core::fmt::Debug for Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl core::str::FromStr for Address {
+    type Err = AddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let address = bs58::decode(s)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .with_check(None)
+            .into_vec()?;
+        // Addresses come from users, a wrong length mustn't panic.
+        let len = address.len();
+        let address: Hash = address
+            .try_into()
+            .map_err(|_| AddressError::InvalidLength(len))?;
+        Ok(Address(address))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub prev_block_hash: BlockHash,
+    pub merkle_root: MerkleRoot,
+    // Root of the application state after this block, for sidechains whose
+    // state machine can compute one.
+    pub state_root: Option<Hash>,
+}
+
+impl Header {
+    pub fn with_state_root(mut self, state_root: Hash) -> Self {
+        self.state_root = Some(state_root);
+        self
+    }
+
+    pub fn hash(&self) -> BlockHash {
+        self.hash_with::<Sha256>()
+    }
+
+    pub fn hash_with<H: Hasher>(&self) -> BlockHash {
+        tagged_hash_with::<H, _>(BLOCK_HASH_TAG, self).into()
+    }
+
+    // Fixed size encoding for exchanging and storing headers in bulk. Block
+    // hashes are still computed over the consensus encoding.
+    pub fn to_compact(&self) -> [u8; COMPACT_HEADER_SIZE] {
+        let mut bytes = [0; COMPACT_HEADER_SIZE];
+        bytes[..SHA256_LENGTH].copy_from_slice(&self.prev_block_hash.0);
+        bytes[SHA256_LENGTH..2 * SHA256_LENGTH].copy_from_slice(&self.merkle_root.0);
+        if let Some(state_root) = self.state_root {
+            bytes[2 * SHA256_LENGTH] = 1;
+            bytes[2 * SHA256_LENGTH + 1..].copy_from_slice(&state_root);
+        }
+        bytes
+    }
+
+    pub fn from_compact(bytes: &[u8; COMPACT_HEADER_SIZE]) -> Result<Self, HeaderError> {
+        let hash_at = |start: usize| -> Hash {
+            bytes[start..start + SHA256_LENGTH]
+                .try_into()
+                .expect("slice is a hash long")
+        };
+        let state_root = hash_at(2 * SHA256_LENGTH + 1);
+        // Anything but zeros after a missing state root would give the same
+        // header several encodings.
+        let state_root = match bytes[2 * SHA256_LENGTH] {
+            0 if state_root == Hash::default() => None,
+            0 => return Err(HeaderError::Padding),
+            1 => Some(state_root),
+            flag => return Err(HeaderError::StateRootFlag(flag)),
+        };
+        Ok(Self {
+            prev_block_hash: hash_at(0).into(),
+            merkle_root: hash_at(SHA256_LENGTH).into(),
+            state_root,
+        })
+    }
+}
+
+// Both hashes, a byte telling whether there is a state root and the state
+// root, zeros if there isn't one.
+pub const COMPACT_HEADER_SIZE: usize = 3 * SHA256_LENGTH + 1;
+
+pub fn encode_compact_headers(headers: &[Header]) -> Vec<u8> {
+    headers.iter().flat_map(Header::to_compact).collect()
+}
+
+pub fn decode_compact_headers(bytes: &[u8]) -> Result<Vec<Header>, HeaderError> {
+    if !bytes.len().is_multiple_of(COMPACT_HEADER_SIZE) {
+        return Err(HeaderError::Length(bytes.len()));
+    }
+    bytes
+        .chunks_exact(COMPACT_HEADER_SIZE)
+        .map(|chunk| Header::from_compact(chunk.try_into().expect("chunk is a header long")))
+        .collect()
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum HeaderError {
+    Length(usize),
+    StateRootFlag(u8),
+    Padding,
+}
+
+impl core::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Length(len) => write!(
+                f,
+                "compact headers must be a multiple of {} bytes, got {}",
+                COMPACT_HEADER_SIZE, len
+            ),
+            Self::StateRootFlag(flag) => write!(f, "invalid state root flag {}", flag),
+            Self::Padding => write!(f, "missing state root must be all zeros"),
+        }
+    }
+}
+
+impl core::error::Error for HeaderError {}
+
+// Proof that a transaction is a leaf of the merkle tree a header commits to.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    // Siblings of the nodes on the path from the txid up to the root.
+    pub siblings: Vec<Hash>,
+    // Index of the leaf, the coinbase is leaf 0 and the transactions follow
+    // it. Bit n of it tells whether the n-th sibling is on the left.
+    pub position: u32,
+}
+
+impl MerkleProof {
+    pub fn verify(&self, root: &MerkleRoot, txid: &Txid) -> bool {
+        self.verify_with::<Sha256>(root, txid)
+    }
+
+    pub fn verify_with<H: Hasher>(&self, root: &MerkleRoot, txid: &Txid) -> bool {
+        // Bits of the position past the height of the tree would be ignored,
+        // so several positions would verify.
+        if self.siblings.len() < 32 && self.position >> self.siblings.len() != 0 {
+            return false;
+        }
+        let mut node = Hash::from(*txid);
+        for (height, sibling) in self.siblings.iter().enumerate() {
+            let is_right = height < 32 && self.position >> height & 1 == 1;
+            node = if is_right {
+                merkle_parent::<H>(sibling, &node)
+            } else {
+                merkle_parent::<H>(&node, sibling)
+            };
+        }
+        MerkleRoot::from(node) == *root
+    }
+}
+
+// Root over the leaves of a body, the hash of its coinbase followed by its
+// txids, see Body::compute_merkle_root_with.
+pub fn merkle_root_with<H: Hasher>(leaves: Vec<Hash>) -> MerkleRoot {
+    let mut level = leaves;
+    while level.len() > 1 {
+        level = merkle_level_up::<H>(&level);
+    }
+    level.first().copied().unwrap_or_default().into()
+}
+
+// Inner nodes have their own hash domain, so they can't be passed off as
+// txids.
+fn merkle_parent<H: Hasher>(left: &Hash, right: &Hash) -> Hash {
+    tagged_hash_with::<H, _>(MERKLE_NODE_TAG, &(left, right))
+}
+
+// The last node of a level with an odd number of them is paired with a zero
+// hash. Pairing it with itself like bitcoin does would give a body with the
+// last transaction repeated the same root.
+pub(crate) fn merkle_level_up<H: Hasher>(level: &[Hash]) -> Vec<Hash> {
+    map_chunks(level, 2, |pair| {
+        merkle_parent::<H>(&pair[0], pair.get(1).unwrap_or(&Hash::default()))
+    })
+}
+
+#[cfg(feature = "rayon")]
+const MIN_PARALLEL_HASHES: usize = 256;
+
+// Hashes chunks of items, on all cores with the rayon feature once there are
+// enough of them to make up for handing them out to threads.
+pub(crate) fn map_chunks<T: Sync>(
+    items: &[T],
+    chunk_size: usize,
+    f: impl Fn(&[T]) -> Hash + Send + Sync,
+) -> Vec<Hash> {
+    #[cfg(feature = "rayon")]
+    if items.len() >= MIN_PARALLEL_HASHES {
+        use rayon::prelude::*;
+        return items.par_chunks(chunk_size).map(f).collect();
+    }
+    items.chunks(chunk_size).map(f).collect()
+}
+
+// Digest used for txids, block hashes and merkle roots. Every node of a
+// sidechain has to use the same one.
+pub trait Hasher {
+    fn digest(data: &[u8]) -> Hash;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256;
+
+impl Hasher for Sha256 {
+    fn digest(data: &[u8]) -> Hash {
+        sha2::Sha256::digest(data).into()
+    }
+}
+
+#[cfg(feature = "blake3")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3;
+
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3 {
+    fn digest(data: &[u8]) -> Hash {
+        blake3::hash(data).into()
+    }
+}
+
+pub fn hash<T: Serialize>(data: &T) -> Hash {
+    hash_with::<Sha256, T>(data)
+}
+
+// Tags of the hash domains consensus depends on. The same bytes hashed in two
+// domains give unrelated hashes, so a header can't be passed off as a
+// transaction or a signature for one thing replayed for another.
+pub const TXID_TAG: &str = "sdk/txid";
+pub const BLOCK_HASH_TAG: &str = "sdk/block";
+pub const MERKLE_NODE_TAG: &str = "sdk/merkle";
+pub const COINBASE_TAG: &str = "sdk/coinbase";
+pub const SIGHASH_TAG: &str = "sdk/sighash";
+
+// BIP340 style tagged hash, the digest of the tag's digest twice followed by
+// the data.
+pub fn tagged_hash_with<H: Hasher, T: Serialize>(tag: &str, data: &T) -> Hash {
+    H::digest(&tagged_preimage::<H, _>(tag, data))
+}
+
+pub(crate) fn tagged_preimage<H: Hasher, T: Serialize>(tag: &str, data: &T) -> Vec<u8> {
+    let encoded = encode::to_vec(data).expect("failed to serialize a type to compute a hash");
+    tagged_preimage_encoded::<H>(tag, &encoded)
+}
+
+// For data that is already consensus encoded.
+pub(crate) fn tagged_preimage_encoded<H: Hasher>(tag: &str, encoded: &[u8]) -> Vec<u8> {
+    let tag = H::digest(tag.as_bytes());
+    let mut preimage = Vec::with_capacity(2 * tag.len() + encoded.len());
+    preimage.extend_from_slice(&tag);
+    preimage.extend_from_slice(&tag);
+    preimage.extend_from_slice(encoded);
+    preimage
+}
+
+pub fn hash_with<H: Hasher, T: Serialize>(data: &T) -> Hash {
+    let data_serialized =
+        encode::to_vec(data).expect("failed to serialize a type to compute a hash");
+    H::digest(&data_serialized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_round_trip_in_both_forms() {
+        let address: Address = [7; 32].into();
+        let bech32 = address.to_bech32("sc0").unwrap();
+        assert!(bech32.starts_with("sc01q"));
+        assert!(matches!(
+            address.to_bech32("Sc0"),
+            Err(AddressError::Bech32(_))
+        ));
+        assert!(address.to_bech32("").is_err());
+        assert!(address.to_bech32("sc\u{e9}").is_err());
+        assert_eq!(Address::parse(&bech32, "sc0").unwrap(), address);
+        assert_eq!(
+            Address::parse(&bech32.to_uppercase(), "sc0").unwrap(),
+            address
+        );
+        assert_eq!(
+            Address::parse(&address.to_string(), "sc0").unwrap(),
+            address
+        );
+        assert!(matches!(
+            Address::parse(&bech32, "sc1"),
+            Err(AddressError::WrongHrp { .. })
+        ));
+        // A single typo breaks the checksum.
+        let mut typo = bech32.into_bytes();
+        let last = typo.len() - 1;
+        typo[last] = if typo[last] == b'q' { b'p' } else { b'q' };
+        let typo = String::from_utf8(typo).unwrap();
+        assert!(matches!(
+            Address::parse(&typo, "sc0"),
+            Err(AddressError::Bech32(_))
+        ));
+        assert!(Address::parse("not an address", "sc0").is_err());
+        let short = bs58::encode([7; 31])
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .with_check()
+            .into_string();
+        assert!(matches!(
+            short.parse::<Address>(),
+            Err(AddressError::InvalidLength(31))
+        ));
+        let mut typo = address.to_string().into_bytes();
+        typo[0] = if typo[0] == b'2' { b'3' } else { b'2' };
+        assert!(matches!(
+            String::from_utf8(typo).unwrap().parse::<Address>(),
+            Err(AddressError::Base58(_))
+        ));
+    }
+
+    #[test]
+    fn compact_headers_have_a_fixed_size() {
+        let header = Header {
+            prev_block_hash: [1; 32].into(),
+            merkle_root: [2; 32].into(),
+            state_root: None,
+        };
+        let with_state_root = header.clone().with_state_root([3; 32]);
+        let bytes = encode_compact_headers(&[header.clone(), with_state_root.clone()]);
+        assert_eq!(bytes.len(), 2 * COMPACT_HEADER_SIZE);
+        let decoded = decode_compact_headers(&bytes).unwrap();
+        assert_eq!(decoded[0].hash(), header.hash());
+        assert_eq!(decoded[1].hash(), with_state_root.hash());
+        assert_eq!(decoded[1].state_root, Some([3; 32]));
+
+        assert_eq!(
+            decode_compact_headers(&bytes[1..]).unwrap_err(),
+            HeaderError::Length(2 * COMPACT_HEADER_SIZE - 1)
+        );
+        let mut padded = header.to_compact();
+        padded[COMPACT_HEADER_SIZE - 1] = 1;
+        assert_eq!(
+            Header::from_compact(&padded).unwrap_err(),
+            HeaderError::Padding
+        );
+        let mut flagged = with_state_root.to_compact();
+        flagged[64] = 2;
+        assert_eq!(
+            Header::from_compact(&flagged).unwrap_err(),
+            HeaderError::StateRootFlag(2)
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::backend::MainchainBackend;
+use crate::client::{Client, Error};
+use crate::retry::RetryConfig;
+use serde_json::json;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const RPC_USER: &str = "user";
+const RPC_PASSWORD: &str = "password";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+// Coinbase outputs can only be spent after 100 blocks.
+const COINBASE_MATURITY: usize = 100;
+// Upper bound on blocks mined while waiting for a sidechain proposal to
+// be activated.
+const MAX_ACTIVATION_BLOCKS: usize = 2_000;
+
+static NEXT_NODE: AtomicUsize = AtomicUsize::new(0);
+
+// A drivechain daemon running in regtest mode with a throwaway data
+// directory, for end to end tests. The node is stopped and its data
+// directory is removed when this is dropped.
+//
+// The daemon binary is taken from the DRIVECHAIND environment variable and
+// defaults to `drivechaind` from PATH.
+pub struct RegtestNode {
+    pub client: Client,
+    process: Child,
+    datadir: PathBuf,
+    mining_address: String,
+}
+
+impl RegtestNode {
+    pub fn start(this_sidechain: usize) -> Result<Self, Error> {
+        let datadir = std::env::temp_dir().join(format!(
+            "sdk-regtest-{}-{}",
+            std::process::id(),
+            NEXT_NODE.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&datadir)?;
+        let port = free_port()?;
+        let daemon = std::env::var("DRIVECHAIND").unwrap_or_else(|_| "drivechaind".into());
+        let process = Command::new(daemon)
+            .arg("-regtest")
+            .arg("-server")
+            .arg("-listen=0")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-rpcport={}", port))
+            .arg(format!("-rpcuser={}", RPC_USER))
+            .arg(format!("-rpcpassword={}", RPC_PASSWORD))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let client = Client::new(this_sidechain, "127.0.0.1", port, RPC_USER, RPC_PASSWORD)
+            .with_retry(RetryConfig::none());
+        let mut node = Self {
+            client,
+            process,
+            datadir,
+            mining_address: String::new(),
+        };
+        node.wait_until_ready()?;
+        node.mining_address = node.client.send_request("getnewaddress", &[])?;
+        Ok(node)
+    }
+
+    fn wait_until_ready(&mut self) -> Result<(), Error> {
+        let started = Instant::now();
+        loop {
+            match self.client.get_block_count() {
+                Ok(_) => return Ok(()),
+                Err(err) if started.elapsed() < STARTUP_TIMEOUT => {
+                    if let Some(status) = self.process.try_wait()? {
+                        log::error!("drivechaind exited with {}", status);
+                        return Err(err);
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn mine(&self, blocks: usize) -> Result<Vec<bitcoin::BlockHash>, Error> {
+        self.client.send_request(
+            "generatetoaddress",
+            &[json!(blocks), json!(self.mining_address)],
+        )
+    }
+
+    // Mines enough blocks for the wallet to have spendable coins.
+    pub fn fund(&self) -> Result<(), Error> {
+        self.mine(COINBASE_MATURITY + 1)?;
+        Ok(())
+    }
+
+    // Proposes this sidechain and mines blocks until miners have activated
+    // it.
+    pub fn activate_sidechain(&self, title: &str, description: &str) -> Result<(), Error> {
+        let this_sidechain = self.client.this_sidechain;
+        self.client.send_request::<serde_json::Value>(
+            "createsidechainproposal",
+            &[json!(this_sidechain), json!(title), json!(description)],
+        )?;
+        for _ in 0..MAX_ACTIVATION_BLOCKS {
+            self.mine(1)?;
+            let active: Vec<serde_json::Value> =
+                self.client.send_request("listactivesidechains", &[])?;
+            let activated = active
+                .iter()
+                .any(|sidechain| sidechain["nsidechain"] == json!(this_sidechain));
+            if activated {
+                return Ok(());
+            }
+        }
+        Err(Error::Regtest("sidechain was not activated"))
+    }
+
+    // Creates a deposit and mines a block including it.
+    pub fn deposit(
+        &self,
+        deposit_address: &str,
+        amount: bitcoin::Amount,
+    ) -> Result<bitcoin::Txid, Error> {
+        let fee = bitcoin::Amount::from_sat(10_000);
+        let txid = self.client.create_deposit(deposit_address, amount, fee)?;
+        self.mine(1)?;
+        Ok(txid)
+    }
+}
+
+impl Drop for RegtestNode {
+    fn drop(&mut self) {
+        if self
+            .client
+            .send_request::<serde_json::Value>("stop", &[])
+            .is_ok()
+        {
+            let started = Instant::now();
+            while started.elapsed() < STARTUP_TIMEOUT {
+                if let Ok(Some(_)) = self.process.try_wait() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+        if let Ok(None) = self.process.try_wait() {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+        let _ = std::fs::remove_dir_all(&self.datadir);
+    }
+}
+
+fn free_port() -> Result<u16, Error> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Address;
+
+    #[test]
+    #[ignore = "requires a drivechaind binary"]
+    fn deposits_show_up_on_the_sidechain() -> anyhow::Result<()> {
+        let node = RegtestNode::start(0)?;
+        node.fund()?;
+        node.activate_sidechain("sdk", "regtest harness")?;
+        let address: Address = [1; 32].into();
+        let txid = node.deposit(
+            &address.to_deposit_string(),
+            bitcoin::Amount::from_sat(100_000),
+        )?;
+        let deposits = node.client.get_deposits(None)?;
+        assert_eq!(deposits.deposits.len(), 1);
+        assert_eq!(deposits.deposits[0].outpoint.txid, txid);
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+use crate::backend::MainchainBackend;
+use crate::blockchain::BlockChain;
+use crate::bmm::Anchor;
+use crate::concrete::{Output, Signature};
+use crate::fork_choice::BlockTree;
+use crate::mempool::MemPool;
+use crate::p2p::{Message, Network, PeerId};
+use crate::types::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+// Txids remembered per peer, the oldest are forgotten first.
+const MAX_KNOWN_INVENTORY: usize = 5000;
+// A transaction asked for and not received in this time is asked for again
+// when the next peer announces it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Transactions a peer is known to have, because it announced or sent them
+// or we did.
+#[derive(Debug, Default)]
+struct KnownInventory {
+    txids: HashSet<Txid>,
+    order: VecDeque<Txid>,
+}
+
+impl KnownInventory {
+    fn contains(&self, txid: &Txid) -> bool {
+        self.txids.contains(txid)
+    }
+
+    fn insert(&mut self, txid: Txid) {
+        if !self.txids.insert(txid) {
+            return;
+        }
+        self.order.push_back(txid);
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.txids.remove(&oldest);
+            }
+        }
+    }
+}
+
+// Propagates mempool transactions: new ones are announced by txid with Inv,
+// peers ask for the ones they're missing with GetData. Every txid is only
+// announced to a peer once.
+#[derive(Debug, Default)]
+pub struct TransactionRelay {
+    known: HashMap<PeerId, KnownInventory>,
+    requested: HashMap<Txid, Instant>,
+}
+
+impl TransactionRelay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Announces to every peer except `source` the transactions it doesn't
+    // know about yet.
+    pub fn announce(
+        &mut self,
+        network: &Network<Signature, Output>,
+        source: Option<PeerId>,
+        txids: &[Txid],
+    ) {
+        for (peer, _, _) in network.peers() {
+            if Some(peer) == source {
+                continue;
+            }
+            let known = self.known.entry(peer).or_default();
+            let unknown: Vec<Txid> = txids
+                .iter()
+                .filter(|txid| !known.contains(txid))
+                .copied()
+                .collect();
+            if unknown.is_empty() {
+                continue;
+            }
+            if let Err(err) = network.send(peer, &Message::Inv(unknown.clone())) {
+                log::debug!("failed to announce transactions to {}: {}", peer, err);
+                continue;
+            }
+            for txid in unknown {
+                known.insert(txid);
+            }
+        }
+    }
+
+    // Handles Inv, GetData and Transaction messages, transactions that pass
+    // validation go into the mempool and are announced to the other peers.
+    // Returns false for any other message.
+    pub fn handle<H: Hasher>(
+        &mut self,
+        network: &Network<Signature, Output>,
+        blockchain: &BlockChain<Signature, Output, H>,
+        mempool: &mut MemPool,
+        peer: PeerId,
+        message: &Message<Signature, Output>,
+    ) -> bool {
+        match message {
+            Message::Inv(txids) => {
+                let known = self.known.entry(peer).or_default();
+                let mut wanted = vec![];
+                for txid in txids {
+                    known.insert(*txid);
+                    let requested = self
+                        .requested
+                        .get(txid)
+                        .is_some_and(|requested_at| requested_at.elapsed() < REQUEST_TIMEOUT);
+                    if !requested && !mempool.contains(txid) {
+                        self.requested.insert(*txid, Instant::now());
+                        wanted.push(*txid);
+                    }
+                }
+                if !wanted.is_empty() {
+                    if let Err(err) = network.send(peer, &Message::GetData(wanted)) {
+                        log::debug!("failed to request transactions from {}: {}", peer, err);
+                    }
+                }
+            }
+            Message::GetData(txids) => {
+                for txid in txids {
+                    let transaction = match mempool.get(txid) {
+                        Some(transaction) => transaction.clone(),
+                        None => continue,
+                    };
+                    if let Err(err) = network.send(peer, &Message::Transaction(transaction)) {
+                        log::debug!("failed to send transaction to {}: {}", peer, err);
+                        break;
+                    }
+                    self.known.entry(peer).or_default().insert(*txid);
+                }
+            }
+            Message::Transaction(transaction) => {
+                let txid = transaction.txid();
+                self.requested.remove(&txid);
+                self.known.entry(peer).or_default().insert(txid);
+                if mempool.contains(&txid) {
+                    return true;
+                }
+                if !transaction.is_standard_version() {
+                    log::debug!("peer {} sent non-standard transaction {}", peer, txid);
+                    return true;
+                }
+                if transaction
+                    .inputs
+                    .iter()
+                    .any(|outpoint| mempool.spends(outpoint))
+                {
+                    log::debug!("peer {} sent conflicting transaction {}", peer, txid);
+                    return true;
+                }
+                // May spend outputs of transactions already in the mempool.
+                let pending = mempool.outputs();
+                let fee = match blockchain
+                    .validate_transaction_with(transaction, &pending)
+                    .and_then(|()| blockchain.get_fee_with(transaction, &pending))
+                {
+                    Ok(fee) => fee,
+                    Err(err) => {
+                        log::debug!("peer {} sent invalid transaction {}: {}", peer, txid, err);
+                        return true;
+                    }
+                };
+                if FeeRate::new(fee, transaction.vsize()) < mempool.min_fee_rate() {
+                    log::debug!(
+                        "peer {} sent transaction {} below the minimum fee rate",
+                        peer,
+                        txid
+                    );
+                    return true;
+                }
+                mempool.insert(fee, transaction.clone());
+                if mempool.trim().contains(&txid) {
+                    return true;
+                }
+                self.announce(network, Some(peer), &[txid]);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Forgets what a disconnected peer knew.
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.known.remove(&peer);
+    }
+}
+
+// Relays blocks once the initial block download is done: a new block goes
+// into the block tree, the node switches to the best chain the tree knows
+// of and the block is passed on to the other peers. A block whose parent is
+// unknown makes us ask the sender for it, which is how a node that missed
+// blocks catches up. Peers answer those requests with ibd::serve.
+#[derive(Default)]
+pub struct BlockRelay {
+    tree: BlockTree<Signature, Output>,
+}
+
+impl BlockRelay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Handles Block messages, returns false for any other message. The BMM
+    // commitments peers send with blocks are checked against `mainchain`,
+    // a block whose commitment can't be verified is kept without it.
+    pub fn handle<B: MainchainBackend + ?Sized>(
+        &mut self,
+        network: &Network<Signature, Output>,
+        mainchain: &B,
+        blockchain: &mut BlockChain<Signature, Output>,
+        mempool: &mut MemPool,
+        peer: PeerId,
+        message: &Message<Signature, Output>,
+    ) -> bool {
+        let Message::Block {
+            header,
+            body,
+            main_block_hash,
+        } = message
+        else {
+            return false;
+        };
+        let block_hash = header.hash();
+        if self.tree.contains(&block_hash) || blockchain.get_header(&block_hash).is_some() {
+            return true;
+        }
+        let anchor = main_block_hash.and_then(|main_block_hash| {
+            match Anchor::verify(mainchain, &main_block_hash, &block_hash) {
+                Ok(anchor) => Some(anchor),
+                Err(err) => {
+                    log::debug!(
+                        "bmm commitment of block {} not verified: {}",
+                        block_hash,
+                        err
+                    );
+                    None
+                }
+            }
+        });
+        self.tree
+            .insert(blockchain, header.clone(), body.clone(), anchor);
+        let prev_block_hash = header.prev_block_hash;
+        let orphan = prev_block_hash != Hash::default().into()
+            && !self.tree.contains(&prev_block_hash)
+            && blockchain.get_header(&prev_block_hash).is_none();
+        if orphan {
+            if let Err(err) = network.send(peer, &Message::GetBlocks(vec![prev_block_hash])) {
+                log::debug!(
+                    "failed to ask {} for block {}: {}",
+                    peer,
+                    prev_block_hash,
+                    err
+                );
+            }
+        }
+        let reorg = self.tree.reorganize(blockchain);
+        // Transactions of the old chain that are still valid go back to the
+        // mempool, the ones confirmed by the new chain leave it.
+        mempool.revalidate(blockchain);
+        for block_hash in &reorg.disconnected {
+            let Some((_, body)) = self.tree.get(block_hash) else {
+                continue;
+            };
+            for transaction in &body.transactions {
+                if transaction
+                    .inputs
+                    .iter()
+                    .any(|outpoint| mempool.spends(outpoint))
+                {
+                    continue;
+                }
+                let pending = mempool.outputs();
+                if let Ok(fee) = blockchain
+                    .validate_transaction_with(transaction, &pending)
+                    .and_then(|()| blockchain.get_fee_with(transaction, &pending))
+                {
+                    mempool.insert(fee, transaction.clone());
+                }
+            }
+        }
+        if self.tree.is_invalid(&block_hash) {
+            log::debug!("peer {} sent invalid block {}", peer, block_hash);
+            return true;
+        }
+        for (other, _, _) in network.peers() {
+            if other == peer {
+                continue;
+            }
+            if let Err(err) = network.send(other, message) {
+                log::debug!("failed to relay block to {}: {}", other, err);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::{Event, Version, PROTOCOL_VERSION};
+
+    fn network() -> Network<Signature, Output> {
+        Network::new(Version {
+            version: PROTOCOL_VERSION,
+            genesis: [0; 32].into(),
+            best_height: 0,
+            user_agent: "test".into(),
+            listen_port: 0,
+        })
+    }
+
+    struct Node {
+        network: Network<Signature, Output>,
+        relay: TransactionRelay,
+        mempool: MemPool,
+        // Messages received, by kind.
+        received: Vec<&'static str>,
+    }
+
+    impl Node {
+        fn new() -> Self {
+            Self {
+                network: network(),
+                relay: TransactionRelay::new(),
+                mempool: MemPool::default(),
+                received: vec![],
+            }
+        }
+
+        fn poll(&mut self, blockchain: &BlockChain<Signature, Output>) {
+            while let Some(event) = self.network.recv_timeout(Duration::from_millis(50)) {
+                if let Event::Message { peer, message } = event {
+                    self.received.push(match message {
+                        Message::Inv(_) => "inv",
+                        Message::GetData(_) => "getdata",
+                        Message::Transaction(_) => "tx",
+                        _ => "other",
+                    });
+                    self.relay
+                        .handle(&self.network, blockchain, &mut self.mempool, peer, &message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transactions_propagate_and_are_announced_once() -> anyhow::Result<()> {
+        let blockchain = BlockChain::<Signature, Output>::new();
+        let (mut a, mut b, mut c) = (Node::new(), Node::new(), Node::new());
+        let addr = b.network.listen("127.0.0.1:0")?;
+        a.network.connect(addr)?;
+        c.network.connect(addr)?;
+
+        let transaction = Transaction {
+            version: TRANSACTION_VERSION,
+            inputs: vec![],
+            signatures: vec![],
+            outputs: vec![],
+            withdrawal_outputs: vec![],
+            extra: vec![],
+        };
+        let txid = transaction.txid();
+        a.mempool.insert(Amount::ZERO, transaction);
+        a.relay.announce(&a.network, None, &[txid]);
+        a.relay.announce(&a.network, None, &[txid]);
+        for _ in 0..5 {
+            for node in [&mut a, &mut b, &mut c] {
+                node.poll(&blockchain);
+            }
+        }
+        assert!(b.mempool.contains(&txid));
+        assert!(c.mempool.contains(&txid));
+        // b got a single announcement and announced to c only, not back to a.
+        assert_eq!(b.received, ["inv", "tx", "getdata"]);
+        assert_eq!(a.received, ["getdata"]);
+        assert_eq!(c.received, ["inv", "tx"]);
+        Ok(())
+    }
+
+    #[test]
+    fn relayed_blocks_follow_the_first_commitment() -> anyhow::Result<()> {
+        use crate::builder::BlockBuilder;
+        use crate::mock_client::MockMainClient;
+
+        let mainchain = MockMainClient::new();
+        let base = BlockChain::<Signature, Output>::new();
+        let miner: Address = [1; 32].into();
+        let (first, first_body) = BlockBuilder::on(&base).build();
+        let (second, second_body) = BlockBuilder::on(&base)
+            .coinbase(miner, Amount::ZERO)
+            .build();
+        let first_anchor = mainchain.anchor(&first.hash());
+        let second_anchor = mainchain.anchor(&second.hash());
+
+        let (a, b, c) = (network(), network(), network());
+        let addr = b.listen("127.0.0.1:0")?;
+        a.connect(addr)?;
+        c.connect(addr)?;
+        // b hears of the block committed to later first and passes both on.
+        let (to_b, _, _) = a.peers()[0];
+        for (header, body, anchor) in [
+            (second, second_body, second_anchor),
+            (first.clone(), first_body, first_anchor),
+        ] {
+            let message = Message::Block {
+                header,
+                body,
+                main_block_hash: Some(anchor.main_block_hash()),
+            };
+            a.send(to_b, &message)?;
+        }
+        let mut nodes: Vec<_> = [b, c]
+            .into_iter()
+            .map(|network| {
+                let blockchain = BlockChain::<Signature, Output>::new();
+                (network, BlockRelay::new(), blockchain, MemPool::default())
+            })
+            .collect();
+        for _ in 0..5 {
+            for (network, relay, blockchain, mempool) in &mut nodes {
+                while let Some(event) = network.recv_timeout(Duration::from_millis(50)) {
+                    if let Event::Message { peer, message } = event {
+                        relay.handle(network, &mainchain, blockchain, mempool, peer, &message);
+                    }
+                }
+            }
+        }
+        for (_, _, blockchain, _) in &nodes {
+            assert_eq!(blockchain.get_best_block_hash(), Some(first.hash()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use crate::concrete::{Output, Signature};
+use crate::rpc::NodeState;
+use crate::types::*;
+use serde_json::{json, Value};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Read-only HTTP endpoints serving chain data as JSON, for explorers and web
+// frontends:
+//
+//   GET /block/{hash}
+//   GET /tx/{txid}
+//   GET /address/{address}/utxos
+//   GET /mempool
+pub struct RestServer {
+    server: tiny_http::Server,
+    node: Arc<Mutex<NodeState>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl RestServer {
+    pub fn bind(addr: impl ToSocketAddrs, node: Arc<Mutex<NodeState>>) -> Result<Self, Error> {
+        let server = tiny_http::Server::http(addr).map_err(Error::Bind)?;
+        Ok(Self {
+            server,
+            node,
+            shutdown: Arc::default(),
+        })
+    }
+
+    // Setting the flag makes run return.
+    pub fn with_shutdown(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    pub fn run(&self) {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let request = match self.server.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(err) => {
+                    log::error!("rest server stopped: {}", err);
+                    return;
+                }
+            };
+            let (status, body) = match *request.method() {
+                tiny_http::Method::Get => self.get(request.url()),
+                _ => (405, json!({ "error": "only GET is supported" })),
+            };
+            let content_type = tiny_http::Header::from_bytes("Content-Type", "application/json")
+                .expect("invalid header");
+            // Frontends on other origins read from this too.
+            let allow_origin = tiny_http::Header::from_bytes("Access-Control-Allow-Origin", "*")
+                .expect("invalid header");
+            let response = tiny_http::Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(content_type)
+                .with_header(allow_origin);
+            if let Err(err) = request.respond(response) {
+                log::debug!("failed to answer rest request: {}", err);
+            }
+        }
+    }
+
+    fn get(&self, url: &str) -> (u16, Value) {
+        let path = url.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let node = self.node.lock().unwrap();
+        let result = match segments.as_slice() {
+            ["block", block_hash] => get_block(&node, block_hash),
+            ["tx", txid] => get_transaction(&node, txid),
+            ["address", address, "utxos"] => get_utxos(&node, address),
+            ["mempool"] => Ok(json!({
+                "size": node.mempool.len(),
+                "txids": node
+                    .mempool
+                    .txids()
+                    .iter()
+                    .map(Txid::to_string)
+                    .collect::<Vec<_>>(),
+            })),
+            _ => Err((404, "not found".into())),
+        };
+        match result {
+            Ok(value) => (200, value),
+            Err((status, message)) => (status, json!({ "error": message })),
+        }
+    }
+}
+
+type RestResult = Result<Value, (u16, String)>;
+
+fn get_block(node: &NodeState, block_hash: &str) -> RestResult {
+    let block_hash =
+        BlockHash::from_str(block_hash).map_err(|_| (400, "invalid block hash".into()))?;
+    let (header, body) = match (
+        node.blockchain.get_header(&block_hash),
+        node.blockchain.get_body(&block_hash),
+    ) {
+        (Some(header), Some(body)) => (header, body),
+        _ => return Err((404, "block not found".into())),
+    };
+    Ok(json!({
+        "hash": block_hash.to_string(),
+        "prev_block_hash": header.prev_block_hash.to_string(),
+        "merkle_root": header.merkle_root.to_string(),
+        "state_root": header.state_root.map(hex::encode),
+        "coinbase": body.coinbase.iter().map(output_json).collect::<Vec<_>>(),
+        "transactions": body
+            .transactions
+            .iter()
+            .map(|transaction| transaction.txid().to_string())
+            .collect::<Vec<_>>(),
+    }))
+}
+
+fn get_transaction(node: &NodeState, txid: &str) -> RestResult {
+    let txid = Txid::from_str(txid).map_err(|_| (400, "invalid txid".into()))?;
+    if let Some(transaction) = node.blockchain.get_transaction(&txid) {
+        return Ok(transaction_json(&transaction, true));
+    }
+    match node.mempool.get(&txid) {
+        Some(transaction) => Ok(transaction_json(transaction, false)),
+        None => Err((404, "transaction not found".into())),
+    }
+}
+
+fn get_utxos(node: &NodeState, address: &str) -> RestResult {
+    let blockchain = &node.blockchain;
+    let address = Address::parse(address, &blockchain.params().address_hrp())
+        .map_err(|err| (400, err.to_string()))?;
+    let mut utxos = vec![];
+    for outpoint in &blockchain.unspent_outpoints {
+        let (owner, value) = if let Some(output) = blockchain.outputs.get(outpoint) {
+            (output.address, output.value)
+        } else if let Some(deposit) = blockchain.peg.deposit_outputs.get(outpoint) {
+            (deposit.address, deposit.value)
+        } else if let Some(refund) = blockchain.peg.withdrawal_outputs.get(outpoint) {
+            (refund.side_address, refund.value)
+        } else {
+            continue;
+        };
+        if owner == address {
+            utxos.push(json!({
+                "outpoint": outpoint_json(outpoint),
+                "value": value,
+            }));
+        }
+    }
+    Ok(json!(utxos))
+}
+
+fn transaction_json(transaction: &Transaction<Signature, Output>, confirmed: bool) -> Value {
+    json!({
+        "txid": transaction.txid().to_string(),
+        "confirmed": confirmed,
+        "inputs": transaction.inputs.iter().map(outpoint_json).collect::<Vec<_>>(),
+        "outputs": transaction.outputs.iter().map(output_json).collect::<Vec<_>>(),
+        "withdrawal_outputs": transaction
+            .withdrawal_outputs
+            .iter()
+            .map(|withdrawal| json!({
+                "value": withdrawal.value,
+                "fee": withdrawal.fee,
+                "side_address": withdrawal.side_address.to_string(),
+                "main_address": withdrawal.main_address.to_string(),
+            }))
+            .collect::<Vec<_>>(),
+        "extra": hex::encode(&transaction.extra),
+    })
+}
+
+fn output_json(output: &Output) -> Value {
+    json!({
+        "address": output.address.to_string(),
+        "value": output.value,
+    })
+}
+
+fn outpoint_json(outpoint: &OutPoint) -> Value {
+    match outpoint {
+        OutPoint::Regular { txid, vout } => {
+            json!({ "type": "regular", "txid": txid.to_string(), "vout": vout })
+        }
+        OutPoint::Coinbase { block_hash, vout } => {
+            json!({ "type": "coinbase", "block_hash": block_hash.to_string(), "vout": vout })
+        }
+        OutPoint::Withdrawal { txid, vout } => {
+            json!({ "type": "withdrawal", "txid": txid.to_string(), "vout": vout })
+        }
+        OutPoint::Deposit(outpoint) => {
+            json!({ "type": "deposit", "txid": outpoint.txid.to_string(), "vout": outpoint.vout })
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to start rest server: {0}")]
+    Bind(Box<dyn std::error::Error + Send + Sync>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::genesis::GenesisConfig;
+    use crate::mempool::MemPool;
+    use crate::wallet::{Wallet, Wallets};
+
+    fn get(base: &str, path: &str) -> (u16, Value) {
+        match ureq::get(&format!("{}{}", base, path)).call() {
+            Ok(response) => (200, response.into_json().unwrap()),
+            Err(ureq::Error::Status(status, response)) => (status, response.into_json().unwrap()),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    fn chain_data_is_served_as_json() -> anyhow::Result<()> {
+        let address: Address = [1; 32].into();
+        // Outputs made out of nothing only pass as a premine.
+        let genesis = GenesisConfig::new("test", 1_600_000_000).with_premine(vec![Output {
+            address,
+            value: Amount::from_sat(42),
+        }]);
+        let blockchain = BlockChain::new().with_genesis(&genesis)?;
+        let txid = genesis.block::<Signature, Sha256>().1.transactions[0].txid();
+        let block_hash = blockchain.get_best_block_hash().unwrap();
+        let node = NodeState {
+            blockchain,
+            mempool: MemPool::default(),
+            wallet: Wallet::default(),
+            wallets: Wallets::default(),
+            main_fee_rate: None,
+        };
+        let server = RestServer::bind("127.0.0.1:0", Arc::new(Mutex::new(node)))?;
+        let base = format!("http://{}", server.local_addr().unwrap());
+        std::thread::spawn(move || server.run());
+
+        let (status, block) = get(&base, &format!("/block/{}", block_hash));
+        assert_eq!(status, 200);
+        assert_eq!(block["transactions"], json!([txid.to_string()]));
+        let (_, tx) = get(&base, &format!("/tx/{}", txid));
+        assert_eq!(tx["confirmed"], json!(true));
+        assert_eq!(tx["outputs"][0]["value"], json!(42));
+        let (_, utxos) = get(&base, &format!("/address/{}/utxos", address));
+        assert_eq!(utxos[0]["value"], json!(42));
+        assert_eq!(utxos[0]["outpoint"]["txid"], json!(txid.to_string()));
+        let (_, mempool) = get(&base, "/mempool");
+        assert_eq!(mempool["size"], json!(0));
+        assert_eq!(get(&base, "/block/xyz").0, 400);
+        assert_eq!(get(&base, "/address/1111/utxos").0, 400);
+        assert_eq!(get(&base, &format!("/tx/{}", Txid::from([0; 32]))).0, 404);
+        assert_eq!(get(&base, "/blocks").0, 404);
+        Ok(())
+    }
+}
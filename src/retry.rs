@@ -0,0 +1,101 @@
+use rand::Rng;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    // Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Fraction of the backoff that is randomized, between 0.0 and 1.0, so
+    // that many clients don't hammer a recovering node in lockstep.
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    // Delay before retry number `retry` (starting from 0).
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return exponential;
+        }
+        let factor = 1.0 - jitter * rand::thread_rng().gen::<f64>();
+        exponential.mul_f64(factor)
+    }
+
+    pub fn run<T, E>(
+        &self,
+        is_retryable: impl Fn(&E) -> bool,
+        mut f: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut retry = 0;
+        loop {
+            match f() {
+                Err(err) if retry + 1 < self.max_attempts && is_retryable(&err) => {
+                    let backoff = self.backoff(retry);
+                    log::debug!("retrying failed request in {:?}", backoff);
+                    std::thread::sleep(backoff);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_only_retryable_errors() {
+        let retry = RetryConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::ZERO,
+            ..RetryConfig::default()
+        };
+        let mut attempts = 0;
+        let result: Result<(), bool> = retry.run(
+            |retryable| *retryable,
+            || {
+                attempts += 1;
+                Err(true)
+            },
+        );
+        assert_eq!(result, Err(true));
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result: Result<(), bool> = retry.run(
+            |retryable| *retryable,
+            || {
+                attempts += 1;
+                Err(false)
+            },
+        );
+        assert_eq!(result, Err(false));
+        assert_eq!(attempts, 1);
+    }
+}
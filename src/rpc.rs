@@ -0,0 +1,1316 @@
+use crate::backend::MainchainBackend;
+use crate::blockchain::{regular_outputs, BlockChain, Error as BlockChainError};
+use crate::bmm::Anchor;
+use crate::bundle::{withdrawal_cost, BundleLimits};
+use crate::concrete::{Output, Signature};
+use crate::descriptor::Descriptor;
+use crate::mempool::MemPool;
+use crate::miner::{self, BlockTemplate, Miner};
+use crate::p2p::Version;
+use crate::peg::WithdrawalStatus;
+use crate::types::*;
+use crate::wallet::{Wallet, Wallets, WalletsError};
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MAX_REQUEST_SIZE: u64 = 1024 * 1024;
+// How long run waits for a request before looking at the shutdown flag.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// Most transactions submitted together, as in bitcoind.
+const MAX_PACKAGE_TRANSACTIONS: usize = 25;
+
+// Error codes, the same ones bitcoind uses.
+const RPC_PARSE_ERROR: i64 = -32700;
+const RPC_INVALID_REQUEST: i64 = -32600;
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+const RPC_INVALID_PARAMS: i64 = -32602;
+const RPC_MISC_ERROR: i64 = -1;
+const RPC_WALLET_ERROR: i64 = -4;
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+const RPC_WALLET_INSUFFICIENT_FUNDS: i64 = -6;
+const RPC_WALLET_NOT_FOUND: i64 = -18;
+const RPC_DESERIALIZATION_ERROR: i64 = -22;
+const RPC_VERIFY_REJECTED: i64 = -26;
+// Requests to /wallet/<name> act on that wallet instead of the default one.
+const WALLET_PATH: &str = "/wallet/";
+
+// What the RPC methods act on, shared with the rest of the node.
+pub struct NodeState {
+    pub blockchain: BlockChain<Signature, Output>,
+    pub mempool: MemPool,
+    // The default wallet, `wallets` has the named ones.
+    pub wallet: Wallet,
+    pub wallets: Wallets,
+    // Latest mainchain fee estimate, None until the mainchain node has one.
+    pub main_fee_rate: Option<FeeRate>,
+}
+
+impl NodeState {
+    // Makes the wallets see their coins on chain, without the ones the chain
+    // or the mempool already spent.
+    pub(crate) fn sync_wallet(&mut self) {
+        let Self {
+            blockchain,
+            mempool,
+            wallet,
+            wallets,
+            ..
+        } = self;
+        for wallet in std::iter::once(wallet).chain(wallets.iter_mut()) {
+            wallet.add_outputs(&blockchain.outputs);
+            wallet.add_deposit_outputs(&blockchain.peg.deposit_outputs);
+            wallet.remove_spent(&blockchain.unspent_outpoints);
+            // Spends made elsewhere, e.g. with the same keys on another node.
+            wallet
+                .outputs
+                .retain(|outpoint, _| !mempool.spends(outpoint));
+        }
+    }
+
+    // Mainchain fee a withdrawal to `main_address` should offer to make the
+    // next bundle and have it confirm at the current mainchain fee rate.
+    pub fn withdrawal_fee(&self, main_address: &bitcoin::Address) -> Amount {
+        let limits = BundleLimits {
+            main_fee_rate: self.main_fee_rate.unwrap_or_default(),
+            ..BundleLimits::default()
+        };
+        self.blockchain
+            .suggested_withdrawal_fee(&limits)
+            .max(withdrawal_cost(main_address, limits.main_fee_rate))
+    }
+
+    // The default wallet for None, otherwise a loaded named one.
+    pub fn get_wallet_mut(&mut self, name: Option<&str>) -> Option<&mut Wallet> {
+        match name {
+            None => Some(&mut self.wallet),
+            Some(name) => self.wallets.get_mut(name),
+        }
+    }
+
+    // Validates the transaction and adds it to the mempool.
+    pub(crate) fn submit(
+        &mut self,
+        transaction: Transaction<Signature, Output>,
+    ) -> Result<Txid, String> {
+        let txids = self.submit_package(vec![transaction])?;
+        Ok(txids[0])
+    }
+
+    // Validates transactions that may spend each other's outputs, parents
+    // first, and adds them to the mempool all together or not at all. The
+    // minimum fee rate applies to the package as a whole, so a child paying
+    // enough brings in a parent that pays nothing. Members already in the
+    // mempool are left out of it.
+    pub(crate) fn submit_package(
+        &mut self,
+        package: Vec<Transaction<Signature, Output>>,
+    ) -> Result<Vec<Txid>, String> {
+        if package.len() > MAX_PACKAGE_TRANSACTIONS {
+            return Err(format!(
+                "package has more than {} transactions",
+                MAX_PACKAGE_TRANSACTIONS
+            ));
+        }
+        let mut pending = self.mempool.outputs();
+        let mut spent = HashSet::new();
+        let mut txids = vec![];
+        let mut accepted = vec![];
+        let (mut fees, mut vsize) = (Amount::ZERO, 0);
+        for transaction in package {
+            let txid = transaction.txid();
+            txids.push(txid);
+            if self.mempool.contains(&txid) {
+                continue;
+            }
+            if !transaction.is_standard_version() {
+                return Err(format!("{}: non-standard transaction version", txid));
+            }
+            let fee = self
+                .blockchain
+                .validate_transaction_with(&transaction, &pending)
+                .and_then(|()| self.blockchain.get_fee_with(&transaction, &pending))
+                .map_err(|err| format!("{}: {}", txid, err))?;
+            if !transaction
+                .inputs
+                .iter()
+                .all(|outpoint| spent.insert(*outpoint))
+            {
+                return Err(format!("{}: output spent earlier in the package", txid));
+            }
+            if transaction
+                .inputs
+                .iter()
+                .any(|outpoint| self.mempool.spends(outpoint))
+            {
+                return Err(format!("{}: output spent by a mempool transaction", txid));
+            }
+            pending.extend(regular_outputs(txid, &transaction));
+            fees += fee;
+            vsize += transaction.vsize();
+            accepted.push((fee, transaction));
+        }
+        if !accepted.is_empty() && FeeRate::new(fees, vsize) < self.mempool.min_fee_rate() {
+            return Err("fee rate below the mempool minimum".into());
+        }
+        let inserted: HashSet<Txid> = accepted
+            .iter()
+            .map(|(_, transaction)| transaction.txid())
+            .collect();
+        for (fee, transaction) in accepted {
+            self.mempool.insert(fee, transaction);
+        }
+        // Making room may evict only some of the package, like a child
+        // paying a lower rate than its parent. The rest goes too.
+        let evicted = self.mempool.trim();
+        if txids.iter().any(|txid| evicted.contains(txid)) {
+            self.mempool
+                .retain(|transaction| !inserted.contains(&transaction.txid()));
+            return Err("mempool full".into());
+        }
+        Ok(txids)
+    }
+
+    // Submits a transaction the wallet just made, giving its coins back to the
+    // wallet if it is rejected.
+    pub(crate) fn submit_from_wallet(
+        &mut self,
+        wallet: Option<&str>,
+        transaction: Transaction<Signature, Output>,
+    ) -> Result<Txid, String> {
+        self.submit(transaction.clone()).inspect_err(|_| {
+            if let Some(wallet) = self.get_wallet_mut(wallet) {
+                wallet.abandon(&transaction);
+            }
+        })
+    }
+
+    // The next block, for block producers running in their own process.
+    pub fn get_block_template(&mut self, coinbase_address: Address) -> BlockTemplate {
+        Miner::new().get_block_template(&self.blockchain, &mut self.mempool, coinbase_address)
+    }
+
+    // Connects a block made from a template, with its BMM commitment once
+    // it got into a mainchain block.
+    pub fn submit_block(
+        &mut self,
+        header: Header,
+        body: Body<Signature, Output>,
+        anchor: Option<Anchor>,
+    ) -> Result<BlockHash, miner::Error> {
+        Miner::new().connect_block(
+            &mut self.blockchain,
+            &mut self.mempool,
+            None,
+            header,
+            body,
+            anchor,
+        )
+    }
+
+    // Transactions of the blocks it disconnects go back to the mempool, as
+    // far as they are still valid. Mempool transactions spending outputs of
+    // ones that don't make it back are dropped.
+    pub fn invalidate_block(&mut self, block_hash: BlockHash) -> Result<(), BlockChainError> {
+        for (_, body, _) in self.blockchain.invalidate_block(block_hash)? {
+            for transaction in &body.transactions {
+                let _ = self.submit(transaction.clone());
+            }
+        }
+        self.mempool.revalidate(&self.blockchain);
+        Ok(())
+    }
+
+    pub fn reconsider_block(&mut self, block_hash: &BlockHash) -> usize {
+        let connected = self.blockchain.reconsider_block(block_hash);
+        self.mempool.revalidate(&self.blockchain);
+        connected
+    }
+
+    // `peers` are the versions the connected peers announced, nodes that
+    // don't take part in the p2p network pass none.
+    pub fn get_node_info(&mut self, peers: &[Version]) -> NodeInfo {
+        self.sync_wallet();
+        let peg = &self.blockchain.peg;
+        let mut total_paid_out = Amount::ZERO;
+        let mut pending_withdrawals = 0;
+        let mut pending_withdrawal_value = Amount::ZERO;
+        for (outpoint, output) in &peg.withdrawal_outputs {
+            match peg.withdrawal_status(outpoint) {
+                Some(WithdrawalStatus::Paid { .. }) => total_paid_out += output.value,
+                // Refunded withdrawals are spent and won't be paid out.
+                _ if self.blockchain.unspent_outpoints.contains(outpoint) => {
+                    pending_withdrawals += 1;
+                    pending_withdrawal_value += output.value;
+                }
+                _ => {}
+            }
+        }
+        NodeInfo {
+            height: self.blockchain.height(),
+            best_block_hash: self.blockchain.get_best_block_hash(),
+            peers: peers.len(),
+            best_peer_height: peers
+                .iter()
+                .map(|version| version.best_height as usize)
+                .max()
+                .unwrap_or_default(),
+            mempool_size: self.mempool.len(),
+            mempool_fees: self.mempool.iter().map(|(fee, _)| fee).sum(),
+            total_deposited: peg.total_deposited(),
+            total_paid_out,
+            pending_withdrawals,
+            pending_withdrawal_value,
+            wallet_balance: self.wallet.get_balance(),
+        }
+    }
+}
+
+// Everything an operator dashboard shows about a node, gathered under a
+// single lock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub height: usize,
+    pub best_block_hash: Option<BlockHash>,
+    pub peers: usize,
+    // Highest height the peers announced when they connected.
+    pub best_peer_height: usize,
+    pub mempool_size: usize,
+    pub mempool_fees: Amount,
+    pub total_deposited: Amount,
+    pub total_paid_out: Amount,
+    // Withdrawal outputs not paid out on the mainchain yet.
+    pub pending_withdrawals: usize,
+    pub pending_withdrawal_value: Amount,
+    pub wallet_balance: Amount,
+}
+
+impl NodeInfo {
+    // Between 0 and 1, 1 once no peer is known to be ahead.
+    pub fn sync_progress(&self) -> f64 {
+        if self.best_peer_height <= self.height {
+            return 1.0;
+        }
+        self.height as f64 / self.best_peer_height as f64
+    }
+}
+
+// A bitcoind style JSON-RPC server, so wallets and scripts can drive a
+// running node. Requests are answered one at a time on the thread calling
+// run.
+pub struct RpcServer {
+    server: tiny_http::Server,
+    node: Arc<Mutex<NodeState>>,
+    // Base64 of user:password, None accepts every request.
+    auth: Option<String>,
+    // Checks the BMM commitments of submitted blocks, the producer only says
+    // which mainchain block theirs is in.
+    mainchain: Option<Box<dyn MainchainBackend + Send>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl RpcServer {
+    pub fn bind(addr: impl ToSocketAddrs, node: Arc<Mutex<NodeState>>) -> Result<Self, Error> {
+        let server = tiny_http::Server::http(addr).map_err(Error::Bind)?;
+        Ok(Self {
+            server,
+            node,
+            auth: None,
+            mainchain: None,
+            shutdown: Arc::default(),
+        })
+    }
+
+    pub fn with_auth(mut self, user: &str, password: &str) -> Self {
+        self.auth = Some(
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password)),
+        );
+        self
+    }
+
+    pub fn with_mainchain(mut self, mainchain: impl MainchainBackend + Send + 'static) -> Self {
+        self.mainchain = Some(Box::new(mainchain));
+        self
+    }
+
+    // Setting the flag makes run return.
+    pub fn with_shutdown(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    pub fn run(&self) {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let request = match self.server.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(err) => {
+                    log::error!("rpc server stopped: {}", err);
+                    return;
+                }
+            };
+            if let Err(err) = self.respond(request) {
+                log::debug!("failed to answer rpc request: {}", err);
+            }
+        }
+    }
+
+    fn respond(&self, mut request: tiny_http::Request) -> std::io::Result<()> {
+        if let Some(auth) = &self.auth {
+            let expected = format!("Basic {}", auth);
+            let authorized = request.headers().iter().any(|header| {
+                header.field.equiv("Authorization")
+                    && constant_time_eq(header.value.as_str().as_bytes(), expected.as_bytes())
+            });
+            if !authorized {
+                return request.respond(tiny_http::Response::empty(401));
+            }
+        }
+        if *request.method() != tiny_http::Method::Post {
+            return request.respond(tiny_http::Response::empty(405));
+        }
+        let mut body = vec![];
+        request
+            .as_reader()
+            .take(MAX_REQUEST_SIZE)
+            .read_to_end(&mut body)?;
+        let wallet = request
+            .url()
+            .strip_prefix(WALLET_PATH)
+            .map(|name| name.to_string());
+        let wallet = wallet.as_deref();
+        let (status, response) = match serde_json::from_slice::<Value>(&body) {
+            // Batch requests get an array with a response for every call.
+            Ok(Value::Array(calls)) => {
+                let responses: Vec<Response> = calls
+                    .into_iter()
+                    .map(|call| self.call(call, wallet))
+                    .collect();
+                (200, json!(responses))
+            }
+            Ok(call) => {
+                let response = self.call(call, wallet);
+                let status = match &response.error {
+                    None => 200,
+                    Some(error) if error.code == RPC_METHOD_NOT_FOUND => 404,
+                    Some(_) => 500,
+                };
+                (status, json!(response))
+            }
+            Err(err) => (
+                500,
+                json!(Response::error(
+                    Value::Null,
+                    RpcError::new(RPC_PARSE_ERROR, err.to_string())
+                )),
+            ),
+        };
+        let content_type = tiny_http::Header::from_bytes("Content-Type", "application/json")
+            .expect("invalid header");
+        request.respond(
+            tiny_http::Response::from_string(response.to_string())
+                .with_status_code(status)
+                .with_header(content_type),
+        )
+    }
+
+    fn call(&self, call: Value, wallet: Option<&str>) -> Response {
+        let request: Request = match serde_json::from_value(call) {
+            Ok(request) => request,
+            Err(err) => {
+                return Response::error(
+                    Value::Null,
+                    RpcError::new(RPC_INVALID_REQUEST, err.to_string()),
+                )
+            }
+        };
+        let mut node = self.node.lock().unwrap();
+        let mainchain = self.mainchain.as_deref().map(|mainchain| mainchain as _);
+        match dispatch(
+            &mut node,
+            mainchain,
+            wallet,
+            &request.method,
+            &request.params,
+        ) {
+            Ok(result) => Response {
+                id: request.id,
+                result,
+                error: None,
+            },
+            Err(err) => Response::error(request.id, err),
+        }
+    }
+}
+
+// `wallet` is the named wallet the request was sent to, if any.
+fn dispatch(
+    node: &mut NodeState,
+    mainchain: Option<&dyn MainchainBackend>,
+    wallet: Option<&str>,
+    method: &str,
+    params: &[Value],
+) -> Result<Value, RpcError> {
+    match method {
+        "getblockcount" => Ok(json!(node.blockchain.height())),
+        "getbestblockhash" => Ok(json!(node
+            .blockchain
+            .get_best_block_hash()
+            .map(|block_hash| block_hash.to_string()))),
+        "getbalance" => {
+            node.sync_wallet();
+            Ok(json!(get_wallet(node, wallet)?.get_balance()))
+        }
+        "getnewaddress" => {
+            let address_type: String = optional_param(params, 0)?;
+            let wallet = get_wallet(node, wallet)?;
+            match address_type.as_str() {
+                "" | "base58" => Ok(json!(wallet.generate_address().to_string())),
+                "bech32" => wallet
+                    .generate_bech32_address()
+                    .map(|address| json!(address))
+                    .map_err(|err| RpcError::new(RPC_WALLET_ERROR, err.to_string())),
+                _ => Err(RpcError::new(
+                    RPC_INVALID_PARAMS,
+                    format!("unknown address type {}", address_type),
+                )),
+            }
+        }
+        "getdepositaddress" => Ok(json!(get_wallet(node, wallet)?.generate_deposit_address())),
+        "sendtoaddress" => {
+            let address: String = param(params, 0)?;
+            let hrp = node.blockchain.params().address_hrp();
+            let address = Address::parse(&address, &hrp)
+                .map_err(|err| RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, err.to_string()))?;
+            let value = check_amount(param(params, 1)?)?;
+            let fee = check_amount(optional_param(params, 2)?)?;
+            node.sync_wallet();
+            let transaction = get_wallet(node, wallet)?
+                .create_transaction(vec![Output { address, value }], fee)
+                .ok_or_else(|| {
+                    RpcError::new(RPC_WALLET_INSUFFICIENT_FUNDS, "insufficient funds")
+                })?;
+            let txid = node
+                .submit_from_wallet(wallet, transaction)
+                .map_err(|err| RpcError::new(RPC_VERIFY_REJECTED, err))?;
+            Ok(json!(txid.to_string()))
+        }
+        "createwithdrawal" => {
+            let main_address: String = param(params, 0)?;
+            let main_address = bitcoin::Address::from_str(&main_address).map_err(|_| {
+                RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "invalid mainchain address")
+            })?;
+            let value = check_amount(param(params, 1)?)?;
+            let main_fee = match optional_param(params, 2)? {
+                Some(main_fee) => check_amount(main_fee)?,
+                None => node.withdrawal_fee(&main_address),
+            };
+            let fee = check_amount(optional_param(params, 3)?)?;
+            node.sync_wallet();
+            let transaction = get_wallet(node, wallet)?
+                .create_withdrawal(main_address, value, main_fee, fee)
+                .ok_or_else(|| {
+                    RpcError::new(RPC_WALLET_INSUFFICIENT_FUNDS, "insufficient funds")
+                })?;
+            let txid = node
+                .submit_from_wallet(wallet, transaction)
+                .map_err(|err| RpcError::new(RPC_VERIFY_REJECTED, err))?;
+            Ok(json!(txid.to_string()))
+        }
+        // feerate is the mainchain one in sat/kvB, fee what a withdrawal to
+        // the address should offer towards its bundle.
+        "estimatewithdrawalfee" => {
+            let main_address: String = param(params, 0)?;
+            let main_address = bitcoin::Address::from_str(&main_address).map_err(|_| {
+                RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "invalid mainchain address")
+            })?;
+            Ok(json!({
+                "feerate": node.main_fee_rate.map(FeeRate::to_sat_per_kvb),
+                "fee": node.withdrawal_fee(&main_address),
+            }))
+        }
+        "listdescriptors" => Ok(json!(get_wallet(node, wallet)?
+            .export_descriptors()
+            .iter()
+            .map(Descriptor::to_string)
+            .collect::<Vec<_>>())),
+        // Every descriptor is checked before any is imported.
+        "importdescriptors" => {
+            let descriptors: Vec<String> = param(params, 0)?;
+            let descriptors = descriptors
+                .iter()
+                .map(|descriptor| descriptor.parse())
+                .collect::<Result<Vec<Descriptor>, _>>()
+                .map_err(|err| RpcError::new(RPC_INVALID_PARAMS, err.to_string()))?;
+            let wallet = get_wallet(node, wallet)?;
+            for descriptor in &descriptors {
+                wallet.import_descriptor(descriptor);
+            }
+            node.sync_wallet();
+            Ok(json!(descriptors.len()))
+        }
+        "createwallet" => {
+            let name: String = param(params, 0)?;
+            node.wallets.create(&name).map_err(wallet_error)?;
+            Ok(json!({ "name": name }))
+        }
+        "loadwallet" => {
+            let name: String = param(params, 0)?;
+            node.wallets.load(&name).map_err(wallet_error)?;
+            Ok(json!({ "name": name }))
+        }
+        // The wallet is the one named in the params or the request path.
+        "unloadwallet" => {
+            let name: Option<String> = optional_param(params, 0)?;
+            let name = name.as_deref().or(wallet).ok_or_else(|| {
+                RpcError::new(RPC_WALLET_ERROR, "the default wallet can't be unloaded")
+            })?;
+            node.wallets.unload(name).map_err(wallet_error)?;
+            Ok(Value::Null)
+        }
+        // The default wallet is the one without a name.
+        "listwallets" => Ok(json!(std::iter::once("")
+            .chain(node.wallets.names())
+            .collect::<Vec<_>>())),
+        "getnodeinfo" => {
+            let info = node.get_node_info(&[]);
+            Ok(json!({
+                "blocks": info.height,
+                "best_block_hash": info.best_block_hash.map(|block_hash| block_hash.to_string()),
+                "peers": info.peers,
+                "best_peer_height": info.best_peer_height,
+                "sync_progress": info.sync_progress(),
+                "mempool": { "size": info.mempool_size, "fees": info.mempool_fees },
+                "peg": {
+                    "total_deposited": info.total_deposited,
+                    "total_paid_out": info.total_paid_out,
+                    "pending_withdrawals": info.pending_withdrawals,
+                    "pending_withdrawal_value": info.pending_withdrawal_value,
+                },
+                "balance": info.wallet_balance,
+            }))
+        }
+        // Fee rates in satoshis per 1000 virtual bytes, transactions paying
+        // less than mempoolminfee are turned away.
+        "getmempoolinfo" => Ok(json!({
+            "size": node.mempool.len(),
+            "bytes": node.mempool.size(),
+            "maxmempool": node.mempool.max_size(),
+            "mempoolminfee": node.mempool.min_fee_rate().to_sat_per_kvb(),
+        })),
+        // Fee rates as in getmempoolinfo, sizes in bytes.
+        "getmempoolsnapshot" => {
+            let snapshot = node.mempool.snapshot();
+            Ok(json!({
+                "transactions": snapshot
+                    .entries
+                    .iter()
+                    .map(|entry| json!({
+                        "txid": entry.txid.to_string(),
+                        "size": entry.size,
+                        "vsize": entry.vsize,
+                        "fee": entry.fee,
+                        "fee_rate": entry.fee_rate.to_sat_per_kvb(),
+                        "ancestor_fee_rate": entry.ancestor_fee_rate.to_sat_per_kvb(),
+                        "depends": entry
+                            .parents
+                            .iter()
+                            .map(Txid::to_string)
+                            .collect::<Vec<_>>(),
+                    }))
+                    .collect::<Vec<_>>(),
+                "size": snapshot.size,
+                "vsize": snapshot.vsize,
+                "fees": snapshot.fees,
+                "median_fee_rate": snapshot.median_fee_rate.to_sat_per_kvb(),
+                "mempoolminfee": snapshot.min_fee_rate.to_sat_per_kvb(),
+            }))
+        }
+        // Fees go to the address given. Without one the coinbase pays to
+        // nobody, fine for looking at the next block but not for mining it.
+        // `block` is submitted as is once a BMM request for `critical_hash`
+        // was included on the mainchain, together with the hash of the
+        // mainchain block it is in.
+        "getblocktemplate" => {
+            let address: String = optional_param(params, 0)?;
+            let coinbase_address = match address.as_str() {
+                "" => Hash::default().into(),
+                address => Address::parse(address, &node.blockchain.params().address_hrp())
+                    .map_err(|err| RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, err.to_string()))?,
+            };
+            let template = node.get_block_template(coinbase_address);
+            let block = bincode::serialize(&(&template.header, &template.body))
+                .map_err(|err| RpcError::new(RPC_INVALID_REQUEST, err.to_string()))?;
+            Ok(json!({
+                "prev_block_hash": template.header.prev_block_hash.to_string(),
+                "height": template.height,
+                "transactions": template
+                    .body
+                    .transactions
+                    .iter()
+                    .map(|transaction| transaction.txid().to_string())
+                    .collect::<Vec<_>>(),
+                "coinbase_value": template.body.coinbase[0].value,
+                "fees": template.fees,
+                "size": template.body.size(),
+                "critical_hash": template.critical_hash.to_string(),
+                "block": hex::encode(block),
+            }))
+        }
+        "submitblock" => {
+            let block: String = param(params, 0)?;
+            let (header, body): (Header, _) = hex::decode(block)
+                .ok()
+                .and_then(|block| bincode::deserialize(&block).ok())
+                .ok_or_else(|| RpcError::new(RPC_DESERIALIZATION_ERROR, "block decode failed"))?;
+            let main_block_hash: String = optional_param(params, 1)?;
+            let main_block_hash = match main_block_hash.as_str() {
+                "" => None,
+                main_block_hash => {
+                    Some(bitcoin::BlockHash::from_str(main_block_hash).map_err(|_| {
+                        RpcError::new(RPC_INVALID_PARAMS, "invalid mainchain block hash")
+                    })?)
+                }
+            };
+            let anchor = match main_block_hash {
+                Some(main_block_hash) => {
+                    let mainchain = mainchain.ok_or_else(|| {
+                        RpcError::new(RPC_VERIFY_REJECTED, "no mainchain to check bmm against")
+                    })?;
+                    let anchor = Anchor::verify(mainchain, &main_block_hash, &header.hash())
+                        .map_err(|err| RpcError::new(RPC_VERIFY_REJECTED, err.to_string()))?;
+                    Some(anchor)
+                }
+                None => None,
+            };
+            let block_hash = node
+                .submit_block(header, body, anchor)
+                .map_err(|err| RpcError::new(RPC_VERIFY_REJECTED, err.to_string()))?;
+            Ok(json!(block_hash.to_string()))
+        }
+        "invalidateblock" => {
+            node.invalidate_block(block_hash_param(params, 0)?)
+                .map_err(|err| RpcError::new(RPC_MISC_ERROR, err.to_string()))?;
+            Ok(Value::Null)
+        }
+        "reconsiderblock" => {
+            let block_hash = block_hash_param(params, 0)?;
+            if !node.blockchain.is_invalid(&block_hash) {
+                return Err(RpcError::new(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "block is not marked invalid",
+                ));
+            }
+            node.reconsider_block(&block_hash);
+            Ok(Value::Null)
+        }
+        "getblockfilter" => {
+            let block_hash = block_hash_param(params, 0)?;
+            let filter = node
+                .blockchain
+                .get_filter(&block_hash)
+                .ok_or_else(|| RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "block not found"))?;
+            Ok(json!({ "filter": hex::encode(&filter.content) }))
+        }
+        "gettxoutproof" => {
+            let txid: String = param(params, 0)?;
+            let txid = Txid::from_str(&txid)
+                .map_err(|_| RpcError::new(RPC_INVALID_PARAMS, "param 0: invalid txid"))?;
+            let (header, proof) = node.blockchain.get_merkle_proof(&txid).ok_or_else(|| {
+                RpcError::new(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "transaction not found in the best chain",
+                )
+            })?;
+            let proof = bincode::serialize(&(&header, &txid, &proof))
+                .map_err(|err| RpcError::new(RPC_INVALID_REQUEST, err.to_string()))?;
+            Ok(json!(hex::encode(proof)))
+        }
+        // The txid a proof is for, or nothing if it doesn't verify or its
+        // block isn't on our best chain.
+        "verifytxoutproof" => {
+            let proof: String = param(params, 0)?;
+            let (header, txid, proof): (Header, Txid, MerkleProof) = hex::decode(proof)
+                .ok()
+                .and_then(|proof| bincode::deserialize(&proof).ok())
+                .ok_or_else(|| RpcError::new(RPC_DESERIALIZATION_ERROR, "proof decode failed"))?;
+            let confirmed = node.blockchain.get_header(&header.hash()).is_some()
+                && proof.verify(&header.merkle_root, &txid);
+            Ok(json!(confirmed
+                .then(|| txid.to_string())
+                .into_iter()
+                .collect::<Vec<_>>()))
+        }
+        // Transactions encoded like blocks in submitblock, parents first.
+        "submitpackage" => {
+            let package: Vec<String> = param(params, 0)?;
+            let package = package
+                .iter()
+                .map(|transaction| {
+                    hex::decode(transaction)
+                        .ok()
+                        .and_then(|transaction| bincode::deserialize(&transaction).ok())
+                        .ok_or_else(|| {
+                            RpcError::new(RPC_DESERIALIZATION_ERROR, "transaction decode failed")
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let txids = node
+                .submit_package(package)
+                .map_err(|err| RpcError::new(RPC_VERIFY_REJECTED, err))?;
+            Ok(json!(txids.iter().map(Txid::to_string).collect::<Vec<_>>()))
+        }
+        "getrawmempool" => Ok(json!(node
+            .mempool
+            .txids()
+            .iter()
+            .map(Txid::to_string)
+            .collect::<Vec<_>>())),
+        _ => Err(RpcError::new(
+            RPC_METHOD_NOT_FOUND,
+            format!("method {} not found", method),
+        )),
+    }
+}
+
+fn get_wallet<'a>(
+    node: &'a mut NodeState,
+    wallet: Option<&str>,
+) -> Result<&'a mut Wallet, RpcError> {
+    node.get_wallet_mut(wallet).ok_or_else(|| {
+        RpcError::new(
+            RPC_WALLET_NOT_FOUND,
+            format!("wallet {} is not loaded", wallet.unwrap_or_default()),
+        )
+    })
+}
+
+fn wallet_error(err: WalletsError) -> RpcError {
+    let code = match err {
+        WalletsError::NotFound(_) | WalletsError::NotLoaded(_) => RPC_WALLET_NOT_FOUND,
+        _ => RPC_WALLET_ERROR,
+    };
+    RpcError::new(code, err.to_string())
+}
+
+fn param<T: DeserializeOwned>(params: &[Value], index: usize) -> Result<T, RpcError> {
+    let param = params
+        .get(index)
+        .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, format!("missing param {}", index)))?;
+    serde_json::from_value(param.clone())
+        .map_err(|err| RpcError::new(RPC_INVALID_PARAMS, format!("param {}: {}", index, err)))
+}
+
+fn optional_param<T: DeserializeOwned + Default>(
+    params: &[Value],
+    index: usize,
+) -> Result<T, RpcError> {
+    match params.get(index) {
+        None | Some(Value::Null) => Ok(T::default()),
+        Some(_) => param(params, index),
+    }
+}
+
+fn block_hash_param(params: &[Value], index: usize) -> Result<BlockHash, RpcError> {
+    let block_hash: String = param(params, index)?;
+    BlockHash::from_str(&block_hash).map_err(|_| {
+        RpcError::new(
+            RPC_INVALID_PARAMS,
+            format!("param {}: invalid block hash", index),
+        )
+    })
+}
+
+// Amounts are given in satoshis.
+fn check_amount(amount: Amount) -> Result<Amount, RpcError> {
+    if !amount.is_valid() {
+        return Err(RpcError::new(RPC_INVALID_PARAMS, "amount out of range"));
+    }
+    Ok(amount)
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    id: Value,
+    result: Value,
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn error(id: Value, error: RpcError) -> Self {
+        Self {
+            id,
+            result: Value::Null,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// Compares every byte whatever the first difference, so the time taken
+// doesn't tell how much of a guessed credential was right. Only the length
+// shows.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(0, |acc, (a, b)| std::hint::black_box(acc | (a ^ b)));
+    a.len() == b.len() && diff == 0
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to start rpc server: {0}")]
+    Bind(Box<dyn std::error::Error + Send + Sync>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{keypair, BlockBuilder, TxBuilder};
+    use crate::client::{Client, Error as ClientError};
+    use std::collections::HashMap;
+
+    #[test]
+    fn wallet_is_driven_over_rpc() -> anyhow::Result<()> {
+        let mut node = NodeState {
+            blockchain: BlockChain::new(),
+            mempool: MemPool::default(),
+            wallet: Wallet::default(),
+            wallets: Wallets::default(),
+            main_fee_rate: Some(FeeRate::from_sat_per_kvb(2000)),
+        };
+        let address = node.wallet.generate_address();
+        node.blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(bitcoin::OutPoint::default()),
+                DepositOutput {
+                    address,
+                    value: Amount::from_sat(1000),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let server = RpcServer::bind("127.0.0.1:0", Arc::new(Mutex::new(node)))?
+            .with_auth("user", "password");
+        let port = server.local_addr().unwrap().port();
+        std::thread::spawn(move || server.run());
+
+        let client = Client::new(0, "127.0.0.1", port, "user", "password");
+        assert_eq!(client.send_request::<u64>("getblockcount", &[])?, 0);
+        assert_eq!(client.send_request::<u64>("getbalance", &[])?, 1000);
+        let to: String = client.send_request("getnewaddress", &[json!("bech32")])?;
+        assert!(to.starts_with("sc01"));
+        let deposit_address: String = client.send_request("getdepositaddress", &[])?;
+        assert!(deposit_address.starts_with("s0_"));
+        let txid: String = client.send_request("sendtoaddress", &[json!(to), json!(300)])?;
+        assert_eq!(
+            client.send_request::<Vec<String>>("getrawmempool", &[])?,
+            std::slice::from_ref(&txid)
+        );
+        // The deposit is spent by the mempool transaction.
+        assert_eq!(client.send_request::<u64>("getbalance", &[])?, 0);
+        let info: Value = client.send_request("getnodeinfo", &[])?;
+        assert_eq!(info["mempool"]["size"], 1);
+        let mempool_info: Value = client.send_request("getmempoolinfo", &[])?;
+        assert_eq!(mempool_info["mempoolminfee"], 0);
+        assert_eq!(mempool_info["maxmempool"], crate::mempool::DEFAULT_MAX_SIZE);
+        // A 31 byte P2WPKH output at 2 sat/vB.
+        let estimate: Value = client.send_request(
+            "estimatewithdrawalfee",
+            &[json!("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")],
+        )?;
+        assert_eq!(estimate, json!({ "feerate": 2000, "fee": 62 }));
+        let snapshot: Value = client.send_request("getmempoolsnapshot", &[])?;
+        assert_eq!(snapshot["transactions"][0]["txid"], json!(txid));
+        assert_eq!(snapshot["transactions"][0]["depends"], json!([]));
+        assert_eq!(
+            snapshot["median_fee_rate"],
+            snapshot["transactions"][0]["fee_rate"]
+        );
+        assert_eq!(snapshot["vsize"], snapshot["transactions"][0]["vsize"]);
+        let template: Value = client.send_request("getblocktemplate", &[])?;
+        assert_eq!(template["transactions"], json!([txid]));
+        assert_eq!(template["height"], 1);
+        assert_eq!(info["peg"]["total_deposited"], 1000);
+        assert_eq!(info["sync_progress"], 1.0);
+        assert!(matches!(
+            client.send_request::<Value>("sendtoaddress", &[json!(to), json!(1)]),
+            Err(ClientError::InsufficientFunds(_))
+        ));
+        assert!(matches!(
+            client.send_request::<Value>("getblock", &[]),
+            Err(ClientError::MethodNotFound(_))
+        ));
+
+        // Named wallets are picked with the request path.
+        client.send_request::<Value>("createwallet", &[json!("hot")])?;
+        assert_eq!(
+            client.send_request::<Vec<String>>("listwallets", &[])?,
+            vec!["", "hot"]
+        );
+        let auth = base64::engine::general_purpose::STANDARD.encode("user:password");
+        let call_hot = |method: &str, params: Value| -> anyhow::Result<Value> {
+            let response = ureq::post(&format!("http://127.0.0.1:{}/wallet/hot", port))
+                .set("Authorization", &format!("Basic {}", auth))
+                .send_json(json!({ "id": 0, "method": method, "params": params }));
+            match response {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response.into_json()?),
+                Err(err) => Err(err.into()),
+            }
+        };
+        assert_eq!(call_hot("getbalance", json!([]))?["result"], 0);
+        // Watching the default wallet's keys shows its change as watch-only.
+        let descriptors: Vec<String> = client.send_request("listdescriptors", &[])?;
+        let imported = call_hot("importdescriptors", json!([descriptors]))?;
+        assert_eq!(imported["result"], descriptors.len());
+        assert_eq!(
+            call_hot("listdescriptors", json!([]))?["result"],
+            json!(descriptors)
+        );
+        let hot_address = call_hot("getnewaddress", json!([]))?["result"].clone();
+        assert_ne!(hot_address, json!(to));
+        assert_eq!(call_hot("unloadwallet", json!([]))?["error"], Value::Null);
+        assert_eq!(
+            call_hot("getbalance", json!([]))?["error"]["code"],
+            RPC_WALLET_NOT_FOUND
+        );
+
+        // Blocks made by a producer in another process.
+        let template: Value = client.send_request("getblocktemplate", &[json!(to)])?;
+        let block = template["block"].clone();
+        // A commitment the node can't check is no commitment.
+        let main_block_hash = json!("11".repeat(32));
+        assert!(client
+            .send_request::<Value>("submitblock", &[block.clone(), main_block_hash])
+            .is_err());
+        let block_hash: String =
+            client.send_request("submitblock", std::slice::from_ref(&block))?;
+        assert_eq!(json!(block_hash), template["critical_hash"]);
+        assert_eq!(client.send_request::<u64>("getblockcount", &[])?, 1);
+        assert!(client
+            .send_request::<Vec<String>>("getrawmempool", &[])?
+            .is_empty());
+        assert!(client
+            .send_request::<Value>("submitblock", std::slice::from_ref(&block))
+            .is_err());
+        assert!(client
+            .send_request::<Value>("submitblock", &[json!("00")])
+            .is_err());
+        // Operators can take a block back out, and put it back.
+        client.send_request::<Value>("invalidateblock", &[json!(block_hash)])?;
+        assert_eq!(client.send_request::<u64>("getblockcount", &[])?, 0);
+        assert_eq!(
+            client.send_request::<Vec<String>>("getrawmempool", &[])?,
+            std::slice::from_ref(&txid)
+        );
+        assert!(client
+            .send_request::<Value>("submitblock", &[block])
+            .is_err());
+        client.send_request::<Value>("reconsiderblock", &[json!(block_hash)])?;
+        assert_eq!(client.send_request::<u64>("getblockcount", &[])?, 1);
+        let filter = client.send_request::<Value>("getblockfilter", &[json!(block_hash)])?;
+        assert!(filter["filter"].as_str().is_some_and(|f| !f.is_empty()));
+        let proof: String = client.send_request("gettxoutproof", &[json!(txid)])?;
+        assert_eq!(
+            client.send_request::<Vec<String>>("verifytxoutproof", &[json!(proof)])?,
+            std::slice::from_ref(&txid)
+        );
+        assert!(client
+            .send_request::<Vec<String>>("getrawmempool", &[])?
+            .is_empty());
+
+        let unauthorized = Client::new(0, "127.0.0.1", port, "user", "wrong");
+        assert!(unauthorized
+            .send_request::<u64>("getblockcount", &[])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn credentials_are_compared_in_full() {
+        assert!(constant_time_eq(
+            b"Basic dXNlcjpwYXNz",
+            b"Basic dXNlcjpwYXNz"
+        ));
+        assert!(!constant_time_eq(
+            b"Basic dXNlcjpwYXNz",
+            b"Basic dXNlcjpwYXNx"
+        ));
+        assert!(!constant_time_eq(
+            b"Basic dXNlcjpwYXNz",
+            b"Basic dXNlcjpwYXN"
+        ));
+        assert!(!constant_time_eq(b"", b"Basic"));
+    }
+
+    #[test]
+    fn packages_are_evicted_whole() {
+        let alice = keypair([1; 32]);
+        let alice_address: Address = alice.public.into();
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint::default());
+        let mut node = NodeState {
+            blockchain: BlockChain::new(),
+            mempool: MemPool::default(),
+            wallet: Wallet::default(),
+            wallets: Wallets::default(),
+            main_fee_rate: None,
+        };
+        node.blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                deposit,
+                DepositOutput {
+                    address: alice_address,
+                    value: Amount::from_sat(10_000),
+                },
+            )]),
+            deposits: vec![],
+        });
+        // The child pays nothing, so it has the lower rate and is the one
+        // evicted.
+        let parent = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(alice_address, Amount::from_sat(9_000))
+            .build();
+        let child = TxBuilder::new()
+            .spend(
+                OutPoint::Regular {
+                    txid: parent.txid(),
+                    vout: 0,
+                },
+                &alice,
+            )
+            .pay(alice_address, Amount::from_sat(9_000))
+            .build();
+        node.mempool = MemPool::default().with_max_size(parent.size());
+        assert_eq!(
+            node.submit_package(vec![parent.clone(), child]),
+            Err("mempool full".into())
+        );
+        assert!(node.mempool.is_empty());
+        // On its own the parent fits.
+        assert_eq!(node.submit(parent.clone()), Ok(parent.txid()));
+    }
+
+    #[test]
+    fn children_pay_for_their_parents_in_packages() {
+        let alice = keypair([1; 32]);
+        let alice_address: Address = alice.public.into();
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint::default());
+        let mut node = NodeState {
+            blockchain: BlockChain::new(),
+            mempool: MemPool::default().with_min_fee_rate(FeeRate::from_sat_per_kvb(1000)),
+            wallet: Wallet::default(),
+            wallets: Wallets::default(),
+            main_fee_rate: None,
+        };
+        node.blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                deposit,
+                DepositOutput {
+                    address: alice_address,
+                    value: Amount::from_sat(10_000),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let parent = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(alice_address, Amount::from_sat(10_000))
+            .build();
+        let child = TxBuilder::new()
+            .spend(
+                OutPoint::Regular {
+                    txid: parent.txid(),
+                    vout: 0,
+                },
+                &alice,
+            )
+            .pay(alice_address, Amount::from_sat(9_000))
+            .build();
+        assert!(node.submit(parent.clone()).is_err());
+        assert!(node.submit(child.clone()).is_err());
+        assert!(node
+            .submit_package(vec![child.clone(), parent.clone()])
+            .is_err());
+        assert_eq!(
+            node.submit_package(vec![parent.clone(), child.clone()]),
+            Ok(vec![parent.txid(), child.txid()])
+        );
+
+        // A block can have both, the child after its parent.
+        let (header, body) = BlockBuilder::on(&node.blockchain)
+            .transaction(child.clone())
+            .transaction(parent.clone())
+            .build();
+        assert!(!node.blockchain.validate_block(&header, &body, None));
+        let (header, body) = BlockBuilder::on(&node.blockchain)
+            .transaction(parent.clone())
+            .transaction(child.clone())
+            .build();
+        assert!(node.blockchain.validate_block(&header, &body, None));
+
+        // The child brings its parent into the template, after a
+        // transaction paying a higher rate than the two of them together.
+        let bob = keypair([2; 32]);
+        let bob_deposit = OutPoint::Deposit(bitcoin::OutPoint {
+            vout: 1,
+            ..Default::default()
+        });
+        node.blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                bob_deposit,
+                DepositOutput {
+                    address: bob.public.into(),
+                    value: Amount::from_sat(10_000),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let rich = TxBuilder::new()
+            .spend(bob_deposit, &bob)
+            .pay(alice_address, Amount::from_sat(5_000))
+            .build();
+        node.submit(rich.clone()).unwrap();
+        let template = node.get_block_template(alice_address);
+        let txids: Vec<Txid> = template
+            .body
+            .transactions
+            .iter()
+            .map(Transaction::txid)
+            .collect();
+        assert_eq!(txids, [rich.txid(), parent.txid(), child.txid()]);
+        assert_eq!(template.fees, Amount::from_sat(6_000));
+        // Parents are saved first, so a loaded mempool makes the same block.
+        let loaded: MemPool =
+            bincode::deserialize(&bincode::serialize(&node.mempool).unwrap()).unwrap();
+        assert_eq!(
+            loaded
+                .get_block_template(alice_address)
+                .compute_merkle_root(),
+            template.body.compute_merkle_root()
+        );
+        node.submit_block(template.header, template.body, None)
+            .unwrap();
+        assert!(node.mempool.is_empty());
+        // Without its child the parent doesn't pay enough to get back in,
+        // and the child has nothing to spend.
+        node.invalidate_block(node.blockchain.get_best_block_hash().unwrap())
+            .unwrap();
+        assert_eq!(node.mempool.txids(), [rich.txid()]);
+
+        // The child remains after a block confirms its parent alone, and
+        // makes it into the next template on its own fee rate.
+        node.reconsider_block(&header.hash());
+        node.mempool.retain(|_| false);
+        node.submit_package(vec![parent.clone(), child.clone()])
+            .unwrap();
+        let (header, body) = BlockBuilder::on(&node.blockchain)
+            .transaction(parent.clone())
+            .build();
+        node.submit_block(header, body, None).unwrap();
+        assert_eq!(node.mempool.txids(), [child.txid()]);
+        node.mempool.revalidate(&node.blockchain);
+        assert_eq!(node.mempool.txids(), [child.txid()]);
+        let template = node.get_block_template(alice_address);
+        assert_eq!(template.body.transactions.len(), 1);
+        assert_eq!(template.body.transactions[0].txid(), child.txid());
+        let snapshot = node.mempool.snapshot();
+        assert!(snapshot.entries[0].parents.is_empty());
+        assert_eq!(
+            snapshot.entries[0].ancestor_fee_rate,
+            snapshot.entries[0].fee_rate
+        );
+        // Dropping a parent drops its children.
+        node.invalidate_block(node.blockchain.get_best_block_hash().unwrap())
+            .unwrap();
+        assert!(node.mempool.is_empty());
+    }
+
+    #[test]
+    fn conflicting_spends_are_rejected() {
+        let alice = keypair([1; 32]);
+        let alice_address: Address = alice.public.into();
+        let bob_address: Address = keypair([2; 32]).public.into();
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint::default());
+        let mut node = NodeState {
+            blockchain: BlockChain::new(),
+            mempool: MemPool::default(),
+            wallet: Wallet::default(),
+            wallets: Wallets::default(),
+            main_fee_rate: None,
+        };
+        node.blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                deposit,
+                DepositOutput {
+                    address: alice_address,
+                    value: Amount::from_sat(10_000),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let first = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(alice_address, Amount::from_sat(9_000))
+            .build();
+        let second = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(bob_address, Amount::from_sat(9_000))
+            .build();
+        // A transaction listing the same output twice is turned away, and
+        // so is one spending an output a mempool transaction spends.
+        let doubled = TxBuilder::new()
+            .spend(deposit, &alice)
+            .spend(deposit, &alice)
+            .pay(alice_address, Amount::from_sat(19_000))
+            .build();
+        assert_eq!(
+            node.submit(doubled.clone()),
+            Err(format!("{}: duplicate input", doubled.txid()))
+        );
+        assert!(node.mempool.is_empty());
+        assert_eq!(node.submit(first.clone()), Ok(first.txid()));
+        assert!(node.submit(second.clone()).is_err());
+        assert!(node.submit_package(vec![second.clone()]).is_err());
+        assert_eq!(node.mempool.txids(), [first.txid()]);
+        let template = node.get_block_template(alice_address);
+        assert_eq!(template.body.transactions.len(), 1);
+        assert_eq!(template.body.transactions[0].txid(), first.txid());
+    }
+}
@@ -0,0 +1,463 @@
+use crate::backend::MainchainBackend;
+use crate::bundle::Bundle;
+use crate::client::{Error, FailedWithdrawal, MainBlockHeader, SpentWithdrawal, VerifiedBMM};
+use crate::types::{
+    Address, Amount, BlockHash, Deposit, DepositOutput, DepositsChunk, FeeRate, OutPoint,
+    THIS_SIDECHAIN,
+};
+use bitcoin::hashes::Hash;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Blocks a bundle gets to gather its acks in before it fails.
+pub const DEFAULT_VOTE_WINDOW: usize = 10;
+pub const DEFAULT_ACK_THRESHOLD: usize = 6;
+// Chance in percent that a block acks a pending bundle.
+pub const DEFAULT_ACK_PERCENT: u8 = 75;
+
+// A mainchain that only exists in memory. Block hashes, deposit txids and
+// the votes every block casts on pending bundles all follow from the seed,
+// so a test driving it replays the same way every run, without a node and
+// without waiting for real blocks.
+#[derive(Debug)]
+pub struct SimulatedMainchain {
+    seed: u64,
+    vote_window: usize,
+    ack_threshold: usize,
+    ack_percent: u8,
+    state: RefCell<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    blocks: Vec<bitcoin::BlockHash>,
+    // Draws taken from the seed so far.
+    draws: u64,
+    deposits: Vec<(Deposit, DepositOutput)>,
+    // Deposits waiting for the next block.
+    pending_deposits: Vec<(bitcoin::OutPoint, DepositOutput)>,
+    bmm: HashMap<(bitcoin::BlockHash, BlockHash), VerifiedBMM>,
+    // Critical hash, request txid and the tip the request was made on.
+    bmm_requests: Vec<(BlockHash, bitcoin::Txid, bitcoin::BlockHash)>,
+    bundles: Vec<PendingBundle>,
+    spent_withdrawals: Vec<SpentWithdrawal>,
+    failed_withdrawals: Vec<FailedWithdrawal>,
+}
+
+#[derive(Debug)]
+struct PendingBundle {
+    hash: bitcoin::Txid,
+    // Height of the first block that votes on it.
+    first_vote: usize,
+    acks: usize,
+}
+
+impl SimulatedMainchain {
+    pub fn new(seed: u64) -> Self {
+        let mainchain = Self {
+            seed,
+            vote_window: DEFAULT_VOTE_WINDOW,
+            ack_threshold: DEFAULT_ACK_THRESHOLD,
+            ack_percent: DEFAULT_ACK_PERCENT,
+            state: RefCell::default(),
+        };
+        mainchain.mine_block();
+        mainchain
+    }
+
+    pub fn with_bundle_votes(mut self, ack_threshold: usize, vote_window: usize) -> Self {
+        self.ack_threshold = ack_threshold;
+        self.vote_window = vote_window;
+        self
+    }
+
+    pub fn with_ack_percent(mut self, ack_percent: u8) -> Self {
+        self.ack_percent = ack_percent.min(100);
+        self
+    }
+
+    pub fn height(&self) -> usize {
+        self.state.borrow().blocks.len() - 1
+    }
+
+    // Mines the next block: it includes the deposits and BMM requests made
+    // since the last one and votes on every pending bundle.
+    pub fn mine_block(&self) -> bitcoin::BlockHash {
+        let mut state = self.state.borrow_mut();
+        let height = state.blocks.len();
+        let block_hash = bitcoin::BlockHash::hash(&self.preimage(b"block", height as u64));
+        let prev_block_hash = state.blocks.last().copied();
+        for (critical_hash, txid, prev) in std::mem::take(&mut state.bmm_requests) {
+            if Some(prev) == prev_block_hash {
+                let verified_bmm = VerifiedBMM {
+                    time: height as i64,
+                    txid,
+                };
+                state.bmm.insert((block_hash, critical_hash), verified_bmm);
+            }
+        }
+        for (outpoint, output) in std::mem::take(&mut state.pending_deposits) {
+            let prev_total = state
+                .deposits
+                .last()
+                .map_or(0, |(deposit, _)| deposit.total);
+            let deposit = Deposit {
+                outpoint,
+                total: prev_total + output.value.to_sat(),
+                main_block_hash: block_hash,
+                main_height: height,
+            };
+            state.deposits.push((deposit, output));
+        }
+        let bundles = std::mem::take(&mut state.bundles);
+        for mut bundle in bundles {
+            if bundle.first_vote > height {
+                state.bundles.push(bundle);
+                continue;
+            }
+            if self.draw(&mut state) % 100 < self.ack_percent as u64 {
+                bundle.acks += 1;
+            }
+            if bundle.acks >= self.ack_threshold {
+                state.spent_withdrawals.push(SpentWithdrawal {
+                    nsidechain: THIS_SIDECHAIN,
+                    hash: bundle.hash,
+                    hashblock: block_hash,
+                });
+            } else if height + 1 - bundle.first_vote >= self.vote_window {
+                state.failed_withdrawals.push(FailedWithdrawal {
+                    nsidechain: THIS_SIDECHAIN,
+                    hash: bundle.hash,
+                });
+            } else {
+                state.bundles.push(bundle);
+            }
+        }
+        state.blocks.push(block_hash);
+        block_hash
+    }
+
+    pub fn mine_blocks(&self, count: usize) -> Vec<bitcoin::BlockHash> {
+        (0..count).map(|_| self.mine_block()).collect()
+    }
+
+    // The deposit is included in the next block.
+    pub fn deposit(&self, address: Address, value: Amount) -> bitcoin::OutPoint {
+        let mut state = self.state.borrow_mut();
+        let draw = self.draw(&mut state);
+        let outpoint = bitcoin::OutPoint {
+            txid: bitcoin::Txid::hash(&self.preimage(b"deposit", draw)),
+            vout: 0,
+        };
+        state
+            .pending_deposits
+            .push((outpoint, DepositOutput { address, value }));
+        outpoint
+    }
+
+    // One deposit of a value drawn from the seed, up to `max_value`, to each
+    // of `addresses`.
+    pub fn generate_deposits(
+        &self,
+        addresses: &[Address],
+        max_value: Amount,
+    ) -> Vec<bitcoin::OutPoint> {
+        addresses
+            .iter()
+            .map(|address| {
+                let draw = self.draw(&mut self.state.borrow_mut());
+                let value = Amount::from_sat(1 + draw % max_value.to_sat().max(1));
+                self.deposit(*address, value)
+            })
+            .collect()
+    }
+
+    // Puts a bundle up for votes starting with the next block, returns the
+    // mainchain txid it is known by. Submitting a pending bundle again
+    // changes nothing.
+    pub fn submit_bundle(&self, bundle: &Bundle) -> bitcoin::Txid {
+        let mut state = self.state.borrow_mut();
+        let outputs: Vec<u8> = bundle
+            .outputs
+            .iter()
+            .flat_map(bitcoin::consensus::serialize)
+            .collect();
+        let hash = bitcoin::Txid::hash(&[self.preimage(b"bundle", bundle.fee), outputs].concat());
+        if state.bundles.iter().any(|pending| pending.hash == hash) {
+            return hash;
+        }
+        let first_vote = state.blocks.len();
+        state.bundles.push(PendingBundle {
+            hash,
+            first_vote,
+            acks: 0,
+        });
+        hash
+    }
+
+    // Drops the bundles pending votes, like a mainchain node that restarted
+    // and lost its mempool.
+    pub fn forget_bundles(&self) {
+        self.state.borrow_mut().bundles.clear();
+    }
+
+    fn draw(&self, state: &mut State) -> u64 {
+        let hash = bitcoin::hashes::sha256::Hash::hash(&self.preimage(b"draw", state.draws));
+        state.draws += 1;
+        u64::from_le_bytes(hash[..8].try_into().expect("hash is 32 bytes"))
+    }
+
+    fn preimage(&self, tag: &[u8], n: u64) -> Vec<u8> {
+        [tag, &self.seed.to_le_bytes(), &n.to_le_bytes()].concat()
+    }
+}
+
+impl MainchainBackend for SimulatedMainchain {
+    fn get_deposits(&self, last_deposit: Option<Deposit>) -> Result<DepositsChunk, Error> {
+        self.get_deposits_page(last_deposit, usize::MAX)
+    }
+
+    fn get_deposits_page(
+        &self,
+        last_deposit: Option<Deposit>,
+        limit: usize,
+    ) -> Result<DepositsChunk, Error> {
+        let state = self.state.borrow();
+        let start = match last_deposit {
+            Some(last_deposit) => state
+                .deposits
+                .iter()
+                .position(|(deposit, _)| *deposit == last_deposit)
+                .map_or(0, |position| position + 1),
+            None => 0,
+        };
+        let end = start.saturating_add(limit).min(state.deposits.len());
+        let new_deposits = &state.deposits[start..end];
+        let outputs = new_deposits
+            .iter()
+            .map(|(deposit, output)| (OutPoint::Deposit(deposit.outpoint), output.clone()))
+            .collect();
+        let deposits = new_deposits
+            .iter()
+            .map(|(deposit, _)| deposit.clone())
+            .collect();
+        Ok(DepositsChunk { outputs, deposits })
+    }
+
+    fn verify_bmm(
+        &self,
+        main_block_hash: &bitcoin::BlockHash,
+        critical_hash: &BlockHash,
+    ) -> Result<VerifiedBMM, Error> {
+        self.state
+            .borrow()
+            .bmm
+            .get(&(*main_block_hash, *critical_hash))
+            .cloned()
+            .ok_or(Error::Mock("bmm commitment not found"))
+    }
+
+    fn create_bmm_request(
+        &self,
+        critical_hash: &BlockHash,
+        _amount: bitcoin::Amount,
+        _height: usize,
+        prev_main_block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::Txid, Error> {
+        let mut state = self.state.borrow_mut();
+        let draw = self.draw(&mut state);
+        let txid = bitcoin::Txid::hash(&self.preimage(b"bmm", draw));
+        state
+            .bmm_requests
+            .push((*critical_hash, txid, *prev_main_block_hash));
+        Ok(txid)
+    }
+
+    fn get_block_count(&self) -> Result<usize, Error> {
+        Ok(self.height())
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<bitcoin::BlockHash, Error> {
+        self.state
+            .borrow()
+            .blocks
+            .get(height)
+            .copied()
+            .ok_or(Error::Mock("block height out of range"))
+    }
+
+    fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error> {
+        self.state
+            .borrow()
+            .blocks
+            .last()
+            .copied()
+            .ok_or(Error::Mock("no blocks"))
+    }
+
+    fn get_block_header(&self, block_hash: &bitcoin::BlockHash) -> Result<MainBlockHeader, Error> {
+        let state = self.state.borrow();
+        let height = state
+            .blocks
+            .iter()
+            .position(|hash| hash == block_hash)
+            .ok_or(Error::Mock("block not found"))?;
+        Ok(MainBlockHeader {
+            hash: *block_hash,
+            confirmations: (state.blocks.len() - height) as i64,
+            height,
+            time: height as u64,
+            previousblockhash: height.checked_sub(1).map(|prev| state.blocks[prev]),
+            nextblockhash: state.blocks.get(height + 1).copied(),
+        })
+    }
+
+    fn get_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error> {
+        Ok(self.state.borrow().spent_withdrawals.clone())
+    }
+
+    fn get_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error> {
+        Ok(self.state.borrow().failed_withdrawals.clone())
+    }
+
+    fn submit_bundle(&self, bundle: &Bundle) -> Result<bitcoin::Txid, Error> {
+        Ok(SimulatedMainchain::submit_bundle(self, bundle))
+    }
+
+    // Simulated blocks have room for everything, any fee confirms.
+    fn estimate_smart_fee(&self, _conf_target: u16) -> Result<Option<FeeRate>, Error> {
+        Ok(Some(FeeRate::ZERO))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::bundle::BundleLimits;
+    use crate::concrete::{Output, Signature};
+    use crate::mempool::MemPool;
+    use crate::miner::Miner;
+    use crate::peg::WithdrawalStatus;
+    use crate::wallet::Wallet;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    // Connects a sidechain block with the mempool's transactions, committed
+    // to in the next mainchain block.
+    fn mine(
+        mainchain: &SimulatedMainchain,
+        blockchain: &mut BlockChain<Signature, Output>,
+        mempool: &mut MemPool,
+        address: Address,
+    ) -> anyhow::Result<()> {
+        let miner = Miner::new().with_poll_interval(Duration::from_millis(1));
+        let (header, body) = miner.block_template(blockchain, mempool, address);
+        let request = miner.request_bmm(mainchain, &header)?;
+        mainchain.mine_block();
+        let anchor = miner.wait_for_bmm(mainchain, &request)?.unwrap();
+        miner.connect_block(blockchain, mempool, None, header, body, Some(anchor))?;
+        Ok(())
+    }
+
+    #[test]
+    fn deposits_are_spent_and_withdrawn() -> anyhow::Result<()> {
+        let main_address =
+            bitcoin::Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")?;
+        // Runs the whole peg once, returning the mainchain block the
+        // withdrawal was paid in.
+        let run = |seed| -> anyhow::Result<Option<bitcoin::BlockHash>> {
+            let mainchain = SimulatedMainchain::new(seed);
+            let mut wallet = Wallet::default();
+            let address = wallet.generate_address();
+            let mut blockchain = BlockChain::<Signature, Output>::new();
+            let mut mempool = MemPool::default();
+
+            mainchain.generate_deposits(&[address], Amount::from_sat(1000));
+            mainchain.deposit(address, Amount::from_sat(5000));
+            mainchain.mine_block();
+            let deposits = mainchain.get_deposits(None)?;
+            assert_eq!(deposits.deposits.len(), 2);
+            blockchain.add_deposits(deposits);
+            wallet.add_deposit_outputs(&blockchain.peg.deposit_outputs);
+
+            let other = wallet.generate_address();
+            let transaction = wallet
+                .create_transaction(
+                    vec![Output {
+                        address: other,
+                        value: Amount::from_sat(4000),
+                    }],
+                    Amount::from_sat(10),
+                )
+                .unwrap();
+            mempool.insert(blockchain.get_fee(&transaction).unwrap(), transaction);
+            mine(&mainchain, &mut blockchain, &mut mempool, address)?;
+            wallet.add_outputs(&blockchain.outputs);
+            wallet.remove_spent(&blockchain.unspent_outpoints);
+
+            let withdrawal = wallet
+                .create_withdrawal(
+                    main_address.clone(),
+                    Amount::from_sat(3000),
+                    Amount::from_sat(100),
+                    Amount::from_sat(10),
+                )
+                .unwrap();
+            mempool.insert(blockchain.get_fee(&withdrawal).unwrap(), withdrawal);
+            mine(&mainchain, &mut blockchain, &mut mempool, address)?;
+
+            let bundle = blockchain.next_bundle(&BundleLimits::default()).unwrap();
+            let hash = mainchain.submit_bundle(&bundle);
+            blockchain.mark_bundled(hash, &bundle)?;
+            blockchain.peg.mark_broadcast(hash, mainchain.height())?;
+            // The bundle is lost before it gets a single vote, and broadcast
+            // again once it has been quiet for a vote window.
+            mainchain.forget_bundles();
+            while mainchain.get_spent_withdrawals()?.is_empty()
+                && mainchain.get_failed_withdrawals()?.is_empty()
+            {
+                mainchain.mine_block();
+                let stale = blockchain
+                    .peg
+                    .stale_bundles(mainchain.height(), DEFAULT_VOTE_WINDOW);
+                for (hash, bundle) in stale {
+                    assert_eq!(mainchain.submit_bundle(&bundle), hash);
+                    blockchain.peg.mark_broadcast(hash, mainchain.height())?;
+                }
+            }
+            let outpoint = bundle.outpoints[0];
+            for spent_withdrawal in mainchain.get_spent_withdrawals()? {
+                blockchain.peg.mark_paid(&spent_withdrawal)?;
+            }
+            for failed_withdrawal in mainchain.get_failed_withdrawals()? {
+                blockchain.peg.mark_failed(&failed_withdrawal)?;
+            }
+            Ok(match blockchain.peg.withdrawal_status(&outpoint) {
+                Some(WithdrawalStatus::Paid {
+                    main_block_hash, ..
+                }) => Some(main_block_hash),
+                Some(WithdrawalStatus::Failed { .. }) => None,
+                status => panic!("bundle still pending: {:?}", status),
+            })
+        };
+        let paid_in = run(1)?;
+        assert!(paid_in.is_some());
+        // The same seed replays the same votes.
+        assert_eq!(run(1)?, paid_in);
+
+        let mainchain = SimulatedMainchain::new(2).with_ack_percent(0);
+        let bundle = Bundle {
+            outpoints: vec![],
+            outputs: vec![],
+            fee: 0,
+        };
+        let hash = mainchain.submit_bundle(&bundle);
+        mainchain.mine_blocks(DEFAULT_VOTE_WINDOW - 1);
+        assert!(mainchain.get_failed_withdrawals()?.is_empty());
+        mainchain.mine_block();
+        assert_eq!(mainchain.get_failed_withdrawals()?[0].hash, hash);
+        assert!(mainchain.get_spent_withdrawals()?.is_empty());
+        Ok(())
+    }
+}
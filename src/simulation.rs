@@ -0,0 +1,474 @@
+use crate::backend::MainchainBackend;
+use crate::blockchain::BlockChain;
+use crate::bmm::Anchor;
+use crate::concrete::{Output, Signature};
+use crate::fork_choice::BlockTree;
+use crate::mempool::MemPool;
+use crate::miner::Miner;
+use crate::p2p::Message;
+use crate::simulated::SimulatedMainchain;
+use crate::types::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Several nodes in one process, sharing a simulated mainchain and talking
+// over a virtual network. Time is virtual too: messages are delivered in the
+// order of their arrival time, with a delay drawn from the seed, and links
+// between partitions drop everything. The same seed and the same calls give
+// the same run, so a schedule that breaks convergence can be replayed.
+//
+// Nodes keep every block they hear of in a BlockTree and follow the chain
+// its fork choice picks. A block whose parent is unknown makes the node ask
+// the sender for it, which is how partitions catch up once they are healed.
+pub struct Simulation {
+    pub mainchain: SimulatedMainchain,
+    pub nodes: Vec<SimNode>,
+    rng: StdRng,
+    min_latency: Duration,
+    max_latency: Duration,
+    now: Duration,
+    // Messages by arrival time, then by the order they were sent in.
+    in_flight: BTreeMap<(Duration, u64), Envelope>,
+    sent: u64,
+    // Partition of each node, nodes only reach nodes in the same one.
+    partitions: Vec<usize>,
+}
+
+struct Envelope {
+    from: usize,
+    to: usize,
+    message: Message<Signature, Output>,
+}
+
+pub struct SimNode {
+    pub blockchain: BlockChain<Signature, Output>,
+    pub mempool: MemPool,
+    // Every block this node has heard of, on its chain or not.
+    tree: BlockTree<Signature, Output>,
+    last_deposit: Option<Deposit>,
+}
+
+impl SimNode {
+    fn new() -> Self {
+        Self {
+            blockchain: BlockChain::new(),
+            mempool: MemPool::default(),
+            tree: BlockTree::new(),
+            last_deposit: None,
+        }
+    }
+
+    pub fn tip(&self) -> Option<BlockHash> {
+        self.blockchain.get_best_block_hash()
+    }
+
+    // Mempool admission: valid on top of the tip and not conflicting with
+    // anything already in the mempool.
+    fn accept_transaction(&mut self, transaction: &Transaction<Signature, Output>) -> bool {
+        let txid = transaction.txid();
+        if self.mempool.contains(&txid)
+            || transaction
+                .inputs
+                .iter()
+                .any(|outpoint| self.mempool.spends(outpoint))
+            || self.blockchain.validate_transaction(transaction).is_err()
+        {
+            return false;
+        }
+        let Ok(fee) = self.blockchain.get_fee(transaction) else {
+            return false;
+        };
+        self.mempool.insert(fee, transaction.clone());
+        true
+    }
+
+    // Remembers the block and switches to the best chain. Returns false if
+    // the block was already known.
+    fn accept_block(
+        &mut self,
+        header: Header,
+        body: Body<Signature, Output>,
+        anchor: Option<Anchor>,
+    ) -> bool {
+        if !self.tree.insert(&self.blockchain, header, body, anchor) {
+            return false;
+        }
+        let reorg = self.tree.reorganize(&mut self.blockchain);
+        // Transactions of the old chain that are still valid go back to the
+        // mempool, the ones confirmed by the new chain leave it.
+        self.mempool.revalidate(&self.blockchain);
+        for block_hash in reorg.disconnected {
+            let transactions = self
+                .tree
+                .get(&block_hash)
+                .expect("known block")
+                .1
+                .transactions
+                .clone();
+            for transaction in &transactions {
+                self.accept_transaction(transaction);
+            }
+        }
+        true
+    }
+
+    // A known block, with the mainchain block its commitment is in.
+    fn block_message(&self, block_hash: &BlockHash) -> Message<Signature, Output> {
+        let (header, body) = self.tree.get(block_hash).expect("known block").clone();
+        Message::Block {
+            header,
+            body,
+            main_block_hash: self
+                .tree
+                .get_anchor(block_hash)
+                .map(Anchor::main_block_hash),
+        }
+    }
+}
+
+impl Simulation {
+    pub fn new(seed: u64, nodes: usize) -> Self {
+        Self {
+            mainchain: SimulatedMainchain::new(seed),
+            nodes: (0..nodes).map(|_| SimNode::new()).collect(),
+            rng: StdRng::seed_from_u64(seed),
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(100),
+            now: Duration::ZERO,
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            partitions: vec![0; nodes],
+        }
+    }
+
+    // Every message takes between `min` and `max` to arrive, so messages
+    // between the same nodes can overtake each other.
+    pub fn with_latency(mut self, min: Duration, max: Duration) -> Self {
+        self.min_latency = min;
+        self.max_latency = max.max(min);
+        self
+    }
+
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    // Splits the nodes into groups that can't reach each other, nodes not in
+    // any group end up alone. Messages already on their way across the new
+    // boundaries are lost.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        self.partitions = (0..self.nodes.len())
+            .map(|node| groups.len() + node)
+            .collect();
+        for (group, nodes) in groups.iter().enumerate() {
+            for node in *nodes {
+                self.partitions[*node] = group;
+            }
+        }
+        let partitions = &self.partitions;
+        self.in_flight
+            .retain(|_, envelope| partitions[envelope.from] == partitions[envelope.to]);
+    }
+
+    // Reconnects everyone, nodes announce their tips so the others can
+    // fetch what they missed.
+    pub fn heal(&mut self) {
+        self.partitions = vec![0; self.nodes.len()];
+        for node in 0..self.nodes.len() {
+            if let Some(tip) = self.nodes[node].tip() {
+                let message = self.nodes[node].block_message(&tip);
+                self.broadcast(node, None, message);
+            }
+        }
+    }
+
+    // Pulls new deposits from the mainchain into every node.
+    pub fn sync_mainchain(&mut self) -> Result<(), Error> {
+        for node in &mut self.nodes {
+            let deposits = self.mainchain.get_deposits(node.last_deposit.clone())?;
+            if let Some(last_deposit) = deposits.deposits.last() {
+                node.last_deposit = Some(last_deposit.clone());
+            }
+            node.blockchain.add_deposits(deposits);
+        }
+        Ok(())
+    }
+
+    // Puts the transaction into the node's mempool and gossips it. Returns
+    // false if the node didn't accept it.
+    pub fn submit(&mut self, node: usize, transaction: Transaction<Signature, Output>) -> bool {
+        if !self.nodes[node].accept_transaction(&transaction) {
+            return false;
+        }
+        self.broadcast(node, None, Message::Transaction(transaction));
+        true
+    }
+
+    // The node mines a block out of its mempool, commits to it in the next
+    // mainchain block and announces it.
+    pub fn mine(&mut self, node: usize, coinbase_address: Address) -> Result<BlockHash, Error> {
+        let miner = Miner::new().with_inclusion_window(1);
+        let sim_node = &mut self.nodes[node];
+        let (header, body) = miner.block_template(
+            &sim_node.blockchain,
+            &mut sim_node.mempool,
+            coinbase_address,
+        );
+        let request = miner.request_bmm(&self.mainchain, &header)?;
+        self.mainchain.mine_block();
+        let Some(anchor) = miner.wait_for_bmm(&self.mainchain, &request)? else {
+            return Err(Error::NotIncluded(request.critical_hash));
+        };
+        let block_hash = header.hash();
+        self.nodes[node].accept_block(header, body, Some(anchor));
+        let message = self.nodes[node].block_message(&block_hash);
+        self.broadcast(node, None, message);
+        Ok(block_hash)
+    }
+
+    // Delivers messages in arrival order until none are left, returns the
+    // number delivered.
+    pub fn run_until_idle(&mut self) -> usize {
+        self.run_for(Duration::MAX)
+    }
+
+    // Delivers the messages arriving in the next `duration`.
+    pub fn run_for(&mut self, duration: Duration) -> usize {
+        let until = self.now.saturating_add(duration);
+        let mut delivered = 0;
+        while let Some(entry) = self.in_flight.first_entry() {
+            let arrival = entry.key().0;
+            if arrival > until {
+                break;
+            }
+            let envelope = entry.remove();
+            self.now = arrival;
+            self.deliver(envelope);
+            delivered += 1;
+        }
+        if until != Duration::MAX {
+            self.now = until;
+        }
+        delivered
+    }
+
+    // All nodes are on the same tip.
+    pub fn converged(&self) -> bool {
+        self.nodes
+            .windows(2)
+            .all(|pair| pair[0].tip() == pair[1].tip())
+    }
+
+    // Nodes on the same tip have to agree on the coins and the state of
+    // every withdrawal, returns the first pair of nodes that don't.
+    pub fn check_consistency(&self) -> Result<(), Error> {
+        let state = |node: &SimNode| {
+            let mut unspent: Vec<OutPoint> =
+                node.blockchain.unspent_outpoints.iter().copied().collect();
+            unspent.sort();
+            let mut withdrawals = node.blockchain.peg.withdrawals_with_status(|_| true);
+            withdrawals.sort_by_key(|(outpoint, _)| *outpoint);
+            (unspent, withdrawals, node.blockchain.peg.total_deposited())
+        };
+        for (a, node_a) in self.nodes.iter().enumerate() {
+            for (b, node_b) in self.nodes.iter().enumerate().skip(a + 1) {
+                if node_a.tip() == node_b.tip() && state(node_a) != state(node_b) {
+                    return Err(Error::Inconsistent(a, b));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn deliver(&mut self, envelope: Envelope) {
+        let Envelope { from, to, message } = envelope;
+        match message {
+            Message::Transaction(transaction)
+                if self.nodes[to].accept_transaction(&transaction) =>
+            {
+                self.broadcast(to, Some(from), Message::Transaction(transaction));
+            }
+            Message::Block {
+                header,
+                body,
+                main_block_hash,
+            } => {
+                let prev_block_hash = header.prev_block_hash;
+                let block_hash = header.hash();
+                let anchor = main_block_hash.and_then(|main_block_hash| {
+                    Anchor::verify(&self.mainchain, &main_block_hash, &block_hash).ok()
+                });
+                let node = &mut self.nodes[to];
+                if !node.accept_block(header.clone(), body.clone(), anchor) {
+                    return;
+                }
+                let orphan = prev_block_hash != Hash::default().into()
+                    && !node.tree.contains(&prev_block_hash);
+                if orphan {
+                    self.send(to, from, Message::GetBlocks(vec![prev_block_hash]));
+                }
+                if !self.nodes[to].tree.is_invalid(&block_hash) {
+                    let message = Message::Block {
+                        header,
+                        body,
+                        main_block_hash,
+                    };
+                    self.broadcast(to, Some(from), message);
+                }
+            }
+            Message::GetBlocks(block_hashes) => {
+                for block_hash in block_hashes {
+                    if self.nodes[to].tree.contains(&block_hash) {
+                        let message = self.nodes[to].block_message(&block_hash);
+                        self.send(to, from, message);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn broadcast(
+        &mut self,
+        from: usize,
+        except: Option<usize>,
+        message: Message<Signature, Output>,
+    ) {
+        for to in 0..self.nodes.len() {
+            if to != from && Some(to) != except {
+                self.send(from, to, message.clone());
+            }
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, message: Message<Signature, Output>) {
+        if self.partitions[from] != self.partitions[to] {
+            return;
+        }
+        let latency = self.rng.gen_range(
+            self.min_latency.as_micros() as u64,
+            self.max_latency.as_micros() as u64 + 1,
+        );
+        let arrival = self.now + Duration::from_micros(latency);
+        self.in_flight
+            .insert((arrival, self.sent), Envelope { from, to, message });
+        self.sent += 1;
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("mainchain error")]
+    Mainchain(#[from] crate::client::Error),
+    #[error("miner error")]
+    Miner(#[from] crate::miner::Error),
+    #[error("bmm request for {0} wasn't included")]
+    NotIncluded(BlockHash),
+    #[error("nodes {0} and {1} are on the same tip with different states")]
+    Inconsistent(usize, usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{keypair, TxBuilder};
+
+    #[test]
+    fn partitions_converge_after_healing() -> anyhow::Result<()> {
+        let alice = keypair([1; 32]);
+        let alice_address: Address = alice.public.into();
+        let bob: Address = [2; 32].into();
+        let carol: Address = [3; 32].into();
+        let mut sim =
+            Simulation::new(3, 3).with_latency(Duration::from_millis(5), Duration::from_millis(50));
+        let deposit = sim.mainchain.deposit(alice_address, Amount::from_sat(1000));
+        sim.mainchain.mine_block();
+        sim.sync_mainchain()?;
+        let deposit = OutPoint::Deposit(deposit);
+
+        // Relay reaches everyone, and conflicting spends stay out.
+        let pay_bob = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(bob, Amount::from_sat(900))
+            .build();
+        let pay_carol = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(carol, Amount::from_sat(900))
+            .build();
+        assert!(sim.submit(0, pay_bob.clone()));
+        sim.run_until_idle();
+        assert!(sim
+            .nodes
+            .iter()
+            .all(|node| node.mempool.contains(&pay_bob.txid())));
+        assert!(!sim.submit(2, pay_carol.clone()));
+
+        // Each side of a partition confirms a different spend of the deposit.
+        sim.partition(&[&[0, 1], &[2]]);
+        sim.nodes[2].mempool.retain(|_| false);
+        assert!(sim.submit(2, pay_carol.clone()));
+        sim.mine(2, carol)?;
+        sim.mine(2, carol)?;
+        sim.mine(0, bob)?;
+        sim.run_until_idle();
+        assert!(!sim.converged());
+        assert_eq!(sim.nodes[2].blockchain.height(), 2);
+
+        // The longer chain wins and its spend is the one everyone keeps.
+        sim.heal();
+        sim.run_until_idle();
+        assert!(sim.converged());
+        sim.check_consistency()?;
+        for node in &sim.nodes {
+            assert!(node.blockchain.get_transaction(&pay_carol.txid()).is_some());
+            assert!(node.blockchain.get_transaction(&pay_bob.txid()).is_none());
+            assert!(!node.mempool.contains(&pay_bob.txid()));
+        }
+
+        // Replaying the same calls ends on the same tip.
+        let tip = sim.nodes[0].tip();
+        let mut replay =
+            Simulation::new(3, 3).with_latency(Duration::from_millis(5), Duration::from_millis(50));
+        replay
+            .mainchain
+            .deposit(alice_address, Amount::from_sat(1000));
+        replay.mainchain.mine_block();
+        replay.sync_mainchain()?;
+        replay.partition(&[&[0, 1], &[2]]);
+        replay.submit(2, pay_carol);
+        replay.mine(2, carol)?;
+        replay.mine(2, carol)?;
+        replay.heal();
+        replay.run_until_idle();
+        assert_eq!(replay.nodes[1].tip(), tip);
+
+        // Between blocks at the same height the one committed to first on
+        // the mainchain wins, also on the nodes that mined the other.
+        let deposit = sim.mainchain.deposit(alice_address, Amount::from_sat(500));
+        sim.mainchain.mine_block();
+        sim.sync_mainchain()?;
+        let deposit = OutPoint::Deposit(deposit);
+        let spend = |to| {
+            TxBuilder::new()
+                .spend(deposit, &alice)
+                .pay(to, Amount::from_sat(400))
+                .build()
+        };
+        sim.partition(&[&[0, 1], &[2]]);
+        assert!(sim.submit(2, spend(carol)));
+        assert!(sim.submit(0, spend(bob)));
+        let first = sim.mine(2, carol)?;
+        sim.mine(0, bob)?;
+        sim.run_until_idle();
+        assert_eq!(sim.nodes[0].blockchain.height(), 3);
+        assert_ne!(sim.nodes[1].tip(), Some(first));
+        sim.heal();
+        sim.run_until_idle();
+        assert!(sim.converged());
+        assert_eq!(sim.nodes[0].tip(), Some(first));
+        sim.check_consistency()?;
+        Ok(())
+    }
+}
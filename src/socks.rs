@@ -0,0 +1,288 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASSWORD: u8 = 2;
+const USER_PASSWORD_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+// A SOCKS5 proxy to make outbound connections through, like the one Tor
+// listens on at 127.0.0.1:9050. Host names are sent to the proxy as they
+// are, so they are resolved on the other side and don't leak through local
+// DNS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub addr: SocketAddr,
+    // Tor puts streams with different credentials on different circuits.
+    pub credentials: Option<(String, String)>,
+    // For connecting to the proxy and for the proxy connecting to the
+    // target, building a Tor circuit can take a while.
+    pub connect_timeout: Duration,
+}
+
+impl Proxy {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            credentials: None,
+            connect_timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some((user.into(), password.into()));
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        let mut stream = TcpStream::connect_timeout(&self.addr, self.connect_timeout)?;
+        stream.set_read_timeout(Some(self.connect_timeout))?;
+        stream.set_write_timeout(Some(self.connect_timeout))?;
+        self.handshake(&mut stream, host, port)?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+        let method = match self.credentials {
+            Some(_) => USER_PASSWORD,
+            None => NO_AUTH,
+        };
+        stream.write_all(&[VERSION, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(Error::NotSocks5);
+        }
+        if reply[1] != method {
+            return Err(Error::Method);
+        }
+
+        // RFC 1929
+        if let Some((user, password)) = &self.credentials {
+            let mut request = vec![USER_PASSWORD_VERSION];
+            for field in [user, password] {
+                request.push(field.len().try_into().map_err(|_| Error::TooLong)?);
+                request.extend(field.as_bytes());
+            }
+            stream.write_all(&request)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(Error::Auth);
+            }
+        }
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(IPV4);
+                request.extend(ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(IPV6);
+                request.extend(ip.octets());
+            }
+            Err(_) => {
+                request.push(DOMAIN);
+                request.push(host.len().try_into().map_err(|_| Error::TooLong)?);
+                request.extend(host.as_bytes());
+            }
+        }
+        request.extend(port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(Error::NotSocks5);
+        }
+        if reply[1] != 0 {
+            return Err(Error::Connect(reply[1]));
+        }
+        // Followed by the address the proxy connected from, which nobody
+        // needs.
+        let len = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(Error::NotSocks5),
+        };
+        stream.read_exact(&mut vec![0; len + 2])?;
+        Ok(())
+    }
+}
+
+// Accepts connections on a local port and tunnels each of them through the
+// proxy to host:port, for HTTP clients that can't be handed a stream. Keeps
+// running for as long as the process does.
+pub(crate) fn forward(proxy: Proxy, host: String, port: u16) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let local_addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for inbound in listener.incoming() {
+            let Ok(inbound) = inbound else {
+                continue;
+            };
+            let proxy = proxy.clone();
+            let host = host.clone();
+            std::thread::spawn(move || match proxy.connect(&host, port) {
+                Ok(outbound) => {
+                    if let Err(err) = pipe(inbound, outbound) {
+                        log::debug!("tunnel to {}:{} failed: {}", host, port, err);
+                    }
+                }
+                Err(err) => log::warn!(
+                    "failed to connect to {}:{} through proxy {}: {}",
+                    host,
+                    port,
+                    proxy.addr,
+                    err
+                ),
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+// Copies both ways until both sides are done sending.
+fn pipe(a: TcpStream, b: TcpStream) -> std::io::Result<()> {
+    let (mut a_read, mut b_write) = (a.try_clone()?, b.try_clone()?);
+    let upload = std::thread::spawn(move || {
+        let _ = std::io::copy(&mut a_read, &mut b_write);
+        let _ = b_write.shutdown(Shutdown::Write);
+    });
+    let (mut b_read, mut a_write) = (b, a);
+    let result = std::io::copy(&mut b_read, &mut a_write);
+    let _ = a_write.shutdown(Shutdown::Write);
+    let _ = upload.join();
+    result.map(|_| ())
+}
+
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("proxy is not a SOCKS5 proxy")]
+    NotSocks5,
+    #[error("proxy doesn't support the authentication method")]
+    Method,
+    #[error("proxy rejected the credentials")]
+    Auth,
+    #[error("host names and credentials are at most 255 bytes")]
+    TooLong,
+    #[error("proxy failed to connect: {}", reply_message(*.0))]
+    Connect(u8),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Speaks just enough SOCKS5 to check the request and connect it to the
+    // one target there is.
+    fn proxy_server(target: SocketAddr) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut greeting = [0; 3];
+                    stream.read_exact(&mut greeting).unwrap();
+                    assert_eq!(greeting, [VERSION, 1, USER_PASSWORD]);
+                    stream.write_all(&[VERSION, USER_PASSWORD]).unwrap();
+                    let mut auth = [0; 10];
+                    stream.read_exact(&mut auth).unwrap();
+                    let accepted = &auth == b"\x01\x03sdk\x04tor!";
+                    stream.write_all(&[1, !accepted as u8]).unwrap();
+                    if !accepted {
+                        return;
+                    }
+                    let mut request = [0; 5];
+                    stream.read_exact(&mut request).unwrap();
+                    assert_eq!(request[..4], [VERSION, CONNECT, 0, DOMAIN]);
+                    let mut host = vec![0; request[4] as usize + 2];
+                    stream.read_exact(&mut host).unwrap();
+                    if &host[..host.len() - 2] != b"sidechain.onion" {
+                        stream
+                            .write_all(&[VERSION, 4, 0, IPV4, 0, 0, 0, 0, 0, 0])
+                            .unwrap();
+                        return;
+                    }
+                    let outbound = TcpStream::connect(target).unwrap();
+                    stream
+                        .write_all(&[VERSION, 0, 0, IPV4, 0, 0, 0, 0, 0, 0])
+                        .unwrap();
+                    pipe(stream, outbound).unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn connections_are_tunneled_through_the_proxy() {
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = echo.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in echo.incoming() {
+                let stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let _ = std::io::copy(&mut &stream, &mut &stream);
+                });
+            }
+        });
+        let proxy = Proxy::new(proxy_server(target)).with_credentials("sdk", "tor!");
+
+        let mut stream = proxy.connect("sidechain.onion", 80).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"ping");
+
+        let local = forward(proxy.clone(), "sidechain.onion".into(), 80).unwrap();
+        let mut stream = TcpStream::connect(local).unwrap();
+        stream.write_all(b"pong").unwrap();
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"pong");
+
+        assert!(matches!(
+            proxy.connect("example.com", 80),
+            Err(Error::Connect(4))
+        ));
+        let wrong = proxy.with_credentials("sdk", "nope");
+        assert!(matches!(
+            wrong.connect("sidechain.onion", 80),
+            Err(Error::Auth)
+        ));
+    }
+}
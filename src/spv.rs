@@ -0,0 +1,62 @@
+use bitcoin::util::merkleblock::{MerkleBlock, MerkleBlockError};
+
+// Checks that `txid` is committed to by `header`, using a merkle proof as
+// returned by the mainchain's gettxoutproof. `header` has to come from a
+// source the sidechain trusts, e.g. headers it validated itself, not from
+// the same RPC endpoint that produced the proof.
+pub fn verify_inclusion(
+    proof: &MerkleBlock,
+    txid: &bitcoin::Txid,
+    header: &bitcoin::BlockHeader,
+) -> Result<(), Error> {
+    if proof.header != *header {
+        return Err(Error::HeaderMismatch);
+    }
+    header
+        .validate_pow(&header.target())
+        .map_err(|_| Error::InvalidPow)?;
+    let mut matches = vec![];
+    let mut indexes = vec![];
+    proof.extract_matches(&mut matches, &mut indexes)?;
+    if !matches.contains(txid) {
+        return Err(Error::NotIncluded(*txid));
+    }
+    Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("proof is for a different block")]
+    HeaderMismatch,
+    #[error("block header doesn't have enough proof of work")]
+    InvalidPow,
+    #[error("invalid merkle proof: {0:?}")]
+    Merkle(MerkleBlockError),
+    #[error("transaction {0} is not included in the proof")]
+    NotIncluded(bitcoin::Txid),
+}
+
+impl From<MerkleBlockError> for Error {
+    fn from(other: MerkleBlockError) -> Self {
+        Self::Merkle(other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proof_of_genesis_coinbase() -> anyhow::Result<()> {
+        let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Regtest);
+        let coinbase = genesis.txdata[0].txid();
+        let proof = MerkleBlock::from_block_with_predicate(&genesis, |txid| *txid == coinbase);
+        verify_inclusion(&proof, &coinbase, &genesis.header)?;
+        let other = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Testnet);
+        assert!(matches!(
+            verify_inclusion(&proof, &coinbase, &other.header),
+            Err(Error::HeaderMismatch)
+        ));
+        Ok(())
+    }
+}
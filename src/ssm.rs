@@ -0,0 +1,264 @@
+use crate::types::{Body, Hash, Header, Transaction};
+
+// A sidechain's own state machine, kept next to the UTXO set. BlockChain
+// drives it from connect_block_with/disconnect_block_with so both always
+// describe the same chain tip.
+pub trait SSM<S, O> {
+    type Error: std::fmt::Display;
+
+    // Against the current state, for transactions on their own like the
+    // mempool's.
+    fn validate_transaction(&self, transaction: &Transaction<S, O>) -> Result<(), Self::Error>;
+    // Checks every transaction against the state the ones before it in the
+    // block left, so a block can spend what it creates, and changes nothing
+    // if one of them fails.
+    fn connect_block(&mut self, header: &Header, body: &Body<S, O>) -> Result<(), Self::Error>;
+    fn disconnect_block(&mut self, header: &Header, body: &Body<S, O>) -> Result<(), Self::Error>;
+}
+
+// For chains without a state of their own next to the UTXO set.
+impl<S, O> SSM<S, O> for () {
+    type Error = std::convert::Infallible;
+
+    fn validate_transaction(&self, _: &Transaction<S, O>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn connect_block(&mut self, _: &Header, _: &Body<S, O>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn disconnect_block(&mut self, _: &Header, _: &Body<S, O>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+// A state machine that can copy out its whole state and put it back, so a
+// reorg restores the state from before the block instead of undoing every
+// transaction, and that can commit to its state with a single hash.
+pub trait StatefulSSM<S, O>: SSM<S, O> {
+    type Snapshot;
+
+    fn snapshot(&self) -> Self::Snapshot;
+    fn restore(&mut self, snapshot: Self::Snapshot);
+    fn state_root(&self) -> Hash;
+}
+
+// Same as SSM, for state machines that have to await a database or a
+// network service while processing blocks.
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait AsyncSSM<S: Sync, O: Sync> {
+    type Error: std::fmt::Display;
+
+    async fn validate_transaction(
+        &self,
+        transaction: &Transaction<S, O>,
+    ) -> Result<(), Self::Error>;
+    async fn connect_block(
+        &mut self,
+        header: &Header,
+        body: &Body<S, O>,
+    ) -> Result<(), Self::Error>;
+    async fn disconnect_block(
+        &mut self,
+        header: &Header,
+        body: &Body<S, O>,
+    ) -> Result<(), Self::Error>;
+}
+
+// Async counterpart of BlockChain::with_extra_validator, for checks of
+// Transaction::extra that need to look things up. It is passed to
+// BlockChain::validate_transaction_async and connect_block_with_async,
+// which check the extra data with it instead of the extra validator.
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait AsyncValidator<S: Sync, O: Sync> {
+    type Error: std::fmt::Display;
+
+    async fn validate_transaction(
+        &self,
+        transaction: &Transaction<S, O>,
+    ) -> Result<(), Self::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::concrete::{Output, Signature};
+
+    // Counts connected blocks.
+    #[derive(Default)]
+    struct Counter(u64);
+
+    impl SSM<Signature, Output> for Counter {
+        type Error = String;
+
+        fn validate_transaction(
+            &self,
+            _transaction: &Transaction<Signature, Output>,
+        ) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn connect_block(
+            &mut self,
+            _header: &Header,
+            _body: &Body<Signature, Output>,
+        ) -> Result<(), String> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn disconnect_block(
+            &mut self,
+            _header: &Header,
+            _body: &Body<Signature, Output>,
+        ) -> Result<(), String> {
+            self.0 -= 1;
+            Ok(())
+        }
+    }
+
+    impl StatefulSSM<Signature, Output> for Counter {
+        type Snapshot = u64;
+
+        fn snapshot(&self) -> u64 {
+            self.0
+        }
+
+        fn restore(&mut self, snapshot: u64) {
+            self.0 = snapshot;
+        }
+
+        fn state_root(&self) -> Hash {
+            crate::types::hash(&self.0)
+        }
+    }
+
+    #[test]
+    fn wrong_state_root_is_rolled_back() {
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        let mut counter = Counter::default();
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![],
+        };
+        let header = Header::new(&Hash::default().into(), &body);
+        let bad_header = header.clone().with_state_root(crate::types::hash(&2u64));
+        assert!(blockchain
+            .connect_block_stateful(&mut counter, &bad_header, &body, None)
+            .is_err());
+        assert_eq!(counter.0, 0);
+        let header = header.with_state_root(crate::types::hash(&1u64));
+        let snapshot = blockchain
+            .connect_block_stateful(&mut counter, &header, &body, None)
+            .unwrap();
+        assert_eq!(counter.0, 1);
+        blockchain
+            .disconnect_block_stateful(&mut counter, &header, &body, snapshot)
+            .unwrap();
+        assert_eq!(counter.0, 0);
+        assert!(blockchain.validate_block(&header, &body, None));
+    }
+
+    // Accepts extra data that reads "ok".
+    #[cfg(feature = "async")]
+    struct OkExtra;
+
+    #[cfg(feature = "async")]
+    #[async_trait::async_trait]
+    impl AsyncValidator<Signature, Output> for OkExtra {
+        type Error = String;
+
+        async fn validate_transaction(
+            &self,
+            transaction: &Transaction<Signature, Output>,
+        ) -> Result<(), String> {
+            if transaction.extra == b"ok" {
+                Ok(())
+            } else {
+                Err("bad extra data".into())
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[async_trait::async_trait]
+    impl AsyncSSM<Signature, Output> for Counter {
+        type Error = String;
+
+        async fn validate_transaction(
+            &self,
+            transaction: &Transaction<Signature, Output>,
+        ) -> Result<(), String> {
+            SSM::validate_transaction(self, transaction)
+        }
+
+        async fn connect_block(
+            &mut self,
+            header: &Header,
+            body: &Body<Signature, Output>,
+        ) -> Result<(), String> {
+            SSM::connect_block(self, header, body)
+        }
+
+        async fn disconnect_block(
+            &mut self,
+            header: &Header,
+            body: &Body<Signature, Output>,
+        ) -> Result<(), String> {
+            SSM::disconnect_block(self, header, body)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_validator_checks_extra_data() {
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        let mut counter = Counter::default();
+        let transaction = |extra: &[u8]| Transaction::<Signature, Output> {
+            version: crate::types::TRANSACTION_VERSION,
+            inputs: vec![],
+            signatures: vec![],
+            outputs: vec![],
+            withdrawal_outputs: vec![],
+            extra: extra.to_vec(),
+        };
+        assert_eq!(
+            blockchain.validate_transaction(&transaction(b"ok")),
+            Err("unexpected extra data".into())
+        );
+        assert!(blockchain
+            .validate_transaction_async(&OkExtra, &transaction(b"ok"))
+            .await
+            .is_ok());
+        assert_eq!(
+            blockchain
+                .validate_transaction_async(&OkExtra, &transaction(b"no"))
+                .await,
+            Err("bad extra data".into())
+        );
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![transaction(b"no")],
+        };
+        let header = Header::new(&Hash::default().into(), &body);
+        assert!(blockchain
+            .connect_block_with_async(&mut counter, &OkExtra, &header, &body, None)
+            .await
+            .is_err());
+        assert_eq!(counter.0, 0);
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![transaction(b"ok")],
+        };
+        let header = Header::new(&Hash::default().into(), &body);
+        blockchain
+            .connect_block_with_async(&mut counter, &OkExtra, &header, &body, None)
+            .await
+            .unwrap();
+        assert_eq!(counter.0, 1);
+    }
+}
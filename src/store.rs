@@ -0,0 +1,283 @@
+use crate::blockchain::{BlockChain, ExtraValidator};
+use crate::bmm::Anchor;
+use crate::types::{hash, Body, Header, Out, Sig};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Keeps the chainstate, including the two way peg state, in a snapshot file
+// and a journal of the blocks connected since. A save replaces the snapshot
+// atomically and empties the journal, appending a block only writes that
+// block. Either way a crash leaves the state before or after the change on
+// disk, never a mix of the two.
+pub struct ChainStore {
+    path: PathBuf,
+}
+
+impl ChainStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Returns None if nothing was saved yet. The extra validator isn't
+    // saved with the chain, a chain saved with one can only be loaded with
+    // one, otherwise it would go on without checking extra data.
+    pub fn load<S, O>(
+        &self,
+        extra_validator: Option<ExtraValidator<S, O>>,
+    ) -> Result<Option<BlockChain<S, O>>, Error>
+    where
+        S: Sig + Serialize + DeserializeOwned + Clone,
+        O: Out + Serialize + DeserializeOwned + Clone,
+    {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let blockchain: BlockChain<S, O> = bincode::deserialize_from(BufReader::new(file))?;
+        match extra_validator {
+            Some(extra_validator) => Ok(Some(blockchain.with_extra_validator(extra_validator))),
+            None if blockchain.expects_extra_validator() => Err(Error::MissingExtraValidator),
+            None => Ok(Some(blockchain)),
+        }
+    }
+
+    // Connects the blocks journaled since the last save, returns how many.
+    // Ones the chain already has are skipped, they made it into the snapshot
+    // before the journal was emptied. A torn record at the end is what a
+    // crash in the middle of an append leaves, it and anything after it are
+    // dropped.
+    pub fn replay<S, O>(&self, blockchain: &mut BlockChain<S, O>) -> Result<usize, Error>
+    where
+        S: Sig + Serialize + DeserializeOwned + Clone,
+        O: Out + Serialize + DeserializeOwned + Clone,
+    {
+        let file = match File::open(self.journal_path()) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut reader = BufReader::new(file);
+        let mut connected = 0;
+        while let Some(record) = read_record(&mut reader)? {
+            let (header, body, anchor): (Header, Body<S, O>, Option<Anchor>) =
+                bincode::deserialize(&record)?;
+            if blockchain.get_header(&header.hash()).is_some() {
+                continue;
+            }
+            blockchain.connect_block(&header, &body, anchor.as_ref())?;
+            connected += 1;
+        }
+        Ok(connected)
+    }
+
+    // Appends a connected block to the journal, to be connected again by
+    // replay on top of the last save.
+    pub fn append_block<S, O>(
+        &self,
+        header: &Header,
+        body: &Body<S, O>,
+        anchor: Option<&Anchor>,
+    ) -> Result<(), Error>
+    where
+        S: Serialize,
+        O: Serialize,
+    {
+        let record = bincode::serialize(&(header, body, anchor))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.journal_path())?;
+        let created = file.metadata()?.len() == 0;
+        let mut data = Vec::with_capacity(RECORD_PREFIX_SIZE + record.len());
+        data.extend((record.len() as u64).to_le_bytes());
+        data.extend(hash(&record));
+        data.extend(&record);
+        file.write_all(&data)?;
+        file.sync_data()?;
+        if created {
+            self.sync_dir()?;
+        }
+        Ok(())
+    }
+
+    pub fn save<S, O>(&self, blockchain: &BlockChain<S, O>) -> Result<(), Error>
+    where
+        S: Serialize,
+        O: Serialize,
+    {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        bincode::serialize_into(&mut writer, blockchain)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        std::fs::rename(&tmp_path, &self.path)?;
+        // The snapshot has every journaled block now.
+        match std::fs::remove_file(self.journal_path()) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        // Make the rename and the removal durable.
+        self.sync_dir()
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        self.path.with_extension("journal")
+    }
+
+    fn sync_dir(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+// Length and checksum of the record that follows.
+const RECORD_PREFIX_SIZE: usize = 8 + 32;
+
+// None at the end of the journal or at a torn record.
+fn read_record(reader: &mut impl Read) -> Result<Option<Vec<u8>>, Error> {
+    let mut prefix = [0; RECORD_PREFIX_SIZE];
+    if !read_full(reader, &mut prefix)? {
+        return Ok(None);
+    }
+    let len = u64::from_le_bytes(prefix[..8].try_into().unwrap());
+    let mut record = vec![];
+    reader.take(len).read_to_end(&mut record)?;
+    if record.len() as u64 != len || prefix[8..] != hash(&record) {
+        return Ok(None);
+    }
+    Ok(Some(record))
+}
+
+// False if the reader ends before the buffer is filled.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, Error> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+    #[error("chain was saved with an extra validator, it has to be loaded with one")]
+    MissingExtraValidator,
+    #[error("blockchain error")]
+    Blockchain(#[from] crate::blockchain::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::TxBuilder;
+    use crate::concrete::{Output, Signature};
+    use crate::types::*;
+    use bitcoin::hashes::Hash;
+    use std::collections::HashMap;
+
+    #[test]
+    fn peg_state_survives_reload() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("sdk-store-{}", std::process::id()));
+        let store = ChainStore::new(&path);
+        assert!(store.load::<Signature, Output>(None)?.is_none());
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        let outpoint = bitcoin::OutPoint::default();
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(outpoint),
+                DepositOutput {
+                    address: [1; 32].into(),
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![Deposit {
+                outpoint,
+                total: 100,
+                main_block_hash: bitcoin::BlockHash::all_zeros(),
+                main_height: 0,
+            }],
+        });
+        store.save(&blockchain)?;
+        let loaded = store.load::<Signature, Output>(None)?.unwrap();
+        std::fs::remove_file(&path)?;
+        assert_eq!(loaded.peg.total_deposited(), Amount::from_sat(100));
+        assert_eq!(loaded.peg.last_deposit(), blockchain.peg.last_deposit());
+        assert_eq!(loaded.unspent_outpoints, blockchain.unspent_outpoints);
+        Ok(())
+    }
+
+    #[test]
+    fn journaled_blocks_are_replayed() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("sdk-store-journal-{}", std::process::id()));
+        let store = ChainStore::new(&path);
+        let mut blockchain = BlockChain::<Signature, Output>::new();
+        store.save(&blockchain)?;
+        for _ in 0..3 {
+            let body = Body {
+                coinbase: vec![],
+                transactions: vec![],
+            };
+            let prev_block_hash = blockchain
+                .get_best_block_hash()
+                .unwrap_or_else(|| <[u8; 32]>::default().into());
+            let header = Header::new(&prev_block_hash, &body);
+            blockchain.connect_block(&header, &body, None)?;
+            store.append_block(&header, &body, None)?;
+        }
+        // A crash in the middle of the last append.
+        let journal = OpenOptions::new().write(true).open(store.journal_path())?;
+        journal.set_len(journal.metadata()?.len() - 1)?;
+        let mut loaded = store.load::<Signature, Output>(None)?.unwrap();
+        assert_eq!(store.replay(&mut loaded)?, 2);
+        assert_eq!(loaded.height(), 2);
+        // Blocks already in the snapshot are skipped.
+        assert_eq!(store.replay(&mut loaded)?, 0);
+        store.save(&loaded)?;
+        assert!(!store.journal_path().exists());
+        let mut loaded = store.load::<Signature, Output>(None)?.unwrap();
+        assert_eq!(store.replay(&mut loaded)?, 0);
+        assert_eq!(loaded.height(), 2);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn extra_validators_are_registered_again_on_load() -> anyhow::Result<()> {
+        fn validate_extra(transaction: &Transaction<Signature, Output>) -> Result<(), String> {
+            match transaction.extra.as_slice() {
+                b"ok" => Ok(()),
+                _ => Err("bad extra data".into()),
+            }
+        }
+        let path = std::env::temp_dir().join(format!("sdk-store-extra-{}", std::process::id()));
+        let store = ChainStore::new(&path);
+        store.save(&BlockChain::<Signature, Output>::new().with_extra_validator(validate_extra))?;
+        let loaded = store.load::<Signature, Output>(None);
+        assert!(matches!(loaded, Err(Error::MissingExtraValidator)));
+        let loaded = store
+            .load::<Signature, Output>(Some(validate_extra))?
+            .unwrap();
+        std::fs::remove_file(&path)?;
+        let transaction = |extra: &[u8]| TxBuilder::new().with_extra(extra.to_vec()).build();
+        assert_eq!(loaded.validate_transaction(&transaction(b"ok")), Ok(()));
+        assert_eq!(
+            loaded.validate_transaction(&transaction(b"no")),
+            Err("bad extra data".into())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+use crate::ssm::{StatefulSSM, SSM};
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// A fungible token sidechain built only from the SDK traits: outputs carry
+// either coins or tokens, TokenState tracks which outputs hold which
+// tokens and enforces that tokens are neither created nor destroyed except
+// by issuance.
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct TokenId(Hash);
+
+impl TokenId {
+    // A transaction can issue a single token, identified by its first
+    // input. That input can only be spent once, so ids never collide.
+    pub fn issued_by(transaction: &Transaction<impl Serialize, TokenOutput>) -> Option<Self> {
+        transaction.inputs.first().map(|input| Self(hash(input)))
+    }
+}
+
+impl std::fmt::Display for TokenId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+// Which token an output holds and how much of it.
+pub type Token = (TokenId, u64);
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum TokenOutput {
+    Coin {
+        address: Address,
+        value: Amount,
+    },
+    Token {
+        address: Address,
+        token_id: TokenId,
+        amount: u64,
+    },
+}
+
+impl TokenOutput {
+    fn token(&self) -> Option<Token> {
+        match self {
+            Self::Coin { .. } => None,
+            Self::Token {
+                token_id, amount, ..
+            } => Some((*token_id, *amount)),
+        }
+    }
+}
+
+impl Out for TokenOutput {
+    // Only checks coins, tokens are checked by TokenState.
+    fn validate(
+        inputs: &[Self],
+        deposit_inputs: &[DepositOutput],
+        withdrawal_inputs: &[WithdrawalOutput],
+        outputs: &[Self],
+        withdrawal_outputs: &[WithdrawalOutput],
+    ) -> bool {
+        let value_in = Amount::checked_sum(
+            inputs
+                .iter()
+                .map(Out::get_value)
+                .chain(deposit_inputs.iter().map(|i| i.value))
+                .chain(withdrawal_inputs.iter().map(|i| i.value)),
+        );
+        let value_out = Amount::checked_sum(
+            outputs
+                .iter()
+                .map(Out::get_value)
+                .chain(withdrawal_outputs.iter().map(|o| o.value)),
+        );
+        match (value_in, value_out) {
+            (Some(value_in), Some(value_out)) => value_out > value_in,
+            _ => true,
+        }
+    }
+
+    fn get_fee(
+        inputs: &[Self],
+        deposit_inputs: &[DepositOutput],
+        withdrawal_inputs: &[WithdrawalOutput],
+        outputs: &[Self],
+        withdrawal_outputs: &[WithdrawalOutput],
+    ) -> Amount {
+        let value_in: Amount = inputs.iter().map(Out::get_value).sum::<Amount>()
+            + deposit_inputs.iter().map(|i| i.value).sum::<Amount>()
+            + withdrawal_inputs.iter().map(|i| i.value).sum::<Amount>();
+        let value_out: Amount = outputs.iter().map(Out::get_value).sum::<Amount>()
+            + withdrawal_outputs.iter().map(|o| o.value).sum::<Amount>();
+        value_in - value_out
+    }
+
+    fn get_address(&self) -> Address {
+        match self {
+            Self::Coin { address, .. } | Self::Token { address, .. } => *address,
+        }
+    }
+
+    // Token outputs hold no coins.
+    fn get_value(&self) -> Amount {
+        match self {
+            Self::Coin { value, .. } => *value,
+            Self::Token { .. } => Amount::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TokenState {
+    // BTreeMaps so the state root doesn't depend on insertion order.
+    tokens: BTreeMap<OutPoint, Token>,
+    supply: BTreeMap<TokenId, u64>,
+    // Token outputs spent by each block, to put them back when it is
+    // disconnected.
+    #[serde(skip)]
+    spent: HashMap<BlockHash, Vec<(OutPoint, Token)>>,
+}
+
+impl TokenState {
+    pub fn get_token(&self, outpoint: &OutPoint) -> Option<Token> {
+        self.tokens.get(outpoint).copied()
+    }
+
+    pub fn supply(&self, token_id: &TokenId) -> u64 {
+        self.supply.get(token_id).copied().unwrap_or(0)
+    }
+
+    // Takes back the outputs `transactions` created and puts back the ones
+    // they spent.
+    fn undo<S>(
+        &mut self,
+        transactions: &[Transaction<S, TokenOutput>],
+        spent: Vec<(OutPoint, Token)>,
+    ) where
+        S: Serialize + Clone,
+    {
+        let txids: HashSet<Txid> = transactions.iter().map(|tx| tx.txid()).collect();
+        for transaction in transactions {
+            let txid = transaction.txid();
+            for vout in 0..transaction.outputs.len() {
+                let outpoint = OutPoint::Regular {
+                    txid,
+                    vout: vout as u32,
+                };
+                if let Some((token_id, amount)) = self.tokens.remove(&outpoint) {
+                    self.remove_supply(token_id, amount);
+                }
+            }
+        }
+        for (outpoint, (token_id, amount)) in spent {
+            // Outputs created and spent by the same transactions are gone
+            // for good.
+            if let OutPoint::Regular { txid, .. } = outpoint {
+                if txids.contains(&txid) {
+                    continue;
+                }
+            }
+            self.tokens.insert(outpoint, (token_id, amount));
+            *self.supply.entry(token_id).or_default() += amount;
+        }
+    }
+
+    fn remove_supply(&mut self, token_id: TokenId, amount: u64) {
+        if let Some(supply) = self.supply.get_mut(&token_id) {
+            *supply -= amount;
+            if *supply == 0 {
+                self.supply.remove(&token_id);
+            }
+        }
+    }
+}
+
+impl<S: Serialize + Clone> SSM<S, TokenOutput> for TokenState {
+    type Error = Error;
+
+    fn validate_transaction(&self, transaction: &Transaction<S, TokenOutput>) -> Result<(), Error> {
+        let mut balances: HashMap<TokenId, i128> = HashMap::new();
+        for input in &transaction.inputs {
+            if let Some((token_id, amount)) = self.get_token(input) {
+                *balances.entry(token_id).or_default() += amount as i128;
+            }
+        }
+        for (token_id, amount) in transaction.outputs.iter().filter_map(TokenOutput::token) {
+            *balances.entry(token_id).or_default() -= amount as i128;
+        }
+        let issued = TokenId::issued_by(transaction);
+        for (token_id, balance) in balances {
+            if balance == 0 {
+                continue;
+            }
+            if balance > 0 || Some(token_id) != issued || self.supply.contains_key(&token_id) {
+                return Err(Error::Unbalanced(token_id));
+            }
+        }
+        Ok(())
+    }
+
+    fn connect_block(&mut self, header: &Header, body: &Body<S, TokenOutput>) -> Result<(), Error> {
+        let mut spent = vec![];
+        for (index, transaction) in body.transactions.iter().enumerate() {
+            if let Err(err) = self.validate_transaction(transaction) {
+                self.undo(&body.transactions[..index], spent);
+                return Err(err);
+            }
+            for input in &transaction.inputs {
+                if let Some((token_id, amount)) = self.tokens.remove(input) {
+                    self.remove_supply(token_id, amount);
+                    spent.push((*input, (token_id, amount)));
+                }
+            }
+            let txid = transaction.txid();
+            for (vout, output) in transaction.outputs.iter().enumerate() {
+                if let Some((token_id, amount)) = output.token() {
+                    let outpoint = OutPoint::Regular {
+                        txid,
+                        vout: vout as u32,
+                    };
+                    self.tokens.insert(outpoint, (token_id, amount));
+                    *self.supply.entry(token_id).or_default() += amount;
+                }
+            }
+        }
+        self.spent.insert(header.hash(), spent);
+        Ok(())
+    }
+
+    fn disconnect_block(
+        &mut self,
+        header: &Header,
+        body: &Body<S, TokenOutput>,
+    ) -> Result<(), Error> {
+        let spent = self
+            .spent
+            .remove(&header.hash())
+            .ok_or(Error::UnknownBlock(header.hash()))?;
+        self.undo(&body.transactions, spent);
+        Ok(())
+    }
+}
+
+impl<S: Serialize + Clone> StatefulSSM<S, TokenOutput> for TokenState {
+    type Snapshot = TokenState;
+
+    fn snapshot(&self) -> TokenState {
+        self.clone()
+    }
+
+    fn restore(&mut self, snapshot: TokenState) {
+        *self = snapshot;
+    }
+
+    fn state_root(&self) -> Hash {
+        hash(&(&self.tokens, &self.supply))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("transaction creates or destroys token {0}")]
+    Unbalanced(TokenId),
+    #[error("block {0} was never connected")]
+    UnknownBlock(BlockHash),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::concrete::Signature;
+
+    fn sign(
+        keypair: &ed25519_dalek::Keypair,
+        transaction: Transaction<Signature, TokenOutput>,
+    ) -> Transaction<Signature, TokenOutput> {
+        let signatures = transaction
+            .inputs
+            .iter()
+            .map(|_| Signature::new(keypair, &transaction))
+            .collect();
+        Transaction {
+            signatures,
+            ..transaction
+        }
+    }
+
+    fn block(
+        blockchain: &BlockChain<Signature, TokenOutput>,
+        transaction: &Transaction<Signature, TokenOutput>,
+    ) -> (Header, Body<Signature, TokenOutput>) {
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![transaction.clone()],
+        };
+        let prev_block_hash = blockchain
+            .get_best_block_hash()
+            .unwrap_or_else(|| Hash::default().into());
+        (Header::new(&prev_block_hash, &body), body)
+    }
+
+    #[test]
+    fn tokens_are_issued_once_and_conserved() -> anyhow::Result<()> {
+        let keypair = ed25519_dalek::Keypair::generate(&mut rand::thread_rng());
+        let address: Address = keypair.public.into();
+        let mut blockchain = BlockChain::<Signature, TokenOutput>::new();
+        let mut state = TokenState::default();
+        let deposit = bitcoin::OutPoint::default();
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                OutPoint::Deposit(deposit),
+                DepositOutput {
+                    address,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![Deposit {
+                outpoint: deposit,
+                total: 100,
+                main_block_hash: bitcoin::hashes::Hash::all_zeros(),
+                main_height: 0,
+            }],
+        });
+
+        let mut issuance = Transaction {
+            version: TRANSACTION_VERSION,
+            inputs: vec![OutPoint::Deposit(deposit)],
+            signatures: vec![],
+            outputs: vec![TokenOutput::Coin {
+                address,
+                value: Amount::from_sat(100),
+            }],
+            withdrawal_outputs: vec![],
+            extra: vec![],
+        };
+        let token_id = TokenId::issued_by(&issuance).unwrap();
+        issuance.outputs.push(TokenOutput::Token {
+            address,
+            token_id,
+            amount: 1000,
+        });
+        let issuance = sign(&keypair, issuance);
+        let (header, body) = block(&blockchain, &issuance);
+        blockchain
+            .connect_block_stateful(&mut state, &header, &body, None)
+            .unwrap();
+        assert_eq!(state.supply(&token_id), 1000);
+
+        let token_outpoint = OutPoint::Regular {
+            txid: issuance.txid(),
+            vout: 1,
+        };
+        let transfer = |amounts: &[u64]| {
+            sign(
+                &keypair,
+                Transaction {
+                    version: TRANSACTION_VERSION,
+                    inputs: vec![token_outpoint],
+                    signatures: vec![],
+                    outputs: amounts
+                        .iter()
+                        .map(|amount| TokenOutput::Token {
+                            address,
+                            token_id,
+                            amount: *amount,
+                        })
+                        .collect(),
+                    withdrawal_outputs: vec![],
+                    extra: vec![],
+                },
+            )
+        };
+        assert!(SSM::validate_transaction(&state, &transfer(&[600, 401])).is_err());
+        assert!(SSM::validate_transaction(&state, &transfer(&[600])).is_err());
+        let transfer = transfer(&[600, 400]);
+        let (header, body) = block(&blockchain, &transfer);
+        blockchain
+            .connect_block_with(&mut state, &header, &body, None)
+            .unwrap();
+        assert_eq!(state.supply(&token_id), 1000);
+        assert_eq!(state.get_token(&token_outpoint), None);
+
+        blockchain
+            .disconnect_block_with(&mut state, &header, &body)
+            .unwrap();
+        assert_eq!(state.get_token(&token_outpoint), Some((token_id, 1000)));
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_are_spent_in_the_block_issuing_them() -> anyhow::Result<()> {
+        let keypair = ed25519_dalek::Keypair::generate(&mut rand::thread_rng());
+        let address: Address = keypair.public.into();
+        let mut blockchain = BlockChain::<Signature, TokenOutput>::new();
+        let mut state = TokenState::default();
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint::default());
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                deposit,
+                DepositOutput {
+                    address,
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let issuance = Transaction {
+            version: TRANSACTION_VERSION,
+            inputs: vec![deposit],
+            signatures: vec![],
+            outputs: vec![TokenOutput::Coin {
+                address,
+                value: Amount::from_sat(100),
+            }],
+            withdrawal_outputs: vec![],
+            extra: vec![],
+        };
+        let token_id = TokenId::issued_by(&issuance).unwrap();
+        let mut issuance = issuance;
+        issuance.outputs.push(TokenOutput::Token {
+            address,
+            token_id,
+            amount: 1000,
+        });
+        let issuance = sign(&keypair, issuance);
+        let transfer = |amount| {
+            sign(
+                &keypair,
+                Transaction {
+                    version: TRANSACTION_VERSION,
+                    inputs: vec![OutPoint::Regular {
+                        txid: issuance.txid(),
+                        vout: 1,
+                    }],
+                    signatures: vec![],
+                    outputs: vec![TokenOutput::Token {
+                        address,
+                        token_id,
+                        amount,
+                    }],
+                    withdrawal_outputs: vec![],
+                    extra: vec![],
+                },
+            )
+        };
+        let body = |transfer| Body {
+            coinbase: vec![],
+            transactions: vec![issuance.clone(), transfer],
+        };
+
+        // A transfer that doesn't add up takes the issuance before it down
+        // with it.
+        let bad = body(transfer(999));
+        let header = Header::new(&Hash::default().into(), &bad);
+        let state_root = StatefulSSM::<Signature, TokenOutput>::state_root;
+        let root = state_root(&state);
+        assert!(blockchain
+            .connect_block_with(&mut state, &header, &bad, None)
+            .is_err());
+        assert_eq!(state_root(&state), root);
+        assert_eq!(blockchain.height(), 0);
+
+        let transfer = transfer(1000);
+        let body = body(transfer.clone());
+        let header = Header::new(&Hash::default().into(), &body);
+        let snapshot = blockchain
+            .connect_block_stateful(&mut state, &header, &body, None)
+            .unwrap();
+        let outpoint = OutPoint::Regular {
+            txid: transfer.txid(),
+            vout: 0,
+        };
+        assert_eq!(state.get_token(&outpoint), Some((token_id, 1000)));
+        assert_eq!(state.supply(&token_id), 1000);
+        blockchain
+            .disconnect_block_stateful(&mut state, &header, &body, snapshot)
+            .unwrap();
+        assert_eq!(state_root(&state), root);
+        Ok(())
+    }
+}
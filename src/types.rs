@@ -1,142 +1,148 @@
+pub use crate::amount::{Amount, FeeRate};
+use crate::encode;
+pub use crate::primitives::*;
 use serde::{Deserialize, Serialize};
-use sha2::Digest;
 use std::collections::HashMap;
 
-pub const THIS_SIDECHAIN: usize = 0;
-
-const SHA256_LENGTH: usize = 32;
-pub type Hash = [u8; SHA256_LENGTH];
-
-#[derive(Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
-pub struct BlockHash(Hash);
-
-impl From<Hash> for BlockHash {
-    fn from(other: Hash) -> Self {
-        Self(other)
-    }
-}
-
-impl std::fmt::Display for BlockHash {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
-    }
-}
-
-impl std::fmt::Debug for BlockHash {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
-    }
-}
-
-#[derive(Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
-pub struct MerkleRoot(Hash);
-
-impl From<Hash> for MerkleRoot {
-    fn from(other: Hash) -> Self {
-        Self(other)
+impl From<ed25519_dalek::PublicKey> for Address {
+    fn from(other: ed25519_dalek::PublicKey) -> Self {
+        hash(&other.to_bytes()).into()
     }
 }
 
-impl std::fmt::Display for MerkleRoot {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
+// Same derivation as for ed25519 keys, x-only keys are 32 bytes as well.
+impl From<bitcoin::secp256k1::XOnlyPublicKey> for Address {
+    fn from(other: bitcoin::secp256k1::XOnlyPublicKey) -> Self {
+        hash(&other.serialize()).into()
     }
 }
 
-impl std::fmt::Debug for MerkleRoot {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
-    }
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum OutPoint {
+    Regular { txid: Txid, vout: u32 },
+    Coinbase { block_hash: BlockHash, vout: u32 },
+    Withdrawal { txid: Txid, vout: u32 },
+    Deposit(bitcoin::OutPoint),
 }
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
-pub struct Txid(Hash);
-
-impl From<Hash> for Txid {
-    fn from(other: Hash) -> Self {
-        Self(other)
+// Compact form for the RPC and CLI, kind:txid:vout with kind one of regular,
+// coinbase, withdrawal or deposit. Coinbase outpoints have the block hash in
+// place of the txid, deposits the mainchain txid.
+impl core::fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Regular { txid, vout } => write!(f, "regular:{}:{}", txid, vout),
+            Self::Coinbase { block_hash, vout } => write!(f, "coinbase:{}:{}", block_hash, vout),
+            Self::Withdrawal { txid, vout } => write!(f, "withdrawal:{}:{}", txid, vout),
+            Self::Deposit(outpoint) => write!(f, "deposit:{}:{}", outpoint.txid, outpoint.vout),
+        }
     }
 }
 
-impl From<Txid> for Hash {
-    fn from(other: Txid) -> Self {
-        other.0
+impl core::str::FromStr for OutPoint {
+    type Err = OutPointError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (kind, hash, vout) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(hash), Some(vout)) => (kind, hash, vout),
+            _ => return Err(OutPointError::Format(s.into())),
+        };
+        let vout = vout.parse().map_err(|_| OutPointError::Vout(vout.into()))?;
+        let outpoint = match kind {
+            "regular" => Self::Regular {
+                txid: parse_hash(hash)?,
+                vout,
+            },
+            "coinbase" => Self::Coinbase {
+                block_hash: parse_hash(hash)?,
+                vout,
+            },
+            "withdrawal" => Self::Withdrawal {
+                txid: parse_hash(hash)?,
+                vout,
+            },
+            "deposit" => Self::Deposit(bitcoin::OutPoint {
+                txid: parse_hash(hash)?,
+                vout,
+            }),
+            _ => return Err(OutPointError::Kind(kind.into())),
+        };
+        Ok(outpoint)
     }
 }
 
-impl std::fmt::Display for Txid {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
-    }
+fn parse_hash<T: core::str::FromStr>(s: &str) -> Result<T, OutPointError> {
+    s.parse().map_err(|_| OutPointError::Hash(s.into()))
 }
 
-impl std::fmt::Debug for Txid {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
-    }
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum OutPointError {
+    #[error("outpoint {0:?} is not of the form kind:txid:vout")]
+    Format(String),
+    #[error("unknown outpoint kind {0:?}")]
+    Kind(String),
+    #[error("invalid outpoint hash {0:?}")]
+    Hash(String),
+    #[error("invalid outpoint vout {0:?}")]
+    Vout(String),
 }
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
-pub struct Address(Hash);
-
-impl Address {
-    pub fn to_string(&self) -> String {
-        bs58::encode(self.0)
-            .with_alphabet(bs58::Alphabet::BITCOIN)
-            .with_check()
-            .into_string()
-    }
-
-    pub fn to_deposit_string(&self) -> String {
-        format_deposit_address(THIS_SIDECHAIN, &self.to_string())
+// Human readable formats like JSON get the string form. Everything else,
+// including the consensus encoding txids are computed over, gets the derived
+// one of the enum below.
+impl Serialize for OutPoint {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            OutPointRepr::from(*self).serialize(serializer)
+        }
     }
 }
 
-fn format_deposit_address(sidechain_number: usize, address: &str) -> String {
-    let deposit_address: String = format!("s{}_{}_", sidechain_number, address);
-    let hash = sha256::digest(deposit_address.as_bytes());
-    let hash: String = hash[..6].into();
-    format!("{}{}", deposit_address, hash)
-}
-
-impl std::fmt::Display for Address {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+impl<'de> Deserialize<'de> for OutPoint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(serde::de::Error::custom)
+        } else {
+            OutPointRepr::deserialize(deserializer).map(Into::into)
+        }
     }
 }
 
-impl std::fmt::Debug for Address {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
-    }
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "OutPoint")]
+enum OutPointRepr {
+    Regular { txid: Txid, vout: u32 },
+    Coinbase { block_hash: BlockHash, vout: u32 },
+    Withdrawal { txid: Txid, vout: u32 },
+    Deposit(bitcoin::OutPoint),
 }
 
-impl From<ed25519_dalek::PublicKey> for Address {
-    fn from(other: ed25519_dalek::PublicKey) -> Self {
-        Self(hash(&other.to_bytes()))
+impl From<OutPoint> for OutPointRepr {
+    fn from(other: OutPoint) -> Self {
+        match other {
+            OutPoint::Regular { txid, vout } => Self::Regular { txid, vout },
+            OutPoint::Coinbase { block_hash, vout } => Self::Coinbase { block_hash, vout },
+            OutPoint::Withdrawal { txid, vout } => Self::Withdrawal { txid, vout },
+            OutPoint::Deposit(outpoint) => Self::Deposit(outpoint),
+        }
     }
 }
 
-impl std::str::FromStr for Address {
-    type Err = bs58::decode::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let address = bs58::decode(s)
-            .with_alphabet(bs58::Alphabet::BITCOIN)
-            .with_check(None)
-            .into_vec()?;
-        assert_eq!(address.len(), 32);
-        Ok(Address(address.try_into().unwrap()))
+impl From<OutPointRepr> for OutPoint {
+    fn from(other: OutPointRepr) -> Self {
+        match other {
+            OutPointRepr::Regular { txid, vout } => Self::Regular { txid, vout },
+            OutPointRepr::Coinbase { block_hash, vout } => Self::Coinbase { block_hash, vout },
+            OutPointRepr::Withdrawal { txid, vout } => Self::Withdrawal { txid, vout },
+            OutPointRepr::Deposit(outpoint) => Self::Deposit(outpoint),
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
-pub enum OutPoint {
-    Regular { txid: Txid, vout: u32 },
-    Coinbase { block_hash: BlockHash, vout: u32 },
-    Withdrawal { txid: Txid, vout: u32 },
-    Deposit(bitcoin::OutPoint),
-}
-
 pub trait Out: Sized {
     fn validate(
         inputs: &[Self],
@@ -151,35 +157,63 @@ pub trait Out: Sized {
         withdrawal_inputs: &[WithdrawalOutput],
         outputs: &[Self],
         withdrawal_outputs: &[WithdrawalOutput],
-    ) -> u64;
+    ) -> Amount;
     fn get_address(&self) -> Address;
+    fn get_value(&self) -> Amount;
 }
 
 pub trait Sig {
-    fn is_valid(&self, txid_without_signatures: Txid) -> bool;
+    // `sighash` is Transaction::sighash of the transaction carrying the
+    // signature.
+    fn is_valid(&self, sighash: Hash) -> bool;
     fn get_address(&self) -> Address;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DepositOutput {
     pub address: Address,
-    pub value: u64,
+    pub value: Amount,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WithdrawalOutput {
-    pub value: u64,
-    pub fee: u64,
+    pub value: Amount,
+    pub fee: Amount,
     pub side_address: Address,
     pub main_address: bitcoin::Address,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction<S, O> {
+    // Selects the validation rules the transaction is checked with, see
+    // TRANSACTION_VERSION.
+    pub version: u32,
     pub inputs: Vec<OutPoint>,
     pub signatures: Vec<S>,
     pub outputs: Vec<O>,
     pub withdrawal_outputs: Vec<WithdrawalOutput>,
+    // Application specific payload, e.g. token transfers or votes. It is
+    // committed to by the txid and checked by the validator registered with
+    // BlockChain::with_extra_validator.
+    pub extra: Vec<u8>,
+}
+
+// Signatures aren't needed to check anything once a transaction is buried,
+// so they only count a quarter towards its weight.
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+
+// Version of the transactions this node creates and relays. Blocks may carry
+// higher versions: they are checked with the rules every version shares and
+// their extra data is left to whatever fork gives it meaning, so such a fork
+// doesn't split nodes that haven't upgraded yet. Version 0 is invalid.
+pub const TRANSACTION_VERSION: u32 = 1;
+
+impl<S, O> Transaction<S, O> {
+    // False for versions this node doesn't know the rules of, which it
+    // accepts in blocks but doesn't relay or mine.
+    pub fn is_standard_version(&self) -> bool {
+        (1..=TRANSACTION_VERSION).contains(&self.version)
+    }
 }
 
 impl<S: Serialize + Clone, O: Serialize + Clone> Transaction<S, O> {
@@ -191,27 +225,63 @@ impl<S: Serialize + Clone, O: Serialize + Clone> Transaction<S, O> {
     }
 
     pub fn txid(&self) -> Txid {
-        hash(self).into()
+        self.txid_with::<Sha256>()
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Header {
-    pub prev_block_hash: BlockHash,
-    pub merkle_root: MerkleRoot,
+    // What every signature of the transaction signs: the version, the
+    // inputs being spent, the outputs, the withdrawal outputs and the extra
+    // data, everything but the signatures. Committing to the inputs keeps a
+    // signature from being moved onto another transaction spending other
+    // coins of the same key. Always SHA256, whichever hasher the chain uses
+    // for txids.
+    pub fn sighash(&self) -> Hash {
+        let preimage = SighashPreimage {
+            version: self.version,
+            inputs: &self.inputs,
+            outputs: &self.outputs,
+            withdrawal_outputs: &self.withdrawal_outputs,
+            extra: &self.extra,
+        };
+        tagged_hash_with::<Sha256, _>(SIGHASH_TAG, &preimage)
+    }
+
+    pub fn txid_with<H: Hasher>(&self) -> Txid {
+        tagged_hash_with::<H, _>(TXID_TAG, self).into()
+    }
+
+    // Bytes of the consensus encoding.
+    pub fn size(&self) -> usize {
+        encoded_size(self)
+    }
+
+    // Like a segwit weight, the size without signatures counts four times
+    // and the signatures once.
+    pub fn weight(&self) -> usize {
+        let stripped_size = self.without_signatures().size();
+        stripped_size * (WITNESS_SCALE_FACTOR - 1) + self.size()
+    }
+
+    // Weight in bytes, what fee rates are paid for.
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(WITNESS_SCALE_FACTOR)
+    }
 }
 
 impl Header {
     pub fn new<S: Serialize, O: Serialize>(prev_block_hash: &BlockHash, body: &Body<S, O>) -> Self {
+        Self::new_with::<Sha256, S, O>(prev_block_hash, body)
+    }
+
+    pub fn new_with<H: Hasher, S: Serialize, O: Serialize>(
+        prev_block_hash: &BlockHash,
+        body: &Body<S, O>,
+    ) -> Self {
         Self {
             prev_block_hash: *prev_block_hash,
-            merkle_root: body.compute_merkle_root(),
+            merkle_root: body.compute_merkle_root_with::<H>(),
+            state_root: None,
         }
     }
-
-    pub fn hash(&self) -> BlockHash {
-        hash(self).into()
-    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -222,28 +292,408 @@ pub struct Body<S, O> {
 
 impl<S: Serialize, O: Serialize> Body<S, O> {
     pub fn compute_merkle_root(&self) -> MerkleRoot {
-        // FIXME: Compute actual merkle root instead of just a hash.
-        let serialized_transactions = bincode::serialize(&self.transactions).unwrap();
-        hash(&serialized_transactions).into()
+        self.compute_merkle_root_with::<Sha256>()
+    }
+
+    // Root of a binary tree over the hash of the coinbase followed by the
+    // txids, so the header commits to who the fees are paid to.
+    pub fn compute_merkle_root_with<H: Hasher>(&self) -> MerkleRoot {
+        merkle_root_with::<H>(self.leaves_with::<H>())
     }
+
+    pub fn merkle_proof(&self, txid: &Txid) -> Option<MerkleProof> {
+        self.merkle_proof_with::<Sha256>(txid)
+    }
+
+    // None if no transaction of the body has this txid.
+    pub fn merkle_proof_with<H: Hasher>(&self, txid: &Txid) -> Option<MerkleProof> {
+        let mut level = self.leaves_with::<H>();
+        let position = 1 + level[1..]
+            .iter()
+            .position(|leaf| *leaf == Hash::from(*txid))?;
+        let mut index = position;
+        let mut siblings = vec![];
+        while level.len() > 1 {
+            siblings.push(level.get(index ^ 1).copied().unwrap_or_default());
+            level = merkle_level_up::<H>(&level);
+            index /= 2;
+        }
+        Some(MerkleProof {
+            siblings,
+            position: position.try_into().ok()?,
+        })
+    }
+
+    // Transactions are encoded one after another so S and O don't have to
+    // be Sync, only the hashing is spread over threads.
+    fn leaves_with<H: Hasher>(&self) -> Vec<Hash> {
+        let preimages: Vec<Vec<u8>> = self
+            .transactions
+            .iter()
+            .map(|transaction| tagged_preimage::<H, _>(TXID_TAG, transaction))
+            .collect();
+        let txids = map_chunks(&preimages, 1, |preimage| H::digest(&preimage[0]));
+        let coinbase = tagged_hash_with::<H, _>(COINBASE_TAG, &self.coinbase);
+        [vec![coinbase], txids].concat()
+    }
+}
+
+impl<S: Serialize + Clone, O: Serialize + Clone> Body<S, O> {
+    // Bytes of the consensus encoding, what the block size limit is on.
+    pub fn size(&self) -> usize {
+        encoded_size(self)
+    }
+
+    // Signatures get the same discount as in Transaction::weight.
+    pub fn weight(&self) -> usize {
+        let signatures_size: usize = self
+            .transactions
+            .iter()
+            .map(|transaction| transaction.size() - transaction.without_signatures().size())
+            .sum();
+        self.size() * WITNESS_SCALE_FACTOR - signatures_size * (WITNESS_SCALE_FACTOR - 1)
+    }
+}
+
+fn encoded_size<T: Serialize>(data: &T) -> usize {
+    encode::to_vec(data)
+        .expect("failed to serialize a type to compute its size")
+        .len()
 }
 
-pub fn hash<T: Serialize>(data: &T) -> Hash {
-    let mut hasher = sha2::Sha256::new();
-    let data_serialized =
-        bincode::serialize(data).expect("failed to serialize a type to compute a hash");
-    hasher.update(data_serialized);
-    hasher.finalize().into()
+#[derive(Serialize)]
+struct SighashPreimage<'a, O> {
+    version: u32,
+    inputs: &'a [OutPoint],
+    outputs: &'a [O],
+    withdrawal_outputs: &'a [WithdrawalOutput],
+    extra: &'a [u8],
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Deposit {
     pub outpoint: bitcoin::OutPoint,
     pub total: u64,
+    // Mainchain block the deposit was included in, used to notice when a
+    // mainchain reorg disconnects it.
+    pub main_block_hash: bitcoin::BlockHash,
+    // Height of that block, deposits mature relative to it.
+    pub main_height: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DepositsChunk {
     pub outputs: HashMap<OutPoint, DepositOutput>,
     pub deposits: Vec<Deposit>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for a hasher other than SHA256 without the blake3 feature.
+    #[derive(Debug, Clone, Copy, Default)]
+    struct Reversed;
+
+    impl Hasher for Reversed {
+        fn digest(data: &[u8]) -> Hash {
+            let mut hash = Sha256::digest(data);
+            hash.reverse();
+            hash
+        }
+    }
+
+    fn check_chain_hasher<H: Hasher>() {
+        use crate::blockchain::BlockChain;
+        use crate::builder::{keypair, TxBuilder};
+        use crate::concrete::{Output, Signature};
+
+        let body = Body::<Signature, Output> {
+            coinbase: vec![],
+            transactions: vec![Transaction {
+                version: TRANSACTION_VERSION,
+                inputs: vec![],
+                signatures: vec![],
+                outputs: vec![],
+                withdrawal_outputs: vec![],
+                extra: vec![],
+            }],
+        };
+        let header = Header::new_with::<H, _, _>(&Hash::default().into(), &body);
+        assert_ne!(header.hash_with::<H>(), header.hash());
+        // The merkle root was computed with the chain's hasher as well.
+        let sha256_chain = BlockChain::<Signature, Output>::new();
+        assert!(!sha256_chain.validate_block(&header, &body, None));
+        let mut chain = BlockChain::<Signature, Output, H>::default();
+        chain.connect_block(&header, &body, None).unwrap();
+        assert_eq!(chain.get_best_block_hash(), Some(header.hash_with::<H>()));
+        // Signatures are made without knowing the hasher.
+        let alice = keypair([1; 32]);
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint::default());
+        chain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                deposit,
+                DepositOutput {
+                    address: alice.public.into(),
+                    value: Amount::from_sat(100),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let transaction = TxBuilder::new()
+            .spend(deposit, &alice)
+            .pay(alice.public.into(), Amount::from_sat(100))
+            .build();
+        assert_eq!(chain.validate_transaction(&transaction), Ok(()));
+    }
+
+    #[test]
+    fn chain_uses_its_hasher() {
+        check_chain_hasher::<Reversed>();
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_chain_uses_blake3_block_hashes() {
+        check_chain_hasher::<Blake3>();
+    }
+
+    #[test]
+    fn future_transaction_versions_are_valid_but_not_standard() {
+        use crate::blockchain::BlockChain;
+        use crate::concrete::{Output, Signature};
+
+        let blockchain = BlockChain::<Signature, Output>::new();
+        let transaction = |version| Transaction::<Signature, Output> {
+            version,
+            inputs: vec![],
+            signatures: vec![],
+            outputs: vec![],
+            withdrawal_outputs: vec![],
+            extra: vec![1],
+        };
+        let current = transaction(TRANSACTION_VERSION);
+        let future = transaction(TRANSACTION_VERSION + 1);
+        assert_ne!(current.txid(), future.txid());
+        assert!(current.is_standard_version());
+        assert!(blockchain.validate_transaction(&current).is_err());
+        assert!(!future.is_standard_version());
+        assert!(blockchain.validate_transaction(&future).is_ok());
+        assert!(!transaction(0).is_standard_version());
+        assert!(blockchain.validate_transaction(&transaction(0)).is_err());
+    }
+
+    #[test]
+    fn merkle_proofs_verify_against_the_root() {
+        use crate::concrete::{Output, Signature};
+
+        let body = |transactions: u16| Body::<Signature, Output> {
+            coinbase: vec![],
+            transactions: (0..transactions)
+                .map(|i| Transaction {
+                    version: TRANSACTION_VERSION,
+                    inputs: vec![],
+                    signatures: vec![],
+                    outputs: vec![],
+                    withdrawal_outputs: vec![],
+                    extra: i.to_le_bytes().to_vec(),
+                })
+                .collect(),
+        };
+        let coinbase = tagged_hash_with::<Sha256, _>(COINBASE_TAG, &Vec::<Output>::new());
+        assert_eq!(body(0).compute_merkle_root(), coinbase.into());
+        let single = body(1);
+        let txid = single.transactions[0].txid();
+        assert_eq!(
+            single.compute_merkle_root(),
+            merkle_root_with::<Sha256>(vec![coinbase, txid.into()])
+        );
+        // Paying the fees to someone else changes the root.
+        let mut paid = single.clone();
+        paid.coinbase.push(Output {
+            address: [1; 32].into(),
+            value: Amount::from_sat(1),
+        });
+        assert_ne!(paid.compute_merkle_root(), single.compute_merkle_root());
+
+        let five = body(5);
+        let root = five.compute_merkle_root();
+        for (position, transaction) in five.transactions.iter().enumerate() {
+            let txid = transaction.txid();
+            let proof = five.merkle_proof(&txid).unwrap();
+            assert_eq!(proof.position as usize, position + 1);
+            assert_eq!(proof.siblings.len(), 3);
+            assert!(proof.verify(&root, &txid));
+            assert!(!proof.verify(&single.compute_merkle_root(), &txid));
+            let other = five.transactions[(position + 1) % 5].txid();
+            assert!(!proof.verify(&root, &other));
+            let moved = MerkleProof {
+                position: proof.position ^ 1,
+                ..proof.clone()
+            };
+            assert!(!moved.verify(&root, &txid));
+            let too_far = MerkleProof {
+                position: proof.position + 8,
+                ..proof
+            };
+            assert!(!too_far.verify(&root, &txid));
+        }
+
+        // Big enough to be hashed in parallel with the rayon feature.
+        let large = body(1000);
+        let root = large.compute_merkle_root();
+        for position in [0, 511, 999] {
+            let txid = large.transactions[position].txid();
+            let proof = large.merkle_proof(&txid).unwrap();
+            assert_eq!(proof.siblings.len(), 10);
+            assert!(proof.verify(&root, &txid));
+        }
+        assert!(body(4).merkle_proof(&five.transactions[4].txid()).is_none());
+        // Repeating the last transaction changes the root.
+        let mut repeated = five.clone();
+        repeated.transactions.push(five.transactions[4].clone());
+        assert_ne!(repeated.compute_merkle_root(), root);
+    }
+
+    #[test]
+    fn outpoints_round_trip_through_strings() {
+        let txid: Txid = [1; 32].into();
+        let outpoints = [
+            OutPoint::Regular { txid, vout: 0 },
+            OutPoint::Coinbase {
+                block_hash: [2; 32].into(),
+                vout: 1,
+            },
+            OutPoint::Withdrawal { txid, vout: 2 },
+            OutPoint::Deposit(bitcoin::OutPoint {
+                txid: <bitcoin::Txid as bitcoin::hashes::Hash>::hash(b"deposit"),
+                vout: u32::MAX,
+            }),
+        ];
+        for outpoint in outpoints {
+            let string = outpoint.to_string();
+            assert_eq!(string.parse(), Ok(outpoint));
+            let json = serde_json::to_string(&outpoint).unwrap();
+            assert_eq!(json, format!("{:?}", string));
+            assert_eq!(serde_json::from_str::<OutPoint>(&json).unwrap(), outpoint);
+            let encoded = encode::to_vec(&outpoint).unwrap();
+            assert_eq!(
+                encoded,
+                encode::to_vec(&OutPointRepr::from(outpoint)).unwrap()
+            );
+            assert_eq!(
+                bincode::deserialize::<OutPoint>(&encoded).unwrap(),
+                outpoint
+            );
+        }
+        assert_eq!(
+            OutPoint::Regular { txid, vout: 7 }.to_string(),
+            format!("regular:{}:7", "01".repeat(32))
+        );
+        let hash = "01".repeat(32);
+        for (invalid, error) in [
+            (
+                format!("regular:{}", hash),
+                OutPointError::Format(format!("regular:{}", hash)),
+            ),
+            (
+                format!("spent:{}:0", hash),
+                OutPointError::Kind("spent".into()),
+            ),
+            ("regular:0101:0".into(), OutPointError::Hash("0101".into())),
+            (
+                format!("deposit:{}:-1", hash),
+                OutPointError::Vout("-1".into()),
+            ),
+        ] {
+            assert_eq!(invalid.parse::<OutPoint>(), Err(error));
+        }
+    }
+
+    #[test]
+    fn hashes_and_addresses_are_strings_in_json() {
+        use crate::concrete::Output;
+
+        let txid: Txid = [1; 32].into();
+        let address: Address = [2; 32].into();
+        let json = serde_json::to_value(Header {
+            prev_block_hash: [3; 32].into(),
+            merkle_root: [4; 32].into(),
+            state_root: None,
+        })
+        .unwrap();
+        assert_eq!(json["prev_block_hash"], "03".repeat(32));
+        assert_eq!(json["merkle_root"], "04".repeat(32));
+        assert_eq!(serde_json::to_value(txid).unwrap(), "01".repeat(32));
+        let output = Output {
+            address,
+            value: Amount::from_sat(5),
+        };
+        assert_eq!(
+            serde_json::to_value(&output).unwrap(),
+            serde_json::json!({ "address": address.to_string(), "value": 5 })
+        );
+        let parsed: Output =
+            serde_json::from_value(serde_json::to_value(&output).unwrap()).unwrap();
+        assert_eq!(parsed.address, address);
+        assert_eq!(
+            serde_json::from_str::<Txid>(&format!("{:?}", "01".repeat(32))).unwrap(),
+            txid
+        );
+        assert!(serde_json::from_str::<Txid>("\"01\"").is_err());
+        // The consensus encoding is still the raw bytes.
+        assert_eq!(encode::to_vec(&address).unwrap(), [2; 32]);
+        assert_eq!(
+            bincode::deserialize::<Txid>(&bincode::serialize(&txid).unwrap()).unwrap(),
+            txid
+        );
+    }
+
+    #[test]
+    fn hash_domains_are_separated() {
+        let data = [7u8; 32];
+        let domains = [
+            hash(&data),
+            tagged_hash_with::<Sha256, _>(TXID_TAG, &data),
+            tagged_hash_with::<Sha256, _>(BLOCK_HASH_TAG, &data),
+            tagged_hash_with::<Sha256, _>(MERKLE_NODE_TAG, &data),
+            tagged_hash_with::<Sha256, _>(SIGHASH_TAG, &data),
+        ];
+        for (i, a) in domains.iter().enumerate() {
+            for b in &domains[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        let header = Header {
+            prev_block_hash: [1; 32].into(),
+            merkle_root: [2; 32].into(),
+            state_root: None,
+        };
+        let block_hash: BlockHash = tagged_hash_with::<Sha256, _>(BLOCK_HASH_TAG, &header).into();
+        assert_eq!(header.hash(), block_hash);
+        assert_ne!(header.hash(), hash(&header).into());
+        let transaction = Transaction::<(), ()> {
+            version: TRANSACTION_VERSION,
+            inputs: vec![],
+            signatures: vec![(); 2],
+            outputs: vec![],
+            withdrawal_outputs: vec![],
+            extra: vec![],
+        };
+        assert_ne!(transaction.sighash(), Hash::from(transaction.txid()));
+        assert_eq!(
+            transaction.sighash(),
+            transaction.without_signatures().sighash()
+        );
+    }
+
+    #[test]
+    fn sha256_is_the_default() {
+        let header = Header {
+            prev_block_hash: Hash::default().into(),
+            merkle_root: Hash::default().into(),
+            state_root: None,
+        };
+        assert_eq!(header.hash(), header.hash_with::<Sha256>());
+    }
+}
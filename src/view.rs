@@ -0,0 +1,306 @@
+use crate::types::*;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+// Read only views of consensus encoded bodies and transactions, which is
+// also how bincode encodes them on the wire and in block files. Txids and
+// merkle roots are hashed straight from the encoded bytes and fields are only
+// decoded when asked for, so a block can be checked against its header
+// before anything in it is allocated.
+
+pub struct BodyView<'a, S, O> {
+    bytes: &'a [u8],
+    // Encoding of the coinbase with its length, it comes first.
+    coinbase_bytes: &'a [u8],
+    coinbase: Items<'a, O>,
+    transactions: Vec<TransactionView<'a, S, O>>,
+}
+
+impl<'a, S: DeserializeOwned, O: DeserializeOwned> BodyView<'a, S, O> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes };
+        let coinbase = reader.items()?;
+        let coinbase_bytes = &bytes[..bytes.len() - reader.bytes.len()];
+        let len = reader.len()?;
+        let mut transactions = vec![];
+        for _ in 0..len {
+            transactions.push(TransactionView::read(&mut reader)?);
+        }
+        reader.finish()?;
+        Ok(Self {
+            bytes,
+            coinbase_bytes,
+            coinbase,
+            transactions,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn coinbase(&self) -> &Items<'a, O> {
+        &self.coinbase
+    }
+
+    pub fn transactions(&self) -> &[TransactionView<'a, S, O>] {
+        &self.transactions
+    }
+
+    pub fn compute_merkle_root(&self) -> MerkleRoot {
+        self.compute_merkle_root_with::<Sha256>()
+    }
+
+    // Same as Body::compute_merkle_root_with.
+    pub fn compute_merkle_root_with<H: Hasher>(&self) -> MerkleRoot {
+        let preimages: Vec<Vec<u8>> = self
+            .transactions
+            .iter()
+            .map(|transaction| tagged_preimage_encoded::<H>(TXID_TAG, transaction.bytes))
+            .collect();
+        let txids = map_chunks(&preimages, 1, |preimage| H::digest(&preimage[0]));
+        let coinbase = H::digest(&tagged_preimage_encoded::<H>(
+            COINBASE_TAG,
+            self.coinbase_bytes,
+        ));
+        merkle_root_with::<H>([vec![coinbase], txids].concat())
+    }
+
+    pub fn to_body(&self) -> Result<Body<S, O>, Error> {
+        Ok(bincode::deserialize(self.bytes)?)
+    }
+}
+
+pub struct TransactionView<'a, S, O> {
+    bytes: &'a [u8],
+    version: u32,
+    inputs: Items<'a, OutPoint>,
+    signatures: Items<'a, S>,
+    outputs: Items<'a, O>,
+    withdrawal_outputs: Items<'a, WithdrawalOutput>,
+    extra: &'a [u8],
+}
+
+impl<'a, S: DeserializeOwned, O: DeserializeOwned> TransactionView<'a, S, O> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes };
+        let transaction = Self::read(&mut reader)?;
+        reader.finish()?;
+        Ok(transaction)
+    }
+
+    fn read(reader: &mut Reader<'a>) -> Result<Self, Error> {
+        let start = reader.bytes;
+        let version = reader.u32()?;
+        let inputs = reader.items()?;
+        let signatures = reader.items()?;
+        let outputs = reader.items()?;
+        let withdrawal_outputs = reader.items()?;
+        let len = reader.len()?;
+        let extra = reader.take(len)?;
+        let bytes = &start[..start.len() - reader.bytes.len()];
+        Ok(Self {
+            bytes,
+            version,
+            inputs,
+            signatures,
+            outputs,
+            withdrawal_outputs,
+            extra,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn inputs(&self) -> &Items<'a, OutPoint> {
+        &self.inputs
+    }
+
+    pub fn signatures(&self) -> &Items<'a, S> {
+        &self.signatures
+    }
+
+    pub fn outputs(&self) -> &Items<'a, O> {
+        &self.outputs
+    }
+
+    pub fn withdrawal_outputs(&self) -> &Items<'a, WithdrawalOutput> {
+        &self.withdrawal_outputs
+    }
+
+    pub fn extra(&self) -> &'a [u8] {
+        self.extra
+    }
+
+    pub fn txid(&self) -> Txid {
+        self.txid_with::<Sha256>()
+    }
+
+    // Same as Transaction::txid_with.
+    pub fn txid_with<H: Hasher>(&self) -> Txid {
+        H::digest(&tagged_preimage_encoded::<H>(TXID_TAG, self.bytes)).into()
+    }
+
+    pub fn to_transaction(&self) -> Result<Transaction<S, O>, Error> {
+        Ok(bincode::deserialize(self.bytes)?)
+    }
+}
+
+// Encoded items of a sequence, decoded one at a time by iter.
+pub struct Items<'a, T> {
+    bytes: &'a [u8],
+    len: usize,
+    item: PhantomData<T>,
+}
+
+impl<'a, T: DeserializeOwned> Items<'a, T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + 'a {
+        let mut bytes = self.bytes;
+        // Every item was decoded once already while parsing.
+        (0..self.len).map(move |_| {
+            bincode::deserialize_from(&mut bytes).expect("item was checked while parsing")
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(Error::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().expect("took 4 bytes")))
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        let bytes = self.take(8)?;
+        let len = u64::from_le_bytes(bytes.try_into().expect("took 8 bytes"));
+        // Each item takes at least a byte, so a larger length is a lie that
+        // would otherwise only be caught after a long loop.
+        if len > self.bytes.len() as u64 {
+            return Err(Error::Truncated);
+        }
+        Ok(len as usize)
+    }
+
+    // Items are decoded into a throwaway value just to find where they end,
+    // none of them is kept.
+    fn items<T: DeserializeOwned>(&mut self) -> Result<Items<'a, T>, Error> {
+        let len = self.len()?;
+        let start = self.bytes;
+        for _ in 0..len {
+            bincode::deserialize_from::<_, T>(&mut self.bytes)?;
+        }
+        Ok(Items {
+            bytes: &start[..start.len() - self.bytes.len()],
+            len,
+            item: PhantomData,
+        })
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        if !self.bytes.is_empty() {
+            return Err(Error::TrailingBytes(self.bytes.len()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("encoding ends early")]
+    Truncated,
+    #[error("{0} bytes after the end of the encoding")]
+    TrailingBytes(usize),
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concrete::{Output, Signature};
+
+    #[test]
+    fn views_match_decoded_bodies() -> anyhow::Result<()> {
+        let output = |value| Output {
+            address: [1; 32].into(),
+            value: Amount::from_sat(value),
+        };
+        let transaction = |vout, extra: &[u8]| Transaction::<Signature, Output> {
+            version: TRANSACTION_VERSION,
+            inputs: vec![OutPoint::Regular {
+                txid: [2; 32].into(),
+                vout,
+            }],
+            signatures: vec![],
+            outputs: vec![output(10), output(20)],
+            withdrawal_outputs: vec![],
+            extra: extra.to_vec(),
+        };
+        let body = Body {
+            coinbase: vec![output(1)],
+            transactions: vec![transaction(0, b"first"), transaction(1, b"")],
+        };
+        let bytes = bincode::serialize(&body)?;
+        let view = BodyView::<Signature, Output>::parse(&bytes)?;
+        assert_eq!(view.compute_merkle_root(), body.compute_merkle_root());
+        assert_eq!(view.coinbase().len(), 1);
+        assert_eq!(view.transactions().len(), 2);
+        let first = &view.transactions()[0];
+        assert_eq!(first.txid(), body.transactions[0].txid());
+        assert_eq!(first.size(), body.transactions[0].size());
+        assert_eq!(first.extra(), b"first");
+        assert_eq!(
+            first.inputs().iter().collect::<Vec<_>>(),
+            body.transactions[0].inputs
+        );
+        let values: Vec<_> = first.outputs().iter().map(|output| output.value).collect();
+        assert_eq!(values, vec![Amount::from_sat(10), Amount::from_sat(20)]);
+        assert_eq!(bincode::serialize(&view.to_body()?)?, bytes);
+
+        assert!(matches!(
+            BodyView::<Signature, Output>::parse(&bytes[..bytes.len() - 1]),
+            Err(Error::Truncated)
+        ));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(
+            BodyView::<Signature, Output>::parse(&trailing),
+            Err(Error::TrailingBytes(1))
+        ));
+        Ok(())
+    }
+}
@@ -1,44 +1,177 @@
-use crate::types::*;
 use crate::concrete::*;
+use crate::descriptor::Descriptor;
+use crate::params::SidechainParams;
+use crate::types::*;
 use anyhow::Result;
 use ed25519_dalek::Keypair;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+// Tag of the hash that derives the key at an index from a wallet seed.
+const DERIVATION_TAG: &str = "sdk/wallet";
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+pub const URI_SCHEME: &str = "sidechain";
+// Most coins a sweep transaction spends, larger wallets are swept with
+// several so each stays far below the block size limit.
+const MAX_SWEEP_INPUTS: usize = 100;
 
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct Wallet {
     keypairs: HashMap<Address, Keypair>,
-    pub outputs: BTreeMap<Output, OutPoint>,
+    // Keys of a wallet made from a seed are derived from it in order, so the
+    // seed alone is enough to restore the wallet.
+    seed: Option<Hash>,
+    // Index of each derived address. Past the next_index handed out, keys
+    // are derived gap_limit ahead so funds sent to them are found.
+    indices: HashMap<Address, u32>,
+    next_index: u32,
+    gap_limit: u32,
+    // Descriptors imported without a key, by address. Their outputs are
+    // tracked apart from the spendable ones.
+    watched: HashMap<Address, Descriptor>,
+    watched_outputs: HashMap<OutPoint, Output>,
+    // Keys given up by rotate, coins that still reach them can be swept by
+    // rotating again.
+    retired: HashSet<Address>,
+    pub outputs: BTreeMap<OutPoint, Output>,
+    // Outputs spent by transactions we made that aren't confirmed yet, they
+    // are kept out of coin selection until confirmed or abandoned.
+    pending: HashSet<OutPoint>,
+    #[serde(skip)]
+    params: SidechainParams,
 }
 
 struct Coins {
     outputs: HashMap<OutPoint, Output>,
-    change: u64,
+    change: Amount,
 }
 
 impl Wallet {
+    pub fn from_seed(seed: Hash) -> Self {
+        let mut wallet = Self {
+            seed: Some(seed),
+            gap_limit: DEFAULT_GAP_LIMIT,
+            ..Self::default()
+        };
+        wallet.fill_keypool();
+        wallet
+    }
+
+    // A wallet with a fresh random seed.
+    pub fn generate() -> Self {
+        let mut seed = Hash::default();
+        rand::Rng::fill(&mut rand::thread_rng(), &mut seed);
+        Self::from_seed(seed)
+    }
+
+    // Unused addresses to look ahead when restoring from a seed, funds past
+    // that many unused addresses in a row aren't found.
+    pub fn with_gap_limit(mut self, gap_limit: u32) -> Self {
+        self.gap_limit = gap_limit;
+        self.fill_keypool();
+        self
+    }
+
+    pub fn with_params(mut self, params: SidechainParams) -> Self {
+        self.params = params;
+        self
+    }
+
     pub fn create_transaction(
         &mut self,
-        mut outputs: Vec<Output>,
-        fee: u64,
+        outputs: Vec<Output>,
+        fee: Amount,
     ) -> Option<Transaction<Signature, Output>> {
-        let amount: u64 = outputs.iter().map(|o| o.value).sum();
-        let coins = match self.select_coins(amount) {
-            Some(coins) => coins,
-            None => return None,
-        };
-        if coins.change > fee {
-            let change = self.create_output(coins.change - fee);
-            outputs.push(change);
-        }
-        let inputs: Vec<OutPoint> = coins.outputs.keys().copied().collect();
-        let transaction = Transaction {
-            inputs,
-            signatures: vec![],
-            outputs,
-            withdrawal_outputs: vec![],
+        self.build_transaction(outputs, vec![], |_| fee)
+    }
+
+    // Pays at least `fee_rate` for the virtual size of the transaction.
+    pub fn create_transaction_with_fee_rate(
+        &mut self,
+        outputs: Vec<Output>,
+        fee_rate: FeeRate,
+    ) -> Option<Transaction<Signature, Output>> {
+        self.build_transaction(outputs, vec![], |vsize| fee_rate.fee(vsize))
+    }
+
+    // Pays `value` out to `main_address` on the mainchain, `main_fee` is what
+    // the withdrawal offers towards the mainchain fee of its bundle. A fresh
+    // address of ours gets the coins back if the withdrawal fails.
+    pub fn create_withdrawal(
+        &mut self,
+        main_address: bitcoin::Address,
+        value: Amount,
+        main_fee: Amount,
+        fee: Amount,
+    ) -> Option<Transaction<Signature, Output>> {
+        let withdrawal = WithdrawalOutput {
+            value,
+            fee: main_fee,
+            side_address: self.generate_address(),
+            main_address,
         };
+        self.build_transaction(vec![], vec![withdrawal], |_| fee)
+    }
+
+    // `fee` gets the virtual size of the transaction and returns what it has
+    // to pay for it.
+    fn build_transaction(
+        &mut self,
+        outputs: Vec<Output>,
+        withdrawal_outputs: Vec<WithdrawalOutput>,
+        fee: impl Fn(usize) -> Amount,
+    ) -> Option<Transaction<Signature, Output>> {
+        let amount = Amount::checked_sum(
+            outputs
+                .iter()
+                .map(|o| o.value)
+                .chain(withdrawal_outputs.iter().map(|o| o.value)),
+        )?;
+        // The size depends on how many coins get spent and whether there is
+        // change, so coins are selected again until they cover the fee of the
+        // transaction they make up.
+        let mut paid = Amount::ZERO;
+        loop {
+            let coins = self.select_coins(amount.checked_add(paid)?)?;
+            let mut transaction = Transaction {
+                version: TRANSACTION_VERSION,
+                inputs: coins.outputs.keys().copied().collect(),
+                signatures: vec![],
+                outputs: outputs.clone(),
+                withdrawal_outputs: withdrawal_outputs.clone(),
+                extra: vec![],
+            };
+            // Change below the dust limit would be rejected, it goes to the
+            // fee instead.
+            let has_change = coins.change > Amount::ZERO && coins.change >= self.params.dust_limit;
+            if has_change {
+                // Addresses are all the same size, the real one is generated
+                // once the fee is settled.
+                transaction.outputs.push(Output {
+                    address: [0; 32].into(),
+                    value: coins.change,
+                });
+            }
+            let required = fee(self.sign(&coins, transaction.clone()).vsize());
+            if paid < required {
+                paid = required;
+                continue;
+            }
+            if has_change {
+                let change = transaction.outputs.last_mut().expect("no change output");
+                change.address = self.generate_address();
+            }
+            self.pending.extend(coins.outputs.keys().copied());
+            return Some(self.sign(&coins, transaction));
+        }
+    }
+
+    fn sign(
+        &self,
+        coins: &Coins,
+        transaction: Transaction<Signature, Output>,
+    ) -> Transaction<Signature, Output> {
         let signatures = transaction
             .inputs
             .iter()
@@ -48,37 +181,49 @@ impl Wallet {
                 Signature::new(keypair, &transaction)
             })
             .collect();
-        let transaction = Transaction {
+        Transaction {
             signatures,
             ..transaction
-        };
-        Some(transaction)
+        }
     }
 
     pub fn generate_address(&mut self) -> Address {
+        if let Some(seed) = self.seed {
+            let address = derive_keypair(&seed, self.next_index).public.into();
+            self.next_index += 1;
+            self.fill_keypool();
+            return address;
+        }
         let mut csprng = rand::thread_rng();
         let keypair: Keypair = Keypair::generate(&mut csprng);
         let address: Address = keypair.public.into();
-        self.keypairs.insert(address.clone(), keypair);
+        self.keypairs.insert(address, keypair);
         address
     }
 
-    pub fn create_output(&mut self, value: u64) -> Output {
+    pub fn create_output(&mut self, value: Amount) -> Output {
         Output {
             value,
             address: self.generate_address(),
         }
     }
 
-    fn select_coins(&self, value: u64) -> Option<Coins> {
-        let mut total: u64 = 0;
+    fn select_coins(&self, value: Amount) -> Option<Coins> {
+        // Smallest coins first.
+        let mut candidates: Vec<(&OutPoint, &Output)> = self
+            .outputs
+            .iter()
+            .filter(|(outpoint, _)| !self.pending.contains(outpoint))
+            .collect();
+        candidates.sort_by_key(|(_, output)| output.value);
+        let mut total = Amount::ZERO;
         let mut outputs: HashMap<OutPoint, Output> = HashMap::new();
-        for (output, outpoint) in self.outputs.iter() {
+        for (outpoint, output) in candidates {
             if total >= value {
                 break;
             }
             total += output.value;
-            outputs.insert(outpoint.clone(), output.clone());
+            outputs.insert(*outpoint, output.clone());
         }
         if total < value {
             return None;
@@ -107,11 +252,781 @@ impl Wallet {
         self.keypairs.keys().cloned().collect()
     }
 
+    pub fn generate_bech32_address(&mut self) -> Result<String, AddressError> {
+        let hrp = self.params.address_hrp();
+        self.generate_address().to_bech32(&hrp)
+    }
+
+    // A fresh address in the format mainchain deposits are made to.
+    pub fn generate_deposit_address(&mut self) -> String {
+        let address = self.generate_address();
+        address.to_deposit_string_for(self.params.sidechain_number)
+    }
+
+    pub fn get_deposit_addresses(&self) -> Vec<String> {
+        self.keypairs
+            .keys()
+            .map(|address| address.to_deposit_string_for(self.params.sidechain_number))
+            .collect()
+    }
+
+    // Funds on an address from the keypool extend it, which can bring more
+    // outputs into view, so outputs are scanned until the keypool stops
+    // growing.
     pub fn add_outputs(&mut self, outputs: &HashMap<OutPoint, Output>) {
-        for (outpoint, output) in outputs {
-            if self.keypairs.contains_key(&output.address) {
-                self.outputs.insert(output.clone(), outpoint.clone());
+        loop {
+            let derived = self.indices.len();
+            for (outpoint, output) in outputs {
+                self.add_output(*outpoint, output.clone());
+            }
+            if self.indices.len() == derived {
+                break;
+            }
+        }
+    }
+
+    pub fn add_deposit_outputs(&mut self, deposit_outputs: &HashMap<OutPoint, DepositOutput>) {
+        loop {
+            let derived = self.indices.len();
+            for (outpoint, output) in deposit_outputs {
+                let output = Output {
+                    address: output.address,
+                    value: output.value,
+                };
+                self.add_output(*outpoint, output);
+            }
+            if self.indices.len() == derived {
+                break;
+            }
+        }
+    }
+
+    fn add_output(&mut self, outpoint: OutPoint, output: Output) {
+        if self.keypairs.contains_key(&output.address) {
+            self.mark_used(&output.address);
+            self.outputs.insert(outpoint, output);
+        } else if self.watched.contains_key(&output.address) {
+            self.watched_outputs.insert(outpoint, output);
+        }
+    }
+
+    // Addresses up to a used one count as handed out, so the gap is counted
+    // from past it.
+    fn mark_used(&mut self, address: &Address) {
+        if let Some(&index) = self.indices.get(address) {
+            if index >= self.next_index {
+                self.next_index = index + 1;
+                self.fill_keypool();
+            }
+        }
+    }
+
+    fn fill_keypool(&mut self) {
+        let Some(seed) = self.seed else {
+            return;
+        };
+        let end = self.next_index + self.gap_limit;
+        for index in self.indices.len() as u32..end {
+            let keypair = derive_keypair(&seed, index);
+            let address: Address = keypair.public.into();
+            self.indices.insert(address, index);
+            self.keypairs.insert(address, keypair);
+        }
+    }
+
+    // Makes the coins spent by a transaction that won't be confirmed, e.g.
+    // one that was never broadcast, spendable again.
+    pub fn abandon(&mut self, transaction: &Transaction<Signature, Output>) {
+        for outpoint in &transaction.inputs {
+            self.pending.remove(outpoint);
+        }
+    }
+
+    // Forgets outputs that are no longer in `unspent`, confirmed spends are
+    // no longer pending.
+    pub fn remove_spent(&mut self, unspent: &HashSet<OutPoint>) {
+        self.outputs
+            .retain(|outpoint, _| unspent.contains(outpoint));
+        self.watched_outputs
+            .retain(|outpoint, _| unspent.contains(outpoint));
+        self.pending.retain(|outpoint| unspent.contains(outpoint));
+    }
+
+    // Sweeps every spendable coin to fresh addresses of `new_wallet`, paying
+    // `fee_rate`, and retires all keys of this wallet, e.g. after they may
+    // have leaked. The seed is dropped as well, so addresses handed out
+    // afterwards don't come from it. Coins worth less than the fee of
+    // spending them are left behind.
+    pub fn rotate(
+        &mut self,
+        new_wallet: &mut Wallet,
+        fee_rate: FeeRate,
+    ) -> Vec<Transaction<Signature, Output>> {
+        let coins: Vec<(OutPoint, Output)> = self
+            .outputs
+            .iter()
+            .filter(|(outpoint, _)| !self.pending.contains(outpoint))
+            .map(|(outpoint, output)| (*outpoint, output.clone()))
+            .collect();
+        let mut transactions = vec![];
+        for chunk in coins.chunks(MAX_SWEEP_INPUTS) {
+            let coins = Coins {
+                outputs: chunk.iter().cloned().collect(),
+                change: Amount::ZERO,
+            };
+            let Some(value) = Amount::checked_sum(chunk.iter().map(|(_, output)| output.value))
+            else {
+                continue;
+            };
+            // Like change, the real address is generated once the fee is
+            // known to be covered.
+            let mut transaction = Transaction {
+                version: TRANSACTION_VERSION,
+                inputs: coins.outputs.keys().copied().collect(),
+                signatures: vec![],
+                outputs: vec![Output {
+                    address: [0; 32].into(),
+                    value,
+                }],
+                withdrawal_outputs: vec![],
+                extra: vec![],
+            };
+            let fee = fee_rate.fee(self.sign(&coins, transaction.clone()).vsize());
+            let value = match value.checked_sub(fee) {
+                Some(value) if value > Amount::ZERO && value >= self.params.dust_limit => value,
+                _ => continue,
+            };
+            transaction.outputs[0] = Output {
+                address: new_wallet.generate_address(),
+                value,
+            };
+            self.pending.extend(coins.outputs.keys().copied());
+            transactions.push(self.sign(&coins, transaction));
+        }
+        self.retired.extend(self.keypairs.keys().copied());
+        self.seed = None;
+        transactions
+    }
+
+    pub fn is_retired(&self, address: &Address) -> bool {
+        self.retired.contains(address)
+    }
+
+    // A key descriptor for every key and the imported ones, sorted so the same wallet always exports the same list.
+    pub fn export_descriptors(&self) -> Vec<Descriptor> {
+        let mut descriptors: Vec<Descriptor> = self
+            .keypairs
+            .values()
+            .map(|keypair| Descriptor::Key(keypair.public.to_bytes()))
+            .chain(self.watched.values().copied())
+            .collect();
+        descriptors.sort();
+        descriptors
+    }
+
+    // Descriptors only carry public data, so imported ones are watched. A
+    // descriptor for a key the wallet already has changes nothing.
+    pub fn import_descriptor(&mut self, descriptor: &Descriptor) {
+        let address = descriptor.address();
+        if !self.keypairs.contains_key(&address) {
+            self.watched.insert(address, *descriptor);
+        }
+    }
+
+    pub fn get_watch_only_balance(&self) -> Amount {
+        self.watched_outputs
+            .values()
+            .map(|output| output.value)
+            .sum()
+    }
+
+    // A request for `amount` to a fresh address, e.g. for a QR code at a
+    // point of sale.
+    pub fn request_payment(&mut self, amount: Amount, label: &str) -> PaymentUri {
+        PaymentUri::new(self.generate_address())
+            .with_amount(amount)
+            .with_label(label)
+    }
+
+    // Pending spends don't count, their coins are on their way out.
+    pub fn get_balance(&self) -> Amount {
+        self.outputs
+            .iter()
+            .filter(|(outpoint, _)| !self.pending.contains(outpoint))
+            .map(|(_, output)| output.value)
+            .sum()
+    }
+}
+
+// Named wallets a node has loaded next to its default one, each saved to its
+// own file in `dir`. Without a directory they only live in memory.
+#[derive(Debug, Default)]
+pub struct Wallets {
+    dir: Option<PathBuf>,
+    params: SidechainParams,
+    loaded: BTreeMap<String, Wallet>,
+}
+
+impl Wallets {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir: Some(dir),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_params(mut self, params: SidechainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn create(&mut self, name: &str) -> Result<&mut Wallet, WalletsError> {
+        let path = self.path(name)?;
+        if self.loaded.contains_key(name) || path.as_ref().is_some_and(|path| path.exists()) {
+            return Err(WalletsError::AlreadyExists(name.into()));
+        }
+        let wallet = Wallet::default().with_params(self.params.clone());
+        if let Some(path) = path {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            wallet.save(path)?;
+        }
+        Ok(self.loaded.entry(name.into()).or_insert(wallet))
+    }
+
+    pub fn load(&mut self, name: &str) -> Result<&mut Wallet, WalletsError> {
+        let path = self.path(name)?;
+        if self.loaded.contains_key(name) {
+            return Err(WalletsError::AlreadyLoaded(name.into()));
+        }
+        let path = path
+            .filter(|path| path.exists())
+            .ok_or_else(|| WalletsError::NotFound(name.into()))?;
+        let wallet = Wallet::load(path)?.with_params(self.params.clone());
+        Ok(self.loaded.entry(name.into()).or_insert(wallet))
+    }
+
+    // Saves the wallet before dropping it.
+    pub fn unload(&mut self, name: &str) -> Result<(), WalletsError> {
+        let path = self.path(name)?;
+        let wallet = self
+            .loaded
+            .remove(name)
+            .ok_or_else(|| WalletsError::NotLoaded(name.into()))?;
+        if let Some(path) = path {
+            wallet.save(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Wallet> {
+        self.loaded.get_mut(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.loaded.keys().map(String::as_str)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Wallet> {
+        self.loaded.values_mut()
+    }
+
+    pub fn save(&self) -> Result<(), WalletsError> {
+        for (name, wallet) in &self.loaded {
+            if let Some(path) = self.path(name)? {
+                wallet.save(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Names become file names, so they are kept to characters that can't
+    // leave the directory.
+    fn path(&self, name: &str) -> Result<Option<PathBuf>, WalletsError> {
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(WalletsError::InvalidName(name.into()));
+        }
+        Ok(self
+            .dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.dat", name))))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WalletsError {
+    #[error("invalid wallet name {0:?}")]
+    InvalidName(String),
+    #[error("wallet {0} already exists")]
+    AlreadyExists(String),
+    #[error("wallet {0} is already loaded")]
+    AlreadyLoaded(String),
+    #[error("wallet {0} not found")]
+    NotFound(String),
+    #[error("wallet {0} is not loaded")]
+    NotLoaded(String),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("failed to read or write wallet file: {0}")]
+    File(#[from] anyhow::Error),
+}
+
+// BIP21 style payment request, like sidechain:ADDRESS?amount=1.5&label=Shop.
+// Amounts are in coins, labels and messages are percent encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentUri {
+    pub address: Address,
+    pub amount: Option<Amount>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+impl PaymentUri {
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            amount: None,
+            label: None,
+            message: None,
+        }
+    }
+
+    pub fn with_amount(mut self, amount: Amount) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_message(mut self, message: &str) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    // Addresses can be in either form, bech32 ones have to be for `hrp`.
+    pub fn parse(s: &str, hrp: &str) -> Result<Self, UriError> {
+        let rest = match s.split_once(':') {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case(URI_SCHEME) => rest,
+            _ => return Err(UriError::Scheme),
+        };
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut uri = Self::new(Address::parse(address, hrp)?);
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            let value = percent_decode(value)?;
+            let field = match key {
+                "amount" => {
+                    if uri.amount.replace(value.parse()?).is_some() {
+                        return Err(UriError::Duplicate(key.into()));
+                    }
+                    continue;
+                }
+                "label" => &mut uri.label,
+                "message" => &mut uri.message,
+                // Parameters a payer must understand start with req-.
+                _ if key.starts_with("req-") => return Err(UriError::Required(key.into())),
+                _ => continue,
+            };
+            if field.replace(value).is_some() {
+                return Err(UriError::Duplicate(key.into()));
+            }
+        }
+        Ok(uri)
+    }
+}
+
+impl core::fmt::Display for PaymentUri {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", URI_SCHEME, self.address)?;
+        let parameters = [
+            ("amount", self.amount.map(|amount| amount.to_string())),
+            ("label", self.label.as_deref().map(percent_encode)),
+            ("message", self.message.as_deref().map(percent_encode)),
+        ];
+        let mut separator = '?';
+        for (key, value) in parameters {
+            if let Some(value) = value {
+                write!(f, "{}{}={}", separator, key, value)?;
+                separator = '&';
+            }
+        }
+        Ok(())
+    }
+}
+
+// Everything but the RFC 3986 unreserved characters is encoded.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
             }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Result<String, UriError> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).ok_or(UriError::Encoding)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| UriError::Encoding)?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| UriError::Encoding)?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| UriError::Encoding)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UriError {
+    #[error("not a {} URI", URI_SCHEME)]
+    Scheme,
+    #[error(transparent)]
+    Address(#[from] AddressError),
+    #[error(transparent)]
+    Amount(#[from] crate::amount::Error),
+    #[error("invalid percent encoding")]
+    Encoding,
+    #[error("parameter {0} is given twice")]
+    Duplicate(String),
+    #[error("unsupported required parameter {0}")]
+    Required(String),
+}
+
+fn derive_keypair(seed: &Hash, index: u32) -> Keypair {
+    let secret = tagged_hash_with::<Sha256, _>(DERIVATION_TAG, &(seed, index));
+    let secret = ed25519_dalek::SecretKey::from_bytes(&secret).expect("hash is 32 bytes");
+    let public = (&secret).into();
+    Keypair { secret, public }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_coins_are_not_spent_twice() {
+        let mut wallet = Wallet::default();
+        let address = wallet.generate_address();
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint::default());
+        wallet.add_deposit_outputs(&HashMap::from([(
+            deposit,
+            DepositOutput {
+                address,
+                value: Amount::from_sat(1000),
+            },
+        )]));
+        let pay = |wallet: &mut Wallet| {
+            let output = Output {
+                address: [1; 32].into(),
+                value: Amount::from_sat(500),
+            };
+            wallet.create_transaction(vec![output], Amount::from_sat(10))
+        };
+        let transaction = pay(&mut wallet).unwrap();
+        assert_eq!(transaction.inputs, vec![deposit]);
+        assert_eq!(wallet.get_balance(), Amount::ZERO);
+        assert!(pay(&mut wallet).is_none());
+
+        wallet.abandon(&transaction);
+        assert!(pay(&mut wallet).is_some());
+        // Once the spend is confirmed the coin is gone for good.
+        wallet.remove_spent(&HashSet::new());
+        assert!(wallet.outputs.is_empty());
+        assert!(wallet.pending.is_empty());
+    }
+
+    #[test]
+    fn restored_wallets_find_funds_within_the_gap() {
+        let mut original = Wallet::from_seed([3; 32]);
+        let addresses: Vec<Address> = (0..25).map(|_| original.generate_address()).collect();
+        // The runs of unused addresses between them are 4, 4 and 6 long.
+        let outputs: HashMap<OutPoint, Output> = [3, 8, 13, 20]
+            .into_iter()
+            .enumerate()
+            .map(|(n, index)| {
+                let outpoint = OutPoint::Regular {
+                    txid: [n as u8; 32].into(),
+                    vout: 0,
+                };
+                let output = Output {
+                    address: addresses[index],
+                    value: Amount::from_sat(100 * (n as u64 + 1)),
+                };
+                (outpoint, output)
+            })
+            .collect();
+
+        let mut restored = Wallet::from_seed([3; 32]).with_gap_limit(5);
+        restored.add_outputs(&outputs);
+        assert_eq!(restored.get_balance(), Amount::from_sat(600));
+        assert_eq!(restored.generate_address(), addresses[14]);
+        let mut wider = Wallet::from_seed([3; 32]).with_gap_limit(7);
+        wider.add_outputs(&outputs);
+        assert_eq!(wider.get_balance(), Amount::from_sat(1000));
+    }
+
+    #[test]
+    fn payment_uris_round_trip() {
+        let mut wallet = Wallet::default();
+        let uri = wallet
+            .request_payment(Amount::from_sat(150_000_000), "Coffee & cake")
+            .with_message("100% arabica");
+        let string = uri.to_string();
+        assert!(string.contains("?amount=1.50000000&label=Coffee%20%26%20cake&"));
+        assert_eq!(PaymentUri::parse(&string, "sc0").unwrap(), uri);
+
+        let address = uri.address;
+        let bech32 = format!(
+            "SIDECHAIN:{}",
+            address.to_bech32("sc0").unwrap().to_uppercase()
+        );
+        assert_eq!(
+            PaymentUri::parse(&bech32, "sc0").unwrap(),
+            PaymentUri::new(address)
+        );
+        let unknown = format!("sidechain:{}?unknown=1", address);
+        assert!(PaymentUri::parse(&unknown, "sc0").is_ok());
+        for bad in [
+            format!("bitcoin:{}", address),
+            format!("sidechain:{}?req-unknown=1", address),
+            format!("sidechain:{}?amount=1&amount=2", address),
+            format!("sidechain:{}?amount=0.000000001", address),
+            format!("sidechain:{}?label=%2", address),
+        ] {
+            assert!(PaymentUri::parse(&bad, "sc0").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn named_wallets_are_kept_apart() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("sdk-wallets-{}", std::process::id()));
+        let mut wallets = Wallets::new(dir.clone());
+        let hot = wallets.create("hot")?.generate_address();
+        let cold = wallets.create("cold")?.generate_address();
+        assert_ne!(hot, cold);
+        assert!(matches!(
+            wallets.create("hot"),
+            Err(WalletsError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            wallets.create("../hot"),
+            Err(WalletsError::InvalidName(_))
+        ));
+
+        wallets.unload("cold")?;
+        assert_eq!(wallets.names().collect::<Vec<_>>(), vec!["hot"]);
+        assert!(wallets.get_mut("cold").is_none());
+        // A fresh store finds the saved wallet on disk.
+        let mut reopened = Wallets::new(dir.clone());
+        assert!(reopened.load("cold")?.get_addresses().contains(&cold));
+        assert!(matches!(
+            reopened.load("missing"),
+            Err(WalletsError::NotFound(_))
+        ));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn imported_descriptors_are_watched() {
+        let mut wallet = Wallet::default();
+        let own = wallet.generate_address();
+        let other = Wallet::default().generate_address();
+        wallet.import_descriptor(&Descriptor::Addr(other));
+        let descriptors = wallet.export_descriptors();
+        assert_eq!(descriptors.len(), 2);
+        assert!(descriptors.contains(&Descriptor::Addr(other)));
+        assert!(descriptors
+            .iter()
+            .any(|descriptor| descriptor.address() == own));
+
+        let outputs = HashMap::from([
+            (
+                OutPoint::Regular {
+                    txid: [1; 32].into(),
+                    vout: 0,
+                },
+                Output {
+                    address: own,
+                    value: Amount::from_sat(100),
+                },
+            ),
+            (
+                OutPoint::Regular {
+                    txid: [2; 32].into(),
+                    vout: 0,
+                },
+                Output {
+                    address: other,
+                    value: Amount::from_sat(200),
+                },
+            ),
+        ]);
+        wallet.add_outputs(&outputs);
+        assert_eq!(wallet.get_balance(), Amount::from_sat(100));
+        assert_eq!(wallet.get_watch_only_balance(), Amount::from_sat(200));
+        // Watched coins can't be signed for, so they are never selected.
+        let output = Output {
+            address: other,
+            value: Amount::from_sat(150),
+        };
+        assert!(wallet
+            .create_transaction(vec![output], Amount::ZERO)
+            .is_none());
+        wallet.remove_spent(&HashSet::new());
+        assert_eq!(wallet.get_watch_only_balance(), Amount::ZERO);
+    }
+
+    #[test]
+    fn rotation_sweeps_everything_to_the_new_wallet() {
+        let mut old = Wallet::default();
+        let mut blockchain = crate::blockchain::BlockChain::<Signature, Output>::new();
+        let deposits: HashMap<OutPoint, DepositOutput> = (0..150u32)
+            .map(|vout| {
+                let outpoint = OutPoint::Deposit(bitcoin::OutPoint {
+                    vout,
+                    ..bitcoin::OutPoint::default()
+                });
+                let output = DepositOutput {
+                    address: old.generate_address(),
+                    value: Amount::from_sat(1000 + vout as u64),
+                };
+                (outpoint, output)
+            })
+            .collect();
+        old.add_deposit_outputs(&deposits);
+        blockchain.add_deposits(DepositsChunk {
+            outputs: deposits,
+            deposits: vec![],
+        });
+        let total = old.get_balance();
+
+        let mut new = Wallet::generate();
+        let fee_rate = FeeRate::from_sat_per_kvb(1000);
+        let sweeps = old.rotate(&mut new, fee_rate);
+        assert_eq!(sweeps.len(), 2);
+        let mut swept = Amount::ZERO;
+        for sweep in &sweeps {
+            assert_eq!(blockchain.validate_transaction(sweep), Ok(()));
+            let fee = blockchain.get_fee(sweep).unwrap();
+            assert!(FeeRate::new(fee, sweep.vsize()) >= fee_rate);
+            swept += sweep.outputs[0].value + fee;
+            assert!(new.get_addresses().contains(&sweep.outputs[0].address));
         }
+        assert_eq!(swept, total);
+        assert_eq!(old.get_balance(), Amount::ZERO);
+        let retired = old.get_addresses();
+        assert!(retired.iter().all(|address| old.is_retired(address)));
+        let fresh = old.generate_address();
+        assert!(!old.is_retired(&fresh));
+    }
+
+    #[test]
+    fn rotation_sweeps_identical_outputs() {
+        let mut old = Wallet::default();
+        let address = old.generate_address();
+        let deposits: HashMap<OutPoint, DepositOutput> = (0..2u32)
+            .map(|vout| {
+                let outpoint = OutPoint::Deposit(bitcoin::OutPoint {
+                    vout,
+                    ..bitcoin::OutPoint::default()
+                });
+                let output = DepositOutput {
+                    address,
+                    value: Amount::from_sat(1000),
+                };
+                (outpoint, output)
+            })
+            .collect();
+        old.add_deposit_outputs(&deposits);
+        assert_eq!(old.get_balance(), Amount::from_sat(2000));
+
+        let mut new = Wallet::generate();
+        let sweeps = old.rotate(&mut new, FeeRate::from_sat_per_kvb(0));
+        assert_eq!(sweeps.len(), 1);
+        assert_eq!(sweeps[0].inputs.len(), 2);
+        assert_eq!(sweeps[0].outputs[0].value, Amount::from_sat(2000));
+        assert_eq!(old.get_balance(), Amount::ZERO);
+    }
+
+    #[test]
+    fn deposits_are_spent_like_any_other_coin() {
+        use crate::blockchain::BlockChain;
+        use crate::builder::BlockBuilder;
+        use crate::mock_client::MockMainClient;
+
+        let mut wallet = Wallet::default();
+        let address = wallet.generate_address();
+        let mainchain = MockMainClient::new();
+        let mut blockchain = BlockChain::<Signature, Output>::new().with_deposit_maturity(1);
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint::default());
+        blockchain.add_deposits(DepositsChunk {
+            outputs: HashMap::from([(
+                deposit,
+                DepositOutput {
+                    address,
+                    value: Amount::from_sat(1000),
+                },
+            )]),
+            deposits: vec![],
+        });
+        let sync = |wallet: &mut Wallet, blockchain: &BlockChain<Signature, Output>| {
+            wallet.add_deposit_outputs(&blockchain.peg.deposit_outputs);
+            wallet.add_outputs(&blockchain.outputs);
+            wallet.remove_spent(&blockchain.unspent_outpoints);
+        };
+        sync(&mut wallet, &blockchain);
+        assert_eq!(wallet.get_balance(), Amount::ZERO);
+        // Matures once the chain is anchored a mainchain block past genesis.
+        let (header, body) = BlockBuilder::on(&blockchain).build();
+        let anchor = mainchain.anchor(&header.hash());
+        blockchain
+            .connect_block(&header, &body, Some(&anchor))
+            .unwrap();
+        sync(&mut wallet, &blockchain);
+        assert_eq!(wallet.get_balance(), Amount::from_sat(1000));
+
+        let output = Output {
+            address: [1; 32].into(),
+            value: Amount::from_sat(900),
+        };
+        let pay = wallet
+            .create_transaction(vec![output], Amount::from_sat(100))
+            .unwrap();
+        assert_eq!(pay.inputs, vec![deposit]);
+        assert_eq!(blockchain.validate_transaction(&pay), Ok(()));
+        let mut stolen = pay.clone();
+        stolen.signatures[0] = Signature::new(&crate::builder::keypair([2; 32]), &stolen);
+        assert_eq!(
+            blockchain.validate_transaction(&stolen),
+            Err("addresses don't match".into())
+        );
+
+        let (header, body) = BlockBuilder::on(&blockchain)
+            .transaction(pay.clone())
+            .build();
+        blockchain.connect_block(&header, &body, None).unwrap();
+        sync(&mut wallet, &blockchain);
+        assert!(wallet.outputs.is_empty());
+        // Reorged out, the deposit is the wallet's to spend again.
+        blockchain.disconnect_block(&header, &body);
+        wallet.abandon(&pay);
+        sync(&mut wallet, &blockchain);
+        assert_eq!(wallet.get_balance(), Amount::from_sat(1000));
     }
 }
@@ -0,0 +1,258 @@
+use crate::backend::MainchainBackend;
+use crate::client::{Error, FailedWithdrawal, SpentWithdrawal};
+use crate::types::{Deposit, DepositsChunk};
+#[cfg(feature = "zmq")]
+use crate::zmq_listener::ZmqListener;
+use std::collections::HashSet;
+use std::time::Duration;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DEPOSITS_PAGE_SIZE: usize = 1000;
+// Mainchain blocks a deposit has to be buried under before it is no longer
+// checked on reorgs, reorgs deeper than this aren't followed.
+const MAX_REORG_DEPTH: usize = 100;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Tip {
+    pub height: usize,
+    pub block_hash: bitcoin::BlockHash,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Reorg {
+    pub disconnected: Tip,
+    pub new_tip: Tip,
+}
+
+type Callback<'a, T> = Box<dyn FnMut(&T) + 'a>;
+
+// Keeps track of the mainchain tip and notifies registered callbacks about
+// everything that changed since the last seen block.
+pub struct MainchainWatcher<'a, C: MainchainBackend> {
+    client: &'a C,
+    poll_interval: Duration,
+    tip: Option<Tip>,
+    // Processed deposits less than MAX_REORG_DEPTH blocks deep and the last
+    // one, oldest first, checked against the main chain on every reorg.
+    deposits: Vec<Deposit>,
+    spent_withdrawals: HashSet<bitcoin::Txid>,
+    failed_withdrawals: HashSet<bitcoin::Txid>,
+    on_deposits: Vec<Callback<'a, DepositsChunk>>,
+    on_deposits_disconnected: Vec<Callback<'a, Vec<Deposit>>>,
+    on_withdrawals: Vec<Callback<'a, Vec<SpentWithdrawal>>>,
+    on_failed_withdrawals: Vec<Callback<'a, Vec<FailedWithdrawal>>>,
+    on_reorg: Vec<Callback<'a, Reorg>>,
+    #[cfg(feature = "zmq")]
+    listener: Option<ZmqListener>,
+}
+
+impl<'a, C: MainchainBackend> MainchainWatcher<'a, C> {
+    pub fn new(client: &'a C, last_deposit: Option<Deposit>) -> Self {
+        Self {
+            client,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            tip: None,
+            deposits: last_deposit.into_iter().collect(),
+            spent_withdrawals: HashSet::new(),
+            failed_withdrawals: HashSet::new(),
+            on_deposits: vec![],
+            on_deposits_disconnected: vec![],
+            on_withdrawals: vec![],
+            on_failed_withdrawals: vec![],
+            on_reorg: vec![],
+            #[cfg(feature = "zmq")]
+            listener: None,
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    #[cfg(feature = "zmq")]
+    pub fn with_zmq(mut self, listener: ZmqListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    pub fn on_deposits(&mut self, callback: impl FnMut(&DepositsChunk) + 'a) {
+        self.on_deposits.push(Box::new(callback));
+    }
+
+    // Called with the deposits, newest first, whose mainchain block is no
+    // longer in the main chain.
+    pub fn on_deposits_disconnected(&mut self, callback: impl FnMut(&Vec<Deposit>) + 'a) {
+        self.on_deposits_disconnected.push(Box::new(callback));
+    }
+
+    pub fn on_withdrawals(&mut self, callback: impl FnMut(&Vec<SpentWithdrawal>) + 'a) {
+        self.on_withdrawals.push(Box::new(callback));
+    }
+
+    pub fn on_failed_withdrawals(&mut self, callback: impl FnMut(&Vec<FailedWithdrawal>) + 'a) {
+        self.on_failed_withdrawals.push(Box::new(callback));
+    }
+
+    pub fn on_reorg(&mut self, callback: impl FnMut(&Reorg) + 'a) {
+        self.on_reorg.push(Box::new(callback));
+    }
+
+    pub fn tip(&self) -> Option<Tip> {
+        self.tip
+    }
+
+    // Checks the mainchain once, returns true if a new tip was processed.
+    pub fn poll(&mut self) -> Result<bool, Error> {
+        let height = self.client.get_block_count()?;
+        let block_hash = self.client.get_block_hash(height)?;
+        let new_tip = Tip { height, block_hash };
+        if self.tip == Some(new_tip) {
+            return Ok(false);
+        }
+        if let Some(old_tip) = self.tip {
+            let still_connected = old_tip.height <= height
+                && self.client.get_block_hash(old_tip.height)? == old_tip.block_hash;
+            if !still_connected {
+                let reorg = Reorg {
+                    disconnected: old_tip,
+                    new_tip,
+                };
+                for callback in &mut self.on_reorg {
+                    callback(&reorg);
+                }
+                self.disconnect_deposits()?;
+            }
+        }
+        self.process_deposits(height)?;
+        self.process_withdrawals()?;
+        self.tip = Some(new_tip);
+        Ok(true)
+    }
+
+    // Blocks until a new mainchain block is announced over zmq, or until the
+    // poll interval elapses if there is no zmq listener.
+    pub fn wait(&self) {
+        #[cfg(feature = "zmq")]
+        if let Some(listener) = &self.listener {
+            if listener.is_running() {
+                listener.recv_timeout(self.poll_interval);
+                // Drain notifications that arrived in the meantime, a single
+                // poll will process all of them.
+                while listener.try_recv().is_some() {}
+                return;
+            }
+        }
+        std::thread::sleep(self.poll_interval);
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        loop {
+            self.poll()?;
+            self.wait();
+        }
+    }
+
+    fn process_deposits(&mut self, height: usize) -> Result<(), Error> {
+        let last_deposit = self.deposits.last().cloned();
+        for deposits in self.client.deposit_pages(last_deposit, DEPOSITS_PAGE_SIZE) {
+            let deposits = deposits?;
+            self.deposits.extend(deposits.deposits.iter().cloned());
+            for callback in &mut self.on_deposits {
+                callback(&deposits);
+            }
+        }
+        // The last deposit stays, the next poll picks up after it.
+        let buried = self
+            .deposits
+            .partition_point(|deposit| deposit.main_height + MAX_REORG_DEPTH <= height)
+            .min(self.deposits.len().saturating_sub(1));
+        self.deposits.drain(..buried);
+        Ok(())
+    }
+
+    // Deposits form a chain, so once a deposit in the main chain is found all
+    // older ones are in it as well.
+    fn disconnect_deposits(&mut self) -> Result<(), Error> {
+        let mut disconnected = vec![];
+        while let Some(deposit) = self.deposits.last() {
+            let header = self.client.get_block_header(&deposit.main_block_hash)?;
+            if header.confirmations >= 0 {
+                break;
+            }
+            disconnected.extend(self.deposits.pop());
+        }
+        if disconnected.is_empty() {
+            return Ok(());
+        }
+        for callback in &mut self.on_deposits_disconnected {
+            callback(&disconnected);
+        }
+        Ok(())
+    }
+
+    fn process_withdrawals(&mut self) -> Result<(), Error> {
+        let withdrawals: Vec<SpentWithdrawal> = self
+            .client
+            .get_spent_withdrawals()?
+            .into_iter()
+            .filter(|withdrawal| self.spent_withdrawals.insert(withdrawal.hash))
+            .collect();
+        if !withdrawals.is_empty() {
+            for callback in &mut self.on_withdrawals {
+                callback(&withdrawals);
+            }
+        }
+        let failed_withdrawals: Vec<FailedWithdrawal> = self
+            .client
+            .get_failed_withdrawals()?
+            .into_iter()
+            .filter(|withdrawal| self.failed_withdrawals.insert(withdrawal.hash))
+            .collect();
+        if failed_withdrawals.is_empty() {
+            return Ok(());
+        }
+        for callback in &mut self.on_failed_withdrawals {
+            callback(&failed_withdrawals);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_client::MockMainClient;
+    use crate::types::Address;
+
+    #[test]
+    fn buried_deposits_are_forgotten() -> anyhow::Result<()> {
+        let client = MockMainClient::new();
+        let address: Address = [1; 32].into();
+        client.add_deposit(address, 100);
+        client.mine_block();
+        client.add_deposit(address, 50);
+        let mut watcher = MainchainWatcher::new(&client, None);
+        watcher.poll()?;
+        assert_eq!(watcher.deposits.len(), 2);
+        // The first deposit is as deep as reorgs go, the second a block less.
+        for _ in 1..MAX_REORG_DEPTH {
+            client.mine_block();
+        }
+        client.add_deposit(address, 70);
+        watcher.poll()?;
+        let totals: Vec<u64> = watcher
+            .deposits
+            .iter()
+            .map(|deposit| deposit.total)
+            .collect();
+        assert_eq!(totals, [150, 220]);
+        for _ in 0..MAX_REORG_DEPTH {
+            client.mine_block();
+        }
+        watcher.poll()?;
+        assert_eq!(watcher.deposits.len(), 1);
+        assert_eq!(watcher.deposits[0].total, 220);
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use crate::peg::WithdrawalStatus;
+use crate::types::*;
+use serde::Serialize;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Events a subscriber can fall behind by before it is disconnected.
+const MAX_QUEUED_EVENTS: usize = 1024;
+// How long run sleeps when nobody is connecting.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+// Pushed to every subscriber as a JSON text message, tagged with its type:
+//
+//   {"type": "block_connected", "hash": "...", "height": 12}
+//   {"type": "tx_accepted", "txid": "..."}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    BlockConnected {
+        hash: String,
+        height: usize,
+    },
+    BlockDisconnected {
+        hash: String,
+        height: usize,
+    },
+    TxAccepted {
+        txid: String,
+    },
+    WithdrawalStatus {
+        txid: String,
+        vout: u32,
+        status: &'static str,
+        bundle: Option<String>,
+    },
+}
+
+impl Event {
+    pub fn block_connected(block_hash: BlockHash, height: usize) -> Self {
+        Self::BlockConnected {
+            hash: block_hash.to_string(),
+            height,
+        }
+    }
+
+    pub fn block_disconnected(block_hash: BlockHash, height: usize) -> Self {
+        Self::BlockDisconnected {
+            hash: block_hash.to_string(),
+            height,
+        }
+    }
+
+    pub fn tx_accepted(txid: Txid) -> Self {
+        Self::TxAccepted {
+            txid: txid.to_string(),
+        }
+    }
+
+    // Returns None for outpoints that aren't withdrawal outputs.
+    pub fn withdrawal_status(outpoint: &OutPoint, status: &WithdrawalStatus) -> Option<Self> {
+        match outpoint {
+            OutPoint::Withdrawal { txid, vout } => Some(Self::WithdrawalStatus {
+                txid: txid.to_string(),
+                vout: *vout,
+                status: status.name(),
+                bundle: status.bundle().map(|bundle| bundle.to_string()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+type Subscribers = Arc<Mutex<Vec<SyncSender<String>>>>;
+
+// WebSocket endpoint streaming node events, so UIs and trading systems don't
+// have to poll. Subscribers only receive, whatever they send is ignored.
+pub struct EventServer {
+    listener: TcpListener,
+    subscribers: Subscribers,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl EventServer {
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)?;
+        // Accepting doesn't block, so run notices the shutdown flag.
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            subscribers: Arc::default(),
+            shutdown: Arc::default(),
+        })
+    }
+
+    // Setting the flag makes run disconnect every subscriber and return.
+    pub fn with_shutdown(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Handle for the node loop to publish events with.
+    pub fn publisher(&self) -> EventPublisher {
+        EventPublisher {
+            subscribers: self.subscribers.clone(),
+        }
+    }
+
+    // Accepts subscribers, each one is served by its own thread.
+    pub fn run(&self) {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(err) => {
+                    log::debug!("failed to accept event subscriber: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = stream.set_nonblocking(false) {
+                log::debug!("failed to accept event subscriber: {}", err);
+                continue;
+            }
+            // Subscribed before the handshake, so events published once the
+            // client is connected reach it.
+            let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_EVENTS);
+            self.subscribers.lock().unwrap().push(sender);
+            std::thread::spawn(move || {
+                if let Err(err) = serve(stream, receiver) {
+                    log::debug!("event subscriber disconnected: {}", err);
+                }
+            });
+        }
+        // Their threads close the connections once the senders are gone.
+        self.subscribers.lock().unwrap().clear();
+    }
+}
+
+fn serve(stream: TcpStream, events: Receiver<String>) -> Result<(), Error> {
+    let mut websocket =
+        tungstenite::accept(stream).map_err(|err| Error::Handshake(err.to_string()))?;
+    // Ends once the publisher dropped this subscriber.
+    for event in events {
+        websocket.send(tungstenite::Message::Text(event))?;
+    }
+    websocket.close(None)?;
+    websocket.flush()?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct EventPublisher {
+    subscribers: Subscribers,
+}
+
+impl EventPublisher {
+    pub fn publish(&self, event: &Event) {
+        let event = serde_json::to_string(event).expect("failed to serialize event");
+        // Disconnected subscribers and ones too slow to keep up are dropped.
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("websocket handshake failed: {0}")]
+    Handshake(String),
+    #[error("websocket error")]
+    WebSocket(Box<tungstenite::Error>),
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn events_are_pushed_to_subscribers() -> anyhow::Result<()> {
+        let server = EventServer::bind("127.0.0.1:0")?;
+        let addr = server.local_addr()?;
+        let publisher = server.publisher();
+        std::thread::spawn(move || server.run());
+
+        let (mut websocket, _) = tungstenite::connect(format!("ws://{}", addr))?;
+        let block_hash = BlockHash::from([1; 32]);
+        let txid = Txid::from([2; 32]);
+        publisher.publish(&Event::block_connected(block_hash, 1));
+        publisher.publish(&Event::tx_accepted(txid));
+        let outpoint = OutPoint::Withdrawal { txid, vout: 0 };
+        publisher
+            .publish(&Event::withdrawal_status(&outpoint, &WithdrawalStatus::Created).unwrap());
+        publisher.publish(&Event::block_disconnected(block_hash, 1));
+
+        let mut events = vec![];
+        for _ in 0..4 {
+            let message = websocket.read()?;
+            events.push(serde_json::from_str::<Value>(message.to_text()?)?);
+        }
+        assert_eq!(
+            events,
+            [
+                json!({ "type": "block_connected", "hash": block_hash.to_string(), "height": 1 }),
+                json!({ "type": "tx_accepted", "txid": txid.to_string() }),
+                json!({
+                    "type": "withdrawal_status",
+                    "txid": txid.to_string(),
+                    "vout": 0,
+                    "status": "created",
+                    "bundle": null,
+                }),
+                json!({ "type": "block_disconnected", "hash": block_hash.to_string(), "height": 1 }),
+            ]
+        );
+
+        drop(websocket);
+        // Publishing notices the closed connection and forgets the subscriber.
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while publisher.subscriber_count() > 0 && std::time::Instant::now() < deadline {
+            publisher.publish(&Event::tx_accepted(txid));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(publisher.subscriber_count(), 0);
+        Ok(())
+    }
+}
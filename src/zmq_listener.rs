@@ -0,0 +1,141 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Topic {
+    HashBlock,
+    RawBlock,
+}
+
+impl Topic {
+    fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Self::HashBlock => b"hashblock",
+            Self::RawBlock => b"rawblock",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Notification {
+    HashBlock {
+        block_hash: bitcoin::BlockHash,
+        sequence: u32,
+    },
+    RawBlock {
+        block: bitcoin::Block,
+        sequence: u32,
+    },
+}
+
+impl Notification {
+    pub fn block_hash(&self) -> bitcoin::BlockHash {
+        match self {
+            Self::HashBlock { block_hash, .. } => *block_hash,
+            Self::RawBlock { block, .. } => block.block_hash(),
+        }
+    }
+}
+
+// Subscribes to the mainchain node's zmqpubhashblock/zmqpubrawblock
+// endpoints on a background thread and forwards every new block.
+pub struct ZmqListener {
+    receiver: Receiver<Notification>,
+    handle: JoinHandle<Result<(), Error>>,
+}
+
+impl ZmqListener {
+    pub fn new(subscriptions: &[(Topic, &str)]) -> Result<Self, Error> {
+        let context = zmq::Context::new();
+        let socket = context.socket(zmq::SUB)?;
+        for (topic, endpoint) in subscriptions {
+            socket.connect(endpoint)?;
+            socket.set_subscribe(topic.as_bytes())?;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let handle = std::thread::spawn(move || listen(socket, sender));
+        Ok(Self { receiver, handle })
+    }
+
+    pub fn recv(&self) -> Option<Notification> {
+        self.receiver.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Notification> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    pub fn try_recv(&self) -> Option<Notification> {
+        self.receiver.try_recv().ok()
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.handle.is_finished()
+    }
+}
+
+fn listen(socket: zmq::Socket, sender: Sender<Notification>) -> Result<(), Error> {
+    loop {
+        let message = socket.recv_multipart(0)?;
+        let notification = match parse_message(&message) {
+            Ok(notification) => notification,
+            Err(err) => {
+                log::warn!("ignoring malformed zmq message: {}", err);
+                continue;
+            }
+        };
+        // The receiving half was dropped, nobody is listening anymore.
+        if sender.send(notification).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn parse_message(message: &[Vec<u8>]) -> Result<Notification, Error> {
+    let [topic, body, sequence] = message else {
+        return Err(Error::WrongPartCount(message.len()));
+    };
+    let sequence: [u8; 4] = sequence
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::WrongSequenceLength(sequence.len()))?;
+    let sequence = u32::from_le_bytes(sequence);
+    match topic.as_slice() {
+        b"hashblock" => {
+            // Block hashes are published in RPC (reversed) byte order.
+            let mut bytes = body.clone();
+            bytes.reverse();
+            let block_hash = bitcoin::BlockHash::from_slice(&bytes)?;
+            Ok(Notification::HashBlock {
+                block_hash,
+                sequence,
+            })
+        }
+        b"rawblock" => {
+            let block = deserialize(body)?;
+            Ok(Notification::RawBlock { block, sequence })
+        }
+        topic => Err(Error::UnknownTopic(
+            String::from_utf8_lossy(topic).into_owned(),
+        )),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("zmq error")]
+    Zmq(#[from] zmq::Error),
+    #[error("bitcoin encoding error")]
+    BitcoinEncode(#[from] bitcoin::consensus::encode::Error),
+    #[error("invalid block hash")]
+    BlockHash(#[from] bitcoin::hashes::Error),
+    #[error("expected 3 message parts, got {0}")]
+    WrongPartCount(usize),
+    #[error("expected 4 byte sequence number, got {0} bytes")]
+    WrongSequenceLength(usize),
+    #[error("unknown topic {0}")]
+    UnknownTopic(String),
+}